@@ -0,0 +1,362 @@
+//! # comet-derive
+//!
+//! Derive macros for [Trace](../comet/api/trait.Trace.html), [Finalize](../comet/api/trait.Finalize.html) and
+//! [Collectable](../comet/api/trait.Collectable.html). These are re-exported from `comet::api` so you do not need to depend on this
+//! crate directly.
+//!
+//! ```ignore
+//! use comet::api::{Collectable, Finalize, Gc, Trace};
+//!
+//! #[derive(Trace, Finalize, Collectable)]
+//! pub enum Node<H: GcBase> {
+//!     None,
+//!     Some {
+//!         next: Gc<Node<H>, H>,
+//!         #[unsafe_ignore_trace]
+//!         value: i64,
+//!     },
+//! }
+//! ```
+//!
+//! # Attributes
+//! - `#[unsafe_ignore_trace]` on a field skips it when tracing. It is UB to put it on a field that might contain GC pointers.
+//! - `#[collectable(allocation_size = path)]` on a type overrides [Collectable::allocation_size](../comet/api/trait.Collectable.html#method.allocation_size).
+//!   `path` must point to a function with `fn(&Self) -> usize` signature.
+//!
+//! No bounds are added to generic parameters. If a field has generic type `T` you must declare `T: Trace` on the type itself:
+//! ```compile_fail
+//! use comet::api::Trace;
+//!
+//! #[derive(Trace)]
+//! struct Pair<T> {
+//!     first: T,
+//!     second: T,
+//! }
+//! ```
+//!
+//! Every traced field must implement `Trace`:
+//! ```compile_fail
+//! use comet::api::Trace;
+//!
+//! struct NotTraced;
+//!
+//! #[derive(Trace)]
+//! struct Holder {
+//!     field: NotTraced,
+//! }
+//! ```
+//!
+//! `Trace` can't be derived for unions, and `collectable` attribute accepts only `allocation_size`:
+//! ```compile_fail
+//! use comet::api::Trace;
+//!
+//! #[derive(Trace)]
+//! union Bits {
+//!     int: u64,
+//!     float: f64,
+//! }
+//! ```
+//! ```compile_fail
+//! use comet::api::{Collectable, Finalize, Trace};
+//!
+//! #[derive(Trace, Finalize, Collectable)]
+//! #[collectable(size = 8)]
+//! struct Buffer {
+//!     value: u64,
+//! }
+//! ```
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input, Attribute, Data, DeriveInput, Error, Fields, Ident, Path, Token,
+};
+
+#[proc_macro_derive(Trace, attributes(unsafe_ignore_trace))]
+pub fn derive_trace(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let (pattern, trace) = trace_fields(&data.fields);
+            quote! {
+                let Self #pattern = self;
+                #trace
+            }
+        }
+        Data::Enum(data) => {
+            let arms = data.variants.iter().map(|variant| {
+                let ident = &variant.ident;
+                let (pattern, trace) = trace_fields(&variant.fields);
+                quote! {
+                    Self::#ident #pattern => {
+                        #trace
+                    }
+                }
+            });
+            if data.variants.is_empty() {
+                quote! { match *self {} }
+            } else {
+                quote! {
+                    match self {
+                        #(#arms)*
+                    }
+                }
+            }
+        }
+        Data::Union(data) => {
+            return Error::new_spanned(
+                data.union_token,
+                "Trace cannot be derived for unions, implement it by hand",
+            )
+            .to_compile_error()
+            .into();
+        }
+    };
+
+    quote! {
+        unsafe impl #impl_generics ::comet::api::Trace for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn trace(&mut self, vis: &mut dyn ::comet::api::Visitor) {
+                #body
+            }
+        }
+    }
+    .into()
+}
+
+#[proc_macro_derive(Finalize)]
+pub fn derive_finalize(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    quote! {
+        unsafe impl #impl_generics ::comet::api::Finalize for #name #ty_generics #where_clause {}
+    }
+    .into()
+}
+
+#[proc_macro_derive(Collectable, attributes(collectable))]
+pub fn derive_collectable(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let mut allocation_size = None;
+    for attr in input.attrs.iter().filter(|attr| attr.path.is_ident("collectable")) {
+        match parse_collectable_attr(attr) {
+            Ok(CollectableAttr::AllocationSize(path)) => allocation_size = Some(path),
+            Err(err) => return err.to_compile_error().into(),
+        }
+    }
+
+    let allocation_size = allocation_size.map(|path| {
+        quote! {
+            #[inline]
+            fn allocation_size(&self) -> usize {
+                #path(self)
+            }
+        }
+    });
+
+    quote! {
+        impl #impl_generics ::comet::api::Collectable for #name #ty_generics #where_clause {
+            #allocation_size
+        }
+    }
+    .into()
+}
+
+/// Builds destructuring pattern for `fields` and list of `Trace::trace` calls for every field that is not marked
+/// with `#[unsafe_ignore_trace]`.
+fn trace_fields(fields: &Fields) -> (TokenStream2, TokenStream2) {
+    let bindings = (0..fields.len())
+        .map(|i| format_ident!("__field{}", i))
+        .collect::<Vec<_>>();
+
+    let pattern = match fields {
+        Fields::Named(named) => {
+            let names = named.named.iter().map(|field| field.ident.as_ref().unwrap());
+            quote! { { #(#names: #bindings),* } }
+        }
+        Fields::Unnamed(_) => quote! { ( #(#bindings),* ) },
+        Fields::Unit => quote! {},
+    };
+
+    let trace = fields
+        .iter()
+        .zip(bindings.iter())
+        .filter(|(field, _)| !field.attrs.iter().any(is_ignore_trace))
+        .map(|(_, binding)| {
+            quote! {
+                ::comet::api::Trace::trace(#binding, vis);
+            }
+        });
+
+    (pattern, quote! { #(#trace)* })
+}
+
+fn is_ignore_trace(attr: &Attribute) -> bool {
+    attr.path.is_ident("unsafe_ignore_trace")
+}
+
+enum CollectableAttr {
+    AllocationSize(Path),
+}
+
+impl Parse for CollectableAttr {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key: Ident = input.parse()?;
+        if key == "allocation_size" {
+            input.parse::<Token![=]>()?;
+            Ok(Self::AllocationSize(input.parse()?))
+        } else {
+            Err(Error::new_spanned(
+                key,
+                "unknown collectable attribute, expected `allocation_size`",
+            ))
+        }
+    }
+}
+
+fn parse_collectable_attr(attr: &Attribute) -> syn::Result<CollectableAttr> {
+    attr.parse_args()
+}
+
+#[cfg(test)]
+mod tests {
+    use comet::api::{Collectable, Finalize, HeapObjectHeader, Trace, Visitor};
+    use std::{cell::Cell, ptr::NonNull, rc::Rc};
+
+    /// Field that counts how many times it was traced.
+    #[derive(Clone, Default)]
+    struct Probe(Rc<Cell<usize>>);
+
+    impl Probe {
+        fn traced(&self) -> usize {
+            self.0.get()
+        }
+    }
+
+    unsafe impl Trace for Probe {
+        fn trace(&mut self, _vis: &mut dyn Visitor) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    struct NoopVisitor;
+
+    impl Visitor for NoopVisitor {
+        fn mark_object(&mut self, _root: &mut NonNull<HeapObjectHeader>) {}
+    }
+
+    #[derive(Trace, Finalize, Collectable)]
+    struct Named {
+        first: Probe,
+        #[unsafe_ignore_trace]
+        ignored: Probe,
+        second: Option<Probe>,
+    }
+
+    #[derive(Trace, Finalize, Collectable)]
+    struct Tuple(Probe, #[unsafe_ignore_trace] Probe, Vec<Probe>);
+
+    #[derive(Trace, Finalize, Collectable)]
+    struct Unit;
+
+    #[derive(Trace, Finalize, Collectable)]
+    enum Shape {
+        Empty,
+        Tuple(Probe, Probe),
+        Named {
+            #[unsafe_ignore_trace]
+            ignored: Probe,
+            traced: Probe,
+        },
+    }
+
+    #[derive(Trace, Finalize, Collectable)]
+    struct Pair<T: Trace + 'static, U: Trace + 'static>
+    where
+        U: Clone,
+    {
+        first: T,
+        second: U,
+    }
+
+    #[derive(Trace, Finalize, Collectable)]
+    #[collectable(allocation_size = Bytes::size)]
+    struct Bytes {
+        #[unsafe_ignore_trace]
+        len: usize,
+    }
+
+    impl Bytes {
+        fn size(&self) -> usize {
+            std::mem::size_of::<Self>() + self.len
+        }
+    }
+
+    #[test]
+    fn test_derive_struct() {
+        let probe = Probe::default();
+        let ignored = Probe::default();
+        let mut named = Named {
+            first: probe.clone(),
+            ignored: ignored.clone(),
+            second: Some(probe.clone()),
+        };
+        named.trace(&mut NoopVisitor);
+        assert_eq!(probe.traced(), 2);
+        assert_eq!(ignored.traced(), 0);
+
+        let mut tuple = Tuple(probe.clone(), ignored.clone(), vec![probe.clone(); 3]);
+        tuple.trace(&mut NoopVisitor);
+        assert_eq!(probe.traced(), 6);
+        assert_eq!(ignored.traced(), 0);
+
+        Unit.trace(&mut NoopVisitor);
+        assert_eq!(Unit.allocation_size(), 0);
+    }
+
+    #[test]
+    fn test_derive_enum() {
+        let probe = Probe::default();
+        let ignored = Probe::default();
+        Shape::Empty.trace(&mut NoopVisitor);
+        Shape::Tuple(probe.clone(), probe.clone()).trace(&mut NoopVisitor);
+        assert_eq!(probe.traced(), 2);
+        Shape::Named {
+            ignored: ignored.clone(),
+            traced: probe.clone(),
+        }
+        .trace(&mut NoopVisitor);
+        assert_eq!(probe.traced(), 3);
+        assert_eq!(ignored.traced(), 0);
+    }
+
+    #[test]
+    fn test_derive_generics() {
+        let probe = Probe::default();
+        let mut pair = Pair {
+            first: probe.clone(),
+            second: vec![probe.clone(), probe.clone()],
+        };
+        pair.trace(&mut NoopVisitor);
+        assert_eq!(probe.traced(), 3);
+        assert_eq!(
+            pair.allocation_size(),
+            std::mem::size_of::<Pair<Probe, Vec<Probe>>>()
+        );
+    }
+
+    #[test]
+    fn test_derive_allocation_size() {
+        let bytes = Bytes { len: 100 };
+        assert_eq!(bytes.allocation_size(), std::mem::size_of::<Bytes>() + 100);
+    }
+}
@@ -6,3 +6,6 @@ pub mod array;
 pub mod hash;
 pub mod string;
 pub mod vector;
+pub mod weak_hash;
+
+pub use weak_hash::WeakHashMap;
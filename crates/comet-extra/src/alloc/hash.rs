@@ -12,25 +12,15 @@ use std::{
 };
 const THRESHOLD: f64 = 0.75;
 
+#[derive(Trace, Finalize, Collectable)]
 struct Entry<Key: Trace + 'static, Value: Trace + 'static, H: GcBase> {
     key: Key,
     value: Value,
+    #[unsafe_ignore_trace]
     hash: u64,
     next: Option<Gc<Self, H>>,
 }
 
-unsafe impl<Key: Trace, Value: Trace, H: GcBase> Trace for Entry<Key, Value, H> {
-    fn trace(&mut self, vis: &mut dyn comet::api::Visitor) {
-        self.key.trace(vis);
-        self.value.trace(vis);
-        self.next.trace(vis);
-    }
-}
-
-unsafe impl<Key: Trace, Value: Trace, H: GcBase> Finalize for Entry<Key, Value, H> {}
-
-impl<Key: Trace + 'static, Value: Trace + 'static, H: GcBase> Collectable for Entry<Key, Value, H> {}
-
 pub struct HashMap<Key: Trace + 'static, Value: Trace + 'static, H: GcBase, S = RandomState> {
     hash_builder: S,
     len: usize,
@@ -355,7 +345,12 @@ impl<Key: Trace + 'static, Value: Trace + 'static, H: GcBase, S: 'static> Collec
 }
 #[cfg(test)]
 mod test_map {
-    use comet::letroot;
+    use comet::{
+        api::Identity,
+        gc_base::AllocationSpace,
+        letroot,
+        semispace::{instantiate_semispace, SemiSpace},
+    };
 
     use crate::{alloc::hash::HashMap, create_heap_for_tests};
 
@@ -437,6 +432,30 @@ mod test_map {
             }
         }
     }
+
+    #[test]
+    fn test_identity_keys() {
+        let mut heap = instantiate_semispace(4 * 1024 * 1024);
+        letroot!(
+            m = heap.shadow_stack(),
+            HashMap::<Identity<i32, SemiSpace>, i32, _>::new(&mut heap)
+        );
+        letroot!(keys = heap.shadow_stack(), Vec::new());
+        for i in 0..100 {
+            let key = heap.allocate(i % 10, AllocationSpace::New);
+            keys.push(key);
+            assert!(m.insert(&mut heap, Identity(key), i));
+        }
+        // keys are moved by every collection, their identity hashes must stay the same.
+        for _ in 0..3 {
+            heap.collect(&mut []);
+            for (i, key) in keys.iter().enumerate() {
+                assert_eq!(*m.get(&Identity(*key)).unwrap(), i as i32);
+            }
+        }
+        let other = heap.allocate(5, AllocationSpace::New);
+        assert!(m.get(&Identity(other)).is_none());
+    }
 }
 
 pub struct HashSet<K: Trace + 'static, H: GcBase, S = RandomState> {
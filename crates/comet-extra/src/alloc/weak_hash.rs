@@ -0,0 +1,299 @@
+//! Hash map with weak keys.
+//!
+//! Each entry of [WeakHashMap] is an [Ephemeron]: entry does not keep its key alive and keeps its value alive only while
+//! the key is alive. Once key dies GC clears the entry and it is removed from the map lazily.
+use super::array::Array;
+use super::hash::{make_hash, DefaultHashBuilder};
+use comet::{
+    api::{Collectable, Ephemeron, Finalize, Gc, Trace},
+    gc_base::GcBase,
+    letroot,
+    mutator::MutatorRef,
+};
+use std::hash::{BuildHasher, Hash};
+const THRESHOLD: f64 = 0.75;
+
+#[derive(Trace, Finalize, Collectable)]
+struct Entry<Key: Collectable + 'static, Value: Collectable + 'static, H: GcBase> {
+    ephemeron: Ephemeron<Key, Value, H>,
+    #[unsafe_ignore_trace]
+    hash: u64,
+    next: Option<Gc<Self, H>>,
+}
+
+/// Hash map that holds its keys weakly, like JS `WeakMap`. Value of an entry is kept alive only while its key is alive.
+///
+/// Keys are hashed and compared by value of `Key`.
+pub struct WeakHashMap<
+    Key: Collectable + 'static,
+    Value: Collectable + 'static,
+    H: GcBase,
+    S = DefaultHashBuilder,
+> {
+    hash_builder: S,
+    len: usize,
+    table: Gc<Array<Option<Gc<Entry<Key, Value, H>, H>>>, H>,
+}
+
+impl<Key: Collectable + 'static, Value: Collectable + 'static, H: GcBase>
+    WeakHashMap<Key, Value, H, DefaultHashBuilder>
+{
+    pub fn new(mutator: &mut MutatorRef<H>) -> Self {
+        Self::with_hasher(mutator, DefaultHashBuilder::default())
+    }
+
+    pub fn with_capacity(mutator: &mut MutatorRef<H>, capacity: usize) -> Self {
+        Self::with_capacity_and_hasher(mutator, capacity, DefaultHashBuilder::default())
+    }
+}
+
+impl<Key: Collectable + 'static, Value: Collectable + 'static, H: GcBase, S>
+    WeakHashMap<Key, Value, H, S>
+{
+    pub fn with_capacity_and_hasher(
+        mutator: &mut MutatorRef<H>,
+        mut capacity: usize,
+        hash_builder: S,
+    ) -> Self {
+        if capacity < 1 {
+            capacity = 4;
+        }
+        Self {
+            hash_builder,
+            len: 0,
+            table: Array::new_with_default(mutator, capacity),
+        }
+    }
+
+    pub fn with_hasher(mutator: &mut MutatorRef<H>, hash_builder: S) -> Self {
+        Self::with_capacity_and_hasher(mutator, 4, hash_builder)
+    }
+
+    /// Number of entries in the map. Entries whose keys died are removed lazily (on insertion or by
+    /// [remove_dead_entries](Self::remove_dead_entries)) and are counted until then.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.table.len()
+    }
+
+    /// Remove entries whose keys died. Returns number of removed entries.
+    pub fn remove_dead_entries(&mut self, mutator: &mut MutatorRef<H>) -> usize {
+        let mut removed = 0;
+        for i in 0..self.table.len() {
+            let mut prevnode: Option<Gc<Entry<Key, Value, H>, H>> = None;
+            let mut node = self.table[i];
+            while let Some(n) = node {
+                if n.ephemeron.key().is_none() {
+                    if let Some(mut prevnode) = prevnode {
+                        prevnode.next = n.next;
+                        mutator.write_barrier(prevnode.to_dyn());
+                    } else {
+                        self.table[i] = n.next;
+                    }
+                    removed += 1;
+                } else {
+                    prevnode = Some(n);
+                }
+                node = n.next;
+            }
+        }
+        mutator.write_barrier(self.table.to_dyn());
+        self.len -= removed;
+        removed
+    }
+
+    fn resize(&mut self, mutator: &mut MutatorRef<H>) {
+        let stack = mutator.shadow_stack();
+        letroot!(prev_table = stack, self.table);
+        self.table = Array::new_with_default(mutator, self.capacity() * 2);
+        let new_len = self.table.len();
+        let mut node;
+        let mut next;
+        for i in 0..prev_table.len() {
+            node = prev_table[i];
+            while let Some(mut n) = node {
+                next = n.next;
+
+                let pos = (n.hash % new_len as u64) as usize;
+                n.next = self.table[pos];
+                mutator.write_barrier(n.to_dyn());
+                self.table[pos] = Some(n);
+                node = next;
+            }
+        }
+        mutator.write_barrier(self.table.to_dyn());
+    }
+
+    /// Invoke `callback` for each entry whose key is alive.
+    pub fn for_each(&self, mut callback: impl FnMut(Gc<Key, H>, Gc<Value, H>)) {
+        for i in 0..self.table.len() {
+            let mut node = &self.table[i];
+            while let Some(n) = node {
+                if let (Some(key), Some(value)) = (n.ephemeron.key(), n.ephemeron.value()) {
+                    callback(key, value);
+                }
+                node = &n.next;
+            }
+        }
+    }
+}
+
+impl<Key: Collectable + Hash + Eq + 'static, Value: Collectable + 'static, H: GcBase, S>
+    WeakHashMap<Key, Value, H, S>
+where
+    S: BuildHasher,
+{
+    fn find(&self, key: &Key) -> Option<Gc<Entry<Key, Value, H>, H>> {
+        let hash = make_hash::<Key, Key, S>(&self.hash_builder, key);
+        let position = (hash % self.table.len() as u64) as usize;
+        let mut node = self.table.at(position);
+        while let Some(n) = node {
+            if n.hash == hash {
+                if let Some(k) = n.ephemeron.key() {
+                    if *k == *key {
+                        return Some(*n);
+                    }
+                }
+            }
+            node = &n.next;
+        }
+        None
+    }
+
+    pub fn get(&self, key: &Key) -> Option<Gc<Value, H>> {
+        self.find(key).and_then(|entry| entry.ephemeron.value())
+    }
+
+    pub fn contains_key(&self, key: &Key) -> bool {
+        self.find(key).is_some()
+    }
+
+    /// Insert `value` for `key`. Returns `true` if key was not present in the map.
+    pub fn insert(
+        &mut self,
+        mutator: &mut MutatorRef<H>,
+        key: Gc<Key, H>,
+        value: Gc<Value, H>,
+    ) -> bool {
+        let stack = mutator.shadow_stack();
+        // GC might happen while allocating ephemeron or entry, protect key and value.
+        letroot!(key = stack, key);
+        letroot!(value = stack, value);
+        if let Some(entry) = self.find(&**key) {
+            letroot!(entry = stack, entry);
+            let ephemeron = mutator.allocate_ephemeron(*key, *value);
+            entry.ephemeron = ephemeron;
+            mutator.write_barrier(entry.to_dyn());
+            return false;
+        }
+
+        let hash = make_hash::<Key, Key, S>(&self.hash_builder, &**key);
+        if self.len >= (self.table.len() as f64 * THRESHOLD) as usize
+            && self.remove_dead_entries(mutator) == 0
+        {
+            self.resize(mutator);
+        }
+        let position = (hash % self.table.len() as u64) as usize;
+        let ephemeron = mutator.allocate_ephemeron(*key, *value);
+        letroot!(ephemeron = stack, ephemeron);
+        let node = mutator.allocate(
+            Entry::<Key, Value, H> {
+                ephemeron: *ephemeron,
+                hash,
+                next: *self.table.at(position),
+            },
+            comet::gc_base::AllocationSpace::New,
+        );
+        *self.table.at_mut(position) = Some(node);
+        mutator.write_barrier(self.table.to_dyn());
+        self.len += 1;
+        true
+    }
+
+    pub fn remove(&mut self, mutator: &mut MutatorRef<H>, key: &Key) -> bool {
+        let hash = make_hash::<Key, Key, S>(&self.hash_builder, key);
+        let position = (hash % self.table.len() as u64) as usize;
+        let mut node = *self.table.at(position);
+        let mut prevnode: Option<Gc<Entry<Key, Value, H>, H>> = None;
+        while let Some(n) = node {
+            let found = n.hash == hash && n.ephemeron.key().map_or(false, |k| *k == *key);
+            if found {
+                if let Some(mut prevnode) = prevnode {
+                    prevnode.next = n.next;
+                    mutator.write_barrier(prevnode.to_dyn());
+                } else {
+                    *self.table.at_mut(position) = n.next;
+                    mutator.write_barrier(self.table.to_dyn());
+                }
+                n.ephemeron.clear();
+                self.len -= 1;
+                return true;
+            }
+            prevnode = Some(n);
+            node = n.next;
+        }
+        false
+    }
+}
+
+unsafe impl<Key: Collectable + 'static, Value: Collectable + 'static, H: GcBase, S> Trace
+    for WeakHashMap<Key, Value, H, S>
+{
+    fn trace(&mut self, vis: &mut dyn comet::api::Visitor) {
+        self.table.trace(vis);
+    }
+}
+unsafe impl<Key: Collectable + 'static, Value: Collectable + 'static, H: GcBase, S> Finalize
+    for WeakHashMap<Key, Value, H, S>
+{
+}
+
+impl<Key: Collectable + 'static, Value: Collectable + 'static, H: GcBase, S: 'static> Collectable
+    for WeakHashMap<Key, Value, H, S>
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use comet::{gc_base::AllocationSpace, letroot};
+
+    use crate::{alloc::weak_hash::WeakHashMap, create_heap_for_tests};
+
+    #[test]
+    fn test_dead_keys_are_removed() {
+        let mut heap = create_heap_for_tests();
+        letroot!(
+            map = heap.shadow_stack(),
+            WeakHashMap::<i32, i32, _>::new(&mut heap)
+        );
+        letroot!(
+            live_key = heap.shadow_stack(),
+            heap.allocate(1, AllocationSpace::New)
+        );
+        let value = heap.allocate(10, AllocationSpace::New);
+        assert!(map.insert(&mut heap, *live_key, value));
+        for i in 2..100 {
+            letroot!(
+                key = heap.shadow_stack(),
+                heap.allocate(i, AllocationSpace::New)
+            );
+            let value = heap.allocate(i * 10, AllocationSpace::New);
+            assert!(map.insert(&mut heap, *key, value));
+        }
+        assert_eq!(*map.get(&5).unwrap(), 50);
+
+        heap.full_collection(&mut []);
+
+        assert_eq!(*map.get(&1).unwrap(), 10);
+        assert!(map.get(&5).is_none());
+        assert_eq!(map.remove_dead_entries(&mut heap), 98);
+        assert_eq!(map.len(), 1);
+    }
+}
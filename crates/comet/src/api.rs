@@ -1,12 +1,13 @@
 use std::{
     borrow::{Borrow, BorrowMut},
+    collections::VecDeque,
     hash::Hash,
     hint::unreachable_unchecked,
     marker::PhantomData,
     mem::{size_of, MaybeUninit},
     ops::{Deref, DerefMut, Range},
     ptr::{null_mut, DynMetadata, NonNull},
-    sync::atomic::AtomicU16,
+    sync::atomic::{AtomicU16, AtomicU64},
 };
 
 use crate::{
@@ -18,6 +19,9 @@ use crate::{
 };
 use atomic::Ordering;
 use mopa::mopafy;
+use parking_lot::Mutex;
+
+pub use comet_derive::{Collectable, Finalize, Trace};
 
 /// Indicates that a type can be traced by a garbage collector.
 ///
@@ -116,6 +120,8 @@ pub union VTable {
 
 /// Minimal allocation size in GC heap.
 pub const MIN_ALLOCATION: usize = 8;
+/// Byte that memory reclaimed by GC is filled with in debug builds, see [poison](crate::utils::poison).
+pub const POISON_BYTE: u8 = 0xfb;
 
 impl HeapObjectHeader {
     #[inline]
@@ -126,6 +132,11 @@ impl HeapObjectHeader {
     pub fn is_free(&self) -> bool {
         self.type_id == 0
     }
+    /// Returns true if header is located in memory that was [poisoned](crate::utils::poison) by GC.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.type_id == u32::from_ne_bytes([POISON_BYTE; 4])
+    }
     #[inline(always)]
     pub fn get_dyn(&mut self) -> &mut dyn Collectable {
         unsafe { &mut *std::ptr::from_raw_parts_mut(self.data() as *mut (), self.value.metadata) }
@@ -183,33 +194,45 @@ impl HeapObjectHeader {
     pub fn marked_bit(&self) -> bool {
         MarkBit::decode(self.padding as _) != 0
     }
+    /// Mark bit shares the word with color that concurrent marker updates, so it is cleared and set atomically.
     #[inline(always)]
     pub fn unmark(&mut self) {
-        self.padding = MarkBit::update(self.padding as _, 0) as _;
+        unsafe {
+            let atomic = &*(&self.padding as *const u16 as *const AtomicU16);
+            atomic.fetch_and(!(MarkBit::encode(1) as u16), Ordering::AcqRel);
+        }
     }
     #[inline(always)]
     pub fn set_marked_bit(&mut self) {
-        self.padding = MarkBit::update(self.padding as _, 1) as _;
+        unsafe {
+            let atomic = &*(&self.padding as *const u16 as *const AtomicU16);
+            atomic.fetch_or(MarkBit::encode(1) as u16, Ordering::AcqRel);
+        }
     }
     #[inline(always)]
     pub fn type_id(&self) -> u32 {
         self.type_id
     }
 
+    /// Atomically change color from `current` to `new`. Returns `true` if object does not have `current` color.
     #[inline]
     pub fn set_color(&self, current: u8, new: u8) -> bool {
         unsafe {
             let atomic = &*(&self.padding as *const u16 as *const AtomicU16);
-            let word = atomic.load(atomic::Ordering::Relaxed);
-            match atomic.compare_exchange_weak(
-                ColourBit::update(word as _, current as _) as _,
-                ColourBit::update(word as _, new as _) as _,
-                atomic::Ordering::AcqRel,
-                atomic::Ordering::Relaxed,
-            ) {
-                Ok(_) => false,
-                Err(_) => true,
+            let mut word = atomic.load(atomic::Ordering::Relaxed);
+            // other bits of the word might be updated concurrently, retry until the color itself differs.
+            while ColourBit::decode(word as _) == current as u64 {
+                match atomic.compare_exchange_weak(
+                    word,
+                    ColourBit::update(word as _, new as _) as _,
+                    atomic::Ordering::AcqRel,
+                    atomic::Ordering::Relaxed,
+                ) {
+                    Ok(_) => return false,
+                    Err(actual) => word = actual,
+                }
             }
+            true
         }
     }
     #[inline]
@@ -242,6 +265,74 @@ impl HeapObjectHeader {
     pub fn set_pinned_bit(&mut self, bit: bool) {
         self.padding = Pinned::update(self.padding as _, bit as _) as _;
     }
+    /// Returns `true` if this object is in remembered set of generational heap.
+    #[inline(always)]
+    pub fn remembered_bit(&self) -> bool {
+        Remembered::decode(self.padding as _) != 0
+    }
+
+    #[inline(always)]
+    pub fn set_remembered_bit(&mut self, bit: bool) {
+        self.padding = Remembered::update(self.padding as _, bit as _) as _;
+    }
+    /// Returns `true` if identity hash of this object was requested.
+    #[inline(always)]
+    pub fn hashed_bit(&self) -> bool {
+        Hashed::decode(self.padding as _) != 0
+    }
+    /// Returns `true` if this object was moved after its identity hash was requested. Hash of such object is stored in the
+    /// word right after its data.
+    #[inline(always)]
+    pub fn hashed_and_moved_bit(&self) -> bool {
+        HashedAndMoved::decode(self.padding as _) != 0
+    }
+    /// Identity hash of this object. Hash is derived from address of the object when it is requested for the first time and
+    /// it does not change when object is moved, see [copy_size](Self::copy_size).
+    pub fn identity_hash(&mut self) -> u64 {
+        unsafe {
+            if self.hashed_and_moved_bit() {
+                return self.identity_hash_slot().read();
+            }
+            if !self.hashed_bit() {
+                let atomic = &*(&self.padding as *const u16 as *const AtomicU16);
+                atomic.fetch_or(Hashed::encode(1) as u16, Ordering::AcqRel);
+            }
+        }
+        address_hash(self)
+    }
+    unsafe fn identity_hash_slot(&mut self) -> *mut u64 {
+        let offset = align_usize(size_of::<Self>() + self.get_dyn().allocation_size(), 8);
+        (self as *mut Self).cast::<u8>().add(offset).cast()
+    }
+    /// Number of bytes that must be allocated for a copy of this object. Copy of hashed object that is moved for the first
+    /// time is one word larger than the object: its identity hash is appended to it by [finish_copy](Self::finish_copy).
+    #[inline(always)]
+    pub fn copy_size(&self) -> usize {
+        if self.hashed_bit() && !self.hashed_and_moved_bit() {
+            self.size() + size_of::<u64>()
+        } else {
+            self.size()
+        }
+    }
+    /// Must be invoked by moving collectors on the copy of object that was located at `from` after [size](Self::size) bytes
+    /// of object are copied to memory of [copy_size](Self::copy_size) bytes.
+    ///
+    /// # Safety
+    ///
+    /// `self` must be a copy of `from` with enough space for identity hash.
+    #[inline(always)]
+    pub unsafe fn finish_copy(&mut self, from: *const HeapObjectHeader) {
+        if self.hashed_bit() && !self.hashed_and_moved_bit() {
+            self.set_size(self.size() + size_of::<u64>());
+            self.identity_hash_slot().write(address_hash(from));
+            self.padding = HashedAndMoved::update(self.padding as _, 1) as _;
+        }
+    }
+}
+
+#[inline(always)]
+fn address_hash(object: *const HeapObjectHeader) -> u64 {
+    (object as u64 >> 3).wrapping_mul(0x9e37_79b9_7f4a_7c15)
 }
 
 unsafe impl<T: Collectable + ?Sized, H: GcBase> Finalize for Gc<T, H> {}
@@ -314,6 +405,15 @@ impl<T: Collectable + ?Sized, H: GcBase> Gc<T, H> {
     pub unsafe fn downcast_unchecked<U: Collectable>(&self) -> Gc<U, H> {
         self.downcast().unwrap_or_else(|| unreachable_unchecked())
     }
+    /// Identity hash of the object. Unlike [Hash] implementation of `Gc` it does not depend on value of the object, and
+    /// unlike address of the object it stays the same when object is moved by GC.
+    pub fn identity_hash(&self) -> u64 {
+        unsafe { (*H::ReadBarrier::read_barrier(*self).base.as_ptr()).identity_hash() }
+    }
+    /// Returns `true` if both pointers point to the same object.
+    pub fn ptr_eq<U: Collectable + ?Sized>(self, other: Gc<U, H>) -> bool {
+        H::ReadBarrier::read_barrier(self).base == H::ReadBarrier::read_barrier(other).base
+    }
     /// Returns number of bytes that this GC pointer uses on the heap.
     pub fn allocation_size(&self) -> usize {
         unsafe {
@@ -369,6 +469,16 @@ pub trait Visitor {
     fn mark_weak(&mut self, root: &mut NonNull<HeapObjectHeader>) {
         self.mark_object(root);
     }
+    /// Callback to invoke for key and value of an [Ephemeron]. Ephemeron does not keep its key and value alive, so by default it does nothing.
+    /// Collectors that update references by tracing the heap after marking override it to update key and value as well.
+    fn visit_ephemeron(
+        &mut self,
+        key: &mut NonNull<HeapObjectHeader>,
+        value: &mut NonNull<HeapObjectHeader>,
+    ) {
+        let _ = key;
+        let _ = value;
+    }
 }
 
 impl<T: Collectable + ?Sized, H: GcBase> std::fmt::Pointer for Gc<T, H> {
@@ -396,12 +506,34 @@ unsafe impl<T: Trace> Trace for Option<T> {
 unsafe impl<T: Collectable> Finalize for Option<T> {}
 impl<T: Collectable> Collectable for Option<T> {}
 
+/// Panics if `object` of type `type_name` is located in memory reclaimed by GC. Enabled by `check-dangling` feature, without it
+/// this is no-op.
+#[inline(always)]
+unsafe fn check_dangling(object: *const HeapObjectHeader, type_name: &'static str) {
+    #[cfg(feature = "check-dangling")]
+    if (*object).is_poisoned() || (*object).is_free() {
+        use_after_gc(object, type_name);
+    }
+    let _ = (object, type_name);
+}
+
+#[cfg(feature = "check-dangling")]
+#[cold]
+#[inline(never)]
+fn use_after_gc(object: *const HeapObjectHeader, type_name: &str) -> ! {
+    panic!(
+        "use after GC: Gc<{}> points to {:p} which was reclaimed by GC, most likely it was not rooted (see `letroot!`) when GC happened",
+        type_name, object
+    )
+}
+
 impl<T: Collectable, H: GcBase> Deref for Gc<T, H> {
     type Target = T;
     fn deref(&self) -> &Self::Target {
         unsafe {
             let this: Gc<T, H> = H::ReadBarrier::read_barrier::<T>(*self);
             let base = this.base.as_ptr();
+            check_dangling(base, std::any::type_name::<T>());
             &*(*base).data().cast::<T>()
         }
     }
@@ -412,6 +544,7 @@ impl<T: Collectable, H: GcBase> DerefMut for Gc<T, H> {
         unsafe {
             let this: Gc<T, H> = H::ReadBarrier::read_barrier::<T>(*self);
             let base = this.base.as_ptr();
+            check_dangling(base, std::any::type_name::<T>());
             &mut *((*base).data().cast::<T>() as *mut T)
         }
     }
@@ -490,6 +623,8 @@ unsafe impl<T: Trace> Trace for &mut [T] {
 
 pub struct WeakInner<H: GcBase> {
     pub value: Option<Gc<dyn Collectable, H>>,
+    /// Queue that this reference is enqueued to once it is cleared by GC.
+    pub queue: Option<ReferenceQueue<H>>,
 }
 /// Weak reference objects, which do not prevent their referents from being made finalizable, finalized, and then reclaimed. Weak references are most often used to implement canonicalizing mappings.
 ///
@@ -504,7 +639,11 @@ pub struct Weak<T: Collectable + ?Sized, H: GcBase> {
     marker: PhantomData<T>,
 }
 
-unsafe impl<H: GcBase> Trace for WeakInner<H> {}
+unsafe impl<H: GcBase> Trace for WeakInner<H> {
+    fn trace(&mut self, vis: &mut dyn Visitor) {
+        self.queue.trace(vis);
+    }
+}
 unsafe impl<H: GcBase> Finalize for WeakInner<H> {
     unsafe fn finalize(&mut self) {}
 }
@@ -529,7 +668,10 @@ impl<T: Collectable + ?Sized, H: GcBase> Weak<T, H> {
         let stack = mutator.shadow_stack();
         letroot!(value = stack, value);
         let mut inner = mutator.allocate(
-            WeakInner { value: None },
+            WeakInner {
+                value: None,
+                queue: None,
+            },
             crate::gc_base::AllocationSpace::New,
         );
         inner.value = Some(value.to_dyn());
@@ -539,13 +681,28 @@ impl<T: Collectable + ?Sized, H: GcBase> Weak<T, H> {
             marker: PhantomData,
         }
     }
+    /// Register this reference with `queue`. Reference is enqueued once the garbage collector clears it.
+    pub fn set_queue(mut self, mutator: &mut MutatorRef<H>, queue: ReferenceQueue<H>) {
+        mutator.pre_write_barrier(self.value.to_dyn());
+        self.value.queue = Some(queue);
+        mutator.write_barrier(self.value.to_dyn());
+    }
     /// Clears this reference object.
     ///
     ///
     /// This method is invoked only by mutator code; when the garbage collector clears references it does so directly, without invoking this method.
+    /// Reference that is cleared by this method is not enqueued.
     pub fn clear(mut self) {
         self.value.value = None;
     }
+    /// Returns `true` if both weak references are the same reference object.
+    pub fn ptr_eq<U: Collectable + ?Sized>(self, other: Weak<U, H>) -> bool {
+        self.value.base == other.value.base
+    }
+    /// Returns `true` if this reference was cleared, either by the program or by the garbage collector.
+    pub fn is_cleared(self) -> bool {
+        self.value.value.is_none()
+    }
     /// Returns this weak reference object's referent. If this reference object has been cleared, either by the program or by the garbage collector, then this method returns `None`.
     pub fn upgrade(self) -> Option<Gc<T, H>>
     where
@@ -556,7 +713,8 @@ impl<T: Collectable + ?Sized, H: GcBase> Weak<T, H> {
 
     /// # NOT FOR USE BY REGULAR CODE, ONLY FOR GC IMPLEMENTATIONS!
     ///
-    /// Must be invoked for each weak reference after marking cycle to update weak references.
+    /// Must be invoked for each weak reference after marking cycle to update weak references. Reference that is cleared is enqueued
+    /// to its [ReferenceQueue].
     pub unsafe fn after_mark(
         &mut self,
         process: impl FnOnce(*mut HeapObjectHeader) -> *mut HeapObjectHeader,
@@ -567,6 +725,9 @@ impl<T: Collectable + ?Sized, H: GcBase> Weak<T, H> {
                 let new_header = process(value.base.as_ptr());
                 if new_header.is_null() {
                     self.value.value = None;
+                    if let Some(queue) = self.value.queue {
+                        queue.enqueue(Reference::Weak(self.to_dyn()));
+                    }
                 } else {
                     self.value.value = Some(Gc {
                         base: NonNull::new_unchecked(new_header),
@@ -594,6 +755,776 @@ impl<T: Collectable + ?Sized, H: GcBase> Clone for Weak<T, H> {
 
 impl<T: Collectable + ?Sized, H: GcBase> Copy for Weak<T, H> {}
 
+pub struct SoftRefInner<H: GcBase> {
+    pub value: Option<Gc<dyn Collectable, H>>,
+    /// Value of [soft_ref_clock] at the last access to referent.
+    pub timestamp: u64,
+    /// Queue that this reference is enqueued to once it is cleared by GC.
+    pub queue: Option<ReferenceQueue<H>>,
+}
+
+/// Soft reference objects, which are cleared at the discretion of the garbage collector in response to memory demand.
+/// Soft references are most often used to implement memory-sensitive caches.
+///
+/// Referent of a soft reference that is not reachable otherwise is kept alive until heap is close to its size limit or
+/// the heap runs out of memory. Soft references whose referents were not accessed for a long time are cleared first, see
+/// [SoftRefPolicy].
+pub struct SoftRef<T: Collectable + ?Sized, H: GcBase> {
+    value: Gc<SoftRefInner<H>, H>,
+    marker: PhantomData<T>,
+}
+
+unsafe impl<H: GcBase> Trace for SoftRefInner<H> {
+    fn trace(&mut self, vis: &mut dyn Visitor) {
+        self.queue.trace(vis);
+    }
+}
+unsafe impl<H: GcBase> Finalize for SoftRefInner<H> {
+    unsafe fn finalize(&mut self) {}
+}
+
+impl<H: GcBase> Collectable for SoftRefInner<H> {}
+
+unsafe impl<T: Collectable + ?Sized, H: GcBase> Trace for SoftRef<T, H> {
+    fn trace(&mut self, vis: &mut dyn Visitor) {
+        vis.mark_weak(&mut self.value.base);
+    }
+}
+
+impl<T: Collectable + ?Sized, H: GcBase> SoftRef<T, H> {
+    pub unsafe fn base(self) -> *mut HeapObjectHeader {
+        self.value.base.as_ptr()
+    }
+    pub unsafe fn set_base(&mut self, hdr: *mut HeapObjectHeader) {
+        self.value.base = NonNull::new_unchecked(hdr);
+    }
+    /// Creates a new soft reference that refers to the given object.
+    pub unsafe fn create(mutator: &mut MutatorRef<H>, value: Gc<T, H>) -> Self {
+        let stack = mutator.shadow_stack();
+        letroot!(value = stack, value);
+        let mut inner = mutator.allocate(
+            SoftRefInner {
+                value: None,
+                timestamp: soft_ref_clock(),
+                queue: None,
+            },
+            crate::gc_base::AllocationSpace::New,
+        );
+        inner.value = Some(value.to_dyn());
+        mutator.write_barrier(inner.to_dyn());
+        Self {
+            value: inner,
+            marker: PhantomData,
+        }
+    }
+    /// Register this reference with `queue`. Reference is enqueued once the garbage collector clears it.
+    pub fn set_queue(mut self, mutator: &mut MutatorRef<H>, queue: ReferenceQueue<H>) {
+        mutator.pre_write_barrier(self.value.to_dyn());
+        self.value.queue = Some(queue);
+        mutator.write_barrier(self.value.to_dyn());
+    }
+    /// Clears this reference object. Reference that is cleared by this method is not enqueued.
+    pub fn clear(mut self) {
+        self.value.value = None;
+    }
+    /// Returns `true` if both soft references are the same reference object.
+    pub fn ptr_eq<U: Collectable + ?Sized>(self, other: SoftRef<U, H>) -> bool {
+        self.value.base == other.value.base
+    }
+    /// Returns this soft reference object's referent and marks it as recently used. If this reference object has been cleared,
+    /// either by the program or by the garbage collector, then this method returns `None`.
+    pub fn upgrade(mut self) -> Option<Gc<T, H>>
+    where
+        T: Sized,
+    {
+        let value = self.value.value?;
+        self.value.timestamp = soft_ref_clock();
+        Some(unsafe { value.downcast_unchecked() })
+    }
+    /// Value of [soft_ref_clock] at the last access to referent.
+    pub fn timestamp(self) -> u64 {
+        self.value.timestamp
+    }
+
+    /// # NOT FOR USE BY REGULAR CODE, ONLY FOR GC IMPLEMENTATIONS!
+    ///
+    /// Must be invoked for each soft reference after marking cycle and [trace_soft_refs] to update soft references. Reference
+    /// that is cleared is enqueued to its [ReferenceQueue].
+    pub unsafe fn after_mark(
+        &mut self,
+        process: impl FnOnce(*mut HeapObjectHeader) -> *mut HeapObjectHeader,
+    ) {
+        if let Some(value) = self.value.value {
+            let new_header = process(value.base.as_ptr());
+            if new_header.is_null() {
+                self.value.value = None;
+                if let Some(queue) = self.value.queue {
+                    queue.enqueue(Reference::Soft(self.to_dyn()));
+                }
+            } else {
+                self.value.value = Some(Gc {
+                    base: NonNull::new_unchecked(new_header),
+                    marker: PhantomData,
+                });
+            }
+        }
+    }
+
+    pub fn to_dyn(self) -> SoftRef<dyn Collectable, H> {
+        SoftRef {
+            value: H::ReadBarrier::read_barrier(self.value),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Collectable + ?Sized, H: GcBase> Clone for SoftRef<T, H> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: Collectable + ?Sized, H: GcBase> Copy for SoftRef<T, H> {}
+
+/// Reference object that was cleared by the garbage collector, see [ReferenceQueue].
+pub enum Reference<H: GcBase> {
+    Weak(Weak<dyn Collectable, H>),
+    Soft(SoftRef<dyn Collectable, H>),
+}
+
+impl<H: GcBase> Reference<H> {
+    fn base_mut(&mut self) -> &mut NonNull<HeapObjectHeader> {
+        match self {
+            Self::Weak(weak) => &mut weak.value.base,
+            Self::Soft(soft) => &mut soft.value.base,
+        }
+    }
+}
+
+impl<H: GcBase> Clone for Reference<H> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<H: GcBase> Copy for Reference<H> {}
+
+pub struct ReferenceQueueInner<H: GcBase> {
+    references: Mutex<VecDeque<Reference<H>>>,
+}
+
+unsafe impl<H: GcBase> Trace for ReferenceQueueInner<H> {
+    fn trace(&mut self, vis: &mut dyn Visitor) {
+        // enqueued references are kept alive until they are polled.
+        for reference in self.references.get_mut().iter_mut() {
+            vis.mark_object(reference.base_mut());
+        }
+    }
+}
+unsafe impl<H: GcBase> Finalize for ReferenceQueueInner<H> {}
+impl<H: GcBase> Collectable for ReferenceQueueInner<H> {}
+
+/// Reference queue, to which registered reference objects are appended by the garbage collector after their referents are
+/// cleared. Mutator polls the queue to find out which references were cleared, e.g to remove dead entries from side tables.
+///
+/// References are registered with a queue at creation, see [MutatorRef::allocate_weak_with_queue] and
+/// [MutatorRef::allocate_soft_with_queue]. Enqueued reference is kept alive by the queue until it is polled.
+pub struct ReferenceQueue<H: GcBase> {
+    inner: Gc<ReferenceQueueInner<H>, H>,
+}
+
+impl<H: GcBase> ReferenceQueue<H> {
+    /// Creates new empty queue.
+    pub fn new(mutator: &mut MutatorRef<H>) -> Self {
+        Self {
+            inner: mutator.allocate(
+                ReferenceQueueInner {
+                    references: Mutex::new(VecDeque::new()),
+                },
+                crate::gc_base::AllocationSpace::New,
+            ),
+        }
+    }
+    /// Removes the next cleared reference from this queue. Returns `None` if no references are enqueued.
+    pub fn poll(self) -> Option<Reference<H>> {
+        self.inner.references.lock().pop_front()
+    }
+    pub fn len(self) -> usize {
+        self.inner.references.lock().len()
+    }
+    pub fn is_empty(self) -> bool {
+        self.len() == 0
+    }
+    /// # NOT FOR USE BY REGULAR CODE, ONLY FOR GC IMPLEMENTATIONS!
+    ///
+    /// Appends cleared `reference` to this queue. Invoked by [Weak::after_mark] and [SoftRef::after_mark].
+    pub unsafe fn enqueue(self, reference: Reference<H>) {
+        self.inner.references.lock().push_back(reference);
+    }
+}
+
+unsafe impl<H: GcBase> Trace for ReferenceQueue<H> {
+    fn trace(&mut self, vis: &mut dyn Visitor) {
+        self.inner.trace(vis);
+    }
+}
+
+impl<H: GcBase> Clone for ReferenceQueue<H> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<H: GcBase> Copy for ReferenceQueue<H> {}
+
+struct FinalizationCell<H: GcBase> {
+    target: Weak<dyn Collectable, H>,
+    held: Gc<dyn Collectable, H>,
+}
+
+pub struct FinalizationRegistryInner<H: GcBase> {
+    cells: Mutex<Vec<FinalizationCell<H>>>,
+    /// Held values of dead targets that are not polled yet.
+    ready: Mutex<VecDeque<Gc<dyn Collectable, H>>>,
+    /// Weak references to targets are enqueued here once targets die.
+    queue: ReferenceQueue<H>,
+}
+
+unsafe impl<H: GcBase> Trace for FinalizationRegistryInner<H> {
+    fn trace(&mut self, vis: &mut dyn Visitor) {
+        for cell in self.cells.get_mut().iter_mut() {
+            cell.target.trace(vis);
+            cell.held.trace(vis);
+        }
+        for held in self.ready.get_mut().iter_mut() {
+            held.trace(vis);
+        }
+        self.queue.trace(vis);
+    }
+}
+unsafe impl<H: GcBase> Finalize for FinalizationRegistryInner<H> {}
+impl<H: GcBase> Collectable for FinalizationRegistryInner<H> {}
+
+/// Finalization registry, like JS `FinalizationRegistry`. Registry holds target of each registration weakly and held value
+/// strongly: once the target dies, the held value is kept alive and queued, and mutator polls it with [poll](Self::poll) outside
+/// of GC pause. Unlike [Finalize], cleanup code runs on the mutator with full access to the heap and can allocate or keep
+/// the held value alive.
+///
+/// Held value must not reference its target, otherwise the target is never collected.
+pub struct FinalizationRegistry<H: GcBase> {
+    inner: Gc<FinalizationRegistryInner<H>, H>,
+}
+
+impl<H: GcBase> FinalizationRegistry<H> {
+    /// Creates new empty registry.
+    pub fn new(mutator: &mut MutatorRef<H>) -> Self {
+        let stack = mutator.shadow_stack();
+        letroot!(queue = stack, ReferenceQueue::new(mutator));
+        Self {
+            inner: mutator.allocate(
+                FinalizationRegistryInner {
+                    cells: Mutex::new(Vec::new()),
+                    ready: Mutex::new(VecDeque::new()),
+                    queue: *queue,
+                },
+                crate::gc_base::AllocationSpace::New,
+            ),
+        }
+    }
+    /// Register `target`. Once `target` dies `held` is returned by [poll](Self::poll).
+    pub fn register<T: Collectable + ?Sized, U: Collectable + ?Sized>(
+        self,
+        mutator: &mut MutatorRef<H>,
+        target: Gc<T, H>,
+        held: Gc<U, H>,
+    ) {
+        let stack = mutator.shadow_stack();
+        letroot!(this = stack, self);
+        letroot!(held = stack, held.to_dyn());
+        let target = mutator.allocate_weak_with_queue(target, this.inner.queue);
+        this.inner.cells.lock().push(FinalizationCell {
+            target: target.to_dyn(),
+            held: *held,
+        });
+        mutator.write_barrier(this.inner.to_dyn());
+    }
+    /// Removes held value of the next registration whose target is dead. Returns `None` if there are no dead targets.
+    pub fn poll(self) -> Option<Gc<dyn Collectable, H>> {
+        let mut ready = self.inner.ready.lock();
+        if ready.is_empty() && !self.inner.queue.is_empty() {
+            while self.inner.queue.poll().is_some() {}
+            self.inner.cells.lock().retain(|cell| {
+                if cell.target.is_cleared() {
+                    ready.push_back(cell.held);
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        ready.pop_front()
+    }
+    /// Number of registrations whose targets were not polled yet.
+    pub fn len(self) -> usize {
+        self.inner.cells.lock().len() + self.inner.ready.lock().len()
+    }
+    pub fn is_empty(self) -> bool {
+        self.len() == 0
+    }
+}
+
+unsafe impl<H: GcBase> Trace for FinalizationRegistry<H> {
+    fn trace(&mut self, vis: &mut dyn Visitor) {
+        self.inner.trace(vis);
+    }
+}
+
+impl<H: GcBase> Clone for FinalizationRegistry<H> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<H: GcBase> Copy for FinalizationRegistry<H> {}
+
+pub struct EphemeronInner<H: GcBase> {
+    pub key: Option<Gc<dyn Collectable, H>>,
+    pub value: Option<Gc<dyn Collectable, H>>,
+}
+
+/// Ephemeron is a key-value pair that does not keep its key alive and keeps its value alive only while the key is alive, even
+/// if the value references the key. Once the garbage collector determines that the key is not reachable it clears both key and value.
+///
+/// Ephemerons are used to implement weak-keyed tables (e.g JS `WeakMap`) where an entry must not keep its key alive.
+pub struct Ephemeron<K: Collectable + ?Sized, V: Collectable + ?Sized, H: GcBase> {
+    inner: Gc<EphemeronInner<H>, H>,
+    marker: PhantomData<(Gc<K, H>, Gc<V, H>)>,
+}
+
+unsafe impl<H: GcBase> Trace for EphemeronInner<H> {
+    fn trace(&mut self, vis: &mut dyn Visitor) {
+        if let (Some(key), Some(value)) = (self.key.as_mut(), self.value.as_mut()) {
+            vis.visit_ephemeron(&mut key.base, &mut value.base);
+        }
+    }
+}
+unsafe impl<H: GcBase> Finalize for EphemeronInner<H> {
+    unsafe fn finalize(&mut self) {}
+}
+
+impl<H: GcBase> Collectable for EphemeronInner<H> {}
+
+unsafe impl<K: Collectable + ?Sized, V: Collectable + ?Sized, H: GcBase> Trace
+    for Ephemeron<K, V, H>
+{
+    fn trace(&mut self, vis: &mut dyn Visitor) {
+        vis.mark_weak(&mut self.inner.base);
+    }
+}
+
+impl<K: Collectable + ?Sized, V: Collectable + ?Sized, H: GcBase> Ephemeron<K, V, H> {
+    pub unsafe fn base(self) -> *mut HeapObjectHeader {
+        self.inner.base.as_ptr()
+    }
+    pub unsafe fn set_base(&mut self, hdr: *mut HeapObjectHeader) {
+        self.inner.base = NonNull::new_unchecked(hdr);
+    }
+    /// Creates a new ephemeron that maps `key` to `value`.
+    pub unsafe fn create(mutator: &mut MutatorRef<H>, key: Gc<K, H>, value: Gc<V, H>) -> Self {
+        let stack = mutator.shadow_stack();
+        letroot!(key = stack, key);
+        letroot!(value = stack, value);
+        let mut inner = mutator.allocate(
+            EphemeronInner {
+                key: None,
+                value: None,
+            },
+            crate::gc_base::AllocationSpace::New,
+        );
+        inner.key = Some(key.to_dyn());
+        inner.value = Some(value.to_dyn());
+        mutator.write_barrier(inner.to_dyn());
+        Self {
+            inner,
+            marker: PhantomData,
+        }
+    }
+    /// Clears this ephemeron.
+    pub fn clear(mut self) {
+        self.inner.key = None;
+        self.inner.value = None;
+    }
+    /// Returns key of this ephemeron or `None` if it was cleared.
+    pub fn key(self) -> Option<Gc<K, H>>
+    where
+        K: Sized,
+    {
+        self.inner.key.map(|x| unsafe { x.downcast_unchecked() })
+    }
+    /// Returns value of this ephemeron or `None` if it was cleared.
+    pub fn value(self) -> Option<Gc<V, H>>
+    where
+        V: Sized,
+    {
+        self.inner.value.map(|x| unsafe { x.downcast_unchecked() })
+    }
+
+    /// # NOT FOR USE BY REGULAR CODE, ONLY FOR GC IMPLEMENTATIONS!
+    ///
+    /// Must be invoked for each ephemeron after marking cycle and [trace_ephemerons]. `process` is invoked with the key and returns
+    /// its new location or null if the key is dead, in which case both key and value are cleared.
+    pub unsafe fn after_mark(
+        &mut self,
+        process: impl FnOnce(*mut HeapObjectHeader) -> *mut HeapObjectHeader,
+    ) {
+        if let Some(key) = self.inner.key {
+            let new_header = process(key.base.as_ptr());
+            if new_header.is_null() {
+                self.inner.key = None;
+                self.inner.value = None;
+            } else {
+                self.inner.key = Some(Gc {
+                    base: NonNull::new_unchecked(new_header),
+                    marker: PhantomData,
+                });
+            }
+        }
+    }
+
+    pub fn to_dyn(self) -> Ephemeron<dyn Collectable, dyn Collectable, H> {
+        Ephemeron {
+            inner: H::ReadBarrier::read_barrier(self.inner),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<K: Collectable + ?Sized, V: Collectable + ?Sized, H: GcBase> Clone for Ephemeron<K, V, H> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K: Collectable + ?Sized, V: Collectable + ?Sized, H: GcBase> Copy for Ephemeron<K, V, H> {}
+
+/// # NOT FOR USE BY REGULAR CODE, ONLY FOR GC IMPLEMENTATIONS!
+///
+/// Marks values of `ephemerons` whose keys are marked until no more values can be marked: newly marked value might make
+/// keys of other ephemerons reachable. Must be invoked once mark stack is drained and before ephemerons are processed with
+/// [Ephemeron::after_mark].
+///
+/// - `forwardee` returns location of a marked object (it is different from object address if object was moved) or null if
+/// object is not marked.
+/// - `mark` marks object referenced by slot, same as [Visitor::mark_object].
+/// - `drain` traces all objects marked by `mark`.
+pub unsafe fn trace_ephemerons<H: GcBase>(
+    ephemerons: &[Ephemeron<dyn Collectable, dyn Collectable, H>],
+    mut forwardee: impl FnMut(*mut HeapObjectHeader) -> *mut HeapObjectHeader,
+    mut mark: impl FnMut(&mut NonNull<HeapObjectHeader>),
+    mut drain: impl FnMut(),
+) {
+    let mut pending = ephemerons.to_vec();
+    loop {
+        let mut marked_any = false;
+        pending.retain(|ephemeron| {
+            let inner = forwardee(ephemeron.base());
+            if inner.is_null() {
+                // ephemeron itself is not reachable yet, it might become reachable from value of other ephemeron.
+                return true;
+            }
+            let inner = &mut *((*inner).data() as *mut EphemeronInner<H>);
+            match (inner.key, inner.value.as_mut()) {
+                (Some(key), Some(value)) => {
+                    if forwardee(key.base.as_ptr()).is_null() {
+                        return true;
+                    }
+                    mark(&mut value.base);
+                    marked_any = true;
+                    false
+                }
+                _ => false,
+            }
+        });
+        if !marked_any {
+            break;
+        }
+        drain();
+    }
+}
+
+/// Finalizer that runs in topological order and may resurrect its object. Implement it for types wrapping native resources whose
+/// teardown order matters and allocate them with [MutatorRef::allocate_ordered_finalizable].
+///
+/// Finalization order is computed like in Boehm GC: when finalizable object becomes unreachable, all objects reachable from it
+/// are kept alive for one more cycle. If finalizable object `A` references finalizable object `B` then `A` is finalized first
+/// and `B` only in a later cycle, once `A` is gone. References of finalizable object to itself are ignored, but any longer cycle
+/// that leads back to it keeps it alive: objects that are part of a cycle of finalizable objects, or finalizable object `A` that
+/// references `B` which references `A` again, are never finalized and never freed. Break such cycles before object becomes
+/// unreachable, e.g with [Weak] references.
+///
+/// Finalizers run on the mutator outside of GC pause, when it calls [MutatorRef::run_ordered_finalizers]. Until then object and
+/// everything reachable from it stays alive. Finalizer has full access to the heap and can resurrect `this` by storing it somewhere
+/// reachable, resurrected object is simply treated as live. Finalizer runs at most once for each allocation and weak references
+/// to object are not cleared before it runs.
+pub trait OrderedFinalize<H: GcBase>: Collectable + Sized {
+    fn finalize(this: Gc<Self, H>, mutator: &mut MutatorRef<H>);
+}
+
+/// Registered object with [OrderedFinalize] finalizer. Heaps keep list of them and process it with [trace_ordered_finalizers].
+pub struct OrderedFinalizer<H: GcBase> {
+    object: Gc<dyn Collectable, H>,
+    callback: fn(Gc<dyn Collectable, H>, &mut MutatorRef<H>),
+    ready: bool,
+}
+
+impl<H: GcBase> OrderedFinalizer<H> {
+    pub fn new<T: OrderedFinalize<H>>(object: Gc<T, H>) -> Self {
+        fn callback<T: OrderedFinalize<H>, H: GcBase>(
+            object: Gc<dyn Collectable, H>,
+            mutator: &mut MutatorRef<H>,
+        ) {
+            <T as OrderedFinalize<H>>::finalize(unsafe { object.downcast_unchecked() }, mutator)
+        }
+        Self {
+            object: object.to_dyn(),
+            callback: callback::<T, H>,
+            ready: false,
+        }
+    }
+    pub fn base(&self) -> *mut HeapObjectHeader {
+        self.object.base.as_ptr()
+    }
+    pub unsafe fn set_base(&mut self, hdr: *mut HeapObjectHeader) {
+        self.object.base = NonNull::new_unchecked(hdr);
+    }
+    /// Returns `true` if object is unreachable and its finalizer waits to be run by mutator.
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+    /// Run finalizer. Must be invoked only once and only for ready finalizer removed from heap's list.
+    pub fn run(self, mutator: &mut MutatorRef<H>) {
+        let stack = mutator.shadow_stack();
+        letroot!(object = stack, self.object);
+        (self.callback)(*object, mutator);
+    }
+}
+
+/// Removes one finalizer which is ready to run from `finalizers`.
+pub fn pop_ready_finalizer<H: GcBase>(
+    finalizers: &mut Vec<OrderedFinalizer<H>>,
+) -> Option<OrderedFinalizer<H>> {
+    let index = finalizers.iter().position(|finalizer| finalizer.ready)?;
+    Some(finalizers.swap_remove(index))
+}
+
+/// Visitor that marks children of `object` without updating its fields. References of `object` to itself are ignored.
+struct MarkChildren<F: FnMut(&mut NonNull<HeapObjectHeader>)> {
+    mark: F,
+    object: *mut HeapObjectHeader,
+}
+
+impl<F: FnMut(&mut NonNull<HeapObjectHeader>)> Visitor for MarkChildren<F> {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        if root.as_ptr() == self.object {
+            return;
+        }
+        // object itself is marked and traced later, its fields are updated at that point.
+        let mut object = *root;
+        (self.mark)(&mut object);
+    }
+}
+
+/// Process finalizable objects after marking. Objects reachable from unreachable finalizable objects are marked first and then
+/// finalizable objects that are still unreachable are resurrected and become ready to be finalized. Objects with ready finalizers
+/// are kept alive until their finalizers are run.
+///
+/// Works the same way as [trace_ephemerons]. Base of each finalizer is updated to new location of its object. Returns `true` if any
+/// object was marked, in that case ephemerons must be traced again.
+///
+/// # Safety
+///
+/// Must be invoked inside GC pause after marking.
+pub unsafe fn trace_ordered_finalizers<H: GcBase>(
+    finalizers: &mut [OrderedFinalizer<H>],
+    mut forwardee: impl FnMut(*mut HeapObjectHeader) -> *mut HeapObjectHeader,
+    mut mark: impl FnMut(&mut NonNull<HeapObjectHeader>),
+    mut drain: impl FnMut(),
+) -> bool {
+    let mut marked_any = false;
+    for finalizer in finalizers.iter_mut().filter(|finalizer| finalizer.ready) {
+        let header = forwardee(finalizer.base());
+        if header.is_null() {
+            mark(&mut finalizer.object.base);
+            marked_any = true;
+        } else {
+            finalizer.object.base = NonNull::new_unchecked(header);
+        }
+    }
+    let mut unreachable = Vec::new();
+    for (index, finalizer) in finalizers.iter().enumerate() {
+        if !finalizer.ready && forwardee(finalizer.base()).is_null() {
+            unreachable.push(index);
+        }
+    }
+    for &index in unreachable.iter() {
+        let header = finalizers[index].base();
+        // object might be already marked from other finalizable object. Its children are traced by `drain` in that case.
+        if forwardee(header).is_null() {
+            (*header).get_dyn().trace(&mut MarkChildren {
+                mark: &mut mark,
+                object: header,
+            });
+            marked_any = true;
+        }
+    }
+    drain();
+    for finalizer in finalizers.iter_mut() {
+        if finalizer.ready {
+            continue;
+        }
+        let header = forwardee(finalizer.base());
+        if header.is_null() {
+            finalizer.ready = true;
+            mark(&mut finalizer.object.base);
+            marked_any = true;
+        } else {
+            finalizer.object.base = NonNull::new_unchecked(header);
+        }
+    }
+    drain();
+    marked_any
+}
+
+static SOFT_REF_CLOCK: AtomicU64 = AtomicU64::new(0);
+
+/// Clock used to timestamp accesses to soft references, in milliseconds. It is advanced only at the start of GC cycles so reading it
+/// is cheap.
+pub fn soft_ref_clock() -> u64 {
+    match SOFT_REF_CLOCK.load(Ordering::Relaxed) {
+        0 => advance_soft_ref_clock(),
+        clock => clock,
+    }
+}
+
+fn advance_soft_ref_clock() -> u64 {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_millis() as u64)
+        .unwrap_or(1);
+    SOFT_REF_CLOCK.fetch_max(now, Ordering::Relaxed).max(now)
+}
+
+/// Decides which soft references are cleared in a GC cycle. Policy is LRU-based, like `SoftRefLRUPolicyMSPerMB` in JVM: referent
+/// that is not reachable otherwise is kept alive if it was accessed less than `ms_per_mb` milliseconds per free megabyte of heap ago.
+///
+/// All soft references are cleared if heap is close to its size limit or if the heap asked for an emergency collection with
+/// [request_clear_all](Self::request_clear_all) because it ran out of memory.
+pub struct SoftRefPolicy {
+    ms_per_mb: u64,
+    clear_all_requested: bool,
+    clear_all: bool,
+    max_age: u64,
+}
+
+impl SoftRefPolicy {
+    /// Default number of milliseconds that referent survives for each free megabyte of heap.
+    pub const DEFAULT_MS_PER_MB: u64 = 1000;
+    /// Fraction of heap size limit after which all soft references are cleared.
+    pub const CLEAR_ALL_THRESHOLD: f64 = 0.9;
+
+    pub const fn new() -> Self {
+        Self {
+            ms_per_mb: Self::DEFAULT_MS_PER_MB,
+            clear_all_requested: false,
+            clear_all: false,
+            // nothing is cleared until first cycle decides policy.
+            max_age: u64::MAX,
+        }
+    }
+
+    pub fn ms_per_mb(&self) -> u64 {
+        self.ms_per_mb
+    }
+
+    pub fn set_ms_per_mb(&mut self, ms_per_mb: u64) {
+        self.ms_per_mb = ms_per_mb;
+    }
+
+    /// Request next GC cycle to clear all soft references. Returns `false` if previous cycle already cleared all of them so
+    /// another collection would not free more memory.
+    pub fn request_clear_all(&mut self) -> bool {
+        if self.clear_all {
+            return false;
+        }
+        self.clear_all_requested = true;
+        true
+    }
+
+    /// Decide policy for GC cycle that is about to start. `used` is number of bytes allocated in heap and `limit` is heap size
+    /// limit.
+    pub fn begin_cycle(&mut self, used: usize, limit: usize) {
+        advance_soft_ref_clock();
+        self.clear_all = std::mem::replace(&mut self.clear_all_requested, false)
+            || used as f64 >= limit as f64 * Self::CLEAR_ALL_THRESHOLD;
+        let free_mb = (limit.saturating_sub(used) / (1024 * 1024)) as u64;
+        self.max_age = free_mb.saturating_mul(self.ms_per_mb);
+    }
+
+    /// Returns `true` if referent of soft reference that was accessed at `timestamp` must be kept alive in current cycle.
+    pub fn should_keep(&self, timestamp: u64) -> bool {
+        !self.clear_all && soft_ref_clock().saturating_sub(timestamp) <= self.max_age
+    }
+}
+
+impl Default for SoftRefPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// # NOT FOR USE BY REGULAR CODE, ONLY FOR GC IMPLEMENTATIONS!
+///
+/// Marks referents of reachable `soft_refs` that `policy` decides to keep. Must be invoked once mark stack is drained and before
+/// [trace_ephemerons], soft references are then processed with [SoftRef::after_mark] just like weak references.
+///
+/// Arguments are the same as in [trace_ephemerons].
+pub unsafe fn trace_soft_refs<H: GcBase>(
+    soft_refs: &[SoftRef<dyn Collectable, H>],
+    policy: &SoftRefPolicy,
+    mut forwardee: impl FnMut(*mut HeapObjectHeader) -> *mut HeapObjectHeader,
+    mut mark: impl FnMut(&mut NonNull<HeapObjectHeader>),
+    mut drain: impl FnMut(),
+) {
+    let mut pending = soft_refs
+        .iter()
+        .copied()
+        .filter(|soft_ref| policy.should_keep(soft_ref.timestamp()))
+        .collect::<Vec<_>>();
+    loop {
+        let mut marked_any = false;
+        pending.retain(|soft_ref| {
+            let inner = forwardee(soft_ref.base());
+            if inner.is_null() {
+                // soft reference itself is not reachable yet, it might become reachable from other referent.
+                return true;
+            }
+            let inner = &mut *((*inner).data() as *mut SoftRefInner<H>);
+            if let Some(value) = inner.value {
+                // referent is updated by `after_mark`, which finds new location of moved referent through `forwardee` of the
+                // old one.
+                let mut base = value.base;
+                mark(&mut base);
+                marked_any = true;
+            }
+            false
+        });
+        if !marked_any {
+            break;
+        }
+        drain();
+    }
+}
+
 impl<T: PartialEq + Collectable, H: GcBase> PartialEq for Gc<T, H> {
     fn eq(&self, other: &Self) -> bool {
         **self == **other
@@ -608,6 +1539,38 @@ impl<T: Hash + Collectable, H: GcBase> Hash for Gc<T, H> {
     }
 }
 
+/// GC pointer that is hashed and compared by identity of the object instead of its value. It can be used as a key of hash
+/// maps with any GC policy, even if objects are moved. See [Gc::identity_hash].
+pub struct Identity<T: Collectable + ?Sized, H: GcBase>(pub Gc<T, H>);
+
+unsafe impl<T: Collectable + ?Sized, H: GcBase> Trace for Identity<T, H> {
+    fn trace(&mut self, vis: &mut dyn Visitor) {
+        self.0.trace(vis);
+    }
+}
+
+impl<T: Collectable + ?Sized, H: GcBase> Clone for Identity<T, H> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: Collectable + ?Sized, H: GcBase> Copy for Identity<T, H> {}
+
+impl<T: Collectable + ?Sized, H: GcBase> PartialEq for Identity<T, H> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.ptr_eq(other.0)
+    }
+}
+
+impl<T: Collectable + ?Sized, H: GcBase> Eq for Identity<T, H> {}
+
+impl<T: Collectable + ?Sized, H: GcBase> Hash for Identity<T, H> {
+    fn hash<HS: std::hash::Hasher>(&self, state: &mut HS) {
+        state.write_u64(self.0.identity_hash());
+    }
+}
+
 impl<T: std::fmt::Debug + Collectable, H: GcBase> std::fmt::Debug for Gc<T, H> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:?}", **self)
@@ -663,3 +1626,104 @@ impl<T: Collectable, H: GcBase> BorrowMut<T> for Gc<T, H> {
         &mut **self
     }
 }
+
+#[cfg(all(test, any(debug_assertions, feature = "check-dangling")))]
+mod tests {
+    use crate::{
+        api::{Collectable, Finalize, Gc, Trace, Visitor},
+        gc_base::{AllocationSpace, GcBase},
+        immix::{instantiate_immix, Immix},
+        semispace::{instantiate_semispace, SemiSpace},
+    };
+
+    struct Node<H: GcBase> {
+        value: i64,
+        next: Option<Gc<Node<H>, H>>,
+    }
+
+    unsafe impl<H: GcBase> Trace for Node<H> {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+        }
+    }
+    unsafe impl<H: GcBase> Finalize for Node<H> {}
+    impl<H: GcBase + 'static> Collectable for Node<H> {}
+
+    fn is_poisoned<H: GcBase>(object: Gc<Node<H>, H>) -> bool {
+        unsafe { (*object.base.as_ptr()).is_poisoned() }
+    }
+
+    #[test]
+    fn test_immix_poisoning() {
+        let mut mutator = instantiate_immix(
+            64 * 1024 * 1024,
+            4 * 1024 * 1024,
+            2 * 1024 * 1024,
+            64 * 1024 * 1024,
+            false,
+        );
+        letroot!(
+            live = mutator.shadow_stack(),
+            mutator.allocate(
+                Node::<Immix> {
+                    value: 42,
+                    next: None
+                },
+                AllocationSpace::New
+            )
+        );
+        // garbage occupies lines after the live object so its block stays allocated.
+        let mut garbage = Vec::new();
+        for value in 0..100 {
+            garbage.push(mutator.allocate(Node { value, next: None }, AllocationSpace::New));
+        }
+        mutator.collect(&mut []);
+        assert_eq!(live.value, 42);
+        assert!(!is_poisoned(*live));
+        assert!(is_poisoned(*garbage.last().unwrap()));
+    }
+
+    #[test]
+    fn test_semispace_poisoning() {
+        let mut mutator = instantiate_semispace(4 * 1024 * 1024);
+        letroot!(
+            live = mutator.shadow_stack(),
+            mutator.allocate(
+                Node::<SemiSpace> {
+                    value: 42,
+                    next: None
+                },
+                AllocationSpace::New
+            )
+        );
+        let before = *live;
+        let garbage = mutator.allocate(
+            Node {
+                value: 1,
+                next: None,
+            },
+            AllocationSpace::New,
+        );
+        mutator.collect(&mut []);
+        assert_eq!(live.value, 42);
+        // old copy of live object is reclaimed as well.
+        assert!(is_poisoned(before));
+        assert!(is_poisoned(garbage));
+    }
+
+    #[cfg(feature = "check-dangling")]
+    #[test]
+    #[should_panic(expected = "use after GC")]
+    fn test_use_after_gc() {
+        let mut mutator = instantiate_semispace(4 * 1024 * 1024);
+        let unrooted = mutator.allocate(
+            Node::<SemiSpace> {
+                value: 42,
+                next: None,
+            },
+            AllocationSpace::New,
+        );
+        mutator.collect(&mut []);
+        assert_eq!(unrooted.value, 42);
+    }
+}
@@ -241,22 +241,22 @@ impl<const ALIGN: usize> SpaceBitmap<ALIGN> {
             "bitmap size: {}",
             self.bitmap_size
         );
-        let atomic_entry = unsafe { &mut *self.bitmap_begin.add(index).cast::<usize>() };
-        let old_word = *atomic_entry;
-        if SET_BIT {
+        let atomic_entry = unsafe { &*self.bitmap_begin.add(index) };
+        let old_word = if SET_BIT {
+            let old_word = atomic_entry.load(Ordering::Relaxed);
             // Check the bit before setting the word incase we are trying to mark a read only bitmap
             // like an image space bitmap. This bitmap is mapped as read only and will fault if we
             // attempt to change any words. Since all of the objects are marked, this will never
             // occur if we check before setting the bit. This also prevents dirty pages that would
             // occur if the bitmap was read write and we did not check the bit.
             if (old_word & mask) == 0 {
-                *atomic_entry = old_word | mask;
-                //atomic_entry.store(old_word | mask, Ordering::Relaxed);
+                atomic_entry.fetch_or(mask, Ordering::Relaxed)
+            } else {
+                old_word
             }
         } else {
-            *atomic_entry = old_word & !mask;
-            //atomic_entry.store(old_word & !mask, Ordering::Relaxed);
-        }
+            atomic_entry.fetch_and(!mask, Ordering::Relaxed)
+        };
 
         debug_assert_eq!(self.test(obj), SET_BIT);
         (old_word & mask) != 0
@@ -736,6 +736,20 @@ impl ObjectStartBitmap {
         self.object_start_index_and_bit(addr as _, &mut cell_index, &mut object_bit);
         self.store(cell_index, self.load(cell_index) | (1 << object_bit));
     }
+    /// Same as [set_bit](Self::set_bit) but can be invoked concurrently with other atomic updates.
+    #[inline(always)]
+    pub fn set_bit_atomic(&self, addr: *const u8) {
+        let mut cell_index = 0;
+        let mut object_bit = 0;
+        self.object_start_index_and_bit(addr as _, &mut cell_index, &mut object_bit);
+        unsafe {
+            (*self
+                .bitmap
+                .add(cell_index)
+                .cast::<core::sync::atomic::AtomicU8>())
+            .fetch_or(1 << object_bit, core::sync::atomic::Ordering::Relaxed);
+        }
+    }
     #[inline(always)]
     pub fn clear_bit(&self, addr: *const u8) {
         let mut cell_index = 0;
@@ -743,22 +757,41 @@ impl ObjectStartBitmap {
         self.object_start_index_and_bit(addr as _, &mut cell_index, &mut object_bit);
         self.store(cell_index, self.load(cell_index) & !(1 << object_bit));
     }
+    /// Same as [clear_bit](Self::clear_bit) but can be invoked concurrently with other atomic updates.
+    #[inline(always)]
+    pub fn clear_bit_atomic(&self, addr: *const u8) {
+        let mut cell_index = 0;
+        let mut object_bit = 0;
+        self.object_start_index_and_bit(addr as _, &mut cell_index, &mut object_bit);
+        unsafe {
+            (*self
+                .bitmap
+                .add(cell_index)
+                .cast::<core::sync::atomic::AtomicU8>())
+            .fetch_and(!(1 << object_bit), core::sync::atomic::Ordering::Relaxed);
+        }
+    }
     pub fn check_bit(&self, addr: *const u8) -> bool {
         let mut cell_index = 0;
         let mut object_bit = 0;
         self.object_start_index_and_bit(addr as _, &mut cell_index, &mut object_bit);
         (self.load(cell_index) & (1 << object_bit)) != 0
     }
+    /// Find start of the object that contains `addr_in_middle`: last object start at or before `addr_in_middle`. Returns null
+    /// pointer if there is no object start before `addr_in_middle`.
     pub fn find_header(&self, addr_in_middle: *const u8) -> *mut HeapObjectHeader {
         let mut object_offset = addr_in_middle as usize - self.offset;
         let mut object_start_number = object_offset / MIN_ALLOCATION;
         let mut cell_index = object_start_number / Self::BITS_PER_CELL;
         let bit = object_start_number & Self::CELL_MASK;
-        let mut byte = self.load(cell_index) & ((1 << (bit + 1)) - 1);
+        let mut byte = self.load(cell_index) & ((1usize << (bit + 1)) - 1) as u8;
         while byte == 0 && cell_index != 0 {
             cell_index -= 1;
             byte = self.load(cell_index);
         }
+        if byte == 0 {
+            return null_mut();
+        }
         let leading_zeros = byte.leading_zeros();
         object_start_number =
             (cell_index * Self::BITS_PER_CELL) + (Self::BITS_PER_CELL - 1) - leading_zeros as usize;
@@ -1012,22 +1045,22 @@ macro_rules! gen_const_bitmap {
                     "bitmap size: {}",
                     self.bitmap_size
                 );
-                let atomic_entry = unsafe { &mut *self.bitmap_begin.add(index).cast::<usize>() };
-                let old_word = *atomic_entry;
-                if SET_BIT {
+                let atomic_entry = unsafe { &*self.bitmap_begin.add(index) };
+                let old_word = if SET_BIT {
+                    let old_word = atomic_entry.load(Ordering::Relaxed);
                     // Check the bit before setting the word incase we are trying to mark a read only bitmap
                     // like an image space bitmap. This bitmap is mapped as read only and will fault if we
                     // attempt to change any words. Since all of the objects are marked, this will never
                     // occur if we check before setting the bit. This also prevents dirty pages that would
                     // occur if the bitmap was read write and we did not check the bit.
                     if (old_word & mask) == 0 {
-                        *atomic_entry = old_word | mask;
-                        //atomic_entry.store(old_word | mask, Ordering::Relaxed);
+                        atomic_entry.fetch_or(mask, Ordering::Relaxed)
+                    } else {
+                        old_word
                     }
                 } else {
-                    *atomic_entry = old_word & !mask;
-                    //atomic_entry.store(old_word & !mask, Ordering::Relaxed);
-                }
+                    atomic_entry.fetch_and(!mask, Ordering::Relaxed)
+                };
 
                 debug_assert_eq!(self.test(obj), SET_BIT);
                 (old_word & mask) != 0
@@ -1,12 +1,17 @@
-use std::{ptr::null_mut, sync::atomic::AtomicPtr};
+use std::{mem::size_of, ptr::null_mut, sync::atomic::AtomicPtr};
 
-use crate::utils::mmap::Mmap;
+use crate::{
+    api::HeapObjectHeader,
+    utils::{mmap::Mmap, poison},
+};
 
 pub struct BumpPointerSpace {
     mmap: Mmap,
     start: *mut u8,
     end: *mut u8,
     cursor: AtomicPtr<u8>,
+    /// Sorted address ranges that are skipped by allocation.
+    reserved: Vec<(*mut u8, *mut u8)>,
 }
 
 impl BumpPointerSpace {
@@ -29,14 +34,82 @@ impl BumpPointerSpace {
             start,
             end,
             cursor,
+            reserved: Vec::new(),
         };
 
         this
     }
 
+    /// Reset allocation cursor to the start of the space. Allocated memory is [poisoned](crate::utils::poison) except for
+    /// [reserved](Self::set_reserved) ranges.
     pub fn reset(&self) {
+        let end = self.cursor.load(atomic::Ordering::Relaxed);
+        let mut cursor = self.start;
+        unsafe {
+            for &(start, reserved_end) in self.reserved.iter() {
+                if start >= end {
+                    break;
+                }
+                if start > cursor {
+                    poison(cursor, start as usize - cursor as usize);
+                }
+                cursor = cursor.max(reserved_end);
+            }
+            if cursor < end {
+                poison(cursor, end as usize - cursor as usize);
+            }
+        }
         self.cursor.store(self.start, atomic::Ordering::Relaxed);
     }
+    /// Zero memory of the space and reset it.
+    pub fn clear(&self) {
+        unsafe {
+            std::ptr::write_bytes(self.start, 0, self.end as usize - self.start as usize);
+        }
+        self.cursor.store(self.start, atomic::Ordering::Relaxed);
+    }
+    /// Visit objects allocated in this space. Memory that is not occupied by objects must be zeroed, see [clear](Self::clear).
+    /// Walk stops at object of zero size.
+    ///
+    /// # Safety
+    ///
+    /// Space must not be allocated into while it is walked.
+    pub unsafe fn walk(&self, mut visitor: impl FnMut(*mut HeapObjectHeader)) {
+        let end = self.cursor.load(atomic::Ordering::Relaxed);
+        let mut cursor = self.start;
+        while cursor < end {
+            if cursor.cast::<usize>().read() == 0 {
+                cursor = cursor.add(size_of::<usize>());
+                continue;
+            }
+            let object = cursor.cast::<HeapObjectHeader>();
+            visitor(object);
+            let size = (*object).size();
+            if size == 0 {
+                break;
+            }
+            cursor = cursor.add(size);
+        }
+    }
+
+    /// Exclude `ranges` from allocation. Ranges must be sorted and must not overlap.
+    pub fn set_reserved(&mut self, ranges: Vec<(*mut u8, *mut u8)>) {
+        debug_assert!(ranges.windows(2).all(|pair| pair[0].1 <= pair[1].0));
+        self.reserved = ranges;
+    }
+    /// First address at or after `cursor` where `size` bytes do not overlap reserved ranges.
+    #[inline]
+    fn skip_reserved(&self, mut cursor: *mut u8, size: usize) -> *mut u8 {
+        for &(start, end) in self.reserved.iter() {
+            if cursor as usize + size <= start as usize {
+                break;
+            }
+            if cursor < end {
+                cursor = end;
+            }
+        }
+        cursor
+    }
 
     pub fn decommit(&self) {
         unsafe {
@@ -55,9 +128,11 @@ impl BumpPointerSpace {
     pub fn bump_alloc(&self, size: usize) -> *mut u8 {
         let mut old = self.cursor.load(atomic::Ordering::Relaxed);
         let mut new;
+        let mut start;
         loop {
             unsafe {
-                new = old.add(size);
+                start = self.skip_reserved(old, size);
+                new = start.add(size);
                 if new > self.end {
                     return null_mut();
                 }
@@ -74,11 +149,11 @@ impl BumpPointerSpace {
                 }
             }
         }
-        old
+        start
     }
 
     pub unsafe fn thread_bump_alloc_unsafe(&self, size: usize) -> *mut u8 {
-        let old = self.cursor.load(atomic::Ordering::Relaxed);
+        let old = self.skip_reserved(self.cursor.load(atomic::Ordering::Relaxed), size);
 
         let new = old.add(size);
         if new > self.end {
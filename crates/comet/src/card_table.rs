@@ -1,4 +1,16 @@
-use std::mem::size_of;
+//! # Card table
+//!
+//! Card table divides heap into cards of [CARD_SIZE] bytes and keeps one byte for each card. Write barrier dirties
+//! card of an object after write to it, this is a single byte store, so barrier does not need any locks and many mutators
+//! can dirty cards at the same time. At GC time dirty cards are used to find objects that might contain interesting pointers
+//! (e.g old-to-young pointers in generational GC).
+//!
+//! Large objects are not allocated in continuous memory so they have no entry in card table, instead each
+//! [PreciseAllocation](crate::large_space::PreciseAllocation) has its own card.
+use std::{
+    mem::size_of,
+    sync::atomic::{AtomicU8, Ordering},
+};
 
 use crate::utils::mmap::Mmap;
 
@@ -6,7 +18,11 @@ pub const CARD_SIZE: usize = 512;
 pub const CARD_SIZE_BITS: usize = 9;
 pub const CARD_REFS: usize = CARD_SIZE / size_of::<usize>();
 
-#[allow(dead_code)]
+/// Value of card that has no interesting pointers.
+pub const CARD_CLEAN: u8 = 0;
+/// Value of card that was written to since it was cleared.
+pub const CARD_DIRTY: u8 = 0x70;
+
 pub struct CardTable {
     start: *mut u8,
     end: *mut u8,
@@ -14,3 +30,173 @@ pub struct CardTable {
     heap_begin: *mut u8,
     heap_size: usize,
 }
+
+impl CardTable {
+    /// Create card table that covers `[heap_begin, heap_begin + heap_size)`. All cards are clean.
+    pub fn new(heap_begin: *mut u8, heap_size: usize) -> Self {
+        let num_cards = (heap_size + CARD_SIZE - 1) >> CARD_SIZE_BITS;
+        // card table is scanned word by word, so it is padded to word size.
+        let map = Mmap::new(num_cards + size_of::<usize>(), 0);
+        Self {
+            start: map.start(),
+            end: unsafe { map.start().add(num_cards) },
+            map,
+            heap_begin,
+            heap_size,
+        }
+    }
+
+    /// Returns `true` if `addr` is covered by this card table.
+    #[inline(always)]
+    pub fn covers(&self, addr: *const u8) -> bool {
+        addr as usize >= self.heap_begin as usize
+            && (addr as usize) < self.heap_begin as usize + self.heap_size
+    }
+
+    /// Card that covers `addr`.
+    #[inline(always)]
+    pub fn card_from_addr(&self, addr: *const u8) -> *mut u8 {
+        debug_assert!(self.covers(addr));
+        unsafe {
+            self.start
+                .add((addr as usize - self.heap_begin as usize) >> CARD_SIZE_BITS)
+        }
+    }
+
+    /// First address covered by `card`.
+    #[inline(always)]
+    pub fn addr_from_card(&self, card: *const u8) -> *mut u8 {
+        debug_assert!(card >= self.start as *const u8 && card < self.end as *const u8);
+        unsafe {
+            self.heap_begin
+                .add((card as usize - self.start as usize) << CARD_SIZE_BITS)
+        }
+    }
+
+    /// Dirty card that covers `addr`. Can be invoked concurrently from any number of threads.
+    #[inline(always)]
+    pub fn mark_card(&self, addr: *const u8) {
+        unsafe {
+            (*self.card_from_addr(addr).cast::<AtomicU8>()).store(CARD_DIRTY, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns `true` if card that covers `addr` is dirty.
+    #[inline(always)]
+    pub fn is_dirty(&self, addr: *const u8) -> bool {
+        unsafe {
+            (*self.card_from_addr(addr).cast::<AtomicU8>()).load(Ordering::Relaxed) == CARD_DIRTY
+        }
+    }
+
+    /// Clear cards that cover `[begin, end)`.
+    ///
+    /// # Safety
+    ///
+    /// `[begin, end)` must be covered by this card table.
+    pub unsafe fn clear_card_range(&self, begin: *const u8, end: *const u8) {
+        if begin >= end {
+            return;
+        }
+        let start_card = self.card_from_addr(begin);
+        let end_card = self.card_from_addr(end.sub(1));
+        std::ptr::write_bytes(
+            start_card,
+            CARD_CLEAN,
+            end_card as usize - start_card as usize + 1,
+        );
+    }
+
+    /// Clear all cards.
+    pub fn clear_card_table(&self) {
+        self.map
+            .dontneed_and_zero(self.map.start(), self.map.size());
+    }
+
+    /// Invoke `visitor` with first address of each dirty card in `[begin, end)`. If `clear` is true cards are cleared before
+    /// visitor is invoked.
+    ///
+    /// # Safety
+    ///
+    /// `[begin, end)` must be covered by this card table. Must not be invoked while mutators might dirty cards.
+    pub unsafe fn visit_dirty_cards(
+        &self,
+        begin: *const u8,
+        end: *const u8,
+        clear: bool,
+        mut visitor: impl FnMut(*mut u8),
+    ) {
+        if begin >= end {
+            return;
+        }
+        let mut card = self.card_from_addr(begin);
+        let end_card = self.card_from_addr(end.sub(1)).add(1);
+        while card < end_card {
+            // skip clean cards word at a time.
+            if card as usize & (size_of::<usize>() - 1) == 0
+                && card.add(size_of::<usize>()) <= end_card
+                && card.cast::<usize>().read() == 0
+            {
+                card = card.add(size_of::<usize>());
+                continue;
+            }
+            if card.read() == CARD_DIRTY {
+                if clear {
+                    card.write(CARD_CLEAN);
+                }
+                visitor(self.addr_from_card(card));
+            }
+            card = card.add(1);
+        }
+    }
+
+    pub fn heap_begin(&self) -> *mut u8 {
+        self.heap_begin
+    }
+
+    pub fn heap_size(&self) -> usize {
+        self.heap_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CardTable, CARD_SIZE};
+
+    #[test]
+    fn test_card_marking() {
+        let heap = vec![0u8; 64 * CARD_SIZE];
+        let begin = heap.as_ptr() as *mut u8;
+        let table = CardTable::new(begin, heap.len());
+        unsafe {
+            let addr = |card: usize, offset: usize| begin.add(card * CARD_SIZE + offset);
+            for card in [0, 3, 9, 10, 63] {
+                table.mark_card(addr(card, CARD_SIZE / 2));
+            }
+            assert!(table.is_dirty(addr(3, 0)));
+            assert!(!table.is_dirty(addr(4, 0)));
+            assert_eq!(
+                table.addr_from_card(table.card_from_addr(addr(9, 7))),
+                addr(9, 0)
+            );
+
+            let mut dirty = vec![];
+            table.visit_dirty_cards(addr(1, 0), addr(63, 1), false, |card| dirty.push(card));
+            assert_eq!(
+                dirty,
+                vec![addr(3, 0), addr(9, 0), addr(10, 0), addr(63, 0)]
+            );
+
+            table.clear_card_range(addr(9, 0), addr(10, 1));
+            let mut dirty = vec![];
+            table.visit_dirty_cards(begin, addr(64, 0), true, |card| dirty.push(card));
+            assert_eq!(dirty, vec![addr(0, 0), addr(3, 0), addr(63, 0)]);
+            // visitor cleared the cards.
+            table.visit_dirty_cards(begin, addr(64, 0), false, |_| unreachable!());
+
+            table.mark_card(addr(5, 0));
+            table.clear_card_table();
+            assert!(!table.is_dirty(addr(5, 0)));
+        }
+    }
+}
@@ -1,9 +1,10 @@
 //! # Concurrent Mark-and-Sweep
 //!
-//! Simple CMS collector. Heap is divided into 128KB pages, small objects (<=64KB) are allocated
-//! into normal pages which contain free-list. Large objects go to large pages and large page contains only
-//! one object.
+//! Simple CMS collector. Heap is divided into 16KB blocks, each block stores cells of a single size class and free
+//! cells are linked into block free-list. Size classes are built by [build_size_class_table](space::build_size_class_table).
+//! Objects larger than [LARGE_CUTOFF](space::LARGE_CUTOFF) are allocated in large object space.
 //!
+//! Each mutator owns one block per size class and allocates from its free-list without any synchronization.
 //!
 //! ## GC cycle
 //!
@@ -23,12 +24,13 @@
 //!         marker.worklist.push(object)
 //! ```
 //!
-//! When marking worklist is empty it stops and executes final marking cycle.
+//! When marking worklist is empty GC thread stops and requests final marking. GC thread has access only to the marker and
+//! heap space, everything else is owned by mutators.
 //!
 //! ## Final marking
 //!
-//! Final marking is executed in STW pause and in final marking phase we re-mark roots, process weak refs, execute finalizers
-//! and setup concurrent sweeper with currently allocated heap pages.
+//! Final marking is executed in STW pause by the next mutator that needs a new block. In final marking phase we re-mark
+//! roots, process weak refs, execute finalizers and setup concurrent sweeper with currently allocated heap pages.
 //!
 //! ## Concurrent sweeping
 //!
@@ -42,9 +44,15 @@
 //! In this cycle we stop all mutators and execute all stages of GC in STW pause.
 //!
 //! # How GC decides when there is no enough memory?
-//! It does not, at the start of cycle we set GC threshold to 35% of current GC heap and when it is reached
-//! we simply switch to Degraded GC. At the end of the sweeping threshold is updated to be current_heap_size + 50% of current heap size.
+//! It does not, GC cycle is started when allocated bytes reach GC threshold and if mutator can't get a block while cycle is
+//! running we simply switch to Degraded GC. At the end of the sweeping threshold is updated to be current_heap_size + 50% of current heap size.
 //! This allows us to perform concurrent cycles more often without going to degraded cycles.
+//!
+//! # Write barrier
+//!
+//! Mutators must invoke [MutatorRef::write_barrier](crate::mutator::MutatorRef::write_barrier) on object right after
+//! they store reference into it, otherwise concurrent marker might miss objects that are reachable only from black objects.
+//! Mutators must also poll safepoints regularly: final marking can't start until all mutators reach safepoint.
 
 pub mod block;
 pub mod marker;
@@ -52,9 +60,1199 @@ pub mod marking_worklist;
 pub mod space;
 pub mod write_barrier;
 
+use std::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    mem::size_of,
+    ptr::{null_mut, NonNull},
+    sync::{atomic::AtomicUsize, Arc},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use atomic::{Atomic, Ordering};
+use im::Vector;
+use parking_lot::{lock_api::RawMutex, Mutex, RawMutex as Lock};
+
+use crate::{
+    api::{
+        pop_ready_finalizer, trace_ephemerons, trace_ordered_finalizers, trace_soft_refs,
+        vtable_of, Collectable, Ephemeron, Gc, HeapObjectHeader, OrderedFinalizer, SoftRef,
+        SoftRefPolicy, Trace, VTable, Weak, GC_BLACK, GC_WHITE,
+    },
+    gc_base::{
+        AllocError, AllocationSpace, GcBase, MarkingConstraint, MarkingConstraintRuns, NoHelp,
+        NoReadBarrier, OomHandler, OomHandlerSlot, TLAB,
+    },
+    gc_log::{GcLogger, GcPhase, GcRecord, PhaseTimer},
+    large_space::LargeObjectSpace,
+    make_small_type_id,
+    mutator::{JoinData, Mutator, MutatorRef, ThreadState},
+    persistent::PersistentRoots,
+    pin::PinnedObjects,
+    safepoint::{GlobalSafepoint, SafepointScope},
+    small_type_id,
+    stats::{GcCycle, GcKind, GcListener, GcReason, GcStats, HeapStats},
+    utils::align_usize,
+};
+
+use self::{
+    block::Block,
+    marker::Marker,
+    space::{Space, LARGE_CUTOFF, NUM_SIZE_CLASSES},
+    write_barrier::write_barrier_impl,
+};
+
+pub struct CmsOptions {
+    pub verbose: bool,
+    /// Size of memory reserved for CMS space.
+    pub size: usize,
+    /// Number of allocated bytes at which first GC cycle is started.
+    pub initial_size: usize,
+    pub min_heap_size: usize,
+    pub max_heap_size: usize,
+    /// Progression of size classes that are larger than [PRECISE_CUTOFF](space::PRECISE_CUTOFF).
+    pub size_class_progression: f64,
+    /// Print size classes at heap creation.
+    pub dump_size_classes: bool,
+}
+
+impl Default for CmsOptions {
+    fn default() -> Self {
+        Self {
+            verbose: false,
+            size: 512 * 1024 * 1024,
+            initial_size: 32 * 1024 * 1024,
+            min_heap_size: 32 * 1024 * 1024,
+            max_heap_size: 512 * 1024 * 1024,
+            size_class_progression: 1.4,
+            dump_size_classes: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Phase {
+    Idle,
+    /// GC thread marks objects concurrently with mutators.
+    Marking,
+    /// Concurrent marking is finished, final marking is performed by the next mutator that polls GC cycle.
+    Remark,
+    /// GC thread sweeps blocks concurrently with mutators.
+    Sweeping,
+    /// Concurrent sweeping is finished, GC cycle is finished by the next mutator that polls it.
+    Swept,
+}
+
+/// Heap state that is shared with GC thread. GC thread does not access the heap itself because mutators use it concurrently,
+/// it only traces objects with [Marker] and sweeps [Space].
+struct Shared {
+    marker: Marker,
+    space: Space,
+    phase: Atomic<Phase>,
+}
+
+// marker worklists and phase are atomic, blocks of the space are protected by its lock.
+unsafe impl Send for Shared {}
+unsafe impl Sync for Shared {}
+
 /// Concurrent Mark&Sweep heap.
 ///
-///
+/// Read top level module documentation for more information.
 ///
 /// `CONCURRENT` const determines if GC does concurrent marking&sweeping or always performs collection in STW, useful for debugging.
-pub struct ConcurrentMarkSweep<const CONCURRENT: bool = true> {}
+pub struct ConcurrentMarkSweep<const CONCURRENT: bool = true> {
+    shared: Arc<Shared>,
+    pub(crate) global_heap_lock: Lock,
+    pub(crate) large_space_lock: Lock,
+    pub(crate) large_space: LargeObjectSpace,
+    pub(crate) mutators: Vec<*mut Mutator<Self>>,
+    pub(crate) safepoint: GlobalSafepoint,
+    /// GC cycle is started when allocated bytes reach this threshold.
+    threshold: AtomicUsize,
+    min_heap_size: usize,
+    max_heap_size: usize,
+    /// Background thread that runs concurrent marking or sweeping.
+    gc_thread: Mutex<Option<JoinHandle<()>>>,
+    cycle_start: Option<std::time::Instant>,
+    /// Bytes allocated before final marking of concurrent cycle.
+    bytes_before_sweep: usize,
+    total_gcs: usize,
+    weak_refs: Vec<Weak<dyn Collectable, Self>>,
+    ephemerons: Vec<Ephemeron<dyn Collectable, dyn Collectable, Self>>,
+    soft_refs: Vec<SoftRef<dyn Collectable, Self>>,
+    soft_ref_policy: SoftRefPolicy,
+    ordered_finalizers: Vec<OrderedFinalizer<Self>>,
+    persistent_roots: Arc<PersistentRoots<Self>>,
+    pinned_objects: Arc<PinnedObjects<Self>>,
+    constraints: Vec<Box<dyn MarkingConstraint>>,
+    finalize_list: Vector<*mut HeapObjectHeader>,
+    finalize_lock: Lock,
+    oom_handler: OomHandlerSlot<Self>,
+    gc_stats: GcStats,
+    /// Concurrent GC cycle that is in progress.
+    concurrent_cycle: Option<GcCycle>,
+}
+
+pub fn instantiate_cms<const CONCURRENT: bool>(
+    options: CmsOptions,
+) -> MutatorRef<ConcurrentMarkSweep<CONCURRENT>> {
+    let heap = Arc::new(UnsafeCell::new(ConcurrentMarkSweep::<CONCURRENT> {
+        shared: Arc::new(Shared {
+            marker: Marker::new(),
+            space: Space::new(
+                options.size,
+                options.size_class_progression,
+                options.dump_size_classes,
+            ),
+            phase: Atomic::new(Phase::Idle),
+        }),
+        global_heap_lock: Lock::INIT,
+        large_space_lock: Lock::INIT,
+        large_space: LargeObjectSpace::new(),
+        mutators: vec![],
+        safepoint: GlobalSafepoint::new(),
+        threshold: AtomicUsize::new(options.initial_size),
+        min_heap_size: options.min_heap_size,
+        max_heap_size: options.max_heap_size,
+        gc_thread: Mutex::new(None),
+        cycle_start: None,
+        bytes_before_sweep: 0,
+        total_gcs: 0,
+        weak_refs: vec![],
+        ephemerons: vec![],
+        soft_refs: vec![],
+        soft_ref_policy: SoftRefPolicy::new(),
+        ordered_finalizers: vec![],
+        persistent_roots: Arc::new(PersistentRoots::new()),
+        pinned_objects: Arc::new(PinnedObjects::new()),
+        constraints: vec![],
+        finalize_list: Vector::new(),
+        finalize_lock: Lock::INIT,
+        oom_handler: OomHandlerSlot::new(),
+        gc_stats: GcStats::new("ConcurrentMarkSweep"),
+        concurrent_cycle: None,
+    }));
+    let href = unsafe { &mut *heap.get() };
+    if options.verbose {
+        href.safepoint
+            .set_logger(Some(Arc::new(GcLogger::stderr())));
+    }
+    let join_data = JoinData::new();
+    let mut mutator = MutatorRef::new(Mutator::new(
+        heap.clone(),
+        &href.safepoint,
+        join_data.internal.clone(),
+    ));
+    href.mutators.push(&mut *mutator);
+    href.safepoint
+        .n_mutators
+        .fetch_add(1, atomic::Ordering::Relaxed);
+    mutator.state_set(ThreadState::Safe, ThreadState::Unsafe);
+    mutator
+}
+
+/// CMS does not use bump pointer TLABs, instead each mutator owns one block per size class.
+pub struct CmsTLAB {
+    blocks: Vec<*mut Block>,
+}
+
+impl CmsTLAB {
+    /// Allocate cell from mutator block of size class `index`. Returns null pointer if there is no block or it is full.
+    #[inline(always)]
+    unsafe fn allocate_cell(&mut self, index: usize) -> *mut u8 {
+        let block = self.blocks[index];
+        if block.is_null() {
+            null_mut()
+        } else {
+            (*block).allocate()
+        }
+    }
+}
+
+impl<const CONCURRENT: bool> TLAB<ConcurrentMarkSweep<CONCURRENT>> for CmsTLAB {
+    fn can_thread_local_allocate(&self, _size: usize) -> bool {
+        false
+    }
+    fn allocate<T: Collectable + 'static>(
+        &mut self,
+        _value: T,
+    ) -> Result<Gc<T, ConcurrentMarkSweep<CONCURRENT>>, T> {
+        unreachable!()
+    }
+    fn refill(
+        &mut self,
+        _mutator: &MutatorRef<ConcurrentMarkSweep<CONCURRENT>>,
+        _alloc_size: usize,
+    ) -> bool {
+        false
+    }
+    fn reset(&mut self) {
+        for block in self.blocks.iter_mut() {
+            *block = null_mut();
+        }
+    }
+    fn create(_heap: Arc<UnsafeCell<ConcurrentMarkSweep<CONCURRENT>>>) -> Self {
+        Self {
+            blocks: vec![null_mut(); NUM_SIZE_CLASSES],
+        }
+    }
+}
+
+impl<const CONCURRENT: bool> ConcurrentMarkSweep<CONCURRENT> {
+    unsafe fn after_mark_constraints(&mut self) {
+        let this = self as *mut Self;
+        (*this).constraints.retain_mut(|constraint| {
+            if constraint.is_over() {
+                false
+            } else {
+                if constraint.runs_at() == MarkingConstraintRuns::AfterMark {
+                    constraint.run(&mut (*this).shared.marker.visitor());
+                }
+                true
+            }
+        });
+    }
+    unsafe fn before_mark_constraints(&mut self) {
+        let this = self as *mut Self;
+        (*this).constraints.retain_mut(|constraint| {
+            if constraint.is_over() {
+                false
+            } else {
+                if constraint.runs_at() == MarkingConstraintRuns::BeforeMark {
+                    constraint.run(&mut (*this).shared.marker.visitor());
+                }
+                true
+            }
+        });
+    }
+
+    fn bytes_allocated(&self) -> usize {
+        self.shared
+            .space
+            .num_bytes_allocated
+            .load(Ordering::Relaxed)
+            + self.large_space.bytes
+    }
+
+    fn should_start_cycle(&self) -> bool {
+        self.shared.phase.load(Ordering::Relaxed) == Phase::Idle
+            && self.bytes_allocated() >= self.threshold.load(Ordering::Relaxed)
+    }
+
+    /// Initial marking. Must be invoked when all mutators are stopped.
+    unsafe fn initial_marking(&mut self, mut keep: &mut [&mut dyn Trace]) {
+        self.large_space.prepare_for_marking(false);
+        self.large_space.begin_marking(true);
+        self.shared.marker.set_marking(true);
+        self.before_mark_constraints();
+        let mut visitor = self.shared.marker.visitor();
+        for i in 0..self.mutators.len() {
+            let mutator = self.mutators[i];
+            (*mutator).shadow_stack().walk(|entry| {
+                entry.trace(&mut visitor);
+            });
+        }
+        self.persistent_roots.trace(&mut visitor);
+        self.pinned_objects.trace(&mut visitor);
+        keep.trace(&mut visitor);
+    }
+
+    /// Final marking. Must be invoked when all mutators are stopped. Re-marks roots, processes soft references, ephemerons, weak references and finalizers,
+    /// sweeps large object space and prepares blocks for sweeping.
+    unsafe fn final_marking(&mut self, mut keep: &mut [&mut dyn Trace]) {
+        let mut visitor = self.shared.marker.visitor();
+        for i in 0..self.mutators.len() {
+            let mutator = self.mutators[i];
+            (*mutator).reset_tlab();
+            (*mutator).shadow_stack().walk(|entry| {
+                entry.trace(&mut visitor);
+            });
+        }
+        self.persistent_roots.trace(&mut visitor);
+        self.pinned_objects.trace(&mut visitor);
+        keep.trace(&mut visitor);
+        self.shared.marker.drain();
+        self.after_mark_constraints();
+        self.shared.marker.drain();
+        self.soft_ref_policy
+            .begin_cycle(self.bytes_allocated(), self.max_heap_size);
+        let forwardee = |header: *mut HeapObjectHeader| {
+            if (*header).get_color() == GC_BLACK {
+                header
+            } else {
+                null_mut()
+            }
+        };
+        let this = self as *mut Self;
+        trace_soft_refs(
+            &(*this).soft_refs,
+            &(*this).soft_ref_policy,
+            forwardee,
+            |slot| (*this).shared.marker.mark_object(slot),
+            || (*this).shared.marker.drain(),
+        );
+        trace_ephemerons(
+            &(*this).ephemerons,
+            forwardee,
+            |slot| (*this).shared.marker.mark_object(slot),
+            || (*this).shared.marker.drain(),
+        );
+        if trace_ordered_finalizers(
+            &mut (*this).ordered_finalizers,
+            forwardee,
+            |slot| (*this).shared.marker.mark_object(slot),
+            || (*this).shared.marker.drain(),
+        ) {
+            trace_ephemerons(
+                &(*this).ephemerons,
+                forwardee,
+                |slot| (*this).shared.marker.mark_object(slot),
+                || (*this).shared.marker.drain(),
+            );
+        }
+        self.shared.marker.set_marking(false);
+
+        self.weak_refs.retain_mut(|object| {
+            let header = object.base();
+            if (*header).get_color() == GC_BLACK {
+                object.after_mark(|header| {
+                    if (*header).get_color() == GC_BLACK {
+                        header
+                    } else {
+                        null_mut()
+                    }
+                });
+                true
+            } else {
+                false
+            }
+        });
+        self.soft_refs.retain_mut(|soft_ref| {
+            if (*soft_ref.base()).get_color() == GC_BLACK {
+                soft_ref.after_mark(forwardee);
+                true
+            } else {
+                false
+            }
+        });
+        self.ephemerons.retain_mut(|ephemeron| {
+            if (*ephemeron.base()).get_color() == GC_BLACK {
+                ephemeron.after_mark(|key| {
+                    if (*key).get_color() == GC_BLACK {
+                        key
+                    } else {
+                        null_mut()
+                    }
+                });
+                true
+            } else {
+                false
+            }
+        });
+        self.finalize_lock.lock();
+        self.finalize_list.retain(|object| {
+            if (**object).get_color() == GC_BLACK {
+                true
+            } else {
+                (**object).get_dyn().finalize();
+                false
+            }
+        });
+        self.finalize_lock.unlock();
+
+        self.large_space.prepare_for_allocation(false);
+        self.large_space.sweep();
+        for allocation in self.large_space.allocations.iter() {
+            (*(**allocation).cell()).force_set_color(GC_WHITE);
+        }
+        self.shared.space.prepare_for_sweep();
+    }
+
+    /// Set threshold for the next GC cycle. Returns bytes allocated and new threshold.
+    fn update_threshold(&mut self) -> (usize, usize) {
+        let bytes_allocated = self.bytes_allocated();
+        let threshold = self
+            .min_heap_size
+            .max(bytes_allocated + bytes_allocated / 2)
+            .min(self.max_heap_size);
+        self.threshold.store(threshold, Ordering::Relaxed);
+        (bytes_allocated, threshold)
+    }
+
+    /// Concurrent marking, runs in GC thread. Final marking is requested when marking worklists are empty.
+    fn concurrent_mark(shared: &Shared, logger: Option<Arc<GcLogger>>, gc_id: usize) {
+        let time = std::time::Instant::now();
+        shared.marker.drain();
+        if let Some(logger) = logger {
+            logger.log_phase(
+                "ConcurrentMarkSweep",
+                gc_id,
+                "Concurrent Mark",
+                time.elapsed(),
+                &[],
+            );
+        }
+        shared.phase.store(Phase::Remark, Ordering::Release);
+    }
+
+    /// Concurrent sweeping, runs in GC thread.
+    fn concurrent_sweep(shared: &Shared, logger: Option<Arc<GcLogger>>, gc_id: usize) {
+        let time = std::time::Instant::now();
+        shared.space.sweep_all();
+        if let Some(logger) = logger {
+            logger.log_phase(
+                "ConcurrentMarkSweep",
+                gc_id,
+                "Concurrent Sweep",
+                time.elapsed(),
+                &[],
+            );
+        }
+        shared.phase.store(Phase::Swept, Ordering::Release);
+    }
+
+    /// Run `phase` of concurrent cycle in GC thread. GC thread gets only [Shared] state of the heap.
+    fn spawn_gc_thread(&mut self, phase: fn(&Shared, Option<Arc<GcLogger>>, usize)) {
+        let shared = self.shared.clone();
+        let logger = self.safepoint.logger();
+        let gc_id = self.total_gcs;
+        let handle = std::thread::Builder::new()
+            .name("cms-gc".to_string())
+            .spawn(move || phase(&shared, logger, gc_id))
+            .expect("failed to spawn CMS thread");
+        if let Some(prev) = self.gc_thread.lock().replace(handle) {
+            // previous phase is finished, join it to release thread resources.
+            let _ = prev.join();
+        }
+    }
+
+    /// Perform final marking if concurrent marking is finished or finish GC cycle if concurrent sweeping is finished.
+    /// All of the values that mutator holds must be rooted.
+    fn poll_concurrent_cycle(&mut self, mutator: &mut MutatorRef<Self>) {
+        match self.shared.phase.load(Ordering::Acquire) {
+            Phase::Remark => self.final_remark(mutator),
+            Phase::Swept => self.finish_concurrent_cycle(),
+            _ => (),
+        }
+    }
+
+    /// Stop all mutators, perform final marking and start concurrent sweeping.
+    fn final_remark(&mut self, mutator: &mut MutatorRef<Self>) {
+        let safepoint = match SafepointScope::new(mutator.clone()) {
+            Some(safepoint) => safepoint,
+            None => return,
+        };
+        // other mutator might have performed final marking before this one stopped the world.
+        if self.shared.phase.load(Ordering::Acquire) != Phase::Remark {
+            return;
+        }
+        let time = std::time::Instant::now();
+        unsafe {
+            self.global_heap_lock.lock();
+            self.large_space_lock.lock();
+            self.bytes_before_sweep = self.bytes_allocated();
+            self.final_marking(&mut []);
+            self.shared.phase.store(Phase::Sweeping, Ordering::Release);
+            self.large_space_lock.unlock();
+            self.global_heap_lock.unlock();
+        }
+        drop(safepoint);
+        self.gc_stats.record_pause(time.elapsed());
+        self.log_phase("Pause Final Mark", time.elapsed(), &[]);
+        self.spawn_gc_thread(Self::concurrent_sweep);
+    }
+
+    /// Finish concurrent cycle after concurrent sweeping: update threshold and record statistics.
+    fn finish_concurrent_cycle(&mut self) {
+        // only one mutator finishes the cycle. Next cycle can't start its pause until this mutator reaches safepoint.
+        if self
+            .shared
+            .phase
+            .compare_exchange(
+                Phase::Swept,
+                Phase::Idle,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            return;
+        }
+        let (bytes_allocated, threshold) = self.update_threshold();
+        if let Some(cycle) = self.concurrent_cycle.take() {
+            self.gc_stats.end_cycle(cycle, bytes_allocated);
+        }
+        if let Some(start) = self.cycle_start.take() {
+            self.log_phase(
+                "Concurrent Cycle",
+                start.elapsed(),
+                &[
+                    ("heap_before", self.bytes_before_sweep),
+                    ("heap_after", bytes_allocated),
+                    ("footprint", threshold),
+                ],
+            );
+        }
+        self.total_gcs += 1;
+    }
+
+    fn log_phase(&self, phase: &str, duration: Duration, extra: &[(&'static str, usize)]) {
+        if let Some(logger) = self.safepoint.logger() {
+            logger.log_phase(
+                "ConcurrentMarkSweep",
+                self.total_gcs,
+                phase,
+                duration,
+                extra,
+            );
+        }
+    }
+
+    /// Stop all mutators and start concurrent GC cycle. Does nothing if GC cycle is already running.
+    fn start_concurrent_cycle(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        if self
+            .shared
+            .phase
+            .compare_exchange(Phase::Idle, Phase::Marking, Ordering::AcqRel, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+        match SafepointScope::new(mutator.clone()) {
+            Some(safepoint) => unsafe {
+                self.global_heap_lock.lock();
+                self.large_space_lock.lock();
+                self.concurrent_cycle = Some(self.gc_stats.start_cycle(
+                    GcKind::Concurrent,
+                    GcReason::HeapFull,
+                    self.bytes_allocated(),
+                ));
+                let time = std::time::Instant::now();
+                self.initial_marking(keep);
+                self.gc_stats.record_pause(time.elapsed());
+                self.cycle_start = Some(time);
+                self.log_phase("Pause Initial Mark", time.elapsed(), &[]);
+                self.spawn_gc_thread(Self::concurrent_mark);
+                self.large_space_lock.unlock();
+                self.global_heap_lock.unlock();
+                drop(safepoint);
+            },
+            None => self.shared.phase.store(Phase::Idle, Ordering::Release),
+        }
+    }
+
+    /// Wait until concurrent GC cycle is finished. Mutator performs final marking and finishes the cycle itself, while GC
+    /// thread runs it enters unsafe state so other mutator can stop it. All of the values that mutator holds must be rooted.
+    fn wait_for_gc_to_complete(&mut self, mutator: &mut MutatorRef<Self>) {
+        loop {
+            match self.shared.phase.load(Ordering::Acquire) {
+                Phase::Idle => return,
+                Phase::Remark | Phase::Swept => self.poll_concurrent_cycle(mutator),
+                Phase::Marking | Phase::Sweeping => {
+                    let state = mutator.enter_unsafe();
+                    while matches!(
+                        self.shared.phase.load(Ordering::Acquire),
+                        Phase::Marking | Phase::Sweeping
+                    ) {
+                        std::thread::yield_now();
+                    }
+                    drop(state);
+                }
+            }
+        }
+    }
+
+    /// Wait for concurrent cycle to complete and perform full GC cycle in STW pause.
+    fn perform_full_collection(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        keep: &mut [&mut dyn Trace],
+        reason: GcReason,
+    ) {
+        {
+            letroot!(_keep = mutator.shadow_stack(), &mut *keep);
+            loop {
+                self.wait_for_gc_to_complete(mutator);
+                if self
+                    .shared
+                    .phase
+                    .compare_exchange(Phase::Idle, Phase::Marking, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    break;
+                }
+            }
+        }
+        match SafepointScope::new(mutator.clone()) {
+            Some(safepoint) => unsafe {
+                self.global_heap_lock.lock();
+                self.large_space_lock.lock();
+                let time = std::time::Instant::now();
+                let mut timer = PhaseTimer::new(self.safepoint.last_sync());
+                let prev = self.bytes_allocated();
+                let kind = if reason == GcReason::HeapFull {
+                    GcKind::Major
+                } else {
+                    GcKind::Full
+                };
+                let cycle = self.gc_stats.start_cycle(kind, reason, prev);
+                self.initial_marking(keep);
+                timer.end(GcPhase::RootScan);
+                self.shared.marker.drain();
+                self.final_marking(keep);
+                timer.end(GcPhase::Mark);
+                self.shared.space.sweep_all();
+                timer.end(GcPhase::Sweep);
+                let (bytes_allocated, threshold) = self.update_threshold();
+                self.gc_stats.end_cycle(cycle, bytes_allocated);
+                if let Some(logger) = self.safepoint.logger() {
+                    logger.log_gc(&GcRecord {
+                        policy: "ConcurrentMarkSweep",
+                        gc_id: self.total_gcs,
+                        kind,
+                        reason,
+                        heap_before: prev,
+                        heap_after: bytes_allocated,
+                        footprint: threshold,
+                        duration: time.elapsed(),
+                        phases: timer.phases(),
+                        extra: &[],
+                    });
+                }
+                self.total_gcs += 1;
+                self.large_space_lock.unlock();
+                self.global_heap_lock.unlock();
+                drop(safepoint);
+            },
+            None => (),
+        }
+        self.shared.phase.store(Phase::Idle, Ordering::Release);
+    }
+
+    /// Start GC cycle when threshold is reached. In non concurrent mode full STW cycle is performed.
+    fn trigger_cycle(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        if CONCURRENT {
+            self.start_concurrent_cycle(mutator, keep);
+        } else {
+            self.perform_full_collection(mutator, keep, GcReason::HeapFull);
+        }
+    }
+
+    /// Get new block for size class `index` and allocate cell from it. Values in `keep` are rooted while mutator
+    /// waits for GC. Returns null pointer if heap is out of memory.
+    #[cold]
+    unsafe fn alloc_slow(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        index: usize,
+        cell_size: usize,
+        keep: &mut [&mut dyn Trace],
+    ) -> *mut u8 {
+        letroot!(_keep = mutator.shadow_stack(), keep);
+        self.poll_concurrent_cycle(mutator);
+        // give final marking of other mutator a chance to stop this mutator.
+        mutator.poll_safepoint();
+        if self.should_start_cycle() {
+            self.trigger_cycle(mutator, &mut []);
+        }
+        let mut block = self.shared.space.acquire_block(index, cell_size);
+        if block.is_null() {
+            // Degraded cycle: wait for concurrent cycle and collect memory in STW pause.
+            self.collect_alloc_failure(mutator, &mut []);
+            block = self.shared.space.acquire_block(index, cell_size);
+            if block.is_null() {
+                return null_mut();
+            }
+        }
+        mutator.tlab.blocks[index] = block;
+        (*block).allocate()
+    }
+}
+
+impl<const CONCURRENT: bool> GcBase for ConcurrentMarkSweep<CONCURRENT> {
+    type TLAB = CmsTLAB;
+    const SUPPORTS_TLAB: bool = false;
+    type ReadBarrier = NoReadBarrier;
+    const LARGE_ALLOCATION_SIZE: usize = LARGE_CUTOFF;
+
+    fn inline_allocation_helpers(&self) -> Self::InlineAllocationHelpers {
+        NoHelp
+    }
+
+    fn add_constraint<T: MarkingConstraint + 'static>(&mut self, constraint: T) {
+        self.global_lock();
+        self.constraints.push(Box::new(constraint));
+        self.global_unlock();
+    }
+
+    fn try_allocate_raw(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        size: usize,
+        type_id: std::any::TypeId,
+        vtable: usize,
+    ) -> Result<*mut HeapObjectHeader, AllocError<()>> {
+        let size = align_usize(size + size_of::<HeapObjectHeader>(), 8);
+        unsafe {
+            let object = if size < Self::LARGE_ALLOCATION_SIZE {
+                let (index, cell_size) = self.shared.space.size_class(size);
+                let mut memory = mutator.tlab.allocate_cell(index);
+                if memory.is_null() {
+                    memory = self.alloc_slow(mutator, index, cell_size, &mut []);
+                    if memory.is_null() {
+                        return Err(AllocError::new((), size, AllocationSpace::New));
+                    }
+                }
+                let object = memory.cast::<HeapObjectHeader>();
+                object.write(HeapObjectHeader {
+                    value: VTable { raw: vtable as _ },
+                    padding: 0,
+                    padding2: 0,
+                    type_id: make_small_type_id(type_id),
+                });
+                (*object).set_size(cell_size);
+                object
+            } else {
+                self.large_space_lock.lock();
+                let mut object = self.large_space.try_allocate(size);
+                self.large_space_lock.unlock();
+                if object.is_null() {
+                    self.collect_alloc_failure(mutator, &mut []);
+                    self.large_space_lock.lock();
+                    object = self.large_space.try_allocate(size);
+                    self.large_space_lock.unlock();
+                    if object.is_null() {
+                        return Err(AllocError::new((), size, AllocationSpace::Large));
+                    }
+                }
+                (*object).set_vtable(vtable);
+                (*object).type_id = make_small_type_id(type_id);
+                (*object).force_set_color(GC_WHITE);
+                object
+            };
+
+            let gced: Gc<(), Self> = Gc {
+                base: NonNull::new_unchecked(object),
+                marker: Default::default(),
+            };
+            self.post_alloc(gced);
+            Ok(object)
+        }
+    }
+
+    fn allocate_weak<T: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        value: Gc<T, Self>,
+    ) -> Weak<T, Self> {
+        let weak_ref = unsafe { Weak::<T, Self>::create(mutator, value) };
+        self.global_heap_lock.lock();
+        self.weak_refs.push(weak_ref.to_dyn());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        weak_ref
+    }
+    fn allocate_soft<T: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        value: Gc<T, Self>,
+    ) -> SoftRef<T, Self> {
+        let soft_ref = unsafe { SoftRef::<T, Self>::create(mutator, value) };
+        self.global_heap_lock.lock();
+        self.soft_refs.push(soft_ref.to_dyn());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        soft_ref
+    }
+    fn set_soft_ref_lru_policy(&mut self, ms_per_mb: u64) {
+        self.global_heap_lock.lock();
+        self.soft_ref_policy.set_ms_per_mb(ms_per_mb);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn allocate_ephemeron<K: Collectable + ?Sized, V: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        key: Gc<K, Self>,
+        value: Gc<V, Self>,
+    ) -> Ephemeron<K, V, Self> {
+        let ephemeron = unsafe { Ephemeron::create(mutator, key, value) };
+        self.global_heap_lock.lock();
+        self.ephemerons.push(ephemeron.to_dyn());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        ephemeron
+    }
+    fn persistent_roots(&self) -> Arc<PersistentRoots<Self>> {
+        self.persistent_roots.clone()
+    }
+    fn pinned_objects(&self) -> Arc<PinnedObjects<Self>> {
+        self.pinned_objects.clone()
+    }
+    fn stats(&self) -> HeapStats {
+        self.gc_stats.snapshot(
+            self.bytes_allocated(),
+            self.threshold.load(Ordering::Relaxed),
+        )
+    }
+    fn add_gc_listener(&mut self, listener: Box<dyn GcListener>) {
+        self.gc_stats.add_listener(listener);
+    }
+    fn register_ordered_finalizer(&mut self, finalizer: OrderedFinalizer<Self>) {
+        self.global_heap_lock.lock();
+        self.ordered_finalizers.push(finalizer);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn pop_ready_finalizer(&mut self) -> Option<OrderedFinalizer<Self>> {
+        self.global_heap_lock.lock();
+        let finalizer = pop_ready_finalizer(&mut self.ordered_finalizers);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        finalizer
+    }
+    fn set_oom_handler(&mut self, handler: OomHandler<Self>) {
+        self.oom_handler.set(handler);
+    }
+    fn handle_oom(&mut self, mutator: &mut MutatorRef<Self>, size: usize) -> bool {
+        // emergency collection: clear all soft references before giving up.
+        if !self.soft_refs.is_empty() && self.soft_ref_policy.request_clear_all() {
+            self.full_collection(mutator, &mut []);
+            return true;
+        }
+        self.oom_handler.invoke(mutator, size)
+    }
+
+    #[inline(always)]
+    fn try_alloc_inline<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+        space: AllocationSpace,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
+        let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
+        let (index, cell_size) = self.shared.space.size_class(size);
+        unsafe {
+            let mut memory = mutator.tlab.allocate_cell(index);
+            if memory.is_null() {
+                memory = self.alloc_slow(mutator, index, cell_size, &mut [&mut value]);
+                if memory.is_null() {
+                    return Err(AllocError::new(value, size, space));
+                }
+            }
+            let object = memory.cast::<HeapObjectHeader>();
+            object.write(HeapObjectHeader {
+                value: VTable {
+                    metadata: vtable_of::<T>(),
+                },
+                padding: 0,
+                padding2: 0,
+                type_id: small_type_id::<T>(),
+            });
+            (*object).set_size(cell_size);
+            ((*object).data() as *mut T).write(value);
+            let gced = Gc {
+                base: NonNull::new_unchecked(object),
+                marker: Default::default(),
+            };
+            self.post_alloc(gced);
+            Ok(gced)
+        }
+    }
+
+    /// Retreating wavefront write barrier. If marking is in progress and `object` is black it is coloured grey and
+    /// rescanned by marker. This write barrier must be used right after write to an object happened.
+    #[inline]
+    fn write_barrier(&mut self, _: &mut MutatorRef<Self>, object: Gc<dyn Collectable, Self>) {
+        unsafe {
+            write_barrier_impl(&self.shared.marker, object.base.as_ptr());
+        }
+    }
+
+    fn collect_alloc_failure(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        keep: &mut [&mut dyn Trace],
+    ) {
+        self.perform_full_collection(mutator, keep, GcReason::AllocationFailure);
+    }
+
+    fn collect(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        self.perform_full_collection(mutator, keep, GcReason::RequestedByUser);
+    }
+
+    fn alloc_tlab_area(&mut self, _mutator: &MutatorRef<Self>, _size: usize) -> *mut u8 {
+        null_mut()
+    }
+    fn safepoint(&self) -> &GlobalSafepoint {
+        &self.safepoint
+    }
+
+    fn attach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
+        self.global_heap_lock.lock();
+        self.safepoint.n_mutators.fetch_add(1, Ordering::Relaxed);
+        self.mutators.push(mutator);
+        unsafe { self.global_heap_lock.unlock() };
+    }
+
+    unsafe fn detach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
+        self.global_heap_lock.lock();
+
+        let mut detached = false;
+        self.mutators.retain(|x| {
+            let x = *x;
+            let y = mutator;
+            if x == y {
+                detached = true;
+                false
+            } else {
+                true
+            }
+        });
+        self.safepoint.n_mutators.fetch_sub(1, Ordering::Relaxed);
+        assert!(detached, "mutator must be detached");
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+
+    fn global_lock(&self) {
+        self.global_heap_lock.lock();
+    }
+    fn global_unlock(&self) {
+        unsafe {
+            debug_assert!(self.global_heap_lock.is_locked());
+            self.global_heap_lock.unlock();
+        }
+    }
+
+    fn mutators(&self) -> &[*mut Mutator<Self>] {
+        assert!(self.global_heap_lock.is_locked());
+        &self.mutators
+    }
+
+    fn try_allocate_large<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
+        unsafe {
+            let size = value.allocation_size() + size_of::<HeapObjectHeader>();
+            if self.should_start_cycle() {
+                self.trigger_cycle(mutator, &mut [&mut value]);
+            }
+            self.large_space_lock.lock();
+            let mut object = self.large_space.try_allocate(size);
+            if object.is_null() {
+                self.large_space_lock.unlock();
+                self.collect_alloc_failure(mutator, &mut [&mut value]);
+                self.large_space_lock.lock();
+                object = self.large_space.try_allocate(size);
+                if object.is_null() {
+                    self.large_space_lock.unlock();
+                    return Err(AllocError::new(value, size, AllocationSpace::Large));
+                }
+            }
+            (*object).set_metadata(vtable_of::<T>());
+            (*object).type_id = small_type_id::<T>();
+            (*object).force_set_color(GC_WHITE);
+            let gc = Gc {
+                base: NonNull::new_unchecked(object),
+                marker: PhantomData,
+            };
+            ((*object).data() as *mut T).write(value);
+            self.large_space_lock.unlock();
+            self.post_alloc(gc);
+            Ok(gc)
+        }
+    }
+
+    #[inline(always)]
+    fn post_alloc<T: Collectable + Sized + 'static>(&mut self, value: Gc<T, Self>) {
+        if std::mem::needs_drop::<T>() {
+            unsafe {
+                self.finalize_lock.lock();
+                self.finalize_list.push_back(value.base.as_ptr());
+                self.finalize_lock.unlock();
+            }
+        }
+    }
+}
+
+impl<const CONCURRENT: bool> Drop for ConcurrentMarkSweep<CONCURRENT> {
+    fn drop(&mut self) {
+        if let Some(handle) = self.gc_thread.get_mut().take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{instantiate_cms, CmsOptions, ConcurrentMarkSweep, Phase};
+    use crate::{
+        api::{Collectable, Finalize, Gc, Trace, Visitor, GC_BLACK, GC_GREY},
+        gc_base::{AllocationSpace, GcBase},
+        mutator::MutatorRef,
+    };
+    use atomic::Ordering;
+
+    type Cms = ConcurrentMarkSweep<true>;
+
+    struct Node<H: GcBase = Cms> {
+        value: usize,
+        next: Option<Gc<Node<H>, H>>,
+    }
+
+    unsafe impl<H: GcBase> Trace for Node<H> {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+        }
+    }
+    unsafe impl<H: GcBase> Finalize for Node<H> {}
+    impl<H: GcBase + 'static> Collectable for Node<H> {}
+
+    struct Slots {
+        slots: [Option<Gc<Node, Cms>>; 64],
+    }
+
+    unsafe impl Trace for Slots {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            for slot in self.slots.iter_mut() {
+                slot.trace(vis);
+            }
+        }
+    }
+    unsafe impl Finalize for Slots {}
+    impl Collectable for Slots {}
+
+    fn options() -> CmsOptions {
+        CmsOptions {
+            size: 64 * 1024 * 1024,
+            initial_size: 256 * 1024,
+            min_heap_size: 256 * 1024,
+            max_heap_size: 64 * 1024 * 1024,
+            ..Default::default()
+        }
+    }
+
+    fn heap() -> MutatorRef<Cms> {
+        instantiate_cms::<true>(options())
+    }
+
+    fn node(mutator: &mut MutatorRef<Cms>, value: usize) -> Gc<Node, Cms> {
+        mutator.allocate(Node { value, next: None }, AllocationSpace::New)
+    }
+
+    #[test]
+    fn test_final_remark() {
+        let mut mutator = heap();
+        letroot!(holder = mutator.shadow_stack(), node(&mut mutator, 1));
+        holder.next = Some(node(&mut mutator, 2));
+        let heap = unsafe { &mut *mutator.heap.get() };
+        heap.start_concurrent_cycle(&mut mutator, &mut []);
+        while heap.shared.phase.load(Ordering::Acquire) == Phase::Marking {
+            std::thread::yield_now();
+        }
+        // `holder` is black, young object is stored only in it.
+        unsafe {
+            assert_eq!((*holder.base.as_ptr()).get_color(), GC_BLACK);
+        }
+        holder.next = Some(node(&mut mutator, 3));
+        mutator.write_barrier(holder.to_dyn());
+        unsafe {
+            assert_eq!((*holder.base.as_ptr()).get_color(), GC_GREY);
+        }
+        heap.wait_for_gc_to_complete(&mut mutator);
+        assert_eq!(heap.shared.phase.load(Ordering::Acquire), Phase::Idle);
+        assert_eq!(mutator.stats().concurrent_collections, 1);
+        // cells of dead objects are reused by new objects.
+        for value in 0..10000 {
+            node(&mut mutator, value);
+        }
+        assert_eq!(holder.value, 1);
+        assert_eq!(holder.next.unwrap().value, 3);
+    }
+
+    #[test]
+    fn test_marking_while_mutating() {
+        let mut mutator = heap();
+        letroot!(
+            slots = mutator.shadow_stack(),
+            mutator.allocate(Slots { slots: [None; 64] }, AllocationSpace::New)
+        );
+        for value in 0..200000 {
+            let object = node(&mut mutator, value);
+            slots.slots[value % 64] = Some(object);
+            mutator.write_barrier(slots.to_dyn());
+            if value % 64 == 63 {
+                for (index, slot) in slots.slots.iter().enumerate() {
+                    assert_eq!(slot.unwrap().value, value - 63 + index);
+                }
+            }
+        }
+        assert!(mutator.stats().concurrent_collections > 0);
+        mutator.collect(&mut []);
+        for (index, slot) in slots.slots.iter().enumerate() {
+            assert_eq!(slot.unwrap().value, 200000 - 64 + index);
+        }
+    }
+    #[test]
+    fn test_stop_the_world() {
+        type StwCms = ConcurrentMarkSweep<false>;
+        let mut mutator = instantiate_cms::<false>(options());
+        letroot!(
+            head = mutator.shadow_stack(),
+            None::<Gc<Node<StwCms>, StwCms>>
+        );
+        for value in 0..100000 {
+            let next = *head;
+            *head = Some(mutator.allocate(Node { value, next }, AllocationSpace::New));
+            // garbage that is freed by the next cycle.
+            mutator.allocate(Node::<StwCms> { value, next: None }, AllocationSpace::New);
+        }
+        let stats = mutator.stats();
+        assert!(stats.major_collections > 0);
+        assert_eq!(stats.concurrent_collections, 0);
+        mutator.collect(&mut []);
+
+        let mut node = *head;
+        let mut expected = 100000;
+        while let Some(current) = node {
+            expected -= 1;
+            assert_eq!(current.value, expected);
+            node = current.next;
+        }
+        assert_eq!(expected, 0);
+    }
+
+    #[test]
+    fn test_pinning() {
+        let mut mutator = heap();
+        let object = node(&mut mutator, 42);
+        let pinned = mutator.pin(object);
+        let address = pinned.as_ptr();
+        letroot!(weak = mutator.shadow_stack(), mutator.allocate_weak(object));
+        for _ in 0..4 {
+            for value in 0..10000 {
+                node(&mut mutator, value);
+            }
+            mutator.collect(&mut []);
+            assert_eq!(pinned.as_ptr(), address);
+            assert_eq!(pinned.value, 42);
+            assert!(weak.upgrade().is_some());
+        }
+
+        drop(pinned);
+        mutator.collect(&mut []);
+        assert!(weak.upgrade().is_none());
+    }
+}
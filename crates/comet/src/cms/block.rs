@@ -1,6 +1,6 @@
 use std::{mem::size_of, ptr::null_mut};
 
-use crate::api::HeapObjectHeader;
+use crate::api::{HeapObjectHeader, GC_BLACK, GC_WHITE};
 
 pub const BLOCK_SIZE: usize = 16 * 1024;
 pub const ATOM_SIZE: usize = 16;
@@ -8,11 +8,15 @@ pub const BLOCK_PAYLOAD: usize = BLOCK_SIZE - size_of::<Block>();
 
 pub struct FreeList {
     head: *mut HeapObjectHeader,
+    len: usize,
 }
 
 impl FreeList {
     pub fn new() -> Self {
-        Self { head: null_mut() }
+        Self {
+            head: null_mut(),
+            len: 0,
+        }
     }
 
     pub fn add(&mut self, entry: *mut u8) {
@@ -21,21 +25,120 @@ impl FreeList {
             (*entry).set_free();
             (*entry).value.raw = self.head as u64;
             self.head = entry;
+            self.len += 1;
         }
     }
 
+    /// Take first entry from free-list. Returns null pointer if free-list is empty.
     pub fn take(&mut self) -> *mut HeapObjectHeader {
         unsafe {
             let prev = self.head;
+            if prev.is_null() {
+                return null_mut();
+            }
             self.head = (*prev).value.raw as *mut HeapObjectHeader;
+            self.len -= 1;
             prev
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_null()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
 }
 
+/// Block of memory that stores cells of the same size. Free cells are linked into free-list.
+///
+/// Block header is located at the start of the block and cells follow it.
+#[repr(C, align(16))]
 pub struct Block {
     free_list: FreeList,
-    cell_size: u8,
+    cell_size: u32,
+    /// Index in size class table.
+    size_class_index: u32,
+    /// Number of bytes this block contributes to heap allocated bytes.
+    pub(crate) accounted_bytes: usize,
 }
 
-impl Block {}
+impl Block {
+    /// Initialize block at `at` with `cell_size` cells and put all of the cells to free-list.
+    pub fn new(at: *mut u8, cell_size: usize, size_class_index: usize) -> *mut Self {
+        debug_assert!(cell_size.is_multiple_of(ATOM_SIZE) && cell_size <= BLOCK_PAYLOAD);
+        unsafe {
+            let block = at.cast::<Self>();
+            block.write(Self {
+                free_list: FreeList::new(),
+                cell_size: cell_size as _,
+                size_class_index: size_class_index as _,
+                accounted_bytes: 0,
+            });
+            for i in (0..(*block).cell_count()).rev() {
+                let cell = (*block).cell(i);
+                (*block).free_list.add(cell);
+            }
+            block
+        }
+    }
+
+    pub fn start(&self) -> *mut u8 {
+        self as *const Self as _
+    }
+
+    pub fn end(&self) -> *mut u8 {
+        (self as *const Self as usize + BLOCK_SIZE) as _
+    }
+
+    pub fn cell_size(&self) -> usize {
+        self.cell_size as _
+    }
+
+    pub fn size_class_index(&self) -> usize {
+        self.size_class_index as _
+    }
+
+    pub fn cell_count(&self) -> usize {
+        BLOCK_PAYLOAD / self.cell_size()
+    }
+
+    pub fn cell(&self, index: usize) -> *mut u8 {
+        unsafe { self.start().add(size_of::<Self>() + index * self.cell_size()) }
+    }
+
+    pub fn free_list(&mut self) -> &mut FreeList {
+        &mut self.free_list
+    }
+
+    /// Allocate cell from this block. Returns null pointer if there is no free cells in block.
+    #[inline]
+    pub fn allocate(&mut self) -> *mut u8 {
+        self.free_list.take().cast()
+    }
+
+    /// Sweep block. All cells that are not marked black are put to free-list and black cells are coloured white
+    /// for the next GC cycle. Returns number of live cells in block.
+    pub fn sweep(&mut self) -> usize {
+        let mut live = 0;
+        self.free_list = FreeList::new();
+        for i in (0..self.cell_count()).rev() {
+            let cell = self.cell(i);
+            let object = cell.cast::<HeapObjectHeader>();
+            unsafe {
+                if !(*object).is_free() && (*object).get_color() == GC_BLACK {
+                    (*object).force_set_color(GC_WHITE);
+                    live += 1;
+                } else {
+                    self.free_list.add(cell);
+                }
+            }
+        }
+        live
+    }
+
+    pub fn align(addr: *const u8) -> *mut Self {
+        (addr as usize & !(BLOCK_SIZE - 1)) as _
+    }
+}
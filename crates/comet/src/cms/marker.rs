@@ -1,12 +1,97 @@
+use std::{ptr::NonNull, sync::atomic::AtomicBool};
+
+use atomic::Ordering;
+
 use super::marking_worklist::MarkingWorklists;
+use crate::{
+    api::{HeapObjectHeader, Visitor, GC_BLACK, GC_GREY, GC_WHITE},
+    large_space::PreciseAllocation,
+};
 
+/// Tri-color marker. White objects are pushed to marking worklist and coloured grey, when object is traced
+/// it is coloured black.
+///
+/// Marker is shared between mutators and GC thread, all of its methods take shared reference.
 pub struct Marker {
     marking_worklists: MarkingWorklists,
-    is_marking: bool,
+    is_marking: AtomicBool,
 }
 
 impl Marker {
+    pub fn new() -> Self {
+        Self {
+            marking_worklists: MarkingWorklists::new(),
+            is_marking: AtomicBool::new(false),
+        }
+    }
+
     pub fn marking_worklists(&self) -> &MarkingWorklists {
         &self.marking_worklists
     }
+
+    /// Returns `true` if marking cycle is in progress and write barrier must be executed.
+    #[inline(always)]
+    pub fn is_marking(&self) -> bool {
+        self.is_marking.load(Ordering::Acquire)
+    }
+
+    pub fn set_marking(&self, is_marking: bool) {
+        self.is_marking.store(is_marking, Ordering::Release);
+    }
+
+    /// Returns [Visitor] that marks objects with this marker.
+    pub fn visitor(&self) -> MarkingVisitor<'_> {
+        MarkingVisitor { marker: self }
+    }
+
+    /// Colour white object grey and push it to marking worklist.
+    pub fn mark_object(&self, root: &mut NonNull<HeapObjectHeader>) {
+        let object = root.as_ptr();
+        unsafe {
+            if !(*object).set_color(GC_WHITE, GC_GREY) {
+                if (*object).is_precise() {
+                    (*PreciseAllocation::from_cell(object)).test_and_set_marked();
+                }
+                self.marking_worklists
+                    .marking_worklist()
+                    .push(object as usize);
+            }
+        }
+    }
+
+    /// Process grey objects until both marking and write barrier worklists are empty.
+    pub fn drain(&self) {
+        let mut visitor = self.visitor();
+        while let Some(object) = self
+            .marking_worklists
+            .marking_worklist()
+            .pop()
+            .or_else(|| self.marking_worklists.write_barrier_worklist().pop())
+        {
+            let object = object as *mut HeapObjectHeader;
+            unsafe {
+                // object might be pushed twice if write barrier re-greyed it before it was popped from marking worklist.
+                if !(*object).set_color(GC_GREY, GC_BLACK) {
+                    (*object).get_dyn().trace(&mut visitor);
+                }
+            }
+        }
+    }
+}
+
+impl Default for Marker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// [Visitor] that marks objects with [Marker].
+pub struct MarkingVisitor<'a> {
+    marker: &'a Marker,
+}
+
+impl Visitor for MarkingVisitor<'_> {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        self.marker.mark_object(root);
+    }
 }
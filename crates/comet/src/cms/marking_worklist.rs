@@ -6,7 +6,28 @@ pub struct MarkingWorklists {
 }
 
 impl MarkingWorklists {
+    pub fn new() -> Self {
+        Self {
+            marking_worklists: SegQueue::new(),
+            write_barrier_worklist: SegQueue::new(),
+        }
+    }
+
+    pub fn marking_worklist(&self) -> &SegQueue<usize> {
+        &self.marking_worklists
+    }
+
     pub fn write_barrier_worklist(&self) -> &SegQueue<usize> {
         &self.write_barrier_worklist
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.marking_worklists.is_empty() && self.write_barrier_worklist.is_empty()
+    }
+}
+
+impl Default for MarkingWorklists {
+    fn default() -> Self {
+        Self::new()
+    }
 }
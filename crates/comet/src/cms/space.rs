@@ -1,6 +1,11 @@
-use crate::bitmap::round_up;
+use std::{ptr::null_mut, sync::atomic::AtomicUsize};
 
-use super::block::{Block, ATOM_SIZE, BLOCK_PAYLOAD};
+use atomic::Ordering;
+use parking_lot::Mutex;
+
+use crate::{bitmap::round_up, utils::mmap::Mmap};
+
+use super::block::{Block, ATOM_SIZE, BLOCK_PAYLOAD, BLOCK_SIZE};
 /// The largest cell we're willing to allocate in a [Block] the "normal way" (i.e. using size
 /// classes, rather than a large allocation) is half the size of the payload, rounded down. This
 /// ensures that we only use the size class approach if it means being able to pack two things
@@ -36,7 +41,7 @@ pub fn size_classes(size_class_progression: f64, dump: bool) -> Vec<usize> {
         let cells_per_block = BLOCK_PAYLOAD / size_class;
         let possibly_better_size_class = (BLOCK_PAYLOAD / cells_per_block) & !(ATOM_SIZE - 1);
 
-        let original_wastage = BLOCK_PAYLOAD - cells_per_block * ATOM_SIZE;
+        let original_wastage = BLOCK_PAYLOAD - cells_per_block * size_class;
         let new_wastage = (possibly_better_size_class - size_class) * cells_per_block;
 
         let better_size_class = if new_wastage > original_wastage {
@@ -74,7 +79,7 @@ pub fn build_size_class_table(progression: f64, dump: bool) -> [usize; NUM_SIZE_
         for i in next_index..=index {
             table[i] = size_class;
         }
-        next_index += 1;
+        next_index = index + 1;
     }
 
     for i in next_index..NUM_SIZE_CLASSES {
@@ -83,8 +88,26 @@ pub fn build_size_class_table(progression: f64, dump: bool) -> [usize; NUM_SIZE_
     table
 }
 
+struct Blocks {
+    /// Pointer to the first block that was never used.
+    bump: *mut u8,
+    /// Empty blocks.
+    free_blocks: Vec<*mut Block>,
+    /// Swept blocks that have free cells, indexed by size class index.
+    available: Vec<Vec<*mut Block>>,
+    /// All blocks that contain live objects or were allocated into since last sweep.
+    live_blocks: Vec<*mut Block>,
+    /// Blocks that are not yet swept in current GC cycle.
+    unswept: Vec<*mut Block>,
+}
+
+/// Heap space that is divided into [Block]s, each block stores cells of single size class.
 pub struct Space {
+    map: Mmap,
     size_class_for_size_step: [usize; NUM_SIZE_CLASSES],
+    blocks: Mutex<Blocks>,
+    /// Number of bytes in cells that were handed out to mutators or that survived last GC cycle.
+    pub num_bytes_allocated: AtomicUsize,
 }
 
 impl Space {
@@ -96,4 +119,118 @@ impl Space {
         let result = index * ATOM_SIZE;
         result
     }
+
+    pub fn new(size: usize, size_class_progression: f64, dump_size_classes: bool) -> Self {
+        let size = round_up(size as _, BLOCK_SIZE as _) as usize;
+        let map = Mmap::new(size, BLOCK_SIZE);
+        Self {
+            blocks: Mutex::new(Blocks {
+                bump: map.aligned_start(),
+                free_blocks: vec![],
+                available: vec![vec![]; NUM_SIZE_CLASSES],
+                live_blocks: vec![],
+                unswept: vec![],
+            }),
+            map,
+            size_class_for_size_step: build_size_class_table(
+                size_class_progression,
+                dump_size_classes,
+            ),
+            num_bytes_allocated: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns size class index and cell size for allocation of `size` bytes. `size` must be less or equal to [LARGE_CUTOFF].
+    #[inline]
+    pub fn size_class(&self, size: usize) -> (usize, usize) {
+        let cell_size = self.size_class_for_size_step[Self::size_class_to_index(size)];
+        (Self::size_class_to_index(cell_size), cell_size)
+    }
+
+    pub fn has_address(&self, ptr: *const u8) -> bool {
+        ptr >= self.map.aligned_start() && ptr < self.map.end()
+    }
+
+    /// Get block with free cells for size class `index`. Unswept blocks are swept lazily if there is no swept blocks available.
+    /// Returns null pointer if space is exhausted.
+    pub fn acquire_block(&self, index: usize, cell_size: usize) -> *mut Block {
+        let mut blocks = self.blocks.lock();
+        let mut block = blocks.available[index].pop().unwrap_or(null_mut());
+        while block.is_null() {
+            match blocks.unswept.pop() {
+                Some(unswept) => {
+                    self.sweep_block(&mut blocks, unswept);
+                    block = blocks.available[index].pop().unwrap_or(null_mut());
+                }
+                None => break,
+            }
+        }
+
+        if block.is_null() {
+            let memory = match blocks.free_blocks.pop() {
+                Some(memory) => memory.cast::<u8>(),
+                None if (blocks.bump as usize) + BLOCK_SIZE <= self.map.end() as usize => {
+                    let memory = blocks.bump;
+                    blocks.bump = unsafe { memory.add(BLOCK_SIZE) };
+                    memory
+                }
+                None => return null_mut(),
+            };
+            block = Block::new(memory, cell_size, index);
+            blocks.live_blocks.push(block);
+        }
+        unsafe {
+            let bytes = (*block).free_list().len() * cell_size;
+            (*block).accounted_bytes += bytes;
+            self.num_bytes_allocated.fetch_add(bytes, Ordering::Relaxed);
+        }
+        block
+    }
+
+    /// Move all of the blocks to unswept list. Must be invoked when mutators are stopped and
+    /// do not hold any blocks.
+    pub fn prepare_for_sweep(&self) {
+        let mut blocks = self.blocks.lock();
+        let live = std::mem::take(&mut blocks.live_blocks);
+        blocks.unswept.extend(live);
+        for available in blocks.available.iter_mut() {
+            available.clear();
+        }
+    }
+
+    /// Sweep single unswept block. Returns `false` if there is no more blocks to sweep.
+    pub fn sweep_one(&self) -> bool {
+        let mut blocks = self.blocks.lock();
+        match blocks.unswept.pop() {
+            Some(block) => {
+                self.sweep_block(&mut blocks, block);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sweep all of the unswept blocks.
+    pub fn sweep_all(&self) {
+        while self.sweep_one() {}
+    }
+
+    fn sweep_block(&self, blocks: &mut Blocks, block: *mut Block) {
+        unsafe {
+            let live = (*block).sweep();
+            let live_bytes = live * (*block).cell_size();
+            self.num_bytes_allocated
+                .fetch_sub((*block).accounted_bytes - live_bytes, Ordering::Relaxed);
+            (*block).accounted_bytes = live_bytes;
+            if live == 0 {
+                self.map.dontneed(block.cast(), BLOCK_SIZE);
+                blocks.free_blocks.push(block);
+            } else {
+                blocks.live_blocks.push(block);
+                if !(*block).free_list().is_empty() {
+                    blocks.available[(*block).size_class_index()].push(block);
+                }
+            }
+        }
+    }
 }
@@ -8,7 +8,7 @@ pub(super) unsafe fn write_barrier_impl(marker: &Marker, object: *mut HeapObject
     // if object color is black it was already visited, we set its color to grey and push
     // it to write barrier worklist so concurrent marker will eventually process it in
     // concurrent marking cycle or at final marking cycle.
-    if (*object).set_color(GC_BLACK, GC_GREY) {
+    if marker.is_marking() && !(*object).set_color(GC_BLACK, GC_GREY) {
         write_barrier_slow(marker, object);
     }
 }
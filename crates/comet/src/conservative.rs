@@ -0,0 +1,187 @@
+//! Conservative stack scanning.
+//!
+//! When conservative stack scanning is enabled with [MutatorRef::set_conservative_stack_scanning](crate::mutator::MutatorRef::set_conservative_stack_scanning)
+//! GC scans stack of each mutator between its base and the stack pointer recorded at the last safepoint, and callee-saved registers
+//! spilled at the same point. Every word that points into an object (possibly into the middle of it) keeps that object alive and
+//! pins it for the current GC cycle, so raw [Gc](crate::api::Gc) pointers stored in local variables do not have to be rooted with
+//! [letroot!](crate::letroot). Rooting is still required for pointers stored outside of mutator stack.
+//!
+//! Supported by [Immix](crate::immix), [StickyImmix](crate::sticky_immix) and [MarkSweep](crate::marksweep).
+use std::ptr::null_mut;
+
+use crate::{api::HeapObjectHeader, bitmap::SpaceBitmap, utils::align_down};
+
+/// Number of register slots in [Registers].
+pub const REGISTER_SLOTS: usize = 12;
+
+/// Callee-saved registers of mutator thread. Registers are spilled when mutator records its stack pointer, values that are kept in
+/// registers of caller frames are invisible when only stack memory is scanned.
+#[repr(C)]
+pub struct Registers {
+    slots: [usize; REGISTER_SLOTS],
+}
+
+impl Registers {
+    pub const fn new() -> Self {
+        Self {
+            slots: [0; REGISTER_SLOTS],
+        }
+    }
+    /// Spill callee-saved registers of current thread.
+    #[inline(always)]
+    pub fn capture(&mut self) {
+        let slots = self.slots.as_mut_ptr();
+        #[cfg(target_arch = "x86_64")]
+        unsafe {
+            std::arch::asm!(
+                "mov [{0}], rbx",
+                "mov [{0} + 8], rbp",
+                "mov [{0} + 16], r12",
+                "mov [{0} + 24], r13",
+                "mov [{0} + 32], r14",
+                "mov [{0} + 40], r15",
+                "mov [{0} + 48], rsi",
+                "mov [{0} + 56], rdi",
+                in(reg) slots,
+                options(nostack, preserves_flags)
+            );
+        }
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            std::arch::asm!(
+                "stp x19, x20, [{0}]",
+                "stp x21, x22, [{0}, #16]",
+                "stp x23, x24, [{0}, #32]",
+                "stp x25, x26, [{0}, #48]",
+                "stp x27, x28, [{0}, #64]",
+                "stp x29, x30, [{0}, #80]",
+                in(reg) slots,
+                options(nostack, preserves_flags)
+            );
+        }
+        #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+        let _ = slots;
+    }
+
+    pub fn slots(&self) -> &[usize] {
+        &self.slots
+    }
+}
+
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns base (highest address) of current thread stack. Falls back to current stack pointer if stack bounds can't be queried,
+/// in that case only frames that are below the caller are scanned.
+#[allow(unused_mut)]
+pub fn thread_stack_base() -> *mut u8 {
+    let mut base = null_mut::<u8>();
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    unsafe {
+        let mut attr = std::mem::MaybeUninit::<libc::pthread_attr_t>::zeroed();
+        if libc::pthread_getattr_np(libc::pthread_self(), attr.as_mut_ptr()) == 0 {
+            let mut addr = null_mut();
+            let mut size = 0;
+            if libc::pthread_attr_getstack(attr.as_ptr(), &mut addr, &mut size) == 0 {
+                base = addr.cast::<u8>().add(size);
+            }
+            libc::pthread_attr_destroy(attr.as_mut_ptr());
+        }
+    }
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    unsafe {
+        base = libc::pthread_get_stackaddr_np(libc::pthread_self()).cast();
+    }
+    #[cfg(windows)]
+    unsafe {
+        use winapi::um::winnt::{NtCurrentTeb, NT_TIB};
+        base = (*NtCurrentTeb().cast::<NT_TIB>()).StackBase.cast();
+    }
+    if base.is_null() {
+        let mut local = 0usize;
+        base = &mut local as *mut usize as *mut u8;
+    }
+    base
+}
+
+/// Find object that contains `addr` using bitmap of object starts. Search does not go further than `max_size` bytes or `limit`
+/// back from `addr`. Returns null pointer if `addr` does not point into any object.
+///
+/// # Safety
+///
+/// Every bit set in `bitmap` must be start of a valid object, `addr` must be covered by `bitmap`.
+pub unsafe fn find_object_start(
+    bitmap: &SpaceBitmap<8>,
+    addr: *const u8,
+    limit: *const u8,
+    max_size: usize,
+) -> *mut HeapObjectHeader {
+    let addr = addr as usize;
+    let limit = addr
+        .saturating_sub(max_size)
+        .max(limit as usize)
+        .max(bitmap.heap_begin());
+    let mut cursor = align_down(addr, 8);
+    while cursor >= limit {
+        if bitmap.test(cursor as _) {
+            let object = cursor as *mut HeapObjectHeader;
+            return if addr < cursor + (*object).size() {
+                object
+            } else {
+                null_mut()
+            };
+        }
+        if cursor < 8 {
+            break;
+        }
+        cursor -= 8;
+    }
+    null_mut()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::{Collectable, Finalize, Trace},
+        gc_base::AllocationSpace,
+        immix::instantiate_immix,
+    };
+
+    struct Node {
+        value: i64,
+    }
+
+    unsafe impl Trace for Node {}
+    unsafe impl Finalize for Node {}
+    impl Collectable for Node {}
+
+    #[test]
+    fn test_conservative_stack_scanning() {
+        let mut mutator = instantiate_immix(
+            64 * 1024 * 1024,
+            4 * 1024 * 1024,
+            2 * 1024 * 1024,
+            64 * 1024 * 1024,
+            false,
+        );
+        mutator.set_conservative_stack_scanning(true);
+        // not rooted: only conservative scan of the stack keeps it alive.
+        let node = mutator.allocate(Node { value: 42 }, AllocationSpace::New);
+        let weak = mutator.allocate_weak(node);
+        let address = std::hint::black_box(&node).base.as_ptr();
+        for _ in 0..1000 {
+            mutator.allocate(Node { value: 0 }, AllocationSpace::New);
+        }
+        for _ in 0..3 {
+            mutator.full_collection(&mut []);
+        }
+        assert!(weak.upgrade().is_some());
+        // conservatively referenced objects are pinned and never moved by defragmentation.
+        assert_eq!(std::hint::black_box(&node).base.as_ptr(), address);
+        assert_eq!(node.value, 42);
+        assert!(!unsafe { (*address).pinned_bit() });
+    }
+}
@@ -6,11 +6,19 @@ use std::{
     sync::{atomic::AtomicUsize, Arc},
 };
 
+use parking_lot::Mutex;
+
 use crate::{
-    api::{Collectable, Gc, HeapObjectHeader, Trace, Visitor, Weak},
-    mutator::{Mutator, MutatorRef},
+    api::{
+        Collectable, Ephemeron, Gc, HeapObjectHeader, OrderedFinalizer, SoftRef, Trace, Visitor,
+        Weak,
+    },
+    mutator::{oom_abort, Mutator, MutatorRef},
+    persistent::PersistentRoots,
+    pin::PinnedObjects,
     rosalloc_space::RosAllocSpace,
     safepoint::GlobalSafepoint,
+    stats::{GcListener, HeapStats},
 };
 
 #[repr(u8)]
@@ -23,6 +31,108 @@ pub enum AllocationSpace {
 
 pub struct NoHelp;
 
+/// Error returned by fallible allocation functions when heap is out of memory. Owns the value that was not allocated
+/// so it is not lost and can be reused or dropped by the caller.
+pub struct AllocError<T> {
+    value: T,
+    size: usize,
+    space: AllocationSpace,
+}
+
+impl<T> AllocError<T> {
+    pub fn new(value: T, size: usize, space: AllocationSpace) -> Self {
+        Self { value, size, space }
+    }
+    /// Returns value that failed to be allocated.
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+    /// Size of failed allocation request including object header.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn space(&self) -> AllocationSpace {
+        self.space
+    }
+}
+
+impl<T> std::fmt::Debug for AllocError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AllocError")
+            .field("type", &std::any::type_name::<T>())
+            .field("size", &self.size)
+            .field("space", &self.space)
+            .finish()
+    }
+}
+
+impl<T> std::fmt::Display for AllocError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "out of memory: failed to allocate {} bytes for `{}` in {:?} space",
+            self.size,
+            std::any::type_name::<T>(),
+            self.space
+        )
+    }
+}
+
+impl<T> std::error::Error for AllocError<T> {}
+
+/// Callback that is invoked when heap is out of memory even after GC cycle. Receives mutator that failed to allocate and
+/// size of allocation request. Handler might free caches or other data it owns and return `true` to retry allocation,
+/// if `false` is returned allocation fails.
+///
+/// Value that is being allocated is not rooted while handler runs so handler must not trigger GC cycles by itself, heap
+/// will collect memory again before retrying allocation.
+pub type OomHandler<H> = Box<dyn FnMut(&mut MutatorRef<H>, usize) -> bool>;
+
+/// Per-heap storage for [OomHandler].
+pub struct OomHandlerSlot<H: GcBase> {
+    handler: Mutex<Option<OomHandler<H>>>,
+}
+
+impl<H: GcBase> Default for OomHandlerSlot<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: GcBase> OomHandlerSlot<H> {
+    pub const fn new() -> Self {
+        Self {
+            handler: parking_lot::const_mutex(None),
+        }
+    }
+
+    pub fn set(&self, handler: OomHandler<H>) {
+        *self.handler.lock() = Some(handler);
+    }
+    /// Invoke handler if it is set. Handler is taken out of the slot while it runs so it is invoked by one mutator at a time:
+    /// concurrent or recursive allocation failures on other mutators are not retried while handler is running.
+    pub fn invoke(&self, mutator: &mut MutatorRef<H>, size: usize) -> bool {
+        let handler = self.handler.lock().take();
+        match handler {
+            Some(mut handler) => {
+                let retry = handler(mutator, size);
+                let mut slot = self.handler.lock();
+                // do not overwrite handler if it was replaced while we were running.
+                if slot.is_none() {
+                    *slot = Some(handler);
+                }
+                retry
+            }
+            None => false,
+        }
+    }
+}
+
 /// Base trait for all GCs.
 pub trait GcBase: Sized + 'static {
     /// Default large object size. If allocation request exceeds this constant [GcBase::allocate_large] is invoked.
@@ -38,19 +148,33 @@ pub trait GcBase: Sized + 'static {
     fn add_constraint<T: MarkingConstraint + 'static>(&mut self, constraint: T);
 
     /// Allocates `size` bytes on heap and creates object header with `type_id` and `vtable`. This function can be used to allocate dyn sized arrays or strings.
-    fn allocate_raw(
+    /// Returns `Err` if there is no memory for object even after GC cycle.
+    fn try_allocate_raw(
         &mut self,
         mutator: &mut MutatorRef<Self>,
         size: usize,
         type_id: TypeId,
         vtable: usize,
-    ) -> *mut HeapObjectHeader {
+    ) -> Result<*mut HeapObjectHeader, AllocError<()>> {
         let _ = mutator;
         let _ = size;
         let _ = type_id;
         let _ = vtable;
         todo!()
     }
+    /// Same as [GcBase::try_allocate_raw] but aborts the process when heap is out of memory.
+    fn allocate_raw(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        size: usize,
+        type_id: TypeId,
+        vtable: usize,
+    ) -> *mut HeapObjectHeader {
+        match self.try_allocate_raw(mutator, size, type_id, vtable) {
+            Ok(object) => object,
+            Err(_) => oom_abort(),
+        }
+    }
     /// Allocates weak reference on GC heap
     fn allocate_weak<T: Collectable + ?Sized>(
         &mut self,
@@ -62,6 +186,121 @@ pub trait GcBase: Sized + 'static {
             std::any::type_name::<Self>()
         );
     }
+    /// Allocates soft reference on GC heap
+    fn allocate_soft<T: Collectable + ?Sized>(
+        &mut self,
+        _mutator: &mut MutatorRef<Self>,
+        _value: Gc<T, Self>,
+    ) -> SoftRef<T, Self> {
+        panic!(
+            "Soft references are not supported by `{}`",
+            std::any::type_name::<Self>()
+        );
+    }
+    /// Set number of milliseconds that referent of a soft reference is kept alive for each free megabyte of heap since its last
+    /// access. See [SoftRefPolicy](crate::api::SoftRefPolicy). Heaps that do not support soft references ignore it.
+    fn set_soft_ref_lru_policy(&mut self, ms_per_mb: u64) {
+        let _ = ms_per_mb;
+    }
+    /// Allocates ephemeron on GC heap
+    fn allocate_ephemeron<K: Collectable + ?Sized, V: Collectable + ?Sized>(
+        &mut self,
+        _mutator: &mut MutatorRef<Self>,
+        _key: Gc<K, Self>,
+        _value: Gc<V, Self>,
+    ) -> Ephemeron<K, V, Self> {
+        panic!(
+            "Ephemerons are not supported by `{}`",
+            std::any::type_name::<Self>()
+        );
+    }
+    /// Table of [Persistent](crate::persistent::Persistent) roots of this heap.
+    fn persistent_roots(&self) -> Arc<PersistentRoots<Self>> {
+        panic!(
+            "Persistent roots are not supported by `{}`",
+            std::any::type_name::<Self>()
+        );
+    }
+    /// Table of objects pinned with [MutatorRef::pin](crate::mutator::MutatorRef::pin). See [pin](crate::pin).
+    fn pinned_objects(&self) -> Arc<PinnedObjects<Self>> {
+        panic!(
+            "Object pinning is not supported by `{}`",
+            std::any::type_name::<Self>()
+        );
+    }
+    /// Invoked before `object` is pinned. Heaps that can't keep object in place move it to the space where objects are
+    /// not moved and return its new location.
+    fn prepare_pin(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        object: Gc<dyn Collectable, Self>,
+    ) -> Gc<dyn Collectable, Self> {
+        let _ = mutator;
+        object
+    }
+    /// Register object with [OrderedFinalize](crate::api::OrderedFinalize) finalizer.
+    fn register_ordered_finalizer(&mut self, finalizer: OrderedFinalizer<Self>) {
+        let _ = finalizer;
+        panic!(
+            "Ordered finalization is not supported by `{}`",
+            std::any::type_name::<Self>()
+        );
+    }
+    /// Removes ordered finalizer whose object is unreachable and which is ready to be run by mutator.
+    fn pop_ready_finalizer(&mut self) -> Option<OrderedFinalizer<Self>> {
+        None
+    }
+    /// Enable or disable conservative scanning of mutator stacks and registers. See [conservative](crate::conservative).
+    fn set_conservative_stack_scanning(&mut self, mutator: &mut MutatorRef<Self>, enabled: bool) {
+        let _ = mutator;
+        let _ = enabled;
+        panic!(
+            "Conservative stack scanning is not supported by `{}`",
+            std::any::type_name::<Self>()
+        );
+    }
+    /// Enable or disable verification of heap before and after each GC cycle. See [verify](crate::verify).
+    fn set_heap_verification(&mut self, mutator: &mut MutatorRef<Self>, enabled: bool) {
+        let _ = mutator;
+        let _ = enabled;
+        panic!(
+            "Heap verification is not supported by `{}`",
+            std::any::type_name::<Self>()
+        );
+    }
+    /// Statistics of this heap. See [stats](crate::stats).
+    fn stats(&self) -> HeapStats {
+        panic!(
+            "Heap statistics are not supported by `{}`",
+            std::any::type_name::<Self>()
+        );
+    }
+    /// Register listener that is notified about GC cycles. See [stats](crate::stats).
+    fn add_gc_listener(&mut self, listener: Box<dyn GcListener>) {
+        let _ = listener;
+        panic!(
+            "GC listeners are not supported by `{}`",
+            std::any::type_name::<Self>()
+        );
+    }
+    /// Set handler that is invoked when heap is out of memory. See [OomHandler] for more information.
+    fn set_oom_handler(&mut self, handler: OomHandler<Self>) {
+        let _ = handler;
+        panic!(
+            "OOM handlers are not supported by `{}`",
+            std::any::type_name::<Self>()
+        );
+    }
+    /// Set number of GC worker threads used for marking. Heaps that do not support parallel marking ignore it.
+    fn set_marking_workers(&mut self, workers: usize) {
+        let _ = workers;
+    }
+    /// Invoked by mutator when allocation fails even after GC cycle. Returns `true` if allocation should be retried.
+    fn handle_oom(&mut self, mutator: &mut MutatorRef<Self>, size: usize) -> bool {
+        let _ = mutator;
+        let _ = size;
+        false
+    }
     fn get_rosalloc_space(&self) -> *mut RosAllocSpace {
         null_mut()
     }
@@ -76,7 +315,11 @@ pub trait GcBase: Sized + 'static {
     /// Attach mutator to GC heap
     fn attach_current_thread(&mut self, mutator: *mut Mutator<Self>);
     /// Detach mutator from GC heap
-    fn detach_current_thread(&mut self, mutator: *mut Mutator<Self>);
+    ///
+    /// # Safety
+    ///
+    /// `mutator` must point to live mutator that is attached to this heap.
+    unsafe fn detach_current_thread(&mut self, mutator: *mut Mutator<Self>);
 
     /// Get safepoint reference
     fn safepoint(&self) -> &GlobalSafepoint;
@@ -99,12 +342,25 @@ pub trait GcBase: Sized + 'static {
     /// - Atomic bump-pointer/thread-local bump pointer or atomic freelist/thread-local freelist.
     ///
     /// Bump pointer might be used in Immix or SemiSpace GCs. While freelists might be used in case of Mark&Sweep GC.
+    fn try_alloc_inline<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        value: T,
+        space: AllocationSpace,
+    ) -> Result<Gc<T, Self>, AllocError<T>>;
+
+    /// Same as [GcBase::try_alloc_inline] but aborts the process when heap is out of memory.
     fn alloc_inline<T: Collectable + Sized + 'static>(
         &mut self,
         mutator: &mut MutatorRef<Self>,
         value: T,
         space: AllocationSpace,
-    ) -> Gc<T, Self>;
+    ) -> Gc<T, Self> {
+        match self.try_alloc_inline(mutator, value, space) {
+            Ok(value) => value,
+            Err(_) => oom_abort(),
+        }
+    }
 
     /// Post allocation operation e.g set mark in bitmap that this object was allocated.
     ///
@@ -116,12 +372,24 @@ pub trait GcBase: Sized + 'static {
     fn post_alloc<T: Collectable + Sized + 'static>(&mut self, value: Gc<T, Self>) {
         let _ = value;
     }
-    /// Allocates large object in GC heap.
+    /// Allocates large object in GC heap. Returns `Err` if there is no memory for object even after GC cycle.
+    fn try_allocate_large<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        value: T,
+    ) -> Result<Gc<T, Self>, AllocError<T>>;
+
+    /// Same as [GcBase::try_allocate_large] but aborts the process when heap is out of memory.
     fn allocate_large<T: Collectable + Sized + 'static>(
         &mut self,
         mutator: &mut MutatorRef<Self>,
         value: T,
-    ) -> Gc<T, Self>;
+    ) -> Gc<T, Self> {
+        match self.try_allocate_large(mutator, value) {
+            Ok(value) => value,
+            Err(_) => oom_abort(),
+        }
+    }
 
     /// Perform minor GC cycle by stopping all threads and collecting unused memory.
     fn minor_collection(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
@@ -133,7 +401,22 @@ pub trait GcBase: Sized + 'static {
     }
     /// Perform garbage collection cycle by stopping all threads and collecting unused memory.
     fn collect(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]);
+    /// Perform GC cycle forced by [stress](crate::stress) mode. Moving collectors must move objects even if there is no
+    /// space pressure. Performs [collect](Self::collect) by default.
+    fn stress_collection(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        self.collect(mutator, keep);
+    }
 
+    /// Write barrier that is invoked right before references in `object` are overwritten. Snapshot-at-the-beginning
+    /// collectors use it to mark values that are about to be overwritten. No-op by default.
+    fn pre_write_barrier(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        object: Gc<dyn Collectable, Self>,
+    ) {
+        let _ = object;
+        let _ = mutator;
+    }
     /// Write barrier implementation. No-op by default.
     fn write_barrier(&mut self, mutator: &mut MutatorRef<Self>, object: Gc<dyn Collectable, Self>) {
         let _ = object;
@@ -240,3 +523,107 @@ impl<H: GcBase> ReadBarrier<H> for BrooksPointer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{any::TypeId, cell::Cell, rc::Rc};
+
+    use super::{AllocationSpace, GcBase};
+    use crate::{
+        api::{vtable_of, Collectable, Finalize, HeapObjectHeader, Trace},
+        immix::{instantiate_immix, Immix},
+        semispace::{instantiate_semispace, SemiSpace},
+    };
+
+    struct Bytes(usize);
+
+    unsafe impl Trace for Bytes {}
+    unsafe impl Finalize for Bytes {}
+    impl Collectable for Bytes {
+        fn allocation_size(&self) -> usize {
+            self.0
+        }
+    }
+
+    fn bytes_vtable() -> usize {
+        unsafe { std::mem::transmute(vtable_of::<Bytes>()) }
+    }
+
+    /// Initialize data of raw `object` allocated with `size`, heap reads size of the value when it walks or verifies objects.
+    unsafe fn init_bytes(object: *mut HeapObjectHeader, size: usize) {
+        (*object)
+            .data()
+            .cast::<Bytes>()
+            .cast_mut()
+            .write(Bytes(size));
+    }
+
+    #[test]
+    fn test_try_allocate_raw() {
+        let mut mutator = instantiate_semispace(1024 * 1024);
+        let heap = unsafe { &mut *(mutator.heap_ref() as *mut SemiSpace) };
+        let object = heap
+            .try_allocate_raw(&mut mutator, 64, TypeId::of::<Bytes>(), bytes_vtable())
+            .unwrap();
+        unsafe {
+            init_bytes(object, 64);
+            assert_eq!((*object).vtable(), bytes_vtable());
+        }
+
+        let err = heap
+            .try_allocate_raw(
+                &mut mutator,
+                2 * 1024 * 1024,
+                TypeId::of::<Bytes>(),
+                bytes_vtable(),
+            )
+            .unwrap_err();
+        assert_eq!(err.space(), AllocationSpace::New);
+        assert!(err.size() > 2 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_try_allocate_raw_large() {
+        let mut mutator = instantiate_immix(
+            64 * 1024 * 1024,
+            4 * 1024 * 1024,
+            2 * 1024 * 1024,
+            64 * 1024 * 1024,
+            false,
+        );
+        let heap = unsafe { &mut *(mutator.heap_ref() as *mut Immix) };
+        let size = Immix::LARGE_ALLOCATION_SIZE * 2;
+        let object = heap
+            .try_allocate_raw(&mut mutator, size, TypeId::of::<Bytes>(), bytes_vtable())
+            .unwrap();
+        unsafe {
+            init_bytes(object, size);
+            assert!((*object).is_precise());
+        }
+        mutator.collect(&mut []);
+
+        // `malloc` can't satisfy this request so large object space reports OOM instead of panicking.
+        let err = heap
+            .try_allocate_raw(&mut mutator, 1 << 50, TypeId::of::<Bytes>(), bytes_vtable())
+            .unwrap_err();
+        assert_eq!(err.space(), AllocationSpace::Large);
+    }
+
+    #[test]
+    fn test_oom_handler() {
+        let mut mutator = instantiate_semispace(1024 * 1024);
+        let calls = Rc::new(Cell::new(0));
+        let handler_calls = calls.clone();
+        mutator.set_oom_handler(move |_, _| {
+            handler_calls.set(handler_calls.get() + 1);
+            false
+        });
+        let err = match mutator.try_allocate(Bytes(1 << 50), AllocationSpace::New) {
+            Ok(_) => panic!("allocation must fail"),
+            Err(err) => err,
+        };
+        assert_eq!(calls.get(), 1);
+        assert_eq!(err.space(), AllocationSpace::Large);
+        assert_eq!(err.into_inner().0, 1 << 50);
+    }
+}
@@ -0,0 +1,365 @@
+//! Structured GC logging.
+//!
+//! Every heap can have a [GcLogger] that receives a [GcRecord] for each GC pause of [Immix](crate::immix),
+//! [MarkSweep](crate::marksweep), [MiniMark](crate::minimark), [SemiSpace](crate::semispace), [CMS](crate::cms) and
+//! [Shenandoah](crate::shenandoah), and a safepoint record for each safepoint when
+//! [verbose_safepoint](crate::safepoint::verbose_safepoint) is enabled. Logger is set with
+//! [Mutator::set_gc_logger](crate::mutator::Mutator::set_gc_logger), heaps that are created with `verbose` option log to stderr
+//! in text format.
+//!
+//! Records are written either as human readable text or as JSON lines (one JSON object per line):
+//! ```json
+//! {"type":"gc","policy":"Immix","gc_id":3,"kind":"Major","reason":"AllocationFailure","heap_before":4194304,"heap_after":1048576,"footprint":8388608,"duration_us":1200,"phases_us":{"safepoint_sync":12,"root_scan":30,"mark":900,"weak_processing":8,"finalization":2,"sweep":248}}
+//! {"type":"safepoint","mutators":4,"sync_us":12}
+//! {"type":"phase","policy":"MiniMark","gc_id":5,"phase":"Concurrent Mark","duration_us":5300}
+//! ```
+//! Policy specific counters such as promoted bytes of MiniMark are written as additional fields. Policies that collect the heap
+//! concurrently also log a phase record for each pause and concurrent phase of the cycle.
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+use crate::{
+    stats::{GcKind, GcReason},
+    utils::formatted_size,
+};
+
+/// Output format of [GcLogger].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GcLogFormat {
+    /// Human readable text, same as verbose output of heaps.
+    Text,
+    /// One JSON object per line.
+    JsonLines,
+}
+
+/// Phase of GC pause.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum GcPhase {
+    /// Waiting for mutators to reach safepoint.
+    SafepointSync,
+    /// Tracing of roots: shadow stacks, persistent roots, pinned objects and values kept alive by the caller.
+    RootScan,
+    /// Transitive marking (or copying) of objects reachable from roots, including soft references and ephemerons.
+    Mark,
+    /// Clearing and updating of weak, soft and ephemeron references.
+    WeakProcessing,
+    /// Invoking finalizers of dead objects.
+    Finalization,
+    /// Freeing memory of dead objects.
+    Sweep,
+}
+
+/// Durations of phases of GC pause.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct GcPhases {
+    pub safepoint_sync: Duration,
+    pub root_scan: Duration,
+    pub mark: Duration,
+    pub weak_processing: Duration,
+    pub finalization: Duration,
+    pub sweep: Duration,
+}
+
+impl GcPhases {
+    pub fn get_mut(&mut self, phase: GcPhase) -> &mut Duration {
+        match phase {
+            GcPhase::SafepointSync => &mut self.safepoint_sync,
+            GcPhase::RootScan => &mut self.root_scan,
+            GcPhase::Mark => &mut self.mark,
+            GcPhase::WeakProcessing => &mut self.weak_processing,
+            GcPhase::Finalization => &mut self.finalization,
+            GcPhase::Sweep => &mut self.sweep,
+        }
+    }
+
+    fn entries(&self) -> [(&'static str, Duration); 6] {
+        [
+            ("safepoint_sync", self.safepoint_sync),
+            ("root_scan", self.root_scan),
+            ("mark", self.mark),
+            ("weak_processing", self.weak_processing),
+            ("finalization", self.finalization),
+            ("sweep", self.sweep),
+        ]
+    }
+}
+
+/// Measures phases of GC pause. Each call to [end](PhaseTimer::end) attributes time since the previous call to a phase.
+pub struct PhaseTimer {
+    phases: GcPhases,
+    last: Instant,
+}
+
+impl PhaseTimer {
+    /// Start measuring right after mutators reached safepoint in `safepoint_sync`.
+    pub fn new(safepoint_sync: Duration) -> Self {
+        Self {
+            phases: GcPhases {
+                safepoint_sync,
+                ..Default::default()
+            },
+            last: Instant::now(),
+        }
+    }
+    /// End `phase`. Phase can be ended several times, its durations are summed up.
+    pub fn end(&mut self, phase: GcPhase) {
+        let now = Instant::now();
+        *self.phases.get_mut(phase) += now - self.last;
+        self.last = now;
+    }
+
+    pub fn phases(&self) -> GcPhases {
+        self.phases
+    }
+}
+
+/// Record of GC pause.
+#[derive(Clone, Debug)]
+pub struct GcRecord<'a> {
+    /// Name of GC policy, e.g. `"Immix"`.
+    pub policy: &'static str,
+    /// Number of GC cycle, same as in verbose output.
+    pub gc_id: usize,
+    pub kind: GcKind,
+    pub reason: GcReason,
+    /// Bytes allocated in heap when pause started.
+    pub heap_before: usize,
+    /// Bytes allocated in heap when pause ended.
+    pub heap_after: usize,
+    /// Bytes that heap can use before the next GC cycle is triggered.
+    pub footprint: usize,
+    /// Duration of the pause without safepoint synchronization.
+    pub duration: Duration,
+    pub phases: GcPhases,
+    /// Policy specific counters in bytes, e.g. `("promoted", 1024)`.
+    pub extra: &'a [(&'static str, usize)],
+}
+
+/// Sink of GC log records. Write errors are ignored, GC never fails because log could not be written.
+pub struct GcLogger {
+    format: GcLogFormat,
+    sink: Mutex<Box<dyn Write + Send>>,
+}
+
+impl GcLogger {
+    /// Create logger that writes records in `format` to `sink`. Sink is flushed after each record.
+    pub fn new(format: GcLogFormat, sink: Box<dyn Write + Send>) -> Self {
+        Self {
+            format,
+            sink: Mutex::new(sink),
+        }
+    }
+    /// Logger that writes text records to stderr, default for verbose heaps.
+    pub fn stderr() -> Self {
+        Self::new(GcLogFormat::Text, Box::new(io::stderr()))
+    }
+    /// Logger that writes JSON lines to file at `path`. File is truncated if it exists.
+    pub fn json_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self::new(
+            GcLogFormat::JsonLines,
+            Box::new(BufWriter::new(file)),
+        ))
+    }
+
+    pub fn format(&self) -> GcLogFormat {
+        self.format
+    }
+
+    pub fn log_gc(&self, record: &GcRecord) {
+        let mut line = String::new();
+        match self.format {
+            GcLogFormat::Text => {
+                let _ = write!(
+                    line,
+                    "[gc] GC({}) Pause {} {:?} ({:?}) {}->{}({}) {:.4}ms",
+                    record.gc_id,
+                    record.policy,
+                    record.kind,
+                    record.reason,
+                    formatted_size(record.heap_before),
+                    formatted_size(record.heap_after),
+                    formatted_size(record.footprint),
+                    millis(record.duration)
+                );
+                for (name, value) in record.extra {
+                    let _ = write!(line, " {} {}", name, formatted_size(*value));
+                }
+                let _ = write!(line, "\n[gc] GC({}) Phases:", record.gc_id);
+                for (name, duration) in record.phases.entries() {
+                    let _ = write!(line, " {} {:.4}ms", name, millis(duration));
+                }
+            }
+            GcLogFormat::JsonLines => {
+                let _ = write!(
+                    line,
+                    "{{\"type\":\"gc\",\"policy\":\"{}\",\"gc_id\":{},\"kind\":\"{:?}\",\"reason\":\"{:?}\",\
+                     \"heap_before\":{},\"heap_after\":{},\"footprint\":{},\"duration_us\":{},\"phases_us\":{{",
+                    record.policy,
+                    record.gc_id,
+                    record.kind,
+                    record.reason,
+                    record.heap_before,
+                    record.heap_after,
+                    record.footprint,
+                    record.duration.as_micros()
+                );
+                for (index, (name, duration)) in record.phases.entries().into_iter().enumerate() {
+                    if index != 0 {
+                        line.push(',');
+                    }
+                    let _ = write!(line, "\"{}\":{}", name, duration.as_micros());
+                }
+                line.push('}');
+                for (name, value) in record.extra {
+                    let _ = write!(line, ",\"{}\":{}", name, value);
+                }
+                line.push('}');
+            }
+        }
+        self.write_line(&line);
+    }
+
+    pub fn log_safepoint(&self, mutators: usize, sync: Duration) {
+        let line = match self.format {
+            GcLogFormat::Text => format!(
+                "[safepoint] {} mutators reached safepoint in {:.4}ms",
+                mutators,
+                millis(sync)
+            ),
+            GcLogFormat::JsonLines => format!(
+                "{{\"type\":\"safepoint\",\"mutators\":{},\"sync_us\":{}}}",
+                mutators,
+                sync.as_micros()
+            ),
+        };
+        self.write_line(&line);
+    }
+
+    /// Log `phase` of concurrent GC cycle `gc_id`, e.g. `"Pause Initial Mark"` or `"Concurrent Sweep"`. `extra` holds phase
+    /// specific counters in bytes, same as [GcRecord::extra].
+    pub fn log_phase(
+        &self,
+        policy: &'static str,
+        gc_id: usize,
+        phase: &str,
+        duration: Duration,
+        extra: &[(&'static str, usize)],
+    ) {
+        let mut line = String::new();
+        match self.format {
+            GcLogFormat::Text => {
+                let _ = write!(line, "[gc] GC({}) {}", gc_id, phase);
+                for (name, value) in extra {
+                    let _ = write!(line, " {} {}", name, formatted_size(*value));
+                }
+                let _ = write!(line, " {:.4}ms", millis(duration));
+            }
+            GcLogFormat::JsonLines => {
+                let _ = write!(
+                    line,
+                    "{{\"type\":\"phase\",\"policy\":\"{}\",\"gc_id\":{},\"phase\":\"{}\",\"duration_us\":{}",
+                    policy,
+                    gc_id,
+                    phase,
+                    duration.as_micros()
+                );
+                for (name, value) in extra {
+                    let _ = write!(line, ",\"{}\":{}", name, value);
+                }
+                line.push('}');
+            }
+        }
+        self.write_line(&line);
+    }
+
+    fn write_line(&self, line: &str) {
+        let mut sink = self.sink.lock();
+        let _ = writeln!(sink, "{}", line);
+        let _ = sink.flush();
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_micros() as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, sync::Arc};
+
+    use parking_lot::Mutex;
+
+    use super::{GcLogFormat, GcLogger};
+    use crate::{
+        api::{Collectable, Finalize, Trace},
+        gc_base::AllocationSpace,
+        semispace::instantiate_semispace,
+    };
+
+    struct Node {
+        _value: u64,
+    }
+
+    unsafe impl Trace for Node {}
+    unsafe impl Finalize for Node {}
+    impl Collectable for Node {}
+
+    #[derive(Clone)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let mut mutator = instantiate_semispace(256 * 1024);
+        let sink = Sink(Arc::new(Mutex::new(Vec::new())));
+        mutator.set_gc_logger(GcLogger::new(
+            GcLogFormat::JsonLines,
+            Box::new(sink.clone()),
+        ));
+        for _ in 0..100 {
+            mutator.allocate(Node { _value: 0 }, AllocationSpace::New);
+        }
+        mutator.collect(&mut []);
+        mutator.collect(&mut []);
+
+        let output = String::from_utf8(sink.0.lock().clone()).unwrap();
+        let records = output
+            .lines()
+            .filter(|line| line.starts_with("{\"type\":\"gc\""))
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 2);
+        for (index, record) in records.iter().enumerate() {
+            assert!(record.ends_with('}'));
+            assert!(record.contains("\"policy\":\"SemiSpace\""));
+            assert!(record.contains(&format!("\"gc_id\":{},", index)));
+            assert!(record.contains("\"reason\":\"RequestedByUser\""));
+            for phase in [
+                "safepoint_sync",
+                "root_scan",
+                "mark",
+                "weak_processing",
+                "finalization",
+                "sweep",
+            ] {
+                assert!(record.contains(&format!("\"{}\":", phase)));
+            }
+        }
+    }
+}
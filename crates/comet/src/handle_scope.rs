@@ -0,0 +1,254 @@
+//! V8-style handle scopes.
+//!
+//! [HandleScope] is an alternative to [letroot!](crate::letroot): instead of pushing a shadow stack entry for each variable,
+//! handles are allocated in a per-thread chunked [HandleArena] that is scanned together with the shadow stack. All handles
+//! created in a scope are released at once when the scope is dropped. [EscapableHandleScope] allows to return one handle
+//! to the parent scope, so functions can return rooted values instead of raw pointers.
+//!
+//! ```ignore
+//! fn make_pair<'p>(mutator: &mut MutatorRef<Immix>, parent: &'p HandleScope<Immix>) -> Handle<'p, Pair, Immix> {
+//!     let scope = EscapableHandleScope::new(parent);
+//!     let first = scope.handle(mutator.allocate(1, AllocationSpace::New));
+//!     let second = scope.handle(mutator.allocate(2, AllocationSpace::New));
+//!     let pair = scope.handle(mutator.allocate(Pair::new(first.get(), second.get()), AllocationSpace::New));
+//!     scope.escape(pair)
+//! }
+//! ```
+use std::{
+    cell::{Cell, UnsafeCell},
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
+
+use crate::{
+    api::{Collectable, Gc, HeapObjectHeader, Trace, Visitor},
+    gc_base::GcBase,
+    shadow_stack::{Rootable, ShadowStack},
+};
+
+/// Number of handles in one chunk of [HandleArena].
+pub const HANDLE_CHUNK_SIZE: usize = 256;
+
+/// Slot that holds pointer of a single handle.
+pub struct HandleSlot(Option<NonNull<HeapObjectHeader>>);
+
+unsafe impl Trace for HandleSlot {
+    fn trace(&mut self, vis: &mut dyn Visitor) {
+        if let Some(object) = self.0.as_mut() {
+            vis.mark_object(object);
+        }
+    }
+}
+
+/// Per-thread storage for handles. Handles are allocated in fixed-size chunks so their addresses never change, chunks are
+/// reused after scopes that allocated them are closed.
+pub struct HandleArena {
+    // chunks are boxed so handles do not move when vector grows.
+    #[allow(clippy::vec_box)]
+    chunks: UnsafeCell<Vec<Box<[HandleSlot; HANDLE_CHUNK_SIZE]>>>,
+    /// Number of allocated handles.
+    top: Cell<usize>,
+    /// Number of open handle scopes. New handles can be created only in the innermost scope.
+    depth: Cell<usize>,
+}
+
+impl HandleArena {
+    pub fn new() -> Self {
+        Self {
+            chunks: UnsafeCell::new(Vec::new()),
+            top: Cell::new(0),
+            depth: Cell::new(0),
+        }
+    }
+
+    /// Number of allocated handles.
+    pub fn len(&self) -> usize {
+        self.top.get()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn allocate(&self, value: Option<NonNull<HeapObjectHeader>>) -> *mut HandleSlot {
+        let top = self.top.get();
+        let chunks = unsafe { &mut *self.chunks.get() };
+        if top / HANDLE_CHUNK_SIZE == chunks.len() {
+            const EMPTY: HandleSlot = HandleSlot(None);
+            chunks.push(Box::new([EMPTY; HANDLE_CHUNK_SIZE]));
+        }
+        let slot = &mut chunks[top / HANDLE_CHUNK_SIZE][top % HANDLE_CHUNK_SIZE];
+        slot.0 = value;
+        self.top.set(top + 1);
+        slot
+    }
+
+    /// Walk all allocated handles.
+    ///
+    /// # Safety
+    ///
+    /// Must be invoked only by GC when mutator that owns this arena is stopped.
+    pub unsafe fn walk(&self, mut visitor: impl FnMut(&mut dyn Rootable)) {
+        let chunks = &mut *self.chunks.get();
+        for index in 0..self.top.get() {
+            visitor(&mut chunks[index / HANDLE_CHUNK_SIZE][index % HANDLE_CHUNK_SIZE]);
+        }
+    }
+}
+
+impl Default for HandleArena {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Scope that owns handles created in it. Handles are released when scope is dropped. Scopes must be dropped in reverse order
+/// of creation and handles can be created only in the innermost open scope, otherwise it panics.
+pub struct HandleScope<'a, H: GcBase> {
+    arena: &'a HandleArena,
+    /// Value of `arena.top` when this scope was opened.
+    prev_top: usize,
+    depth: usize,
+    marker: PhantomData<*mut H>,
+}
+
+impl<'a, H: GcBase> HandleScope<'a, H> {
+    /// Open new handle scope in the handle arena of `stack`.
+    pub fn new(stack: &'a ShadowStack) -> Self {
+        Self::new_in(&stack.handles)
+    }
+
+    fn new_in(arena: &'a HandleArena) -> Self {
+        let depth = arena.depth.get() + 1;
+        arena.depth.set(depth);
+        Self {
+            arena,
+            prev_top: arena.top.get(),
+            depth,
+            marker: PhantomData,
+        }
+    }
+    /// Create handle for `value`. Handle keeps `value` alive until this scope is dropped.
+    pub fn handle<T: Collectable + ?Sized>(&self, value: Gc<T, H>) -> Handle<'_, T, H> {
+        self.check_innermost();
+        Handle {
+            slot: self.arena.allocate(Some(value.base)),
+            marker: PhantomData,
+        }
+    }
+    /// Number of handles created in this scope.
+    pub fn len(&self) -> usize {
+        self.arena.top.get() - self.prev_top
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn check_innermost(&self) {
+        assert_eq!(
+            self.depth,
+            self.arena.depth.get(),
+            "handles can be created only in the innermost handle scope"
+        );
+    }
+}
+
+impl<H: GcBase> Drop for HandleScope<'_, H> {
+    fn drop(&mut self) {
+        self.check_innermost();
+        self.arena.depth.set(self.depth - 1);
+        self.arena.top.set(self.prev_top);
+    }
+}
+
+/// Handle scope that can return one of its handles to the parent scope with [escape](Self::escape). Slot for escaped handle is
+/// reserved in parent scope when this scope is opened.
+pub struct EscapableHandleScope<'p, 'a, H: GcBase> {
+    escape_slot: *mut HandleSlot,
+    escaped: Cell<bool>,
+    scope: HandleScope<'a, H>,
+    marker: PhantomData<&'p HandleScope<'a, H>>,
+}
+
+impl<'p, 'a, H: GcBase> EscapableHandleScope<'p, 'a, H> {
+    /// Open new scope nested in `parent`. `parent` must be the innermost open scope.
+    pub fn new(parent: &'p HandleScope<'a, H>) -> Self {
+        parent.check_innermost();
+        let escape_slot = parent.arena.allocate(None);
+        Self {
+            escape_slot,
+            escaped: Cell::new(false),
+            scope: HandleScope::new_in(parent.arena),
+            marker: PhantomData,
+        }
+    }
+    /// Move `handle` to the parent scope. Panics if it is invoked more than once.
+    pub fn escape<T: Collectable + ?Sized>(&self, handle: Handle<'_, T, H>) -> Handle<'p, T, H> {
+        assert!(
+            !self.escaped.replace(true),
+            "handle scope can escape only once"
+        );
+        unsafe {
+            (*self.escape_slot).0 = (*handle.slot).0;
+        }
+        Handle {
+            slot: self.escape_slot,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, H: GcBase> Deref for EscapableHandleScope<'_, 'a, H> {
+    type Target = HandleScope<'a, H>;
+    fn deref(&self) -> &Self::Target {
+        &self.scope
+    }
+}
+
+/// Rooted pointer to GC object that lives in a [HandleScope]. Handle is updated when object is moved by GC, so it is safe to keep
+/// it across allocations.
+pub struct Handle<'s, T: Collectable + ?Sized, H: GcBase> {
+    slot: *mut HandleSlot,
+    marker: PhantomData<(&'s (), *mut T, *mut H)>,
+}
+
+impl<T: Collectable + ?Sized, H: GcBase> Handle<'_, T, H> {
+    /// Returns current location of the object.
+    pub fn get(self) -> Gc<T, H> {
+        unsafe {
+            Gc {
+                base: (*self.slot).0.unwrap(),
+                marker: PhantomData,
+            }
+        }
+    }
+    /// Make this handle point to `value`.
+    pub fn set(self, value: Gc<T, H>) {
+        unsafe {
+            (*self.slot).0 = Some(value.base);
+        }
+    }
+}
+
+impl<T: Collectable, H: GcBase> Deref for Handle<'_, T, H> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*(&*self.get() as *const T) }
+    }
+}
+
+impl<T: Collectable, H: GcBase> DerefMut for Handle<'_, T, H> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *(&mut *self.get() as *mut T) }
+    }
+}
+
+impl<T: Collectable + ?Sized, H: GcBase> Clone for Handle<'_, T, H> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: Collectable + ?Sized, H: GcBase> Copy for Handle<'_, T, H> {}
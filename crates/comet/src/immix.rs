@@ -8,18 +8,37 @@
 //! are not collected unless all objects in a line are unreachable, and surprisingly this
 //! coarser granularity leads to performance improvements.
 //! You can find more information about Immix in this [paper](https://users.cecs.anu.edu.au/~steveb/pubs/papers/immix-pldi-2008.pdf)
+//!
+//! Immix performs opportunistic defragmentation: during some GC cycles objects from the most fragmented blocks are evacuated
+//! into clean blocks. Pinned objects are never moved. See [defrag](defrag/index.html) module for details.
+//!
+//! Marking is performed by several GC worker threads (see [parallel_marking](crate::parallel_marking)), number of workers
+//! can be changed with [set_marking_workers](crate::mutator::Mutator::set_marking_workers). Defrag cycles are marked on single thread since
+//! evacuation allocates objects in one copy allocator.
 
 use crate::{
-    api::{vtable_of, Collectable, Gc, HeapObjectHeader, Trace, Visitor, Weak, GC_BLACK, GC_WHITE},
+    api::{
+        pop_ready_finalizer, trace_ephemerons, trace_ordered_finalizers, trace_soft_refs,
+        vtable_of, Collectable, Ephemeron, Gc, HeapObjectHeader, OrderedFinalizer, SoftRef,
+        SoftRefPolicy, Trace, VTable, Visitor, Weak, GC_BLACK, GC_WHITE,
+    },
     gc_base::{
-        AllocationSpace, GcBase, MarkingConstraint, MarkingConstraintRuns, NoHelp, NoReadBarrier,
+        AllocError, AllocationSpace, GcBase, MarkingConstraint, MarkingConstraintRuns, NoHelp,
+        NoReadBarrier, OomHandler, OomHandlerSlot,
     },
+    gc_log::{GcLogger, GcPhase, GcRecord, PhaseTimer},
     large_space::{LargeObjectSpace, PreciseAllocation},
     make_small_type_id,
-    mutator::{oom_abort, JoinData, Mutator, MutatorRef, ThreadState},
+    mutator::{JoinData, Mutator, MutatorRef, ThreadState},
+    parallel_marking::{default_marking_workers, drain_mark_stack, ParallelMark},
+    persistent::PersistentRoots,
+    pin::PinnedObjects,
     safepoint::{GlobalSafepoint, SafepointScope},
     small_type_id,
+    stats::{GcKind, GcListener, GcReason, GcStats, HeapStats},
+    sticky_immix::StickyState,
     utils::{align_usize, formatted_size},
+    verify::{HeapVerifier, VerifyPhase},
 };
 use crate::{
     bitmap::{round_up, ChunkMap},
@@ -30,7 +49,7 @@ use atomic::Ordering;
 use im::Vector;
 use rosalloc::defs::PAGE_SIZE;
 use std::{
-    any::TypeId, cell::UnsafeCell, marker::PhantomData, mem::size_of, ptr::NonNull, sync::Arc,
+    cell::UnsafeCell, marker::PhantomData, mem::size_of, ptr::NonNull, sync::Arc, time::Instant,
 };
 use std::{
     ptr::null_mut,
@@ -46,9 +65,11 @@ pub fn is_line_aligned(ptr: *const u8) -> bool {
 
 pub mod block;
 pub mod chunk;
+pub mod defrag;
 pub mod space;
 use block::*;
 use chunk::*;
+use defrag::*;
 use space::*;
 
 /// Thread local allocator for Immix. This allocator stores two different bump pointers:
@@ -61,8 +82,10 @@ pub struct ImmixAllocator {
     large_cursor: *mut u8,
     large_limit: *mut u8,
     request_for_large: bool,
-    emergency_collection: bool,
+    pub(crate) emergency_collection: bool,
     line: Option<*mut u8>,
+    /// Set to `true` for allocator that is used to evacuate objects during defrag GC cycle.
+    copy: bool,
 }
 
 impl ImmixAllocator {
@@ -125,14 +148,28 @@ impl ImmixAllocator {
         false
     }
     pub fn acquire_clean_block(&mut self) -> bool {
-        if self.is_out_of_memory_on_allocation(IMMIX_BLOCK_SIZE, self.emergency_collection) {
-            return false;
-        }
-        match self.space.get_clean_block() {
+        let block = if self.copy {
+            // Evacuation does not increase heap footprint: evacuated objects are accounted when the space is swept.
+            self.space.get_clean_block_for_defrag()
+        } else {
+            if self.is_out_of_memory_on_allocation(IMMIX_BLOCK_SIZE, self.emergency_collection) {
+                return false;
+            }
+            // Keep headroom for evacuation unless this is an emergency allocation.
+            if !self.emergency_collection
+                && self.space.free_blocks.len() <= self.space.defrag_headroom_blocks()
+            {
+                return false;
+            }
+            self.space.get_clean_block()
+        };
+        match block {
             block if !block.is_null() => unsafe {
-                self.space
-                    .num_bytes_allocated
-                    .fetch_add(IMMIX_BLOCK_SIZE, Ordering::Relaxed);
+                if !self.copy {
+                    self.space
+                        .num_bytes_allocated
+                        .fetch_add(IMMIX_BLOCK_SIZE, Ordering::Relaxed);
+                }
                 if self.request_for_large {
                     self.large_cursor = (*block).start_address();
                     self.large_limit = (*block).end();
@@ -177,8 +214,16 @@ where
         self.line = None;
     }
     fn create(heap: std::sync::Arc<std::cell::UnsafeCell<H>>) -> Self {
+        Self::new(unsafe { (*heap.get()).immix_space() }, false)
+    }
+}
+
+impl ImmixAllocator {
+    /// Create new allocator for `space`. If `copy` is true allocator is used for evacuating objects and allocates
+    /// only into clean blocks reserved for defragmentation.
+    pub fn new(space: &'static ImmixSpace, copy: bool) -> Self {
         Self {
-            space: unsafe { (*heap.get()).immix_space() },
+            space,
             line: None,
             limit: null_mut(),
             large_cursor: null_mut(),
@@ -186,11 +231,40 @@ where
             cursor: null_mut(),
             request_for_large: false,
             emergency_collection: false,
+            copy,
         }
     }
-}
 
-impl ImmixAllocator {
+    /// Try to evacuate marked `object` if it is located in fragmented block. Returns new location of object or `None`
+    /// if object must be marked in place. Must be invoked only on copy allocator.
+    #[inline]
+    pub unsafe fn try_evacuate(
+        &mut self,
+        object: *mut HeapObjectHeader,
+    ) -> Option<*mut HeapObjectHeader> {
+        debug_assert!(self.copy);
+        if !self.space.defrag.in_defrag()
+            || (*object).pinned_bit()
+            || self.space.defrag.space_exhausted()
+        {
+            return None;
+        }
+        let block = ImmixBlock::align(object.cast()).cast::<ImmixBlock>();
+        if !(*block).is_fragmented() {
+            return None;
+        }
+        let size = (*object).copy_size();
+        let memory = self.alloc(size);
+        if memory.is_null() {
+            return None;
+        }
+        std::ptr::copy_nonoverlapping(object.cast::<u8>(), memory, (*object).size());
+        let new_object = memory.cast::<HeapObjectHeader>();
+        (*new_object).finish_copy(object);
+        (*object).set_forwarded(memory as usize);
+        self.space.mark_lines(new_object);
+        Some(new_object)
+    }
     #[inline]
     fn is_out_of_memory_on_allocation(&self, alloc_size: usize, grow: bool) -> bool {
         let mut old_target = self.space.target_footprint.load(Ordering::Relaxed);
@@ -229,15 +303,7 @@ impl ImmixAllocator {
     }
     #[inline(always)]
     pub unsafe fn alloc_slow_inline(&mut self, size: usize) -> *mut u8 {
-        let result = self.alloc_slow_once(size);
-        if !result.is_null() {
-            return result;
-        }
-
-        if self.emergency_collection {
-            oom_abort();
-        }
-        result
+        self.alloc_slow_once(size)
     }
     pub unsafe fn alloc(&mut self, size: usize) -> *mut u8 {
         let result = self.cursor;
@@ -271,7 +337,8 @@ impl ImmixAllocator {
 
     #[cold]
     unsafe fn alloc_slow_hot(&mut self, size: usize) -> *mut u8 {
-        if self.acquire_recyclable_lines() {
+        // Evacuating into recyclable lines of fragmented blocks would defeat defragmentation.
+        if !self.copy && self.acquire_recyclable_lines() {
             self.alloc(size)
         } else {
             self.alloc_slow_inline(size)
@@ -282,7 +349,7 @@ use parking_lot::{lock_api::RawMutex, RawMutex as Lock};
 
 /// Immix GC implementation. Read top level module documentation for more information
 pub struct Immix {
-    space: &'static ImmixSpace,
+    pub(crate) space: &'static ImmixSpace,
     pub(crate) global_heap_lock: Lock,
     pub(crate) large_space_lock: Lock,
     pub(crate) large_space: LargeObjectSpace,
@@ -292,11 +359,33 @@ pub struct Immix {
     pub(crate) verbose: bool,
     pub(crate) alloc_color: u8,
     pub(crate) mark_color: u8,
-    total_gcs: usize,
+    pub(crate) total_gcs: usize,
     weak_refs: Vec<Weak<dyn Collectable, Self>>,
+    ephemerons: Vec<Ephemeron<dyn Collectable, dyn Collectable, Self>>,
+    soft_refs: Vec<SoftRef<dyn Collectable, Self>>,
+    pub(crate) soft_ref_policy: SoftRefPolicy,
+    ordered_finalizers: Vec<OrderedFinalizer<Self>>,
+    persistent_roots: Arc<PersistentRoots<Self>>,
+    pinned_objects: Arc<PinnedObjects<Self>>,
     constraints: Vec<Box<dyn MarkingConstraint>>,
     finalize_list: Vector<*mut HeapObjectHeader>,
     finalize_list_lock: Lock,
+    oom_handler: OomHandlerSlot<Self>,
+    /// Allocator used to evacuate objects from fragmented blocks.
+    copy_allocator: ImmixAllocator,
+    /// Bytes evacuated in current GC cycle.
+    evacuated_bytes: usize,
+    /// GC worker threads used for marking.
+    marking_pool: scoped_threadpool::Pool,
+    /// Scan mutator stacks conservatively, see [conservative](crate::conservative).
+    conservative_stack_scanning: bool,
+    /// Verify heap before and after each GC cycle, see [verify](crate::verify).
+    pub(crate) verify_heap: bool,
+    /// Record object starts in object start bitmap, required by conservative stack scanning and heap verification.
+    pub(crate) track_object_starts: bool,
+    /// Set if heap collects memory generationally, see [sticky_immix](crate::sticky_immix).
+    pub(crate) sticky: Option<StickyState>,
+    pub(crate) gc_stats: GcStats,
 }
 
 impl GetImmixSpace for Immix {
@@ -318,8 +407,23 @@ pub fn instantiate_immix(
         min_heap_size,
         max_heap_size,
         verbose,
+        true,
     )));
+    instantiate_immix_heap(space, verbose, None)
+}
 
+/// Create Immix heap in `space`. Heap collects memory generationally if `sticky` is set.
+pub(crate) fn instantiate_immix_heap(
+    space: &'static ImmixSpace,
+    verbose: bool,
+    sticky: Option<StickyState>,
+) -> MutatorRef<Immix> {
+    let verify_heap = cfg!(feature = "verify-heap");
+    let policy = if sticky.is_some() {
+        "StickyImmix"
+    } else {
+        "Immix"
+    };
     let immix = Arc::new(UnsafeCell::new(Immix {
         space,
         large_space: LargeObjectSpace::new(),
@@ -335,9 +439,28 @@ pub fn instantiate_immix(
         mark_stack: Vec::new(),
         total_gcs: 0,
         weak_refs: vec![],
+        ephemerons: vec![],
+        soft_refs: vec![],
+        soft_ref_policy: SoftRefPolicy::new(),
+        ordered_finalizers: vec![],
+        persistent_roots: Arc::new(PersistentRoots::new()),
+        pinned_objects: Arc::new(PinnedObjects::new()),
         constraints: vec![],
+        oom_handler: OomHandlerSlot::new(),
+        copy_allocator: ImmixAllocator::new(space, true),
+        evacuated_bytes: 0,
+        marking_pool: scoped_threadpool::Pool::new(default_marking_workers() as _),
+        conservative_stack_scanning: false,
+        verify_heap,
+        track_object_starts: verify_heap,
+        sticky,
+        gc_stats: GcStats::new(policy),
     }));
     let href = unsafe { &mut *immix.get() };
+    if verbose {
+        href.safepoint
+            .set_logger(Some(Arc::new(GcLogger::stderr())));
+    }
     let join_data = JoinData::new();
     let mut mutator = MutatorRef::new(Mutator::new(
         immix.clone(),
@@ -353,6 +476,56 @@ pub fn instantiate_immix(
 }
 
 impl Immix {
+    pub(crate) fn allocated_bytes(&self) -> usize {
+        self.space.num_bytes_allocated.load(Ordering::Relaxed) + self.large_space.bytes
+    }
+    /// Trace all objects in mark stack. Defrag cycles are marked on current thread since evacuation allocates objects in
+    /// single copy allocator.
+    pub(crate) unsafe fn process_mark_stack(&mut self, in_defrag: bool) {
+        if in_defrag {
+            while let Some(object) = self.mark_stack.pop() {
+                (*object).get_dyn().trace(self);
+            }
+        } else {
+            let marking = ImmixMarking {
+                space: self.space,
+                alloc_color: self.alloc_color,
+                mark_color: self.mark_color,
+                object_starts: self.track_object_starts,
+            };
+            drain_mark_stack(&mut self.marking_pool, &mut self.mark_stack, &marking);
+        }
+    }
+    /// Conservatively scan stacks of all mutators. Returns objects that were found and objects that were pinned by the scan,
+    /// these are unpinned at the end of GC cycle.
+    pub(crate) unsafe fn scan_conservative_roots(
+        &mut self,
+    ) -> (Vec<*mut HeapObjectHeader>, Vec<*mut HeapObjectHeader>) {
+        let mut roots = vec![];
+        let mut pinned = vec![];
+        if !self.conservative_stack_scanning {
+            return (roots, pinned);
+        }
+        self.large_space.prepare_for_conservative_scan();
+        for i in 0..self.mutators.len() {
+            (*self.mutators[i]).scan_stack(|pointer| {
+                let object = if self.space.has_address(pointer) {
+                    self.space.find_object(pointer)
+                } else {
+                    self.large_space.contains(pointer)
+                };
+                if object.is_null() {
+                    return;
+                }
+                if !(*object).pinned_bit() {
+                    (*object).set_pinned_bit(true);
+                    pinned.push(object);
+                }
+                roots.push(object);
+            });
+        }
+        (roots, pinned)
+    }
     unsafe fn after_mark_constraints(&mut self) {
         let this = self as *mut Self;
         (*this).constraints.retain_mut(|constraint| {
@@ -379,36 +552,330 @@ impl Immix {
             }
         });
     }
-    #[cold]
-    unsafe fn collect_and_alloc<T: Collectable + Sized + 'static>(
+    /// Stop all mutators and perform GC cycle. `full` requests full (`Some(true)`) or nursery (`Some(false)`) collection
+    /// of [sticky](crate::sticky_immix) heap, if it is `None` heap decides itself. Heap that is not sticky always collects
+    /// whole heap, see [Immix::collect_garbage].
+    pub(crate) fn perform_collection(
         &mut self,
         mutator: &mut MutatorRef<Self>,
+        keep: &mut [&mut dyn Trace],
+        reason: GcReason,
+        full: Option<bool>,
+    ) {
+        match SafepointScope::new(mutator.clone()) {
+            Some(safepoint) => unsafe {
+                self.global_heap_lock.lock();
+                self.large_space_lock.lock();
+                if self.sticky.is_some() {
+                    self.sticky_collection(keep, reason, full);
+                } else {
+                    self.collect_garbage(keep, reason, full == Some(true));
+                }
+                drop(safepoint);
 
-        mut value: T,
-    ) -> Gc<T, Self> {
-        self.collect_alloc_failure(mutator, &mut [&mut value]);
+                self.global_heap_lock.unlock();
+                self.large_space_lock.unlock();
+            },
+            None => return,
+        }
+    }
 
-        mutator.tlab.emergency_collection = true;
-        let value = self.alloc_inline(mutator, value, AllocationSpace::New);
-        mutator.tlab.emergency_collection = false;
-        value
+    /// Trace all roots: kept values, conservative roots, shadow stacks of mutators, persistent roots and pinned objects.
+    pub(crate) unsafe fn mark_roots(
+        &mut self,
+        keep: &mut [&mut dyn Trace],
+        conservative_roots: Vec<*mut HeapObjectHeader>,
+    ) {
+        self.before_mark_constraints();
+        for object in keep.iter_mut() {
+            object.trace(self);
+        }
+        for object in conservative_roots {
+            self.mark_object(&mut NonNull::new_unchecked(object));
+        }
+        for i in 0..self.mutators.len() {
+            let mutator = self.mutators[i];
+            (*mutator).reset_tlab();
+            (*mutator).shadow_stack().walk(|entry| {
+                entry.trace(self);
+            });
+        }
+        let this = self as *mut Self;
+        (*this).persistent_roots.trace(&mut *this);
+        (*this).pinned_objects.trace(&mut *this);
     }
 
+    /// Trace all objects reachable from roots that were marked so far, then trace soft references, ephemerons and ordered
+    /// finalizers.
+    pub(crate) unsafe fn finish_marking(&mut self, in_defrag: bool) {
+        let this = self as *mut Self;
+        self.process_mark_stack(in_defrag);
+        self.after_mark_constraints();
+        let mark_color = self.mark_color;
+        let forwardee = |header: *mut HeapObjectHeader| {
+            if (*header).is_forwarded() {
+                (*header).vtable() as *mut HeapObjectHeader
+            } else if (*header).get_color() == mark_color {
+                header
+            } else {
+                null_mut()
+            }
+        };
+        trace_soft_refs(
+            &(*this).soft_refs,
+            &(*this).soft_ref_policy,
+            forwardee,
+            |slot| (*this).mark_object(slot),
+            || (*this).process_mark_stack(in_defrag),
+        );
+        trace_ephemerons(
+            &(*this).ephemerons,
+            forwardee,
+            |slot| (*this).mark_object(slot),
+            || (*this).process_mark_stack(in_defrag),
+        );
+        if trace_ordered_finalizers(
+            &mut (*this).ordered_finalizers,
+            forwardee,
+            |slot| (*this).mark_object(slot),
+            || (*this).process_mark_stack(in_defrag),
+        ) {
+            trace_ephemerons(
+                &(*this).ephemerons,
+                forwardee,
+                |slot| (*this).mark_object(slot),
+                || (*this).process_mark_stack(in_defrag),
+            );
+        }
+    }
+
+    /// Clear weak references, soft references and ephemerons to dead objects and run finalizers of dead objects. Objects
+    /// that are not marked with current mark color are dead.
+    pub(crate) unsafe fn process_weak_refs_and_finalizers(&mut self, timer: &mut PhaseTimer) {
+        let mark_color = self.mark_color;
+        let forwardee = |header: *mut HeapObjectHeader| {
+            if (*header).is_forwarded() {
+                (*header).vtable() as *mut HeapObjectHeader
+            } else if (*header).get_color() == mark_color {
+                header
+            } else {
+                null_mut()
+            }
+        };
+        self.weak_refs.retain_mut(|object| {
+            let mut header = object.base();
+            if (*header).is_forwarded() {
+                header = (*header).vtable() as *mut HeapObjectHeader;
+                object.set_base(header);
+            }
+            if (*header).get_color() == mark_color {
+                object.after_mark(|header| {
+                    if (*header).is_forwarded() {
+                        (*header).vtable() as _
+                    } else if (*header).get_color() == mark_color {
+                        header
+                    } else {
+                        null_mut()
+                    }
+                });
+                true
+            } else {
+                false
+            }
+        });
+        self.soft_refs.retain_mut(|soft_ref| {
+            let header = forwardee(soft_ref.base());
+            if header.is_null() {
+                false
+            } else {
+                soft_ref.set_base(header);
+                soft_ref.after_mark(forwardee);
+                true
+            }
+        });
+        self.ephemerons.retain_mut(|ephemeron| {
+            let header = forwardee(ephemeron.base());
+            if header.is_null() {
+                false
+            } else {
+                ephemeron.set_base(header);
+                ephemeron.after_mark(forwardee);
+                true
+            }
+        });
+        timer.end(GcPhase::WeakProcessing);
+        self.finalize_list_lock.lock();
+        let finalize_list = std::mem::take(&mut self.finalize_list);
+        self.finalize_list = finalize_list
+            .into_iter()
+            .filter_map(|object| {
+                if (*object).is_forwarded() {
+                    // object was evacuated, keep track of its new location.
+                    Some((*object).vtable() as *mut HeapObjectHeader)
+                } else if (*object).get_color() != mark_color {
+                    // if objecct is unmarked we invoke finalizer.
+                    (*object).get_dyn().finalize();
+                    None
+                } else {
+                    Some(object)
+                }
+            })
+            .collect();
+        self.finalize_list_lock.unlock();
+        timer.end(GcPhase::Finalization);
+    }
+
+    /// Mark and sweep whole heap. Must be invoked in GC pause with heap locks held. If `user_triggered_full_collection`
+    /// is true or heap is close to exhaustion this GC cycle is also a defrag cycle and objects in fragmented blocks are evacuated.
+    pub(crate) unsafe fn collect_garbage(
+        &mut self,
+        keep: &mut [&mut dyn Trace],
+        reason: GcReason,
+        user_triggered_full_collection: bool,
+    ) {
+        if self.verify_heap {
+            self.verify(VerifyPhase::BeforeGc, keep);
+        }
+        let time = Instant::now();
+        let mut timer = PhaseTimer::new(self.safepoint.last_sync());
+
+        self.space.defrag.decide_whether_to_defrag(
+            self.space.free_blocks.len() <= self.space.defrag_headroom_blocks(),
+            user_triggered_full_collection,
+            self.space.reusable_blocks.len() == 0,
+        );
+        let in_defrag = self.space.defrag.in_defrag();
+        let kind = if in_defrag {
+            GcKind::Full
+        } else {
+            GcKind::Major
+        };
+        let cycle = self
+            .gc_stats
+            .start_cycle(kind, reason, self.allocated_bytes());
+        self.soft_ref_policy.begin_cycle(
+            self.space.num_bytes_allocated.load(Ordering::Relaxed) + self.large_space.bytes,
+            self.space.max_heap_size,
+        );
+        self.evacuated_bytes = 0;
+        let pinned = self.pinned_objects.set_pinned_bits();
+        let (conservative_roots, conservative_pins) = self.scan_conservative_roots();
+        self.space.prepare(true);
+        self.large_space.prepare_for_marking(false);
+        self.large_space.begin_marking(true);
+        if self.track_object_starts {
+            // object starts are rebuilt by marking and allocation.
+            self.space.object_start_bitmap.clear_all();
+        }
+        self.mark_roots(keep, conservative_roots);
+        timer.end(GcPhase::RootScan);
+        self.finish_marking(in_defrag);
+        for object in conservative_pins.into_iter().chain(pinned) {
+            (*object).set_pinned_bit(false);
+        }
+        // Evacuation is done, next GC cycle must not continue allocating into blocks that are swept now.
+        TLAB::<Self>::reset(&mut self.copy_allocator);
+        let prev = self.space.num_bytes_allocated.load(Ordering::Relaxed) + self.large_space.bytes;
+        self.space.num_bytes_allocated.store(0, Ordering::Relaxed);
+        timer.end(GcPhase::Mark);
+        self.process_weak_refs_and_finalizers(&mut timer);
+        self.large_space.prepare_for_allocation(false);
+        self.large_space.sweep();
+        self.space.release();
+        timer.end(GcPhase::Sweep);
+
+        let bytes_allocated =
+            self.space.num_bytes_allocated.load(Ordering::Relaxed) + self.large_space.bytes;
+        let target_size = self
+            .space
+            .min_heap_size
+            .max((bytes_allocated as f64 * 1.75) as usize)
+            .min(self.space.max_heap_size);
+
+        self.space
+            .target_footprint
+            .store(target_size, Ordering::Relaxed);
+        self.gc_stats.end_cycle(cycle, bytes_allocated);
+        if let Some(logger) = self.safepoint.logger() {
+            let evacuated = [("evacuated", self.evacuated_bytes)];
+            logger.log_gc(&GcRecord {
+                policy: self.gc_stats.policy(),
+                gc_id: self.total_gcs,
+                kind,
+                reason,
+                heap_before: prev,
+                heap_after: bytes_allocated,
+                footprint: target_size,
+                duration: time.elapsed(),
+                phases: timer.phases(),
+                extra: if in_defrag { &evacuated } else { &[] },
+            });
+        }
+        if self.verify_heap {
+            self.verify(VerifyPhase::AfterGc, keep);
+        }
+        self.total_gcs += 1;
+        if self.sticky.is_none() {
+            std::mem::swap(&mut self.alloc_color, &mut self.mark_color);
+        }
+    }
+
+    /// Verify heap, see [verify](crate::verify). Must be invoked in GC pause with heap locks held.
+    pub(crate) unsafe fn verify(&mut self, phase: VerifyPhase, keep: &mut [&mut dyn Trace]) {
+        let mut verifier = HeapVerifier::new(self.gc_stats.policy(), phase, self.total_gcs);
+        let space = self.space;
+        space.visit_objects(|block, object| {
+            if verifier.add_object(object, (*block).end())
+                && phase == VerifyPhase::AfterGc
+                && !space.lines_marked(object)
+            {
+                verifier.report(object, "object is live but its lines are not marked");
+            }
+        });
+        for &allocation in self.large_space.allocations.iter() {
+            verifier.add_large_object(allocation);
+        }
+        verifier.trace_roots("shadow stack", |vis| {
+            for &mutator in self.mutators.iter() {
+                (*mutator).shadow_stack().walk(|entry| entry.trace(vis));
+            }
+        });
+        verifier.trace_roots("persistent root", |vis| self.persistent_roots.trace(vis));
+        verifier.trace_roots("pinned object", |vis| self.pinned_objects.trace(vis));
+        verifier.trace_roots("kept value", |vis| {
+            for object in keep.iter_mut() {
+                object.trace(vis);
+            }
+        });
+        verifier.finish();
+    }
+
+    /// Start or stop recording object starts after conservative stack scanning or heap verification is toggled. Returns true if
+    /// starts of existing objects must be recorded by full collection.
+    fn update_object_start_tracking(&mut self, track: bool) -> bool {
+        let rebuild = track && !self.track_object_starts;
+        if rebuild {
+            // object starts are not tracked while scanning and verification are disabled.
+            self.space.object_start_bitmap.clear_all();
+        }
+        self.track_object_starts = track;
+        rebuild
+    }
+
+    /// Collects memory and tries to allocate `size` bytes in emergency mode. Returns null pointer if heap is out of memory.
     #[cold]
-    unsafe fn collect_and_alloc_raw(
+    unsafe fn collect_and_alloc(
         &mut self,
         mutator: &mut MutatorRef<Self>,
-
         size: usize,
-        vtable: usize,
-        type_id: TypeId,
-    ) -> *mut HeapObjectHeader {
-        self.collect_alloc_failure(mutator, &mut []);
+        keep: &mut [&mut dyn Trace],
+    ) -> *mut u8 {
+        self.collect_alloc_failure(mutator, keep);
 
         mutator.tlab.emergency_collection = true;
-        let value = self.allocate_raw(mutator, size, type_id, vtable);
+        let memory = mutator.tlab.alloc(size);
         mutator.tlab.emergency_collection = false;
-        value
+        memory
     }
 }
 
@@ -428,36 +895,57 @@ impl GcBase for Immix {
         self.global_unlock();
     }
 
-    fn allocate_raw(
+    fn try_allocate_raw(
         &mut self,
         mutator: &mut MutatorRef<Self>,
         size: usize,
         type_id: std::any::TypeId,
         vtable: usize,
-    ) -> *mut HeapObjectHeader {
+    ) -> Result<*mut HeapObjectHeader, AllocError<()>> {
         let alloc = &mut mutator.tlab;
         let size = align_usize(size + size_of::<HeapObjectHeader>(), 8);
         unsafe {
-            let memory = if size >= Self::LARGE_ALLOCATION_SIZE {
-                alloc.alloc(size)
+            let (mut memory, space) = if size < Self::LARGE_ALLOCATION_SIZE {
+                (alloc.alloc(size), AllocationSpace::New)
             } else {
-                self.large_space.allocate(size).cast::<u8>()
+                self.large_space_lock.lock();
+                let memory = self.large_space.try_allocate(size).cast::<u8>();
+                self.large_space_lock.unlock();
+                (memory, AllocationSpace::Large)
             };
 
             if memory.is_null() {
-                return self.collect_and_alloc_raw(mutator, size, vtable, type_id);
+                if space == AllocationSpace::Large {
+                    self.collect_alloc_failure(mutator, &mut []);
+                    self.large_space_lock.lock();
+                    memory = self.large_space.try_allocate(size).cast::<u8>();
+                    self.large_space_lock.unlock();
+                } else {
+                    memory = self.collect_and_alloc(mutator, size, &mut []);
+                }
+                if memory.is_null() {
+                    return Err(AllocError::new((), size, space));
+                }
             }
             let object = memory.cast::<HeapObjectHeader>();
+            object.write(HeapObjectHeader {
+                type_id: make_small_type_id(type_id),
+                padding: 0,
+                padding2: 0,
+                value: VTable { raw: 0 },
+            });
             (*object).set_vtable(vtable);
-            (*object).type_id = make_small_type_id(type_id);
-            (*object).set_size(size);
+            // size of 0 means object is large.
+            if space != AllocationSpace::Large {
+                (*object).set_size(size);
+            }
 
             let gced: Gc<(), Self> = Gc {
                 base: NonNull::new_unchecked(object),
                 marker: Default::default(),
             };
             self.post_alloc(gced);
-            object
+            Ok(object)
         }
     }
     fn allocate_weak<T: Collectable + ?Sized>(
@@ -473,23 +961,145 @@ impl GcBase for Immix {
         }
         weak_ref
     }
-    fn alloc_inline<T: Collectable + Sized + 'static>(
+    fn allocate_soft<T: Collectable + ?Sized>(
         &mut self,
         mutator: &mut MutatorRef<Self>,
-        value: T,
-        _space: AllocationSpace,
-    ) -> Gc<T, Self> {
+        value: Gc<T, Self>,
+    ) -> SoftRef<T, Self> {
+        let soft_ref = unsafe { SoftRef::<T, Self>::create(mutator, value) };
+        self.global_heap_lock.lock();
+        self.soft_refs.push(soft_ref.to_dyn());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        soft_ref
+    }
+    fn set_soft_ref_lru_policy(&mut self, ms_per_mb: u64) {
+        self.global_heap_lock.lock();
+        self.soft_ref_policy.set_ms_per_mb(ms_per_mb);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn allocate_ephemeron<K: Collectable + ?Sized, V: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        key: Gc<K, Self>,
+        value: Gc<V, Self>,
+    ) -> Ephemeron<K, V, Self> {
+        let ephemeron = unsafe { Ephemeron::create(mutator, key, value) };
+        self.global_heap_lock.lock();
+        self.ephemerons.push(ephemeron.to_dyn());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        ephemeron
+    }
+    fn persistent_roots(&self) -> Arc<PersistentRoots<Self>> {
+        self.persistent_roots.clone()
+    }
+    fn pinned_objects(&self) -> Arc<PinnedObjects<Self>> {
+        self.pinned_objects.clone()
+    }
+    fn stats(&self) -> HeapStats {
+        self.gc_stats.snapshot(
+            self.allocated_bytes(),
+            self.space.target_footprint.load(Ordering::Relaxed),
+        )
+    }
+    fn add_gc_listener(&mut self, listener: Box<dyn GcListener>) {
+        self.gc_stats.add_listener(listener);
+    }
+    fn register_ordered_finalizer(&mut self, finalizer: OrderedFinalizer<Self>) {
+        self.global_heap_lock.lock();
+        self.ordered_finalizers.push(finalizer);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn pop_ready_finalizer(&mut self) -> Option<OrderedFinalizer<Self>> {
+        self.global_heap_lock.lock();
+        let finalizer = pop_ready_finalizer(&mut self.ordered_finalizers);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        finalizer
+    }
+    fn set_marking_workers(&mut self, workers: usize) {
+        self.global_heap_lock.lock();
+        self.marking_pool = scoped_threadpool::Pool::new(workers.max(1) as _);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn set_conservative_stack_scanning(&mut self, mutator: &mut MutatorRef<Self>, enabled: bool) {
+        self.global_heap_lock.lock();
+        let rebuild = self.update_object_start_tracking(enabled || self.verify_heap);
+        self.conservative_stack_scanning = enabled;
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        if rebuild {
+            // objects allocated before scanning was enabled get their starts recorded when they are marked.
+            self.full_collection(mutator, &mut []);
+        }
+    }
+    fn set_heap_verification(&mut self, mutator: &mut MutatorRef<Self>, enabled: bool) {
+        self.global_heap_lock.lock();
+        let rebuild =
+            self.update_object_start_tracking(enabled || self.conservative_stack_scanning);
+        if !rebuild {
+            self.verify_heap = enabled;
+        }
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        if rebuild {
+            // heap can be verified only once starts of objects allocated before are recorded by marking.
+            self.full_collection(mutator, &mut []);
+            self.global_heap_lock.lock();
+            self.verify_heap = true;
+            unsafe {
+                self.global_heap_lock.unlock();
+            }
+        }
+    }
+    fn set_oom_handler(&mut self, handler: OomHandler<Self>) {
+        self.oom_handler.set(handler);
+    }
+    fn handle_oom(&mut self, mutator: &mut MutatorRef<Self>, size: usize) -> bool {
+        // emergency collection: clear all soft references before giving up.
+        if !self.soft_refs.is_empty() && self.soft_ref_policy.request_clear_all() {
+            self.full_collection(mutator, &mut []);
+            return true;
+        }
+        self.oom_handler.invoke(mutator, size)
+    }
+    fn try_alloc_inline<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+        space: AllocationSpace,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
         let alloc = &mut mutator.tlab;
         let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
         unsafe {
-            let memory = alloc.alloc(size);
+            let mut memory = alloc.alloc(size);
 
             if memory.is_null() {
-                return self.collect_and_alloc(mutator, value);
+                memory = self.collect_and_alloc(mutator, size, &mut [&mut value]);
+                if memory.is_null() {
+                    return Err(AllocError::new(value, size, space));
+                }
             }
             let object = memory.cast::<HeapObjectHeader>();
+            object.write(HeapObjectHeader {
+                type_id: small_type_id::<T>(),
+                padding: 0,
+                padding2: 0,
+                value: VTable { raw: 0 },
+            });
             (*object).set_metadata(vtable_of::<T>());
-            (*object).type_id = small_type_id::<T>();
             (*object).set_size(size);
             ((*object).data() as *mut T).write(value);
             let gced = Gc {
@@ -497,104 +1107,46 @@ impl GcBase for Immix {
                 marker: Default::default(),
             };
             self.post_alloc(gced);
-            gced
+            Ok(gced)
+        }
+    }
+    /// Generational write barrier of [sticky](crate::sticky_immix) heap: if `object` is old and not in remembered set it
+    /// is put to remembered set. This write barrier must be used right after write to an object happened. No-op if heap
+    /// is not sticky.
+    #[inline]
+    fn write_barrier(&mut self, _: &mut MutatorRef<Self>, object: Gc<dyn Collectable, Self>) {
+        if self.sticky.is_some() {
+            unsafe {
+                self.write_barrier_internal(object.base.as_ptr());
+            }
         }
     }
     fn collect(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
-        match SafepointScope::new(mutator.clone()) {
-            Some(safepoint) => unsafe {
-                let time = if self.verbose {
-                    Some(std::time::Instant::now())
-                } else {
-                    None
-                };
-
-                self.global_heap_lock.lock();
-                self.large_space_lock.lock();
-
-                self.space.prepare(true);
-                self.before_mark_constraints();
-                for object in keep {
-                    object.trace(self);
-                }
-                for i in 0..self.mutators.len() {
-                    let mutator = self.mutators[i];
-                    (*mutator).reset_tlab();
-                    (*mutator).shadow_stack().walk(|entry| {
-                        entry.trace(self);
-                    });
-                }
-                while let Some(object) = self.mark_stack.pop() {
-                    (*object).get_dyn().trace(self);
-                }
-                self.after_mark_constraints();
-                let prev =
-                    self.space.num_bytes_allocated.load(Ordering::Relaxed) + self.large_space.bytes;
-                self.space.num_bytes_allocated.store(0, Ordering::Relaxed);
-                let mark_color = self.mark_color;
-                self.weak_refs.retain_mut(|object| {
-                    let header = object.base();
-                    if (*header).get_color() == mark_color {
-                        object.after_mark(|header| {
-                            if (*header).get_color() == mark_color {
-                                header
-                            } else {
-                                null_mut()
-                            }
-                        });
-                        true
-                    } else {
-                        false
-                    }
-                });
-                self.finalize_list_lock.lock();
-                self.finalize_list.retain(|object| {
-                    let object = *object;
-                    // if objecct is unmarked we invoke finalizer.
-                    if (*object).get_color() != mark_color {
-                        (*object).get_dyn().finalize();
-                        false
-                    } else {
-                        true
-                    }
-                });
-                self.finalize_list_lock.unlock();
-                self.large_space.sweep();
-                self.large_space.prepare_for_allocation(false);
-                self.space.release();
-
-                let bytes_allocated =
-                    self.space.num_bytes_allocated.load(Ordering::Relaxed) + self.large_space.bytes;
-                let target_size = self
-                    .space
-                    .min_heap_size
-                    .max((bytes_allocated as f64 * 1.75) as usize)
-                    .min(self.space.max_heap_size);
-
-                self.space
-                    .target_footprint
-                    .store(target_size, Ordering::Relaxed);
-                if let Some(time) = time {
-                    let elapsed = time.elapsed();
-                    eprintln!(
-                        "[gc] GC({}) Pause Immix collection {}->{}({}) {:.4}ms",
-                        self.total_gcs,
-                        formatted_size(prev),
-                        formatted_size(bytes_allocated),
-                        formatted_size(target_size),
-                        elapsed.as_micros() as f64 / 1000.0
-                    );
-                }
-                self.total_gcs += 1;
-                std::mem::swap(&mut self.alloc_color, &mut self.mark_color);
-                drop(safepoint);
-
-                self.global_heap_lock.unlock();
-                self.large_space_lock.unlock();
-            },
-            None => return,
+        self.perform_collection(mutator, keep, GcReason::RequestedByUser, None);
+    }
+    fn minor_collection(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        if self.sticky.is_some() {
+            self.perform_collection(mutator, keep, GcReason::RequestedByUser, Some(false));
+        } else {
+            self.full_collection(mutator, keep);
         }
     }
+    fn full_collection(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        self.perform_collection(mutator, keep, GcReason::RequestedByUser, Some(true));
+    }
+    fn stress_collection(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        self.space.defrag.force_next_defrag();
+        // sticky heap evacuates objects only in full collections.
+        let full = self.sticky.as_ref().map(|_| true);
+        self.perform_collection(mutator, keep, GcReason::RequestedByUser, full);
+    }
+    fn collect_alloc_failure(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        keep: &mut [&mut dyn Trace],
+    ) {
+        self.perform_collection(mutator, keep, GcReason::AllocationFailure, None);
+    }
 
     fn alloc_tlab_area(&mut self, _mutator: &MutatorRef<Self>, _size: usize) -> *mut u8 {
         null_mut()
@@ -610,7 +1162,7 @@ impl GcBase for Immix {
         unsafe { self.global_heap_lock.unlock() };
     }
 
-    fn detach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
+    unsafe fn detach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
         self.global_heap_lock.lock();
 
         let mut detached = false;
@@ -646,15 +1198,25 @@ impl GcBase for Immix {
         &self.mutators
     }
 
-    fn allocate_large<T: Collectable + Sized + 'static>(
+    fn try_allocate_large<T: Collectable + Sized + 'static>(
         &mut self,
-        _mutator: &mut MutatorRef<Self>,
-        value: T,
-    ) -> crate::api::Gc<T, Self> {
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
         unsafe {
             let size = value.allocation_size() + size_of::<HeapObjectHeader>();
             self.large_space_lock.lock();
-            let object = self.large_space.allocate(size);
+            let mut object = self.large_space.try_allocate(size);
+            if object.is_null() {
+                self.large_space_lock.unlock();
+                self.collect_alloc_failure(mutator, &mut [&mut value]);
+                self.large_space_lock.lock();
+                object = self.large_space.try_allocate(size);
+                if object.is_null() {
+                    self.large_space_lock.unlock();
+                    return Err(AllocError::new(value, size, AllocationSpace::Large));
+                }
+            }
             (*object).set_metadata(vtable_of::<T>());
             (*object).type_id = small_type_id::<T>();
             let gc = Gc {
@@ -664,7 +1226,7 @@ impl GcBase for Immix {
             ((*object).data() as *mut T).write(value);
             self.large_space_lock.unlock();
             self.post_alloc(gc);
-            gc
+            Ok(gc)
         }
     }
     #[inline(always)]
@@ -672,6 +1234,9 @@ impl GcBase for Immix {
         unsafe {
             let base = value.base.as_ptr();
             (*base).force_set_color(self.alloc_color);
+            if self.track_object_starts && self.space.has_address(base.cast()) {
+                self.space.object_start_bitmap.set_sync(base.cast());
+            }
             if std::mem::needs_drop::<T>() {
                 self.finalize_list_lock.lock();
                 self.finalize_list.push_front(base);
@@ -685,11 +1250,27 @@ impl Visitor for Immix {
     fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
         let object = root.as_ptr();
         unsafe {
-            // todo: opportunistic evacuation
+            if (*object).is_forwarded() {
+                // object was already evacuated, update reference to point to new location.
+                *root = NonNull::new_unchecked((*object).vtable() as *mut HeapObjectHeader);
+                return;
+            }
 
             if !(*object).set_color(self.alloc_color, self.mark_color) {
                 if self.space.has_address(object.cast()) {
+                    if let Some(new_object) = self.copy_allocator.try_evacuate(object) {
+                        if self.track_object_starts {
+                            self.space.object_start_bitmap.set(new_object.cast());
+                        }
+                        self.evacuated_bytes += (*new_object).size();
+                        *root = NonNull::new_unchecked(new_object);
+                        self.mark_stack.push(new_object);
+                        return;
+                    }
                     self.space.mark_lines(object);
+                    if self.track_object_starts {
+                        self.space.object_start_bitmap.set(object.cast());
+                    }
                 } else {
                     (*PreciseAllocation::from_cell(object)).test_and_set_marked();
                 }
@@ -699,201 +1280,135 @@ impl Visitor for Immix {
     }
 }
 
-/*
-pub type Histogram = [usize; Defrag::NUM_BINS];
-
-pub struct Defrag {
-    in_defrag_collection: AtomicBool,
-    defrag_space_exhausted: AtomicBool,
-    pub mark_histograms: Mutex<Vec<Histogram>>,
-    /// A block with number of holes greater than this threshold will be defragmented.
-    pub defrag_spill_threshold: AtomicUsize,
-    /// The number of remaining clean pages in defrag space.
-    available_clean_pages_for_defrag: AtomicUsize,
+/// Marking used by GC workers in non-defrag cycles. Object colour and line marks are set atomically.
+struct ImmixMarking {
+    space: &'static ImmixSpace,
+    alloc_color: u8,
+    mark_color: u8,
+    /// Record starts of marked objects for conservative stack scanning.
+    object_starts: bool,
 }
-impl Defrag {
-    const NUM_BINS: usize = (IMMIX_LINES_PER_BLOCK >> 1) + 1;
-    const DEFRAG_LINE_REUSE_RATIO: f32 = 0.99;
-    const MIN_SPILL_THRESHOLD: usize = 2;
-    const DEFRAG_STRESS: bool = false;
-    const DEFRAG_HEADROOM_PERCENT: usize = 2;
 
-    /// Allocate a new local histogram.
-    pub const fn new_histogram(&self) -> Histogram {
-        [0; Self::NUM_BINS]
+unsafe impl Sync for ImmixMarking {}
+
+impl ParallelMark for ImmixMarking {
+    #[inline]
+    fn try_mark(&self, slot: &mut NonNull<HeapObjectHeader>) -> bool {
+        let object = slot.as_ptr();
+        unsafe {
+            if (*object).set_color(self.alloc_color, self.mark_color) {
+                return false;
+            }
+            if self.space.has_address(object.cast()) {
+                self.space.mark_lines_sync(object);
+                if self.object_starts {
+                    self.space.object_start_bitmap.set_sync(object.cast());
+                }
+            } else {
+                (*PreciseAllocation::from_cell(object)).test_and_set_marked();
+            }
+            true
+        }
     }
+}
 
-    /// Report back a completed mark histogram
-    #[inline(always)]
-    pub fn add_completed_mark_histogram(&self, histogram: Histogram) {
-        self.mark_histograms.lock().push(histogram)
+impl Drop for Immix {
+    fn drop(&mut self) {
+        unsafe {
+            if self.verbose {
+                eprintln!("Dispose immix space at {:p}", self);
+            }
+            Box::from_raw(self.space as *const ImmixSpace as *mut ImmixSpace);
+        }
     }
+}
 
-    /// Check if the current GC is a defrag GC.
-    #[inline(always)]
-    pub fn in_defrag(&self) -> bool {
-        self.in_defrag_collection.load(Ordering::Acquire)
-    }
-
-    /// Determine whether the current GC should do defragmentation.
-    pub fn decide_whether_to_defrag(
-        &self,
-        emergency_collection: bool,
-        collect_whole_heap: bool,
-        collection_attempts: usize,
-        user_triggered: bool,
-        exhausted_reusable_space: bool,
-        full_heap_system_gc: bool,
-    ) {
-        let in_defrag = true
-            && (emergency_collection
-                || (collection_attempts > 1)
-                || !exhausted_reusable_space
-                || Self::DEFRAG_STRESS
-                || (collect_whole_heap && user_triggered && full_heap_system_gc));
-        // println!("Defrag: {}", in_defrag);
-        self.in_defrag_collection
-            .store(in_defrag, Ordering::Release)
+#[cfg(test)]
+mod tests {
+    use super::{block::ImmixBlock, instantiate_immix, Immix};
+    use crate::{
+        api::{Collectable, Finalize, Gc, Trace, Visitor},
+        gc_base::AllocationSpace,
+        mutator::MutatorRef,
+    };
+
+    /// Object that occupies most of an Immix line.
+    struct Node {
+        value: usize,
+        _padding: [usize; 27],
     }
 
-    /// Get the number of defrag headroom pages.
-    pub fn defrag_headroom_pages(&self, space: &ImmixSpace) -> usize {
-        space.get_reserved_pages() * Self::DEFRAG_HEADROOM_PERCENT / 100
+    unsafe impl Trace for Node {
+        fn trace(&mut self, _vis: &mut dyn Visitor) {}
+    }
+    unsafe impl Finalize for Node {}
+    impl Collectable for Node {}
+
+    fn node(mutator: &mut MutatorRef<Immix>, value: usize) -> Gc<Node, Immix> {
+        mutator.allocate(
+            Node {
+                value,
+                _padding: [0; 27],
+            },
+            AllocationSpace::New,
+        )
     }
 
-    /// Check if the defrag space is exhausted.
-    #[inline(always)]
-    pub fn space_exhausted(&self) -> bool {
-        self.defrag_space_exhausted.load(Ordering::Acquire)
-    }
-
-    /// Update available_clean_pages_for_defrag counter when a clean block is allocated.
-    pub fn notify_new_clean_block(&self, copy: bool) {
-        if copy {
-            let available_clean_pages_for_defrag =
-                self.available_clean_pages_for_defrag.fetch_update(
-                    Ordering::SeqCst,
-                    Ordering::SeqCst,
-                    |available_clean_pages_for_defrag| {
-                        if available_clean_pages_for_defrag <= IMMIX_BLOCK_SIZE / PAGE_SIZE {
-                            Some(0)
-                        } else {
-                            Some(available_clean_pages_for_defrag - (IMMIX_BLOCK_SIZE / PAGE_SIZE))
-                        }
-                    },
-                );
-            if available_clean_pages_for_defrag.unwrap() <= IMMIX_BLOCK_SIZE / PAGE_SIZE {
-                self.defrag_space_exhausted.store(true, Ordering::SeqCst);
+    #[test]
+    fn test_defrag() {
+        let mut mutator = instantiate_immix(
+            64 * 1024 * 1024,
+            4 * 1024 * 1024,
+            2 * 1024 * 1024,
+            64 * 1024 * 1024,
+            false,
+        );
+        letroot!(
+            nodes = mutator.shadow_stack(),
+            Vec::<Gc<Node, Immix>>::new()
+        );
+        for value in 0..2048 {
+            let object = node(&mut mutator, value);
+            nodes.push(object);
+        }
+        // keep every 4th object so blocks are left with many holes.
+        nodes.retain(|object| object.value % 4 == 0);
+        let before = nodes.iter().map(|object| object.base).collect::<Vec<_>>();
+
+        // first cycle sweeps blocks and records their holes, second one evacuates fragmented blocks.
+        mutator.full_collection(&mut []);
+        mutator.full_collection(&mut []);
+
+        let mut sources = vec![];
+        for (object, old) in nodes.iter().zip(before.iter()) {
+            if object.base != *old {
+                sources.push(ImmixBlock::from_object(old.as_ptr().cast()));
             }
         }
-    }
-
-    /// Prepare work. Should be called in ImmixSpace::prepare.
-    #[allow(clippy::assertions_on_constants)]
-    pub fn prepare(&self, space: &ImmixSpace<VM>) {
-        self.defrag_space_exhausted.store(false, Ordering::Release);
-
-        // Calculate available free space for defragmentation.
-
-        let mut available_clean_pages_for_defrag = VM::VMActivePlan::global().get_total_pages()
-            as isize
-            - VM::VMActivePlan::global().get_pages_reserved() as isize
-            + self.defrag_headroom_pages(space) as isize;
-        if available_clean_pages_for_defrag < 0 {
-            available_clean_pages_for_defrag = 0
-        };
-
-        self.available_clean_pages_for_defrag
-            .store(available_clean_pages_for_defrag as usize, Ordering::Release);
-
-        if self.in_defrag() {
-            self.establish_defrag_spill_threshold(space)
+        assert!(!sources.is_empty(), "no objects were evacuated");
+        for (index, object) in nodes.iter().enumerate() {
+            assert_eq!(object.value, index * 4);
         }
 
-        self.available_clean_pages_for_defrag.store(
-            available_clean_pages_for_defrag as usize
-                + VM::VMActivePlan::global().get_collection_reserve(),
-            Ordering::Release,
+        // lines freed by evacuation are reused by allocator.
+        letroot!(
+            fresh = mutator.shadow_stack(),
+            Vec::<Gc<Node, Immix>>::new()
         );
-    }
-
-    /// Get the numebr of all the recyclable lines in all the reusable blocks.
-    fn get_available_lines(
-        &self,
-        space: &ImmixSpace<VM>,
-        spill_avail_histograms: &mut Histogram,
-    ) -> usize {
-        let mut total_available_lines = 0;
-        for block in space.reusable_blocks.get_blocks().iter() {
-            let bucket = block.get_holes();
-            let unavailable_lines = match block.get_state() {
-                BlockState::Reusable { unavailable_lines } => unavailable_lines as usize,
-                s => unreachable!("{:?} {:?}", block, s),
-            };
-            let available_lines = Block::LINES - unavailable_lines;
-            spill_avail_histograms[bucket] += available_lines;
-            total_available_lines += available_lines;
-        }
-        total_available_lines
-    }
-
-    /// Calculate the defrag threshold.
-    fn establish_defrag_spill_threshold(&self, space: &ImmixSpace) {
-        let mut spill_avail_histograms = self.new_histogram();
-        let clean_lines = self.get_available_lines(space, &mut spill_avail_histograms);
-        let available_lines = clean_lines
-            + (self
-                .available_clean_pages_for_defrag
-                .load(Ordering::Acquire)
-                << (12 as usize - 8));
-
-        // Number of lines we will evacuate.
-        let mut required_lines = 0isize;
-        // Number of to-space free lines we can use for defragmentation.
-        let mut limit = (available_lines as f32 / Self::DEFRAG_LINE_REUSE_RATIO) as isize;
-        let mut threshold = IMMIX_LINES_PER_BLOCK >> 1;
-        let mark_histograms = self.mark_histograms.lock();
-        // Blocks are grouped by buckets, indexed by the number of holes in the block.
-        // `mark_histograms` remembers the number of live lines for each bucket.
-        // Here, reversely iterate all the bucket to find a threshold that all buckets above this
-        // threshold can be evacuated, without causing to-space overflow.
-        for index in (Self::MIN_SPILL_THRESHOLD..Self::NUM_BINS).rev() {
-            threshold = index;
-            // Calculate total number of live lines in this bucket.
-            let this_bucket_mark = mark_histograms
-                .iter()
-                .map(|v| v[threshold] as isize)
-                .sum::<isize>();
-            // Calculate the number of free lines in this bucket.
-            let this_bucket_avail = spill_avail_histograms[threshold] as isize;
-            // Update counters
-            limit -= this_bucket_avail as isize;
-            required_lines += this_bucket_mark;
-            // Stop scanning. Lines to evacuate exceeds the free to-space lines.
-            if limit < required_lines {
-                break;
-            }
-        }
-        // println!("threshold: {}", threshold);
-        debug_assert!(threshold >= Self::MIN_SPILL_THRESHOLD);
-        self.defrag_spill_threshold
-            .store(threshold, Ordering::Release);
-    }
-
-    /// Release work. Should be called in ImmixSpace::release.
-    #[allow(clippy::assertions_on_constants)]
-    pub fn release(&self, _space: &ImmixSpace) {
-        self.in_defrag_collection.store(false, Ordering::Release);
-    }
-}*/
+        for value in 0..2048 {
+            let object = node(&mut mutator, value);
+            fresh.push(object);
+        }
+        assert!(fresh
+            .iter()
+            .any(|object| sources.contains(&ImmixBlock::from_object(object.base.as_ptr().cast()))));
 
-impl Drop for Immix {
-    fn drop(&mut self) {
-        unsafe {
-            if self.verbose {
-                eprintln!("Dispose immix space at {:p}", self);
-            }
-            Box::from_raw(self.space as *const ImmixSpace as *mut ImmixSpace);
+        mutator.full_collection(&mut []);
+        for (index, object) in nodes.iter().enumerate() {
+            assert_eq!(object.value, index * 4);
+        }
+        for (index, object) in fresh.iter().enumerate() {
+            assert_eq!(object.value, index);
         }
     }
 }
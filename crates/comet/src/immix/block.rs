@@ -1,4 +1,5 @@
 use super::*;
+use crate::utils::poison;
 pub const IMMIX_BLOCK_SIZE: usize = 32 * 1024;
 pub const IMMIX_LINE_SIZE: usize = 256;
 pub const IMMIX_LINES_PER_BLOCK: usize = IMMIX_BLOCK_SIZE / IMMIX_LINE_SIZE;
@@ -76,6 +77,10 @@ impl ImmixBlock {
     pub fn is_fragmented(&self) -> bool {
         self.fragmented
     }
+    /// Mark or unmark block as defragmentation source. Objects from fragmented blocks are evacuated during defrag GC cycle.
+    pub fn set_fragmented(&mut self, fragmented: bool) {
+        self.fragmented = fragmented;
+    }
 
     pub fn holes(&self) -> usize {
         self.hole_count as _
@@ -131,7 +136,12 @@ impl ImmixBlock {
     }
 
     /// Sweep Immix block. Returns `true` if block is dead.
-    pub fn sweep(&mut self, space: &ImmixSpace) -> bool {
+    ///
+    /// Number of holes in the block is recorded and marked lines are added to `histogram` bucket for that number of holes,
+    /// this information is later used by [Defrag](super::Defrag) to select blocks for evacuation.
+    /// Unmarked lines are [poisoned](crate::utils::poison).
+    pub fn sweep(&mut self, space: &ImmixSpace, histogram: &mut Histogram) -> bool {
+        self.fragmented = false;
         if self.state == BlockState::Unallocated {
             // unallocated blocks go to free list instantly
             space.free_blocks.push(self as *mut Self);
@@ -140,13 +150,25 @@ impl ImmixBlock {
         let chunk = self.chunk();
         let line_mark_table = unsafe { (&*chunk).line_mark_table() };
         let mut marked_lines = 0;
+        let mut holes = 0;
+        let mut prev_line_is_marked = true;
 
         for i in 1..IMMIX_LINES_PER_BLOCK {
             // count number of marked lines so we can update num_bytes_allocated
             if line_mark_table.test(self.line(i as _)) {
                 marked_lines += 1;
+                prev_line_is_marked = true;
+            } else {
+                if prev_line_is_marked {
+                    holes += 1;
+                }
+                prev_line_is_marked = false;
+                unsafe {
+                    poison(self.line(i as _), IMMIX_LINE_SIZE);
+                }
             }
         }
+        self.hole_count = holes as _;
         if marked_lines == 0 {
             // zero marked lines means object does not have live object. Release it and add to free list
             space.release_block(self as *mut Self);
@@ -158,6 +180,7 @@ impl ImmixBlock {
             space
                 .num_bytes_allocated
                 .fetch_add(marked_lines * IMMIX_LINE_SIZE, Ordering::Relaxed);
+            histogram[holes.min(Defrag::NUM_BINS - 1)] += marked_lines;
 
             if marked_lines != IMMIX_LINES_PER_BLOCK - 1 {
                 // block has unmarked lines that are available for allocation, mark it as reusable
@@ -260,6 +283,7 @@ impl BlockList {
     #[inline]
     pub fn reset(&self) {
         self.head.store(null_mut(), Ordering::Relaxed);
+        self.count.store(0, Ordering::Relaxed);
     }
 
     /// Get an array of all reusable blocks stored in this BlockList.
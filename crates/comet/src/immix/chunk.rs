@@ -1,6 +1,6 @@
 use crate::{bitmap::LineMarkTable, utils::align_down};
 
-use super::{space::ImmixSpace, ImmixBlock, IMMIX_BLOCK_SIZE};
+use super::{space::ImmixSpace, Histogram, ImmixBlock, IMMIX_BLOCK_SIZE};
 
 /// Represents chunk that contains [ImmixBlock]'s. Each chunk can store up to 128 blocks but
 /// only 127 blocks are available for use because first 32KB of memory is reserved for chunk metadata.
@@ -52,13 +52,13 @@ impl Chunk {
     }
 
     /// Sweep single chunk. If chunk is empty it's entry in chunk map is cleared
-    pub fn sweep(&mut self, space: &ImmixSpace) {
+    pub fn sweep(&mut self, space: &ImmixSpace, histogram: &mut Histogram) {
         let mut cursor = 1;
         let mut allocated_blocks = 0;
         while cursor < CHUNK_BLOCKS {
             let block = self.block(cursor);
             unsafe {
-                if !(*block).sweep(space) {
+                if !(*block).sweep(space, histogram) {
                     allocated_blocks += 1;
                 }
             }
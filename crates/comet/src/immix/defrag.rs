@@ -0,0 +1,195 @@
+//! Opportunistic defragmentation for Immix space.
+//!
+//! Sweeping records number of holes in each block and builds histogram of marked lines per number of holes. At the start of
+//! defrag GC cycle these histograms are used to establish "spill threshold": blocks that have more holes than threshold are
+//! marked as fragmented and objects in them are evacuated into clean blocks during marking. Threshold is selected so that
+//! objects from all fragmented blocks fit into the clean blocks that are available for evacuation.
+//!
+//! Evacuation is opportunistic: pinned objects and objects that do not fit into defrag space are marked in place.
+//! Ported from [MMTk](https://github.com/mmtk/mmtk-core).
+use super::*;
+use parking_lot::Mutex;
+use std::sync::atomic::AtomicBool;
+
+pub type Histogram = [usize; Defrag::NUM_BINS];
+
+pub struct Defrag {
+    enabled: bool,
+    in_defrag_collection: AtomicBool,
+    /// Next GC cycle evacuates objects from all blocks, see [force_next_defrag](Self::force_next_defrag).
+    force_defrag: AtomicBool,
+    defrag_space_exhausted: AtomicBool,
+    pub mark_histograms: Mutex<Vec<Histogram>>,
+    /// A block with number of holes greater than this threshold will be defragmented.
+    pub defrag_spill_threshold: AtomicUsize,
+    /// The number of remaining clean blocks in defrag space.
+    available_clean_blocks_for_defrag: AtomicUsize,
+}
+
+impl Defrag {
+    pub const NUM_BINS: usize = (IMMIX_LINES_PER_BLOCK >> 1) + 1;
+    const DEFRAG_LINE_REUSE_RATIO: f32 = 0.99;
+    const MIN_SPILL_THRESHOLD: usize = 2;
+    /// Defragment on each GC cycle. Useful for testing evacuation.
+    const DEFRAG_STRESS: bool = false;
+    /// Percent of Immix blocks that mutators can't allocate into outside of emergency collection so there is always some memory
+    /// to evacuate objects to.
+    const DEFRAG_HEADROOM_PERCENT: usize = 2;
+
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            in_defrag_collection: AtomicBool::new(false),
+            force_defrag: AtomicBool::new(false),
+            defrag_space_exhausted: AtomicBool::new(false),
+            mark_histograms: Mutex::new(vec![]),
+            defrag_spill_threshold: AtomicUsize::new(0),
+            available_clean_blocks_for_defrag: AtomicUsize::new(0),
+        }
+    }
+
+    /// Allocate a new local histogram.
+    pub const fn new_histogram() -> Histogram {
+        [0; Self::NUM_BINS]
+    }
+
+    /// Report back a completed mark histogram
+    #[inline(always)]
+    pub fn add_completed_mark_histogram(&self, histogram: Histogram) {
+        self.mark_histograms.lock().push(histogram)
+    }
+
+    /// Returns `true` if defragmentation is enabled for this space.
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Check if the current GC is a defrag GC.
+    #[inline(always)]
+    pub fn in_defrag(&self) -> bool {
+        self.in_defrag_collection.load(Ordering::Acquire)
+    }
+
+    /// Make the next GC cycle a defrag cycle in which every block is evacuated regardless of its fragmentation. Used by
+    /// [stress](crate::stress) mode, evacuation is still opportunistic and stops once defrag space is exhausted.
+    pub fn force_next_defrag(&self) {
+        self.force_defrag.store(true, Ordering::Release);
+    }
+
+    /// Determine whether the current GC should do defragmentation.
+    pub fn decide_whether_to_defrag(
+        &self,
+        emergency_collection: bool,
+        user_triggered_full_collection: bool,
+        exhausted_reusable_space: bool,
+    ) {
+        let in_defrag = self.enabled
+            && (emergency_collection
+                || user_triggered_full_collection
+                || !exhausted_reusable_space
+                || Self::DEFRAG_STRESS
+                || self.force_defrag.load(Ordering::Acquire));
+        self.in_defrag_collection
+            .store(in_defrag, Ordering::Release)
+    }
+
+    /// Get the number of defrag headroom blocks.
+    pub fn defrag_headroom_blocks(&self, space: &ImmixSpace) -> usize {
+        if self.enabled {
+            space.n_blocks() * Self::DEFRAG_HEADROOM_PERCENT / 100
+        } else {
+            0
+        }
+    }
+
+    /// Check if the defrag space is exhausted.
+    #[inline(always)]
+    pub fn space_exhausted(&self) -> bool {
+        self.defrag_space_exhausted.load(Ordering::Acquire)
+    }
+
+    /// Mark defrag space as exhausted. No more objects are evacuated in current GC cycle.
+    pub fn notify_space_exhausted(&self) {
+        self.defrag_space_exhausted.store(true, Ordering::SeqCst);
+    }
+
+    /// Update available_clean_blocks_for_defrag counter when a clean block is allocated for evacuation. Returns `false`
+    /// if defrag space is exhausted and block must not be used.
+    pub fn notify_new_clean_block(&self) -> bool {
+        let result = self.available_clean_blocks_for_defrag.fetch_update(
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+            |available| available.checked_sub(1),
+        );
+        if result.is_err() {
+            self.notify_space_exhausted();
+            return false;
+        }
+        true
+    }
+
+    /// Prepare work. Should be called in ImmixSpace::prepare.
+    pub fn prepare(&self, space: &ImmixSpace) {
+        self.defrag_space_exhausted.store(false, Ordering::Release);
+        // Mutators are stopped so all of the free blocks can be used for evacuation.
+        self.available_clean_blocks_for_defrag
+            .store(space.free_blocks.len(), Ordering::Release);
+
+        if self.in_defrag() {
+            self.establish_defrag_spill_threshold();
+        }
+    }
+
+    /// Calculate the defrag threshold.
+    fn establish_defrag_spill_threshold(&self) {
+        let available_lines = self
+            .available_clean_blocks_for_defrag
+            .load(Ordering::Acquire)
+            * (IMMIX_LINES_PER_BLOCK - 1);
+
+        // Number of lines we will evacuate.
+        let mut required_lines = 0isize;
+        // Number of to-space free lines we can use for defragmentation.
+        let limit = (available_lines as f32 / Self::DEFRAG_LINE_REUSE_RATIO) as isize;
+        let mut threshold = Self::NUM_BINS - 1;
+        let mark_histograms = self.mark_histograms.lock();
+        // Blocks are grouped by buckets, indexed by the number of holes in the block.
+        // `mark_histograms` remembers the number of live lines for each bucket.
+        // Here, reversely iterate all the bucket to find a threshold that all buckets above this
+        // threshold can be evacuated, without causing to-space overflow.
+        for index in (Self::MIN_SPILL_THRESHOLD..Self::NUM_BINS).rev() {
+            threshold = index;
+            // Calculate total number of live lines in this bucket.
+            let this_bucket_mark = mark_histograms
+                .iter()
+                .map(|v| v[threshold] as isize)
+                .sum::<isize>();
+            required_lines += this_bucket_mark;
+            // Stop scanning. Lines to evacuate exceeds the free to-space lines.
+            if limit < required_lines {
+                break;
+            }
+        }
+        debug_assert!(threshold >= Self::MIN_SPILL_THRESHOLD);
+        self.defrag_spill_threshold
+            .store(threshold, Ordering::Release);
+    }
+
+    /// Returns `true` if block with `holes` holes should be evacuated in current GC cycle.
+    #[inline]
+    pub fn is_defrag_source(&self, holes: usize) -> bool {
+        self.in_defrag()
+            && (self.force_defrag.load(Ordering::Relaxed)
+                || holes > self.defrag_spill_threshold.load(Ordering::Relaxed))
+    }
+
+    /// Release work. Should be called in ImmixSpace::release.
+    pub fn release(&self) {
+        // forced defrag stays pending until defrag cycle happens, e.g. nursery collection of sticky Immix does not defrag.
+        if self.in_defrag() {
+            self.force_defrag.store(false, Ordering::Release);
+        }
+        self.in_defrag_collection.store(false, Ordering::Release);
+        self.mark_histograms.lock().clear();
+    }
+}
@@ -1,5 +1,5 @@
 use super::*;
-use crate::{bitmap::SpaceBitmap, utils::mmap::Mmap};
+use crate::{bitmap::SpaceBitmap, conservative::find_object_start, utils::mmap::Mmap};
 pub struct ImmixSpace {
     map: Mmap,
     pub free_blocks: BlockList,
@@ -13,6 +13,10 @@ pub struct ImmixSpace {
     pub max_heap_size: usize,
     pub growth_limit: usize,
     pub mark_bitmap: SpaceBitmap<8>,
+    /// Starts of objects that survived the last GC cycle or were allocated after it. Maintained only when conservative stack
+    /// scanning is enabled, see [conservative](crate::conservative).
+    pub object_start_bitmap: SpaceBitmap<8>,
+    pub defrag: Defrag,
 }
 
 impl ImmixSpace {
@@ -25,6 +29,7 @@ impl ImmixSpace {
         min_heap_size: usize,
         max_heap_size: usize,
         verbose: bool,
+        defrag: bool,
     ) -> ImmixSpace {
         let size = round_up(size as _, CHUNK_SIZE as _);
         let mmap = Mmap::new(size as _, CHUNK_SIZE);
@@ -72,6 +77,11 @@ impl ImmixSpace {
         assert!(min_heap_size <= size as usize);
         Self {
             mark_bitmap: bitmap,
+            object_start_bitmap: SpaceBitmap::create(
+                "object-start-bitmap",
+                mmap.aligned_start(),
+                aligned_size,
+            ),
             n_chunks,
             map: mmap,
             free_blocks: free_list,
@@ -83,6 +93,7 @@ impl ImmixSpace {
             max_heap_size,
             initial_size,
             growth_limit: size as _,
+            defrag: Defrag::new(defrag),
         }
     }
     pub fn init_bitmap(&mut self) {
@@ -91,6 +102,14 @@ impl ImmixSpace {
     pub fn reserved_pages(&self) -> usize {
         self.free_blocks.len() * PAGE_SIZE
     }
+    /// Total number of blocks in this space.
+    pub fn n_blocks(&self) -> usize {
+        self.n_chunks * (CHUNK_BLOCKS - 1)
+    }
+    /// Number of clean blocks that are reserved for evacuation and mutators can't allocate into outside of emergency collection.
+    pub fn defrag_headroom_blocks(&self) -> usize {
+        self.defrag.defrag_headroom_blocks(self)
+    }
 
     /// Release block by adding it to free list. On Unix platforms it does `madvise` with `MADV_DONTNEED`.
     pub fn release_block(&self, block: *mut ImmixBlock) {
@@ -116,6 +135,18 @@ impl ImmixSpace {
         }
     }
 
+    /// Get clean block for evacuating objects into. Returns null if defrag space is exhausted.
+    pub fn get_clean_block_for_defrag(&self) -> *mut ImmixBlock {
+        if self.defrag.space_exhausted() || !self.defrag.notify_new_clean_block() {
+            return null_mut();
+        }
+        let block = self.get_clean_block();
+        if block.is_null() {
+            self.defrag.notify_space_exhausted();
+        }
+        block
+    }
+
     /// Get first reusable block
     pub fn get_reusable_block(&self) -> *mut ImmixBlock {
         let block = self.reusable_blocks.pop();
@@ -133,11 +164,81 @@ impl ImmixSpace {
     pub fn has_address(&self, ptr: *const u8) -> bool {
         ptr >= self.map.aligned_start() && ptr < self.map.end()
     }
+    /// Find object that contains `addr` in [object_start_bitmap](Self::object_start_bitmap). Returns null pointer if `addr` does
+    /// not point into an object.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be in this space, see [has_address](Self::has_address).
+    pub unsafe fn find_object(&self, addr: *const u8) -> *mut HeapObjectHeader {
+        let block = ImmixBlock::align(addr);
+        // first block of a chunk is chunk header.
+        if block == Chunk::align(addr) {
+            return null_mut();
+        }
+        let block = block.cast::<ImmixBlock>();
+        if (*block).state() == BlockState::Unallocated {
+            return null_mut();
+        }
+        find_object_start(
+            &self.object_start_bitmap,
+            addr,
+            (*block).start_address(),
+            IMMIX_BLOCK_SIZE,
+        )
+    }
+    /// Visit objects recorded in [object_start_bitmap](Self::object_start_bitmap) together with blocks they are allocated in.
+    pub fn visit_objects(&self, mut visitor: impl FnMut(*mut ImmixBlock, *mut HeapObjectHeader)) {
+        self.chunk_map.visit_marked_range(
+            self.map.aligned_start(),
+            self.map.end(),
+            |chunk| unsafe {
+                let chunk = &*chunk.cast::<Chunk>();
+                for i in 1..CHUNK_BLOCKS {
+                    let block = chunk.block(i);
+                    if (*block).state() == BlockState::Unallocated {
+                        continue;
+                    }
+                    self.object_start_bitmap.visit_marked_range(
+                        (*block).start_address(),
+                        (*block).end_address(),
+                        |object| visitor(block, object),
+                    );
+                }
+            },
+        );
+    }
+    /// Returns true if all lines that `object` occupies are marked.
+    ///
+    /// # Safety
+    ///
+    /// `object` must be a valid object allocated in this space.
+    pub unsafe fn lines_marked(&self, object: *const HeapObjectHeader) -> bool {
+        let block = ImmixBlock::align(object.cast()).cast::<ImmixBlock>();
+        let chunk = (*block).chunk();
+        let end = object.cast::<u8>().add((*object).size()) as *mut u8;
+        let mut line = line_align(object.cast());
+        while line < end {
+            if !(*chunk).line_mark_table().test(line) {
+                return false;
+            }
+            line = line.add(IMMIX_LINE_SIZE);
+        }
+        true
+    }
     pub fn object_to_line_num(object: *const u8) -> usize {
         (object as usize % IMMIX_BLOCK_SIZE) / IMMIX_LINE_SIZE
     }
     /// Mark lines for an object. If object is allocated in multiple lines multiple lines are marked.
     pub fn mark_lines(&self, object: *const HeapObjectHeader) {
+        self.modify_lines::<false>(object);
+    }
+    /// Same as [mark_lines](Self::mark_lines) but lines are marked atomically. Used by parallel marking.
+    pub fn mark_lines_sync(&self, object: *const HeapObjectHeader) {
+        self.modify_lines::<true>(object);
+    }
+    #[inline(always)]
+    fn modify_lines<const SYNC: bool>(&self, object: *const HeapObjectHeader) {
         unsafe {
             let block = ImmixBlock::align(object.cast()).cast::<ImmixBlock>();
             let chunk = (*block).chunk();
@@ -153,26 +254,36 @@ impl ImmixSpace {
 
             let mut line = start_line;
             while line < end_line {
-                (*chunk).line_mark_table().set(line);
+                if SYNC {
+                    (*chunk).line_mark_table().atomic_test_and_set(line);
+                } else {
+                    (*chunk).line_mark_table().set(line);
+                }
                 line = line.add(IMMIX_LINE_SIZE);
             }
         }
     }
     /// Prepare for marking phase by settings all blocks state to unamrked and possibly clearing
-    /// line mark table if `major_gc` is true.
+    /// line mark table if `major_gc` is true. If current GC cycle is defrag cycle then blocks
+    /// that have too many holes are marked as fragmented.
     pub fn prepare(&self, major_gc: bool) {
+        self.defrag.prepare(self);
+        let in_defrag = self.defrag.in_defrag();
         self.chunk_map.visit_marked_range(
             self.map.aligned_start(),
             self.map.end(),
             |chunk| unsafe {
                 let chunk = &mut *chunk.cast::<Chunk>();
 
-                for i in 0..CHUNK_BLOCKS {
+                for i in 1..CHUNK_BLOCKS {
                     let block = chunk.block(i);
                     if (*block).state() == BlockState::Unallocated {
                         continue;
                     }
                     (*block).set_state(BlockState::Unmarked);
+                    if in_defrag && self.defrag.is_defrag_source((*block).holes()) {
+                        (*block).set_fragmented(true);
+                    }
                 }
                 if major_gc {
                     // Clear marked lines in order for GC to recycle lines properly after GC
@@ -182,19 +293,21 @@ impl ImmixSpace {
         );
     }
 
-    /// Release dead memory after GC cycle. This function will walk all alive chunks
-    /// and sweep allocated blocks in each chunk.
+    /// Release dead memory after GC cycle. This function will walk all chunks
+    /// and sweep allocated blocks in each chunk. Chunks that were never allocated into are swept too
+    /// so that their blocks are put back to free list.
     pub fn release(&self) {
+        self.defrag.release();
         self.reusable_blocks.reset();
         self.free_blocks.reset();
-        self.chunk_map.visit_marked_range(
-            self.map.aligned_start(),
-            self.map.end(),
-            |chunk| unsafe {
-                let chunk = chunk.cast::<Chunk>();
-                (*chunk).sweep(self);
-            },
-        );
+        let mut histogram = Defrag::new_histogram();
+        for i in 0..self.n_chunks {
+            unsafe {
+                let chunk = self.map.aligned_start().add(i * CHUNK_SIZE).cast::<Chunk>();
+                (*chunk).sweep(self, &mut histogram);
+            }
+        }
+        self.defrag.add_completed_mark_histogram(histogram);
     }
 
     pub fn acquire_recyclable_lines(&self, line: *mut u8) -> (*mut u8, *mut u8) {
@@ -1,5 +1,9 @@
 use super::api::*;
-use std::{ptr::null_mut, sync::atomic::AtomicBool};
+use crate::utils::poison;
+use std::{
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
 /// Precise allocation used for large objects (>= LARGE_CUTOFF).
 /// Starlight uses mimalloc that already knows what to do for large allocations. The GC shouldn't
 /// have to think about such things. That's where PreciseAllocation comes in. We will allocate large
@@ -19,6 +23,8 @@ pub struct PreciseAllocation {
     /// Is this even valid allocation?
     pub has_valid_cell: bool,
     pub is_newly_allocated: bool,
+    /// Card of this allocation, see [card_table](crate::card_table).
+    pub card: u8,
 }
 
 impl PreciseAllocation {
@@ -34,6 +40,23 @@ impl PreciseAllocation {
         unsafe { std::mem::transmute(&self.mark) }
         //as_atomic!(&self.mark;AtomicBool)
     }
+    fn card_atomic(&self) -> &AtomicU8 {
+        unsafe { std::mem::transmute(&self.card) }
+    }
+    /// Dirty card of this allocation. Can be invoked concurrently from any number of threads.
+    #[inline(always)]
+    pub fn mark_card(&self) {
+        self.card_atomic()
+            .store(crate::card_table::CARD_DIRTY, Ordering::Relaxed);
+    }
+    #[inline(always)]
+    pub fn is_card_dirty(&self) -> bool {
+        self.card_atomic().load(Ordering::Relaxed) == crate::card_table::CARD_DIRTY
+    }
+    pub fn clear_card(&self) {
+        self.card_atomic()
+            .store(crate::card_table::CARD_CLEAN, Ordering::Relaxed);
+    }
     /// Create PreciseAllocation from pointer
     pub fn from_cell(ptr: *mut HeapObjectHeader) -> *mut Self {
         unsafe {
@@ -130,11 +153,14 @@ impl PreciseAllocation {
     pub fn sweep(&mut self) -> bool {
         true
     }
-    /// Try to create precise allocation (no way that it will return null for now).
+    /// Try to create precise allocation. Returns null pointer if `malloc` fails.
     pub fn try_create(size: usize, index_in_space: u32) -> *mut Self {
         let adjusted_alignment_allocation_size = Self::header_size() + size + Self::HALF_ALIGNMENT;
         unsafe {
             let mut space = libc::malloc(adjusted_alignment_allocation_size).cast::<u8>();
+            if space.is_null() {
+                return null_mut();
+            }
 
             let mut adjusted_alignment = false;
             if !is_aligned_for_precise_allocation(space) {
@@ -152,6 +178,7 @@ impl PreciseAllocation {
                 cell_size: size,
                 index_in_space,
                 is_newly_allocated: false,
+                card: crate::card_table::CARD_CLEAN,
             });
 
             space.cast()
@@ -168,10 +195,11 @@ impl PreciseAllocation {
     pub fn cell_size(&self) -> usize {
         self.cell_size
     }
-    /// Destroy this allocation
+    /// Destroy this allocation. Cell is [poisoned](crate::utils::poison) before memory is freed.
     pub fn destroy(&mut self) {
         let base = self.base_pointer();
         unsafe {
+            poison(self.cell().cast(), self.cell_size);
             libc::free(base as _);
         }
     }
@@ -242,9 +270,14 @@ impl LargeObjectSpace {
                         self.precise_allocations_for_this_collection_begin,
                         self.precise_allocations_for_this_collection_size,
                     );
-                    let result = slice.binary_search_by(|ptr| ptr.cmp(&prec));
-                    if let Ok(_) = result {
-                        return (*prec).cell();
+                    // pointer might point into the middle of the object, check allocation that starts right before it.
+                    let index = match slice.binary_search_by(|ptr| ptr.cmp(&prec)) {
+                        Ok(index) => index,
+                        Err(0) => return null_mut(),
+                        Err(index) => index - 1,
+                    };
+                    if (*slice[index]).contains(pointer as _) {
+                        return (*slice[index]).cell();
                     }
                 }
             }
@@ -328,18 +361,52 @@ impl LargeObjectSpace {
         freed
     }
 
-    pub fn allocate(&mut self, size: usize) -> *mut HeapObjectHeader {
+    /// Sweep old allocations (allocations that have mark bit set) for which `is_live` returns `false`. Used by generational GC
+    /// that uses mark bit to tell old objects from young ones and tracks liveness of old objects in object header.
+    /// Returns number of freed bytes.
+    pub fn sweep_old(&mut self, mut is_live: impl FnMut(*mut HeapObjectHeader) -> bool) -> usize {
+        let mut freed = 0;
+        let mut dst_index = 0;
+        // young allocations are at the end of the list and are never freed here.
+        let young = self.allocations.len() - self.precise_allocations_nursery_offset;
+        for src_index in 0..self.allocations.len() {
+            let allocation = self.allocations[src_index];
+            unsafe {
+                if (*allocation).is_marked() && !is_live((*allocation).cell()) {
+                    self.bytes -= (*allocation).cell_size();
+                    freed += (*allocation).cell_size();
+                    (*allocation).destroy();
+                    continue;
+                }
+                (*allocation).index_in_space = dst_index as u32;
+                self.allocations[dst_index] = allocation;
+                dst_index += 1;
+            }
+        }
+        self.allocations.truncate(dst_index);
+        self.precise_allocations_nursery_offset = dst_index - young;
+        freed
+    }
+
+    /// Allocates `size` bytes in large object space. Returns null pointer if there is no memory available.
+    pub fn try_allocate(&mut self, size: usize) -> *mut HeapObjectHeader {
         unsafe {
             let index = self.allocations.len();
             let memory = PreciseAllocation::try_create(size, index as _);
             if memory.is_null() {
-                panic!("LargeObjectSpace: OOM");
+                return null_mut();
             }
 
             self.allocations.push(memory);
             self.bytes += (*memory).cell_size();
             let cell = (*memory).cell();
-            (*cell).set_size(0); // size of 0 means object is large.
+            // size of 0 means object is large.
+            cell.write(HeapObjectHeader {
+                type_id: 0,
+                padding: 0,
+                padding2: 0,
+                value: VTable { raw: 0 },
+            });
             (*memory).cell()
         }
     }
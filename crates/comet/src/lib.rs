@@ -15,11 +15,12 @@
 //!
 //! Comet includes a few GC policies implementations. Each GC policy has its own heap layout and allocation strategy.
 //! Here's the list of all GC policies with links to documentation for them:
+//! - [Concurrent Mark&Sweep](cms)
 //! - [Immix](immix)
 //! - [MarkSweep](marksweep)
 //! - [MiniMark](minimark)
 //! - [Semispace](semispace)
-//! - [Shenandoah](shenandoah) (NOTE: Very W.I.P & TBD)
+//! - [Shenandoah](shenandoah)
 
 #![feature(
     new_uninit,
@@ -30,6 +31,8 @@
     type_name_of_val,
     ptr_metadata
 )]
+extern crate self as comet;
+
 #[macro_use]
 pub mod shadow_stack;
 #[macro_use]
@@ -42,20 +45,30 @@ pub mod bitmap;
 pub mod bump_pointer_space;
 pub mod card_table;
 pub mod cms;
+pub mod conservative;
 pub mod gc_base;
+pub mod gc_log;
 pub mod global;
+pub mod handle_scope;
 pub mod immix;
 pub mod large_space;
 pub mod marksweep;
 pub mod minimark;
 pub mod mutator;
+pub mod parallel_marking;
+pub mod persistent;
+pub mod pin;
 pub mod rosalloc_space;
 pub mod safepoint;
 pub mod semispace;
 pub mod shenandoah;
 pub mod space;
+pub mod stats;
 pub mod sticky_immix;
+pub mod stress;
+pub mod sweeper;
 pub mod tlab;
+pub mod verify;
 pub mod waitlists;
 use std::any::TypeId;
 
@@ -1,15 +1,26 @@
-use crate::api::Weak;
+use crate::api::{
+    pop_ready_finalizer, trace_ephemerons, trace_ordered_finalizers, trace_soft_refs, Ephemeron,
+    OrderedFinalizer, SoftRef, SoftRefPolicy, VTable, Weak,
+};
 use crate::bitmap::SpaceBitmap;
+use crate::conservative::find_object_start;
 use crate::gc_base::{
-    AllocationSpace, MarkingConstraint, MarkingConstraintRuns, NoHelp, NoReadBarrier,
+    AllocError, AllocationSpace, MarkingConstraint, MarkingConstraintRuns, NoHelp, NoReadBarrier,
+    OomHandler, OomHandlerSlot,
 };
+use crate::gc_log::{GcLogger, GcPhase, GcRecord, PhaseTimer};
+use crate::parallel_marking::{default_marking_workers, drain_mark_stack, ParallelMark};
 use crate::rosalloc_space::{RosAllocSpace, RosAllocTLAB};
-use crate::utils::formatted_size;
+use crate::stats::{GcKind, GcListener, GcReason, GcStats, HeapStats};
+use crate::sweeper::{rosalloc_parallel_sweep, LazySweeper};
+use crate::verify::{HeapVerifier, VerifyPhase};
 use crate::{
     api::{vtable_of, Collectable, Gc, HeapObjectHeader, Trace, Visitor},
     gc_base::GcBase,
     large_space::{LargeObjectSpace, PreciseAllocation},
-    mutator::{oom_abort, JoinData, Mutator, MutatorRef, ThreadState},
+    mutator::{JoinData, Mutator, MutatorRef, ThreadState},
+    persistent::PersistentRoots,
+    pin::PinnedObjects,
     safepoint::{GlobalSafepoint, SafepointScope},
     small_type_id,
     utils::align_usize,
@@ -20,7 +31,9 @@ use parking_lot::{lock_api::RawMutex, RawMutex as Lock};
 use rosalloc::{Rosalloc, NUM_OF_SLOTS};
 use std::ptr::null_mut;
 use std::sync::atomic::AtomicUsize;
-use std::{cell::UnsafeCell, marker::PhantomData, mem::size_of, ptr::NonNull, sync::Arc};
+use std::{
+    cell::UnsafeCell, marker::PhantomData, mem::size_of, ptr::NonNull, sync::Arc, time::Instant,
+};
 
 #[repr(C)]
 pub struct MarkSweep {
@@ -39,12 +52,26 @@ pub struct MarkSweep {
     max_free: usize,
     min_free: usize,
     pool: scoped_threadpool::Pool,
-    verbose: bool,
+    /// Sweep rosalloc space after GC pause, see [sweeper](crate::sweeper).
+    lazy_sweep: bool,
+    pub(crate) sweeper: LazySweeper,
     total_gcs: usize,
     weak_refs: Vec<Weak<dyn Collectable, Self>>,
+    ephemerons: Vec<Ephemeron<dyn Collectable, dyn Collectable, Self>>,
+    soft_refs: Vec<SoftRef<dyn Collectable, Self>>,
+    soft_ref_policy: SoftRefPolicy,
+    ordered_finalizers: Vec<OrderedFinalizer<Self>>,
+    persistent_roots: Arc<PersistentRoots<Self>>,
+    pinned_objects: Arc<PinnedObjects<Self>>,
     constraints: Vec<Box<dyn MarkingConstraint>>,
     finalize_list: Vector<*mut HeapObjectHeader>,
     finalize_lock: Lock,
+    oom_handler: OomHandlerSlot<Self>,
+    /// Scan mutator stacks conservatively, see [conservative](crate::conservative).
+    conservative_stack_scanning: bool,
+    /// Verify heap before and after each GC cycle, see [verify](crate::verify).
+    verify_heap: bool,
+    gc_stats: GcStats,
 }
 fn max_bytes_bulk_allocated_for(size: usize) -> usize {
     if !Rosalloc::is_size_for_thread_local(size) {
@@ -54,6 +81,40 @@ fn max_bytes_bulk_allocated_for(size: usize) -> usize {
     NUM_OF_SLOTS[idx] * bracket_size
 }
 
+/// Options of [MarkSweep] heap, see [instantiate_marksweep] for description of sizes.
+pub struct MarkSweepOptions {
+    pub initial_size: usize,
+    pub growth_limit: usize,
+    pub min_free: usize,
+    pub max_free: usize,
+    pub growth_multiplier: f64,
+    pub capacity: usize,
+    pub low_memory_mode: bool,
+    /// Number of GC worker threads used for marking and sweeping.
+    pub num_threads: usize,
+    /// Sweep rosalloc space by mutators after GC pause instead of sweeping it in the pause, see [sweeper](crate::sweeper).
+    pub lazy_sweep: bool,
+    pub verbose: bool,
+}
+
+impl Default for MarkSweepOptions {
+    fn default() -> Self {
+        Self {
+            initial_size: MS_DEFAULT_INITIAL_SIZE,
+            growth_limit: MS_DEFAULT_MAXIMUM_SIZE,
+            min_free: MS_DEFAULT_MIN_FREE,
+            max_free: MS_DEFAULT_MAX_FREE,
+            growth_multiplier: 2.0,
+            capacity: MS_DEFAULT_MAXIMUM_SIZE,
+            low_memory_mode: false,
+            num_threads: default_marking_workers(),
+            lazy_sweep: false,
+            verbose: false,
+        }
+    }
+}
+
+/// Create MarkSweep heap. `num_threads` is number of GC worker threads used for marking and sweeping.
 pub fn instantiate_marksweep(
     initial_size: usize,
     growth_limit: usize,
@@ -65,17 +126,35 @@ pub fn instantiate_marksweep(
     num_threads: usize,
     verbose: bool,
 ) -> MutatorRef<MarkSweep> {
-    let heap = Arc::new(UnsafeCell::new(MarkSweep::new(
+    instantiate_marksweep_with_options(MarkSweepOptions {
         initial_size,
         growth_limit,
         min_free,
         max_free,
-        growht_multiplier,
+        growth_multiplier: growht_multiplier,
         capacity,
         low_memory_mode,
         num_threads,
+        lazy_sweep: false,
         verbose,
-    )));
+    })
+}
+
+/// Create MarkSweep heap with `options`.
+pub fn instantiate_marksweep_with_options(options: MarkSweepOptions) -> MutatorRef<MarkSweep> {
+    let mut heap = MarkSweep::new(
+        options.initial_size,
+        options.growth_limit,
+        options.min_free,
+        options.max_free,
+        options.growth_multiplier,
+        options.capacity,
+        options.low_memory_mode,
+        options.num_threads,
+        options.verbose,
+    );
+    heap.lazy_sweep = options.lazy_sweep;
+    let heap = Arc::new(UnsafeCell::new(heap));
     let href = unsafe { &mut *heap.get() };
     let join_data = JoinData::new();
     let mut mutator = MutatorRef::new(Mutator::new(
@@ -163,12 +242,27 @@ impl MarkSweep {
             num_bytes_allocated: AtomicUsize::new(0),
             growth_multiplier,
             pool: scoped_threadpool::Pool::new(num_threads as _),
-            verbose,
+            lazy_sweep: false,
+            sweeper: LazySweeper::new(),
             weak_refs: vec![],
+            ephemerons: vec![],
+            soft_refs: vec![],
+            soft_ref_policy: SoftRefPolicy::new(),
+            ordered_finalizers: vec![],
+            persistent_roots: Arc::new(PersistentRoots::new()),
+            pinned_objects: Arc::new(PinnedObjects::new()),
+            oom_handler: OomHandlerSlot::new(),
+            conservative_stack_scanning: false,
+            verify_heap: cfg!(feature = "verify-heap"),
+            gc_stats: GcStats::new("MarkSweep"),
         };
         unsafe {
             (*(*this.rosalloc).rosalloc()).set_footprint_limit((*this.rosalloc).capacity());
         }
+        if verbose {
+            this.safepoint
+                .set_logger(Some(Arc::new(GcLogger::stderr())));
+        }
 
         this
     }
@@ -203,107 +297,134 @@ impl MarkSweep {
         }
     }
 
-    #[cold]
-    pub fn alloc_slow<T: Collectable + Sized + 'static>(
-        &mut self,
-        mutator: &mut MutatorRef<Self>,
-        mut value: T,
-    ) -> Gc<T, Self> {
-        self.collect(mutator, &mut [&mut value]);
-        self.alloc_once::<T, true, false>(mutator, value)
+    /// Compute GC threshold from the number of allocated bytes. Returns new threshold.
+    fn update_target_footprint(&self) -> usize {
+        let bytes_allocated = self.num_bytes_allocated.load(Ordering::Relaxed);
+        let mut grow_bytes;
+        let delta = (bytes_allocated as f64 * (1.0 / 0.75 - 1.0)) as usize;
+        grow_bytes = delta.min(self.max_free);
+        grow_bytes = grow_bytes.max(self.min_free);
+        let target_size = bytes_allocated + (grow_bytes as f64 * 2.0) as usize;
+        self.target_footprint.store(target_size, Ordering::Relaxed);
+        target_size
     }
-    #[inline(never)]
-    pub fn alloc_once<T: Collectable + Sized + 'static, const GROW: bool, const GC: bool>(
-        &mut self,
-        mut mutator: &mut MutatorRef<Self>,
-        value: T,
-    ) -> Gc<T, Self> {
-        let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
-        let max_bytes_tl_bulk_allocated = max_bytes_bulk_allocated_for(size);
-        if self.is_out_of_memory_on_allocation(max_bytes_tl_bulk_allocated, GROW) {
-            // potentially run GC if we reached GC threshold
 
-            return self.alloc_slow(mutator, value);
-        }
-        let mut bytes_allocated = 0;
-        let mut usable_size = 0;
-        let mut bytes_tl_bulk_allocated = 0;
+    /// Clear mark bitmap and release free pages of rosalloc space. Must be invoked once whole space is swept.
+    fn finish_sweep(&self) {
         unsafe {
-            let mem = (*self.rosalloc).alloc_common::<Self, true>(
-                &mut mutator,
-                size,
-                &mut bytes_allocated,
-                &mut usable_size,
-                &mut bytes_tl_bulk_allocated,
-            );
-            if mem.is_null() && GC {
-                // trigger GC if no memory is available
-                return self.alloc_slow(mutator, value);
-            } else if mem.is_null() && !GC {
-                // if GC hapenned and memory is still unavailbe just OOM
-                oom_abort();
-            }
-            if bytes_tl_bulk_allocated > 0 {
-                // update num_bytes_allocated so we can start GC when necessary
-                self.num_bytes_allocated
-                    .fetch_add(bytes_tl_bulk_allocated, Ordering::Relaxed);
-            }
+            (*(*self.rosalloc).get_mark_bitmap()).clear_all();
+            (*(*self.rosalloc).rosalloc()).trim();
+        }
+    }
 
-            let header = mem.cast::<HeapObjectHeader>();
-            (*header).set_metadata(vtable_of::<T>());
-            (*header).set_size(size);
-            (*header).type_id = small_type_id::<T>();
-            ((*header).data() as *mut T).write(value);
-            (*self.live_bitmap).set(header.cast());
-            Gc {
-                base: NonNull::new_unchecked(header),
-                marker: PhantomData,
+    /// Sweep next chunk of rosalloc space if lazy sweeping is in progress, or all of the remaining chunks if `all` is true.
+    /// Sweeping is finished and GC threshold is recomputed once whole space is swept.
+    fn lazy_sweep_step(&self, all: bool) {
+        unsafe {
+            let (freed, finished) = if all {
+                self.sweeper.sweep_all(self.rosalloc)
+            } else {
+                self.sweeper.sweep_chunk(self.rosalloc)
+            };
+            self.num_bytes_allocated.fetch_sub(freed, Ordering::Relaxed);
+            if finished {
+                self.finish_sweep();
+                self.update_target_footprint();
             }
         }
     }
-}
 
-impl GcBase for MarkSweep {
-    type TLAB = RosAllocTLAB;
-    const SUPPORTS_TLAB: bool = false;
-    type ReadBarrier = NoReadBarrier;
+    /// Conservatively scan stacks of all mutators and mark objects they point to. MarkSweep never moves objects so they do not
+    /// have to be pinned.
+    unsafe fn mark_conservative_roots(&mut self) {
+        if !self.conservative_stack_scanning {
+            return;
+        }
+        self.large_space.prepare_for_conservative_scan();
+        let this = self as *mut Self;
+        for i in 0..self.mutators.len() {
+            (*self.mutators[i]).scan_stack(|pointer| {
+                let object = if (*self.rosalloc).has_address(pointer) {
+                    // live bitmap has bits only for allocated objects, objects are never larger than `LARGE_ALLOCATION_SIZE`.
+                    find_object_start(
+                        &*self.live_bitmap,
+                        pointer,
+                        null_mut(),
+                        Self::LARGE_ALLOCATION_SIZE,
+                    )
+                } else {
+                    self.large_space.contains(pointer)
+                };
+                if !object.is_null() {
+                    (*this).mark_object(&mut NonNull::new_unchecked(object));
+                }
+            });
+        }
+    }
 
-    fn inline_allocation_helpers(&self) -> Self::InlineAllocationHelpers {
-        NoHelp
+    /// Verify heap, see [verify](crate::verify). Must be invoked in GC pause with heap locks held.
+    unsafe fn verify(&mut self, phase: VerifyPhase, keep: &mut [&mut dyn Trace]) {
+        let mut verifier = HeapVerifier::new("MarkSweep", phase, self.total_gcs);
+        verifier.add_marked_objects(
+            &*self.live_bitmap,
+            (*self.rosalloc).begin(),
+            (*self.rosalloc).end(),
+        );
+        for &allocation in self.large_space.allocations.iter() {
+            verifier.add_large_object(allocation);
+        }
+        verifier.trace_roots("shadow stack", |vis| {
+            for &mutator in self.mutators.iter() {
+                (*mutator).shadow_stack().walk(|entry| entry.trace(vis));
+            }
+        });
+        verifier.trace_roots("persistent root", |vis| self.persistent_roots.trace(vis));
+        verifier.trace_roots("pinned object", |vis| self.pinned_objects.trace(vis));
+        verifier.trace_roots("kept value", |vis| {
+            for object in keep.iter_mut() {
+                object.trace(vis);
+            }
+        });
+        verifier.finish();
     }
 
-    fn add_constraint<T: MarkingConstraint + 'static>(&mut self, constraint: T) {
-        self.global_lock();
-        self.constraints.push(Box::new(constraint));
-        self.global_unlock();
+    /// Set live bit of newly allocated object. While lazy sweeping is in progress live bitmap is read by sweepers, so bit is
+    /// set atomically.
+    #[inline(always)]
+    unsafe fn mark_allocated(&self, object: *mut HeapObjectHeader) {
+        if self.sweeper.is_sweeping() {
+            (*self.live_bitmap).set_sync(object.cast());
+        } else {
+            (*self.live_bitmap).set(object.cast());
+        }
     }
 
-    fn allocate_weak<T: Collectable + ?Sized>(
+    fn perform_collection(
         &mut self,
         mutator: &mut MutatorRef<Self>,
-        value: Gc<T, Self>,
-    ) -> Weak<T, Self> {
-        let weak_ref = unsafe { Weak::create(mutator, value) };
-        self.global_heap_lock.lock();
-        self.weak_refs.push(weak_ref.to_dyn());
-        unsafe {
-            self.global_heap_lock.unlock();
-        }
-        weak_ref
-    }
-    fn collect(&mut self, mutator: &mut MutatorRef<MarkSweep>, mut keep: &mut [&mut dyn Trace]) {
+        mut keep: &mut [&mut dyn Trace],
+        reason: GcReason,
+    ) {
         match SafepointScope::new(mutator.clone()) {
             Some(safepoint) => unsafe {
                 self.global_heap_lock.lock();
                 self.large_space_lock.lock();
-                let time = if self.verbose {
-                    Some(std::time::Instant::now())
-                } else {
-                    None
-                };
+                if self.verify_heap {
+                    self.verify(VerifyPhase::BeforeGc, keep);
+                }
+                let time = Instant::now();
+                let mut timer = PhaseTimer::new(self.safepoint.last_sync());
 
+                if self.sweeper.is_sweeping() {
+                    // objects that are still not swept are dead, mark bitmap can be reused only after they are freed.
+                    self.lazy_sweep_step(true);
+                    timer.end(GcPhase::Sweep);
+                }
                 let prev = self.num_bytes_allocated.load(Ordering::Relaxed);
+                let cycle = self.gc_stats.start_cycle(GcKind::Major, reason, prev);
+                self.soft_ref_policy.begin_cycle(prev, self.growth_limit);
                 self.large_space.prepare_for_marking(false);
+                self.mark_conservative_roots();
                 self.before_mark_constraints();
                 for i in 0..self.mutators.len() {
                     let mutator = self.mutators[i];
@@ -315,35 +436,74 @@ impl GcBase for MarkSweep {
                         object.trace(self);
                     });
                 }
+                let this = self as *mut Self;
+                (*this).persistent_roots.trace(&mut *this);
+                (*this).pinned_objects.trace(&mut *this);
                 keep.trace(self);
+                timer.end(GcPhase::RootScan);
 
-                while let Some(object) = self.mark_stack.pop() {
-                    (*object).get_dyn().trace(self);
-                }
+                let marking = MarkSweepMarking {
+                    rosalloc: self.rosalloc,
+                };
+                drain_mark_stack(&mut self.pool, &mut self.mark_stack, &marking);
                 self.after_mark_constraints();
                 let rosalloc = self.rosalloc;
                 let mark = &*(*rosalloc).get_mark_bitmap();
-                self.finalize_list.retain(|x| {
-                    let header = *x;
-                    if (mark.has_address(header.cast()) && mark.test(header.cast()))
+                let is_marked = |header: *mut HeapObjectHeader| {
+                    (mark.has_address(header.cast()) && mark.test(header.cast()))
                         || ((*header).is_precise()
                             && (*PreciseAllocation::from_cell(header)).is_marked())
-                    {
+                };
+                let forwardee = |header: *mut HeapObjectHeader| {
+                    if is_marked(header) {
+                        header
+                    } else {
+                        null_mut()
+                    }
+                };
+                trace_soft_refs(
+                    &(*this).soft_refs,
+                    &(*this).soft_ref_policy,
+                    forwardee,
+                    |slot| (*this).mark_object(slot),
+                    || drain_mark_stack(&mut (*this).pool, &mut (*this).mark_stack, &marking),
+                );
+                trace_ephemerons(
+                    &(*this).ephemerons,
+                    forwardee,
+                    |slot| (*this).mark_object(slot),
+                    || drain_mark_stack(&mut (*this).pool, &mut (*this).mark_stack, &marking),
+                );
+                if trace_ordered_finalizers(
+                    &mut (*this).ordered_finalizers,
+                    forwardee,
+                    |slot| (*this).mark_object(slot),
+                    || drain_mark_stack(&mut (*this).pool, &mut (*this).mark_stack, &marking),
+                ) {
+                    trace_ephemerons(
+                        &(*this).ephemerons,
+                        forwardee,
+                        |slot| (*this).mark_object(slot),
+                        || drain_mark_stack(&mut (*this).pool, &mut (*this).mark_stack, &marking),
+                    );
+                }
+                timer.end(GcPhase::Mark);
+                self.finalize_list.retain(|x| {
+                    let header = *x;
+                    if is_marked(header) {
                         true
                     } else {
                         (*header).get_dyn().finalize();
                         false
                     }
                 });
+                timer.end(GcPhase::Finalization);
 
                 self.weak_refs.retain_mut(|object| {
                     let header = object.base();
                     if mark.test(header.cast()) {
                         object.after_mark(|header| {
-                            if (mark.has_address(header.cast()) && mark.test(header.cast()))
-                                || ((*header).is_precise()
-                                    && (*PreciseAllocation::from_cell(header)).is_marked())
-                            {
+                            if is_marked(header) {
                                 header
                             } else {
                                 null_mut()
@@ -354,6 +514,23 @@ impl GcBase for MarkSweep {
                         false
                     }
                 });
+                self.soft_refs.retain_mut(|soft_ref| {
+                    if mark.test(soft_ref.base().cast()) {
+                        soft_ref.after_mark(forwardee);
+                        true
+                    } else {
+                        false
+                    }
+                });
+                self.ephemerons.retain_mut(|ephemeron| {
+                    if mark.test(ephemeron.base().cast()) {
+                        ephemeron.after_mark(|key| if is_marked(key) { key } else { null_mut() });
+                        true
+                    } else {
+                        false
+                    }
+                });
+                timer.end(GcPhase::WeakProcessing);
 
                 let mut revoke_freed = 0;
                 for i in 0..self.mutators.len() {
@@ -363,42 +540,46 @@ impl GcBase for MarkSweep {
                 }
                 (*(*self.rosalloc).rosalloc()).revoke_thread_unsafe_current_runs();
 
-                let (freed, _) = (*self.rosalloc).sweep(false, |pointers, _| {
-                    (*self.rosalloc).sweep_callback(pointers, false)
-                });
-                /*let freed =
-                crate::sweeper::rosalloc_parallel_sweep(&mut self.pool, self.rosalloc);*/
+                // marked objects become live, objects that were live before GC are swept.
+                (*self.rosalloc).swap_bitmaps();
+                let freed = if self.lazy_sweep {
+                    self.sweeper.start(self.rosalloc);
+                    0
+                } else {
+                    let freed = rosalloc_parallel_sweep(&mut self.pool, self.rosalloc);
+                    self.finish_sweep();
+                    freed
+                };
 
                 let los_freed = self.large_space.sweep();
 
                 let freed = freed + los_freed + revoke_freed;
 
                 self.num_bytes_allocated.fetch_sub(freed, Ordering::Relaxed);
-                (*self.rosalloc).swap_bitmaps();
-                (*self.rosalloc).mark_bitmap.clear_all();
 
-                (*(*self.rosalloc).rosalloc()).trim();
-
-                let target_size;
                 let bytes_allocated = self.num_bytes_allocated.load(Ordering::Relaxed);
-                let mut grow_bytes;
-                let delta = (bytes_allocated as f64 * (1.0 / 0.75 - 1.0)) as usize;
-                grow_bytes = delta.min(self.max_free);
-                grow_bytes = grow_bytes.max(self.min_free);
-                target_size = bytes_allocated + (grow_bytes as f64 * 2.0) as usize;
-                if let Some(time) = time.map(|x| x.elapsed()) {
-                    eprintln!(
-                        "[gc] GC({}) Pause MarkSweep {}->{}({}) {:.4}ms",
-                        self.total_gcs,
-                        formatted_size(prev),
-                        formatted_size(bytes_allocated),
-                        formatted_size(target_size),
-                        time.as_micros() as f64 / 1000.0
-                    );
-                    self.total_gcs += 1;
-                }
+                let target_size = self.update_target_footprint();
                 self.large_space.prepare_for_allocation(false);
-                self.target_footprint.store(target_size, Ordering::Relaxed);
+                timer.end(GcPhase::Sweep);
+                self.gc_stats.end_cycle(cycle, bytes_allocated);
+                if let Some(logger) = self.safepoint.logger() {
+                    logger.log_gc(&GcRecord {
+                        policy: "MarkSweep",
+                        gc_id: self.total_gcs,
+                        kind: GcKind::Major,
+                        reason,
+                        heap_before: prev,
+                        heap_after: bytes_allocated,
+                        footprint: target_size,
+                        duration: time.elapsed(),
+                        phases: timer.phases(),
+                        extra: &[],
+                    });
+                }
+                if self.verify_heap {
+                    self.verify(VerifyPhase::AfterGc, keep);
+                }
+                self.total_gcs += 1;
                 drop(safepoint);
 
                 self.global_heap_lock.unlock();
@@ -409,32 +590,248 @@ impl GcBase for MarkSweep {
             }
         }
     }
+
+    #[cold]
+    pub fn alloc_slow<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
+        if self.sweeper.is_sweeping() {
+            // memory of dead objects might be still not swept, finish sweeping and collect only if allocation still fails.
+            self.lazy_sweep_step(true);
+            match self.alloc_once::<T, false, false>(mutator, value) {
+                Ok(object) => return Ok(object),
+                Err(error) => value = error.into_inner(),
+            }
+        }
+        self.perform_collection(mutator, &mut [&mut value], GcReason::HeapFull);
+        self.alloc_once::<T, true, false>(mutator, value)
+    }
+    #[inline(never)]
+    pub fn alloc_once<T: Collectable + Sized + 'static, const GROW: bool, const GC: bool>(
+        &mut self,
+        mut mutator: &mut MutatorRef<Self>,
+        value: T,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
+        let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
+        if self.sweeper.is_sweeping() {
+            self.lazy_sweep_step(false);
+        }
+        let max_bytes_tl_bulk_allocated = max_bytes_bulk_allocated_for(size);
+        if self.is_out_of_memory_on_allocation(max_bytes_tl_bulk_allocated, GROW) {
+            if !GC {
+                // GC already happened and we're still over the growth limit
+                return Err(AllocError::new(value, size, AllocationSpace::New));
+            }
+            // potentially run GC if we reached GC threshold
+
+            return self.alloc_slow(mutator, value);
+        }
+        let mut bytes_allocated = 0;
+        let mut usable_size = 0;
+        let mut bytes_tl_bulk_allocated = 0;
+        unsafe {
+            let mem = (*self.rosalloc).alloc_common::<Self, true>(
+                &mut mutator,
+                size,
+                &mut bytes_allocated,
+                &mut usable_size,
+                &mut bytes_tl_bulk_allocated,
+            );
+            if mem.is_null() && GC {
+                // trigger GC if no memory is available
+                return self.alloc_slow(mutator, value);
+            } else if mem.is_null() && !GC {
+                // if GC hapenned and memory is still unavailbe just OOM
+                return Err(AllocError::new(value, size, AllocationSpace::New));
+            }
+            if bytes_tl_bulk_allocated > 0 {
+                // update num_bytes_allocated so we can start GC when necessary
+                self.num_bytes_allocated
+                    .fetch_add(bytes_tl_bulk_allocated, Ordering::Relaxed);
+            }
+
+            let header = mem.cast::<HeapObjectHeader>();
+            header.write(HeapObjectHeader {
+                type_id: small_type_id::<T>(),
+                padding: 0,
+                padding2: 0,
+                value: VTable { raw: 0 },
+            });
+            (*header).set_metadata(vtable_of::<T>());
+            (*header).set_size(size);
+            ((*header).data() as *mut T).write(value);
+            self.mark_allocated(header);
+            Ok(Gc {
+                base: NonNull::new_unchecked(header),
+                marker: PhantomData,
+            })
+        }
+    }
+}
+
+impl GcBase for MarkSweep {
+    type TLAB = RosAllocTLAB;
+    const SUPPORTS_TLAB: bool = false;
+    type ReadBarrier = NoReadBarrier;
+
+    fn inline_allocation_helpers(&self) -> Self::InlineAllocationHelpers {
+        NoHelp
+    }
+
+    fn add_constraint<T: MarkingConstraint + 'static>(&mut self, constraint: T) {
+        self.global_lock();
+        self.constraints.push(Box::new(constraint));
+        self.global_unlock();
+    }
+
+    fn allocate_weak<T: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        value: Gc<T, Self>,
+    ) -> Weak<T, Self> {
+        let weak_ref = unsafe { Weak::create(mutator, value) };
+        self.global_heap_lock.lock();
+        self.weak_refs.push(weak_ref.to_dyn());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        weak_ref
+    }
+
+    fn allocate_soft<T: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        value: Gc<T, Self>,
+    ) -> SoftRef<T, Self> {
+        let soft_ref = unsafe { SoftRef::create(mutator, value) };
+        self.global_heap_lock.lock();
+        self.soft_refs.push(soft_ref.to_dyn());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        soft_ref
+    }
+    fn set_soft_ref_lru_policy(&mut self, ms_per_mb: u64) {
+        self.global_heap_lock.lock();
+        self.soft_ref_policy.set_ms_per_mb(ms_per_mb);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn allocate_ephemeron<K: Collectable + ?Sized, V: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        key: Gc<K, Self>,
+        value: Gc<V, Self>,
+    ) -> Ephemeron<K, V, Self> {
+        let ephemeron = unsafe { Ephemeron::create(mutator, key, value) };
+        self.global_heap_lock.lock();
+        self.ephemerons.push(ephemeron.to_dyn());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        ephemeron
+    }
+    fn persistent_roots(&self) -> Arc<PersistentRoots<Self>> {
+        self.persistent_roots.clone()
+    }
+    fn pinned_objects(&self) -> Arc<PinnedObjects<Self>> {
+        self.pinned_objects.clone()
+    }
+    fn stats(&self) -> HeapStats {
+        self.gc_stats.snapshot(
+            self.num_bytes_allocated.load(Ordering::Relaxed),
+            self.target_footprint.load(Ordering::Relaxed),
+        )
+    }
+    fn add_gc_listener(&mut self, listener: Box<dyn GcListener>) {
+        self.gc_stats.add_listener(listener);
+    }
+    fn register_ordered_finalizer(&mut self, finalizer: OrderedFinalizer<Self>) {
+        self.global_heap_lock.lock();
+        self.ordered_finalizers.push(finalizer);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn pop_ready_finalizer(&mut self) -> Option<OrderedFinalizer<Self>> {
+        self.global_heap_lock.lock();
+        let finalizer = pop_ready_finalizer(&mut self.ordered_finalizers);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        finalizer
+    }
+    fn collect(&mut self, mutator: &mut MutatorRef<MarkSweep>, keep: &mut [&mut dyn Trace]) {
+        self.perform_collection(mutator, keep, GcReason::RequestedByUser);
+    }
+    fn collect_alloc_failure(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        keep: &mut [&mut dyn Trace],
+    ) {
+        self.perform_collection(mutator, keep, GcReason::AllocationFailure);
+    }
+    fn set_marking_workers(&mut self, workers: usize) {
+        self.global_heap_lock.lock();
+        self.pool = scoped_threadpool::Pool::new(workers.max(1) as _);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn set_conservative_stack_scanning(&mut self, _mutator: &mut MutatorRef<Self>, enabled: bool) {
+        self.global_heap_lock.lock();
+        self.conservative_stack_scanning = enabled;
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn set_heap_verification(&mut self, _mutator: &mut MutatorRef<Self>, enabled: bool) {
+        self.global_heap_lock.lock();
+        self.verify_heap = enabled;
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn set_oom_handler(&mut self, handler: OomHandler<Self>) {
+        self.oom_handler.set(handler);
+    }
+    fn handle_oom(&mut self, mutator: &mut MutatorRef<Self>, size: usize) -> bool {
+        // emergency collection: clear all soft references before giving up.
+        if !self.soft_refs.is_empty() && self.soft_ref_policy.request_clear_all() {
+            self.full_collection(mutator, &mut []);
+            return true;
+        }
+        self.oom_handler.invoke(mutator, size)
+    }
     #[inline(always)]
-    fn alloc_inline<T: Collectable + Sized + 'static>(
+    fn try_alloc_inline<T: Collectable + Sized + 'static>(
         &mut self,
         mutator: &mut MutatorRef<Self>,
         value: T,
         _space: AllocationSpace,
-    ) -> Gc<T, Self> {
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
         let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
         let val = if Rosalloc::is_size_for_thread_local(size) {
             let obj = unsafe { mutator.allocate_from_tlab(value) };
             match obj {
                 Ok(value) => {
                     unsafe {
-                        (*self.live_bitmap).set(value.base.as_ptr().cast());
+                        self.mark_allocated(value.base.as_ptr());
                     }
 
                     value
                 }
-                Err(value) => self.alloc_once::<T, false, true>(mutator, value),
+                Err(value) => self.alloc_once::<T, false, true>(mutator, value)?,
             }
         } else {
-            self.alloc_once::<T, false, true>(mutator, value)
+            self.alloc_once::<T, false, true>(mutator, value)?
         };
 
         self.post_alloc(val);
-        val
+        Ok(val)
     }
 
     fn post_alloc<T: Collectable + Sized + 'static>(&mut self, value: Gc<T, Self>) {
@@ -461,7 +858,7 @@ impl GcBase for MarkSweep {
         unsafe { self.global_heap_lock.unlock() };
     }
 
-    fn detach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
+    unsafe fn detach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
         self.global_heap_lock.lock();
 
         let mut detached = false;
@@ -497,15 +894,25 @@ impl GcBase for MarkSweep {
         &self.mutators
     }
 
-    fn allocate_large<T: Collectable + Sized + 'static>(
+    fn try_allocate_large<T: Collectable + Sized + 'static>(
         &mut self,
-        _mutator: &mut MutatorRef<Self>,
-        value: T,
-    ) -> crate::api::Gc<T, Self> {
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
         unsafe {
             let size = value.allocation_size() + size_of::<HeapObjectHeader>();
             self.large_space_lock.lock();
-            let object = self.large_space.allocate(size);
+            let mut object = self.large_space.try_allocate(size);
+            if object.is_null() {
+                self.large_space_lock.unlock();
+                self.collect_alloc_failure(mutator, &mut [&mut value]);
+                self.large_space_lock.lock();
+                object = self.large_space.try_allocate(size);
+                if object.is_null() {
+                    self.large_space_lock.unlock();
+                    return Err(AllocError::new(value, size, AllocationSpace::Large));
+                }
+            }
             (*object).set_metadata(vtable_of::<T>());
             (*object).type_id = small_type_id::<T>();
             let gc = Gc {
@@ -519,7 +926,7 @@ impl GcBase for MarkSweep {
             );
             self.large_space_lock.unlock();
             self.post_alloc(gc);
-            gc
+            Ok(gc)
         }
     }
     fn init_tlab(&mut self, tlab: &mut Self::TLAB) {
@@ -529,20 +936,36 @@ impl GcBase for MarkSweep {
 
 impl Visitor for MarkSweep {
     fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
-        let object = root.as_ptr();
+        let marking = MarkSweepMarking {
+            rosalloc: self.rosalloc,
+        };
+        if marking.try_mark(root) {
+            self.mark_stack.push(root.as_ptr());
+        }
+    }
+}
+
+/// Marks objects in rosalloc space and large object space. Both mark bitmap and large object mark bits are set atomically
+/// so it is safe to use from several GC workers.
+struct MarkSweepMarking {
+    rosalloc: *mut RosAllocSpace,
+}
+
+unsafe impl Sync for MarkSweepMarking {}
+
+impl ParallelMark for MarkSweepMarking {
+    #[inline]
+    fn try_mark(&self, slot: &mut NonNull<HeapObjectHeader>) -> bool {
+        let object = slot.as_ptr();
         unsafe {
             if (*object).is_precise() {
-                if !(*PreciseAllocation::from_cell(object)).test_and_set_marked() {
-                    self.mark_stack.push(object);
-                }
+                !(*PreciseAllocation::from_cell(object)).test_and_set_marked()
             } else {
                 // If object is not in LOS it must be in rosalloc space
                 debug_assert!((*self.rosalloc).has_address(object.cast()));
                 let bitmap = (*self.rosalloc).get_mark_bitmap();
                 debug_assert!((*(*self.rosalloc).get_live_bitmap()).test(object.cast()));
-                if !(*bitmap).set_sync(object.cast()) {
-                    self.mark_stack.push(object);
-                }
+                !(*bitmap).set_sync(object.cast())
             }
         }
     }
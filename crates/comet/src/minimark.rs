@@ -9,12 +9,43 @@
 //! - old objects: never move again. These objects are either allocated by [rosalloc](https://github.com/playxe/rosalloc) (if they are small),
 //! or in LOS (if they are not small). Collected by regular mark-n-sweep during major collections.
 //!
+//! [Pinning](crate::pin) object that is still in the nursery promotes it to old space with minor collection, so pinned objects
+//! are always old or large.
+//!
 //! ## Large objects
 //!
 //! Large objects are allocated in [LargeObjectSpace](crate::large_space::LargeObjectSpace) and generational GC
 //! works with them too. If large object is in young space then it is not marked in minor cycle. To promote large object
 //! in minor GC cycle we just set its mark bit to 1. At start of each major collection mark bits of
 //! large objects are cleared and all unmarked large objects at the end of the cycle are dead.
+//!
+//! ## Write barrier
+//!
+//! By default old objects that are written to are recorded in remembered set which is guarded by a lock. When
+//! [MiniMarkOptions::card_marking] is enabled write barrier instead dirties card of an object in [CardTable], this is
+//! lock-free and does not contend when many mutators write at the same time. Minor GC then finds written objects through
+//! [ObjectStartBitmap] of old space.
+//!
+//! ## Concurrent major collection
+//!
+//! When [MiniMarkOptions::concurrent_major] is enabled old space is marked and swept on background thread while mutators
+//! keep running and performing minor collections:
+//!
+//! - Initial mark: at the end of minor GC pause that finds old space full, colors of live and dead objects are flipped so that
+//! all old objects become unmarked, roots are marked and background marker is started. Nursery is empty at this point, so
+//! the roots and old space are the snapshot of the heap that marking starts from.
+//! - Concurrent mark: background marker traces old objects. Marking is snapshot-at-the-beginning: mutators must invoke
+//! [MutatorRef::pre_write_barrier](crate::mutator::MutatorRef::pre_write_barrier) right before references in an object are
+//! overwritten, and old object that is not traced yet is then traced by the mutator itself. Objects promoted by minor GC and
+//! objects allocated directly in old space are allocated black and are not traced.
+//! - Final mark: once marker is done the next minor GC pause re-scans roots, traces remaining grey objects, processes weak
+//! references and sweeps large objects.
+//! - Concurrent sweep: background thread sweeps old space. Objects allocated while sweeping get color of live objects.
+//! The next minor GC pause after sweeping is done updates major collection threshold.
+//!
+//! Background thread and minor GC never run at the same time: background thread works in small steps and minor GC waits for
+//! the current step to finish. If old space can't take promotion of the whole nursery while collection is in progress, the rest
+//! of marking and sweeping is done in minor GC pause (degenerated collection).
 use crate::api::vtable_of;
 use crate::api::Collectable;
 use crate::api::Gc;
@@ -23,19 +54,34 @@ use crate::api::Weak;
 use crate::api::GC_BLACK;
 use crate::api::GC_GREY;
 use crate::api::GC_WHITE;
+use crate::api::{
+    pop_ready_finalizer, trace_ephemerons, trace_ordered_finalizers, trace_soft_refs, Ephemeron,
+    OrderedFinalizer, SoftRef, SoftRefPolicy,
+};
+use crate::bitmap::{ObjectStartBitmap, SpaceBitmap};
+use crate::card_table::{CardTable, CARD_SIZE};
+use crate::gc_base::AllocError;
 use crate::gc_base::AllocationSpace;
 use crate::gc_base::GcBase;
 use crate::gc_base::MarkingConstraint;
 use crate::gc_base::MarkingConstraintRuns;
 use crate::gc_base::NoHelp;
 use crate::gc_base::NoReadBarrier;
+use crate::gc_base::OomHandler;
+use crate::gc_base::OomHandlerSlot;
 use crate::gc_base::TLAB;
+use crate::gc_log::{GcLogger, GcPhase, GcRecord, PhaseTimer};
 use crate::large_space::LargeObjectSpace;
 use crate::mutator::*;
+use crate::persistent::PersistentRoots;
+use crate::pin::PinnedObjects;
 use crate::rosalloc_space::TLABWithRuns;
 use crate::safepoint::*;
 use crate::small_type_id;
+use crate::stats::{GcCycle, GcKind, GcListener, GcReason, GcStats, HeapStats};
+use crate::sweeper::SWEEP_CHUNK_SIZE;
 use crate::utils::align_usize;
+use crate::verify::{HeapVerifier, VerifyPhase};
 use crate::{
     api::{HeapObjectHeader, Trace, Visitor},
     bump_pointer_space::BumpPointerSpace,
@@ -46,8 +92,12 @@ use crate::{
 use atomic::Atomic;
 //use atomic::Atomic;
 use atomic::Ordering;
+use crossbeam::queue::SegQueue;
 use im::Vector;
-use parking_lot::{lock_api::RawMutex, RawMutex as Lock};
+use parking_lot::{
+    lock_api::{RawMutex, RawMutexFair},
+    RawMutex as Lock,
+};
 use rosalloc::dedicated_full_run;
 use rosalloc::Rosalloc;
 use rosalloc::Run;
@@ -60,14 +110,13 @@ use std::marker::PhantomData;
 use std::mem;
 use std::mem::size_of;
 use std::ptr::{null_mut, NonNull};
+use std::sync::atomic::fence;
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-enum GcReason {
-    RequestedByUser,
-    AllocationFailure,
-    OldSpaceFull,
-}
+/// Number of objects background marker traces before it lets minor GC run.
+const MARKING_STEP: usize = 4096;
 
 /// Generational garbage collector. It handles the objects in 2 generations:
 ///
@@ -92,17 +141,16 @@ pub struct MiniMark {
     mutators: Vec<*mut Mutator<Self>>,
     safepoint: GlobalSafepoint,
     large_space: LargeObjectSpace,
-    old_space: *mut RosAllocSpace,
-
-    verbose: bool,
+    /// Old space and state of major collection, shared with background marker and sweeper.
+    shared: Arc<Shared>,
 
     mark_stack: Vec<*mut HeapObjectHeader>,
-    old_mark_stack: Vec<*mut HeapObjectHeader>,
-    num_old_space_allocated: Atomic<usize>,
 
     total_gcs: usize,
     remembered_set: Vec<*mut HeapObjectHeader>,
     rem_set_lock: Lock,
+    /// Card table of old space, used instead of remembered set when card marking is enabled.
+    card_table: Option<CardTable>,
 
     major_collection_threshold: f64,
     next_major_collection_threshold: Atomic<usize>,
@@ -111,23 +159,234 @@ pub struct MiniMark {
     growth_rate_max: f64,
     promoted: usize,
 
-    gc_state: Atomic<MajorPhase>,
-    alloc_color: u8,
-    mark_color: u8,
+    concurrent_major: bool,
+    /// Background threads that run concurrent marking or sweeping. Thread of a phase that was finished in pause might still
+    /// wait for `major_lock`, so threads are joined only once they are finished.
+    gc_tasks: Vec<JoinHandle<()>>,
     growth_limit: usize,
     weak_refs: Vec<Weak<dyn Collectable, Self>>,
+    ephemerons: Vec<Ephemeron<dyn Collectable, dyn Collectable, Self>>,
+    soft_refs: Vec<SoftRef<dyn Collectable, Self>>,
+    soft_ref_policy: SoftRefPolicy,
+    ordered_finalizers: Vec<OrderedFinalizer<Self>>,
+    persistent_roots: Arc<PersistentRoots<Self>>,
+    pinned_objects: Arc<PinnedObjects<Self>>,
     constraints: Vec<Box<dyn MarkingConstraint>>,
     finalize_list: Vector<*mut HeapObjectHeader>,
     finalize_lock: Lock,
     finalize_list_old: Vector<*mut HeapObjectHeader>,
+    oom_handler: OomHandlerSlot<Self>,
+    /// Verify heap before and after each GC cycle, see [verify](crate::verify).
+    verify_heap: bool,
+    /// Zero nursery after minor GC so it can be walked by heap verification.
+    clear_nursery: bool,
+    gc_stats: GcStats,
+    /// Concurrent major collection that is in progress.
+    concurrent_cycle: Option<GcCycle>,
+    cycle_start: Option<Instant>,
+    bytes_before_sweep: usize,
+}
+
+/// Old space and state of major collection that background marker and sweeper work on. Background thread does not access
+/// the heap itself because mutators use it concurrently. Fields that are not atomic are accessed by background thread only
+/// with `major_lock` held, and pauses that modify them hold it as well.
+struct Shared {
+    old_space: *mut RosAllocSpace,
+    /// Grey old objects. Shared by major marker, minor GC pauses and pre-write barrier of mutators.
+    old_mark_stack: SegQueue<usize>,
+    num_old_space_allocated: Atomic<usize>,
+    /// Start of each object in old space, maintained only when card marking is enabled.
+    object_starts: ObjectStartBitmap,
+    /// Card table of the heap is enabled and `object_starts` is maintained.
+    card_marking: bool,
+    gc_state: Atomic<MajorPhase>,
+    /// Held by background thread while it marks or sweeps and by minor GC.
+    major_lock: Lock,
+    /// Id of current concurrent major collection. Background threads exit once it changes.
+    major_gc_id: Atomic<usize>,
+    /// Start of old space chunk that is swept next.
+    sweep_cursor: Atomic<usize>,
+    alloc_color: Atomic<u8>,
+    mark_color: Atomic<u8>,
 }
 
+unsafe impl Send for Shared {}
+unsafe impl Sync for Shared {}
+
+impl Shared {
+    /// Color of old objects that are not marked yet.
+    #[inline(always)]
+    fn alloc_color(&self) -> u8 {
+        self.alloc_color.load(Ordering::Relaxed)
+    }
+
+    /// Color of marked old objects. Objects promoted or allocated in old space get this color.
+    #[inline(always)]
+    fn mark_color(&self) -> u8 {
+        self.mark_color.load(Ordering::Relaxed)
+    }
+
+    /// Swap alloc and mark colors so that all old objects become unmarked. Must be invoked in pause with `major_lock` held.
+    fn flip_colors(&self) {
+        let alloc_color = self.alloc_color();
+        self.alloc_color.store(self.mark_color(), Ordering::Relaxed);
+        self.mark_color.store(alloc_color, Ordering::Relaxed);
+    }
+
+    fn major_gc_id(&self) -> usize {
+        self.major_gc_id.load(Ordering::Relaxed)
+    }
+
+    unsafe fn trace(&self, root: &mut NonNull<HeapObjectHeader>) {
+        let object = root.as_ptr();
+
+        if (*object).is_precise() {
+            // mark bit of large object tells whether it is old, liveness of old large objects is tracked with color.
+            // Young large objects are not traced, major marker traces them once they're promoted.
+            if (*PreciseAllocation::from_cell(object)).is_marked()
+                && !(*object).set_color(self.alloc_color(), GC_GREY)
+            {
+                self.old_mark_stack.push(object as usize);
+            }
+        } else if (*self.old_space).has_address(object.cast()) {
+            if !(*object).set_color(self.alloc_color(), GC_GREY) {
+                self.old_mark_stack.push(object as usize);
+            }
+        } else {
+        }
+    }
+
+    /// Trace grey objects from old mark stack, at most `budget` of them. Returns `true` if there are no grey objects left.
+    unsafe fn drain_old_mark_stack(&self, mut budget: usize) -> bool {
+        let mark_color = self.mark_color();
+        while budget != 0 {
+            let object = match self.old_mark_stack.pop() {
+                Some(object) => object as *mut HeapObjectHeader,
+                None => return true,
+            };
+            // object is blackened only after it is traced: pre-write barrier that sees black object knows that all of its
+            // references are already seen by the marker.
+            (*object).get_dyn().trace(&mut OldVisitor { shared: self });
+            (*object).set_color(GC_GREY, mark_color);
+            budget -= 1;
+        }
+        self.old_mark_stack.is_empty()
+    }
+
+    /// Free dead old space objects. Can be invoked concurrently with mutators that allocate in old space.
+    unsafe fn free_old_objects(&self, pointers: &[*mut u8]) -> usize {
+        let live_bitmap = &*(*self.old_space).get_live_bitmap();
+        for pointer in pointers.iter() {
+            live_bitmap.modify_sync::<false>(*pointer);
+            if self.card_marking {
+                self.object_starts.clear_bit_atomic(*pointer);
+            }
+        }
+        (*self.old_space).bulk_free(pointers)
+    }
+
+    /// Returns `true` if major collection `gc_id` is in `phase`. Must be invoked with `major_lock` held.
+    fn in_phase(&self, gc_id: usize, phase: MajorPhase) -> bool {
+        self.major_gc_id() == gc_id && self.gc_state.load(Ordering::Acquire) == phase
+    }
+
+    /// Background marker. Traces grey objects in small steps so minor GC does not have to wait for whole marking to finish.
+    /// Exits early if marking was finished by degenerated collection.
+    unsafe fn concurrent_mark(&self, logger: Option<Arc<GcLogger>>, gc_id: usize) {
+        let time = Instant::now();
+        loop {
+            self.major_lock.lock();
+            if !self.in_phase(gc_id, MajorPhase::Marking) {
+                self.major_lock.unlock_fair();
+                return;
+            }
+            let done = self.drain_old_mark_stack(MARKING_STEP);
+            if done {
+                self.gc_state
+                    .store(MajorPhase::Finalizing, Ordering::Release);
+            }
+            self.major_lock.unlock_fair();
+            if done {
+                break;
+            }
+        }
+        if let Some(logger) = logger {
+            logger.log_phase("MiniMark", gc_id, "Concurrent Mark", time.elapsed(), &[]);
+        }
+    }
+
+    /// Background sweeper. Old space is swept in chunks of [SWEEP_CHUNK_SIZE] bytes so minor GC does not have to wait for whole
+    /// sweeping to finish. Exits early if sweeping was finished by degenerated collection.
+    unsafe fn concurrent_sweep(&self, logger: Option<Arc<GcLogger>>, gc_id: usize) {
+        let time = Instant::now();
+        loop {
+            self.major_lock.lock();
+            if !self.in_phase(gc_id, MajorPhase::Sweeping) {
+                self.major_lock.unlock_fair();
+                return;
+            }
+            let done = !self.sweep_old_space_chunk();
+            if done {
+                self.finish_sweeping();
+            }
+            self.major_lock.unlock_fair();
+            if done {
+                break;
+            }
+        }
+        if let Some(logger) = logger {
+            logger.log_phase("MiniMark", gc_id, "Concurrent Sweep", time.elapsed(), &[]);
+        }
+    }
+
+    /// Sweep next chunk of old space. Returns `false` if there is nothing left to sweep. Must be invoked with `major_lock` held.
+    unsafe fn sweep_old_space_chunk(&self) -> bool {
+        let end = (*self.old_space).end() as usize;
+        let chunk_begin = self.sweep_cursor.load(Ordering::Relaxed);
+        if chunk_begin >= end {
+            return false;
+        }
+        let chunk_end = end.min(chunk_begin + SWEEP_CHUNK_SIZE);
+        self.sweep_cursor.store(chunk_end, Ordering::Relaxed);
+        let mark_color = self.mark_color();
+        let live_bitmap = &*(*self.old_space).get_live_bitmap();
+        let mut freed = 0;
+        SpaceBitmap::<8>::sweep_walk_color(
+            live_bitmap,
+            chunk_begin,
+            chunk_end,
+            |count, pointers| {
+                freed += self.free_old_objects(std::slice::from_raw_parts(pointers.cast(), count));
+            },
+            None,
+            mark_color,
+            mark_color,
+        );
+        self.num_old_space_allocated
+            .fetch_sub(freed, Ordering::Relaxed);
+        true
+    }
+
+    /// Release memory of swept old space. Must be invoked with `major_lock` held.
+    unsafe fn finish_sweeping(&self) {
+        (*(*self.old_space).rosalloc()).trim();
+        self.gc_state.store(MajorPhase::Swept, Ordering::Release);
+    }
+}
+
+/// Phase of major collection.
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum MajorPhase {
+    /// No major collection is running.
     Scanning,
+    /// Background marker is running.
     Marking,
+    /// Background sweeper is running.
     Sweeping,
+    /// Background marker is done, marking is finished at next minor GC pause.
     Finalizing,
+    /// Background sweeper is done, collection is finished at next minor GC pause.
+    Swept,
 }
 
 pub struct MiniMarkOptions {
@@ -139,6 +398,11 @@ pub struct MiniMarkOptions {
     pub capacity: usize,
     pub low_memory_mode: bool,
     pub growth_rate_max: f64,
+    /// Use lock-free card-marking write barrier instead of remembered set.
+    pub card_marking: bool,
+    /// Mark and sweep old space on background thread. Mutators must invoke
+    /// [MutatorRef::pre_write_barrier](crate::mutator::MutatorRef::pre_write_barrier) before they overwrite references.
+    pub concurrent_major: bool,
 }
 
 impl Default for MiniMarkOptions {
@@ -152,6 +416,8 @@ impl Default for MiniMarkOptions {
             capacity: 512 * 1024 * 1024,
             low_memory_mode: false,
             growth_rate_max: 1.4,
+            card_marking: false,
+            concurrent_major: false,
         }
     }
 }
@@ -166,6 +432,8 @@ pub fn instantiate_minimark(options: MiniMarkOptions) -> MutatorRef<MiniMark> {
         options.capacity,
         options.low_memory_mode,
         Some(options.growth_rate_max),
+        options.card_marking,
+        options.concurrent_major,
     )));
     let href = unsafe { &mut *heap.get() };
     let join_data = JoinData::new();
@@ -192,6 +460,8 @@ impl MiniMark {
         capacity: usize,
         low_memory_mode: bool,
         growth_rate_max: Option<f64>,
+        card_marking: bool,
+        concurrent_major: bool,
     ) -> Self {
         let growth_limit = capacity.min(growth_limit);
         let rosalloc = RosAllocSpace::create(
@@ -202,6 +472,18 @@ impl MiniMark {
             low_memory_mode,
             false,
         );
+        let (card_table, object_starts) = if card_marking {
+            unsafe {
+                let begin = (*rosalloc).begin();
+                let size = (*rosalloc).non_growth_limit_capacity();
+                (
+                    Some(CardTable::new(begin, size)),
+                    ObjectStartBitmap::new(begin, size),
+                )
+            }
+        } else {
+            (None, ObjectStartBitmap::empty())
+        };
 
         let mut this = Self {
             finalize_list: Vector::new(),
@@ -209,10 +491,8 @@ impl MiniMark {
             finalize_lock: Lock::INIT,
             growth_limit,
             constraints: vec![],
-            alloc_color: GC_WHITE,
-            old_mark_stack: Vec::new(),
-            mark_color: GC_BLACK,
-            gc_state: Atomic::new(MajorPhase::Scanning),
+            concurrent_major,
+            gc_tasks: vec![],
             global_heap_lock: Lock::INIT,
             large_space_lock: Lock::INIT,
             large_space: LargeObjectSpace::new(),
@@ -222,17 +502,41 @@ impl MiniMark {
             mark_stack: vec![],
             remembered_set: vec![],
             rem_set_lock: Lock::INIT,
+            card_table,
             nursery: BumpPointerSpace::new(nursery_size.unwrap_or_else(|| 32 * 1024 * 1024)),
-            verbose,
             total_gcs: 0,
             min_heap_size,
             growth_rate_max: growth_rate_max.unwrap_or_else(|| 1.4),
             major_collection_threshold: 1.82,
             next_major_collection_initial: Atomic::new(0),
             next_major_collection_threshold: Atomic::new(0),
-            num_old_space_allocated: Atomic::new(0),
-            old_space: rosalloc,
+            shared: Arc::new(Shared {
+                old_space: rosalloc,
+                old_mark_stack: SegQueue::new(),
+                num_old_space_allocated: Atomic::new(0),
+                object_starts,
+                card_marking,
+                gc_state: Atomic::new(MajorPhase::Scanning),
+                major_lock: Lock::INIT,
+                major_gc_id: Atomic::new(0),
+                sweep_cursor: Atomic::new(0),
+                alloc_color: Atomic::new(GC_WHITE),
+                mark_color: Atomic::new(GC_BLACK),
+            }),
             weak_refs: vec![],
+            ephemerons: vec![],
+            soft_refs: vec![],
+            soft_ref_policy: SoftRefPolicy::new(),
+            ordered_finalizers: vec![],
+            persistent_roots: Arc::new(PersistentRoots::new()),
+            pinned_objects: Arc::new(PinnedObjects::new()),
+            oom_handler: OomHandlerSlot::new(),
+            verify_heap: cfg!(feature = "verify-heap"),
+            clear_nursery: cfg!(feature = "verify-heap"),
+            gc_stats: GcStats::new("MiniMark"),
+            concurrent_cycle: None,
+            cycle_start: None,
+            bytes_before_sweep: 0,
         };
         this.min_heap_size = this
             .min_heap_size
@@ -242,43 +546,54 @@ impl MiniMark {
             .store(this.min_heap_size, Ordering::Relaxed);
         this.next_major_collection_threshold
             .store(this.min_heap_size, Ordering::Release);
+        if verbose {
+            this.safepoint
+                .set_logger(Some(Arc::new(GcLogger::stderr())));
+        }
         this.set_major_threshold_from(0.0);
         this
     }
-    #[allow(dead_code)]
-    fn wait_for_gc_to_complete_locked(&mut self) -> bool {
-        /* let did_gc = if let Some(task) = self.gc_task.take() {
-            let (time, sweeped) = task.join();
-            let prev = self.num_old_space_allocated;
-            self.num_old_space_allocated -= sweeped;
-            if self.verbose {
-                eprintln!(
-                    "[gc] Concurrent GC end: {}->{} {:.4}ms",
-                    formatted_size(prev),
-                    formatted_size(self.num_old_space_allocated),
-                    time
-                );
+    /// Wait until concurrent major collection is finished. Returns `true` if there was a collection to wait for.
+    ///
+    /// Marking is finished in minor GC pause, so when background marker is done this function performs minor GC itself
+    /// instead of waiting for some other mutator to do it. All of the values that mutator holds must be rooted or passed in `keep`.
+    fn wait_for_gc_to_complete(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        keep: &mut [&mut dyn Trace],
+    ) -> bool {
+        if self.shared.gc_state.load(Ordering::Acquire) == MajorPhase::Scanning {
+            return false;
+        }
+        letroot!(_keep = mutator.shadow_stack(), &mut *keep);
+        loop {
+            match self.shared.gc_state.load(Ordering::Acquire) {
+                MajorPhase::Scanning => return true,
+                MajorPhase::Finalizing | MajorPhase::Swept => {
+                    self.minor_collection(mutator, &mut [])
+                }
+                _ => {
+                    let state = mutator.enter_unsafe();
+                    std::thread::yield_now();
+                    drop(state);
+                }
             }
-            let total_bytes = self.num_old_space_allocated + self.large_space.bytes;
-            self.set_major_threshold_from(total_bytes as f64 * self.major_collection_threshold);
-            true
-        } else {
-            false
-        };
+        }
+    }
 
-        did_gc*/
-        return false;
+    /// Returns `true` if major marking is in progress and pre-write barrier must trace old objects.
+    #[inline(always)]
+    fn is_marking(&self) -> bool {
+        matches!(
+            self.shared.gc_state.load(Ordering::Acquire),
+            MajorPhase::Marking | MajorPhase::Finalizing
+        )
     }
-    fn wait_for_gc_to_complete(&mut self) -> bool {
-        /*self.large_space_lock.lock();
-        self.gc_task_lock.lock();
-        let did_gc = self.wait_for_gc_to_complete_locked();
-        unsafe {
-            self.large_space_lock.unlock();
-            self.gc_task_lock.unlock();
-        }
-        did_gc*/
-        return false;
+
+    /// Color object that is promoted to old space. Promoted objects get color of live objects, while marking is in progress
+    /// they are not traced: they were not in the heap when marking started.
+    unsafe fn set_promoted_color(&self, object: *mut HeapObjectHeader) {
+        (*object).force_set_color(self.shared.mark_color());
     }
 
     unsafe fn trace_drag_out(
@@ -294,10 +609,10 @@ impl MiniMark {
             if (*object).is_forwarded() {
                 *root = NonNull::new_unchecked((*object).vtable() as _);
             } else {
-                let size = (*object).size();
+                let size = (*object).copy_size();
                 let mut tl_bulk_allocated = 0;
 
-                let memory = (*self.old_space).alloc_common::<Self, true>(
+                let memory = (*self.shared.old_space).alloc_common::<Self, true>(
                     mutator,
                     size,
                     &mut 0,
@@ -313,15 +628,24 @@ impl MiniMark {
                 self.promoted += size;
                 {
                     // copy young object to memory in old space and update forwarding pointer
-                    std::ptr::copy_nonoverlapping(object.cast::<u8>(), memory.cast::<u8>(), size);
+                    std::ptr::copy_nonoverlapping(
+                        object.cast::<u8>(),
+                        memory.cast::<u8>(),
+                        (*object).size(),
+                    );
+                    (*memory.cast::<HeapObjectHeader>()).finish_copy(object);
                     (*object).set_forwarded(memory as _);
                     *root = NonNull::new_unchecked(memory.cast());
-                    (*memory.cast::<HeapObjectHeader>()).force_set_color(self.mark_color);
+                    self.set_promoted_color(memory.cast());
+                }
+                (*(*self.shared.old_space).get_live_bitmap()).set(memory);
+                if self.card_table.is_some() {
+                    self.shared.object_starts.set_bit(memory);
                 }
-                (*(*self.old_space).get_live_bitmap()).set(memory);
 
                 // incremase num_old_space_allocated. If at the end of minor collection it is larger than target footprint we perform major collection
-                self.num_old_space_allocated
+                self.shared
+                    .num_old_space_allocated
                     .fetch_add(tl_bulk_allocated, Ordering::AcqRel);
                 self.mark_stack.push(memory.cast());
             }
@@ -330,30 +654,16 @@ impl MiniMark {
             // but before performing major collection we do clear them so they can be finally be sweeped
             if !(*PreciseAllocation::from_cell(object)).test_and_set_marked() {
                 self.promoted += (*PreciseAllocation::from_cell(object)).cell_size();
+                self.set_promoted_color(object);
                 self.mark_stack.push(object);
             }
         } else {
             // If we end up here then we're probably processing remembered set
             // we have to do nothing with old space object.
-            debug_assert!((*self.old_space).has_address(object.cast()));
+            debug_assert!((*self.shared.old_space).has_address(object.cast()));
         }
     }
 
-    unsafe fn trace(&mut self, root: &mut NonNull<HeapObjectHeader>) {
-        let object = root.as_ptr();
-
-        if (*object).is_precise() {
-            if !(*PreciseAllocation::from_cell(object)).test_and_set_marked() {
-                (*object).force_set_color(GC_GREY);
-                self.old_mark_stack.push(object);
-            }
-        } else if (*self.old_space).has_address(object.cast()) {
-            if !(*object).set_color(self.alloc_color, GC_GREY) {
-                self.old_mark_stack.push(object);
-            }
-        } else {
-        }
-    }
     pub fn is_young(&self, ptr: *mut HeapObjectHeader) -> bool {
         unsafe {
             self.nursery.contains(ptr.cast())
@@ -374,7 +684,9 @@ impl MiniMark {
                             mutator,
                         });
                     } else {
-                        constraint.run(&mut OldVisitor { minimark: self });
+                        constraint.run(&mut OldVisitor {
+                            shared: &self.shared,
+                        });
                     }
                 }
                 true
@@ -395,7 +707,9 @@ impl MiniMark {
                             mutator,
                         });
                     } else {
-                        constraint.run(&mut OldVisitor { minimark: self });
+                        constraint.run(&mut OldVisitor {
+                            shared: &self.shared,
+                        });
                     }
                 }
                 true
@@ -406,8 +720,8 @@ impl MiniMark {
         let mut revoke_freed = 0;
         for i in 0..self.mutators.len() {
             let mutator = self.mutators[i];
-            revoke_freed +=
-                (*(*self.old_space).rosalloc()).revoke_thread_local_runs(&mut (*mutator).tlab.runs);
+            revoke_freed += (*(*self.shared.old_space).rosalloc())
+                .revoke_thread_local_runs(&mut (*mutator).tlab.runs);
             (*mutator).reset_tlab();
             (*mutator).shadow_stack().walk(|var| {
                 var.trace(&mut YoungVisitor {
@@ -417,8 +731,20 @@ impl MiniMark {
                 });
             });
         }
-        (*(*self.old_space).rosalloc()).revoke_thread_unsafe_current_runs();
-        self.num_old_space_allocated
+        let this = self as *mut Self;
+        (*this).persistent_roots.trace(&mut YoungVisitor {
+            minimark: &mut *this,
+            parent_object: null_mut(),
+            mutator: self_thread,
+        });
+        (*this).pinned_objects.trace(&mut YoungVisitor {
+            minimark: &mut *this,
+            parent_object: null_mut(),
+            mutator: self_thread,
+        });
+        (*(*self.shared.old_space).rosalloc()).revoke_thread_unsafe_current_runs();
+        self.shared
+            .num_old_space_allocated
             .fetch_sub(revoke_freed, Ordering::AcqRel);
     }
 
@@ -426,10 +752,13 @@ impl MiniMark {
         &mut self,
         mutator: &mut MutatorRef<Self>,
         keep: &mut [&mut dyn Trace],
+        timer: &mut PhaseTimer,
     ) {
         self.before_mark_constraints(mutator, false);
         keep.iter_mut().for_each(|item| {
-            item.trace(&mut OldVisitor { minimark: self });
+            item.trace(&mut OldVisitor {
+                shared: &self.shared,
+            });
         });
         let mut revoke_freed = 0;
         // some of mutators might allocate into TLS runs while performing minor collection
@@ -439,38 +768,134 @@ impl MiniMark {
         for i in 0..self.mutators.len() {
             let mutator = self.mutators[i];
 
-            revoke_freed +=
-                (*(*self.old_space).rosalloc()).revoke_thread_local_runs(&mut (*mutator).tlab.runs);
+            revoke_freed += (*(*self.shared.old_space).rosalloc())
+                .revoke_thread_local_runs(&mut (*mutator).tlab.runs);
 
-            (*(*self.old_space).rosalloc()).revoke_thread_unsafe_current_runs();
+            (*(*self.shared.old_space).rosalloc()).revoke_thread_unsafe_current_runs();
             (*mutator).reset_tlab();
-            (*mutator)
-                .shadow_stack()
-                .walk(|var| var.trace(&mut OldVisitor { minimark: self }));
+            (*mutator).shadow_stack().walk(|var| {
+                var.trace(&mut OldVisitor {
+                    shared: &self.shared,
+                })
+            });
         }
+        self.persistent_roots.trace(&mut OldVisitor {
+            shared: &self.shared,
+        });
+        self.pinned_objects.trace(&mut OldVisitor {
+            shared: &self.shared,
+        });
 
         // process remembered set.
         //
         //
         // Note that at the moment remset is always empty at major collection because our marking phase is not concurrent/incremental
         while let Some(object) = self.remembered_set.pop() {
-            (*object)
-                .get_dyn()
-                .trace(&mut OldVisitor { minimark: self });
+            (*object).get_dyn().trace(&mut OldVisitor {
+                shared: &self.shared,
+            });
             (*object).unmark();
         }
+        timer.end(GcPhase::RootScan);
 
         // Drain mark stack and process object references
-        while let Some(object) = self.old_mark_stack.pop() {
-            (*object)
-                .get_dyn()
-                .trace(&mut OldVisitor { minimark: self });
-            (*object).force_set_color(self.mark_color);
-        }
+        self.shared.drain_old_mark_stack(usize::MAX);
         self.after_mark_constraints(mutator, false);
-        let color = self.mark_color;
+        self.shared.drain_old_mark_stack(usize::MAX);
+        self.trace_old_soft_refs_and_ephemerons();
+        timer.end(GcPhase::Mark);
+        self.process_old_weak_refs();
+        timer.end(GcPhase::WeakProcessing);
+
+        self.shared
+            .num_old_space_allocated
+            .fetch_sub(revoke_freed, Ordering::Relaxed);
+    }
 
-        // Get rid of dead weak references
+    /// Mark referents of soft references that soft reference policy keeps alive, values of ephemerons with live keys and unreachable
+    /// objects with ordered finalizers. Must be invoked after major marking, when old mark stack is drained.
+    unsafe fn trace_old_soft_refs_and_ephemerons(&mut self) {
+        self.soft_ref_policy.begin_cycle(
+            self.shared.num_old_space_allocated.load(Ordering::Relaxed) + self.large_space.bytes,
+            self.growth_limit,
+        );
+        let mark_color = self.shared.mark_color();
+        let forwardee = |object: *mut HeapObjectHeader| {
+            if (*object).get_color() == mark_color {
+                object
+            } else {
+                null_mut()
+            }
+        };
+        let this = self as *mut Self;
+        trace_soft_refs(
+            &(*this).soft_refs,
+            &(*this).soft_ref_policy,
+            forwardee,
+            |slot| (*this).shared.trace(slot),
+            || {
+                (*this).shared.drain_old_mark_stack(usize::MAX);
+            },
+        );
+        trace_ephemerons(
+            &(*this).ephemerons,
+            forwardee,
+            |slot| (*this).shared.trace(slot),
+            || {
+                (*this).shared.drain_old_mark_stack(usize::MAX);
+            },
+        );
+        if trace_ordered_finalizers(
+            &mut (*this).ordered_finalizers,
+            forwardee,
+            |slot| (*this).shared.trace(slot),
+            || {
+                (*this).shared.drain_old_mark_stack(usize::MAX);
+            },
+        ) {
+            trace_ephemerons(
+                &(*this).ephemerons,
+                forwardee,
+                |slot| (*this).shared.trace(slot),
+                || {
+                    (*this).shared.drain_old_mark_stack(usize::MAX);
+                },
+            );
+        }
+    }
+
+    /// Get rid of weak and soft references to dead old objects and clear ephemerons with dead keys. Must be invoked after major
+    /// marking.
+    unsafe fn process_old_weak_refs(&mut self) {
+        let color = self.shared.mark_color();
+        self.soft_refs.retain_mut(|soft_ref| {
+            if (*soft_ref.base()).get_color() == color {
+                soft_ref.after_mark(|header| {
+                    if (*header).get_color() == color {
+                        header
+                    } else {
+                        null_mut()
+                    }
+                });
+                true
+            } else {
+                false
+            }
+        });
+        self.ephemerons.retain_mut(|ephemeron| {
+            if (*ephemeron.base()).get_color() == color {
+                ephemeron.after_mark(|key| {
+                    if (*key).get_color() == color {
+                        key
+                    } else {
+                        null_mut()
+                    }
+                });
+                true
+            } else {
+                false
+            }
+        });
         self.weak_refs.retain_mut(|object| {
             let header = object.base();
             if (*header).get_color() == color {
@@ -486,15 +911,251 @@ impl MiniMark {
                 false
             }
         });
+    }
 
-        self.num_old_space_allocated
-            .fetch_sub(revoke_freed, Ordering::Relaxed);
+    /// Mark roots of major collection: mutator shadow stacks, `keep` and marking constraints.
+    unsafe fn mark_old_roots(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        keep: &mut [&mut dyn Trace],
+    ) {
+        self.before_mark_constraints(mutator, false);
+        keep.iter_mut().for_each(|item| {
+            item.trace(&mut OldVisitor {
+                shared: &self.shared,
+            });
+        });
+        for i in 0..self.mutators.len() {
+            let mutator = self.mutators[i];
+            (*mutator).shadow_stack().walk(|var| {
+                var.trace(&mut OldVisitor {
+                    shared: &self.shared,
+                })
+            });
+        }
+        self.persistent_roots.trace(&mut OldVisitor {
+            shared: &self.shared,
+        });
+        self.pinned_objects.trace(&mut OldVisitor {
+            shared: &self.shared,
+        });
+    }
+
+    /// Returns `true` if old space usage exceeds major collection threshold.
+    fn old_space_full(&self) -> bool {
+        self.large_space.bytes + self.shared.num_old_space_allocated.load(Ordering::Relaxed)
+            > self.next_major_collection_threshold.load(Ordering::Acquire)
+    }
+
+    /// Returns `true` if old space can't take promotion of the whole nursery anymore.
+    fn old_space_exhausted(&self) -> bool {
+        self.shared.num_old_space_allocated.load(Ordering::Relaxed) + self.nursery.size()
+            > self.growth_limit
+    }
+
+    /// Advance concurrent major collection. Starts new collection if `old_space_full` is true, finishes marking if background
+    /// marker is done and finishes collection if background sweeper is done. Collection that is in progress is finished in this
+    /// pause if old space is exhausted. Must be invoked in pause right after minor GC.
+    unsafe fn major_step(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        keep: &mut [&mut dyn Trace],
+        mut old_space_full: bool,
+    ) {
+        let mut state = self.shared.gc_state.load(Ordering::Acquire);
+        if state == MajorPhase::Swept {
+            self.finish_concurrent_cycle();
+            old_space_full = self.old_space_full();
+            state = MajorPhase::Scanning;
+        }
+        match state {
+            MajorPhase::Marking | MajorPhase::Finalizing if self.old_space_exhausted() => {
+                self.final_mark(mutator, keep, true)
+            }
+            MajorPhase::Finalizing => self.final_mark(mutator, keep, false),
+            MajorPhase::Sweeping if self.old_space_exhausted() => self.degenerated_sweep(),
+            MajorPhase::Scanning if old_space_full => {
+                if self.concurrent_major {
+                    self.initial_mark(mutator, keep);
+                } else {
+                    self.major(mutator, keep, GcReason::HeapFull);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Start concurrent major collection: unmark all old objects, mark roots and spawn background marker.
+    unsafe fn initial_mark(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        let time = Instant::now();
+        self.start_marking(mutator, keep);
+        self.gc_stats.record_pause(time.elapsed());
+        self.log_phase(
+            self.shared.major_gc_id(),
+            "Pause Initial Mark",
+            time.elapsed(),
+            &[],
+        );
+        self.spawn_gc_task(Shared::concurrent_mark);
+    }
+
+    /// Flip colors so that all old objects become unmarked and grey roots. Marking does not trace objects, this is done
+    /// by background marker.
+    unsafe fn start_marking(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        keep: &mut [&mut dyn Trace],
+    ) {
+        self.concurrent_cycle = Some(self.gc_stats.start_cycle(
+            GcKind::Concurrent,
+            GcReason::HeapFull,
+            self.allocated_bytes(),
+        ));
+        self.cycle_start = Some(Instant::now());
+        // objects that survived previous collection have mark color, after flip they're unmarked.
+        self.shared.flip_colors();
+        self.shared
+            .gc_state
+            .store(MajorPhase::Marking, Ordering::Release);
+        self.mark_old_roots(mutator, keep);
+        self.shared
+            .major_gc_id
+            .store(self.total_gcs, Ordering::Relaxed);
+        self.total_gcs += 1;
+    }
+
+    /// Run `task` on background thread. Threads of previous phases that are finished are joined first.
+    fn spawn_gc_task(&mut self, task: unsafe fn(&Shared, Option<Arc<GcLogger>>, usize)) {
+        let (finished, running): (Vec<_>, Vec<_>) = mem::take(&mut self.gc_tasks)
+            .into_iter()
+            .partition(|task| task.is_finished());
+        for task in finished {
+            let _ = task.join();
+        }
+        self.gc_tasks = running;
+        let shared = self.shared.clone();
+        let logger = self.safepoint.logger();
+        let gc_id = self.shared.major_gc_id();
+        self.gc_tasks.push(
+            std::thread::Builder::new()
+                .name("minimark-gc".to_string())
+                .spawn(move || unsafe { task(&shared, logger, gc_id) })
+                .expect("failed to spawn MiniMark GC thread"),
+        );
+    }
+
+    fn log_phase(
+        &self,
+        gc_id: usize,
+        phase: &str,
+        duration: Duration,
+        extra: &[(&'static str, usize)],
+    ) {
+        if let Some(logger) = self.safepoint.logger() {
+            logger.log_phase("MiniMark", gc_id, phase, duration, extra);
+        }
+    }
+
+    /// Finish concurrent marking: re-scan roots and trace remaining grey objects, then process weak references,
+    /// sweep large objects and spawn background sweeper. If `degenerate` is true marking is finished even if background marker
+    /// is not done yet, and old space is swept in this pause as well.
+    unsafe fn final_mark(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        keep: &mut [&mut dyn Trace],
+        degenerate: bool,
+    ) {
+        let time = Instant::now();
+        self.mark_old_roots(mutator, keep);
+        self.shared.drain_old_mark_stack(usize::MAX);
+        self.after_mark_constraints(mutator, false);
+        self.shared.drain_old_mark_stack(usize::MAX);
+        self.trace_old_soft_refs_and_ephemerons();
+        self.shared
+            .gc_state
+            .store(MajorPhase::Sweeping, Ordering::Release);
+        self.process_old_weak_refs();
+
+        self.bytes_before_sweep =
+            self.shared.num_old_space_allocated.load(Ordering::Relaxed) + self.large_space.bytes;
+        let mark_color = self.shared.mark_color();
+        self.large_space
+            .sweep_old(|object| (*object).get_color() == mark_color);
+        self.shared
+            .sweep_cursor
+            .store((*self.shared.old_space).begin() as usize, Ordering::Relaxed);
+        if degenerate {
+            while self.shared.sweep_old_space_chunk() {}
+            self.shared.finish_sweeping();
+            self.gc_stats.record_pause(time.elapsed());
+            self.log_phase(
+                self.shared.major_gc_id(),
+                "Pause Degenerated Final Mark",
+                time.elapsed(),
+                &[],
+            );
+            self.finish_concurrent_cycle();
+        } else {
+            self.gc_stats.record_pause(time.elapsed());
+            self.log_phase(
+                self.shared.major_gc_id(),
+                "Pause Final Mark",
+                time.elapsed(),
+                &[],
+            );
+            self.spawn_gc_task(Shared::concurrent_sweep);
+        }
+    }
+
+    /// Sweep rest of old space in pause when old space is exhausted before background sweeper is done.
+    unsafe fn degenerated_sweep(&mut self) {
+        let time = Instant::now();
+        while self.shared.sweep_old_space_chunk() {}
+        self.shared.finish_sweeping();
+        self.gc_stats.record_pause(time.elapsed());
+        self.log_phase(
+            self.shared.major_gc_id(),
+            "Pause Degenerated Sweep",
+            time.elapsed(),
+            &[],
+        );
+        self.finish_concurrent_cycle();
+    }
+
+    /// Finish concurrent major collection once old space is swept: update major collection threshold and record the cycle.
+    /// Must be invoked in pause.
+    fn finish_concurrent_cycle(&mut self) {
+        let total_bytes =
+            self.shared.num_old_space_allocated.load(Ordering::Acquire) + self.large_space.bytes;
+        self.set_major_threshold_from(total_bytes as f64 * self.major_collection_threshold);
+        if let Some(cycle) = self.concurrent_cycle.take() {
+            self.gc_stats.end_cycle(cycle, self.allocated_bytes());
+        }
+        if let Some(start) = self.cycle_start.take() {
+            self.log_phase(
+                self.shared.major_gc_id(),
+                "Concurrent Cycle",
+                start.elapsed(),
+                &[
+                    ("heap_before", self.bytes_before_sweep),
+                    ("heap_after", total_bytes),
+                    (
+                        "footprint",
+                        self.next_major_collection_threshold.load(Ordering::Relaxed),
+                    ),
+                ],
+            );
+        }
+        self.shared
+            .gc_state
+            .store(MajorPhase::Scanning, Ordering::Release);
     }
 
     unsafe fn minor_marking_phase(
         &mut self,
         mutator: &mut MutatorRef<Self>,
         keep: &mut [&mut dyn Trace],
+        timer: &mut PhaseTimer,
     ) {
         self.large_space.prepare_for_marking(true);
         self.large_space.begin_marking(false);
@@ -519,17 +1180,126 @@ impl MiniMark {
             });
             (*object).unmark();
         }
+        self.scan_dirty_cards(self_thread);
+        timer.end(GcPhase::RootScan);
 
+        self.drain_young_mark_stack(self_thread);
+        self.after_mark_constraints(self_thread, true);
+        // old soft references, ephemerons and finalizable objects are not traced by minor GC, so all of them are processed here.
+        let this = self as *mut Self;
+        let mutator = self_thread as *mut MutatorRef<Self>;
+        trace_soft_refs(
+            &(*this).soft_refs,
+            &(*this).soft_ref_policy,
+            |object| (*this).young_forwardee(object),
+            |slot| (*this).trace_drag_out(&mut *mutator, slot, null_mut()),
+            || (*this).drain_young_mark_stack(&mut *mutator),
+        );
+        trace_ephemerons(
+            &(*this).ephemerons,
+            |object| (*this).young_forwardee(object),
+            |slot| (*this).trace_drag_out(&mut *mutator, slot, null_mut()),
+            || (*this).drain_young_mark_stack(&mut *mutator),
+        );
+        if trace_ordered_finalizers(
+            &mut (*this).ordered_finalizers,
+            |object| (*this).young_forwardee(object),
+            |slot| (*this).trace_drag_out(&mut *mutator, slot, null_mut()),
+            || (*this).drain_young_mark_stack(&mut *mutator),
+        ) {
+            trace_ephemerons(
+                &(*this).ephemerons,
+                |object| (*this).young_forwardee(object),
+                |slot| (*this).trace_drag_out(&mut *mutator, slot, null_mut()),
+                || (*this).drain_young_mark_stack(&mut *mutator),
+            );
+        }
+        timer.end(GcPhase::Mark);
+    }
+
+    unsafe fn drain_young_mark_stack(&mut self, mutator: &mut MutatorRef<Self>) {
         while let Some(object) = self.mark_stack.pop() {
             (*object).get_dyn().trace(&mut YoungVisitor {
                 minimark: self,
                 parent_object: object,
-                mutator: self_thread,
+                mutator,
             });
-            (*object).set_color(GC_GREY, GC_BLACK);
         }
-        self.after_mark_constraints(self_thread, true);
     }
+
+    /// New location of `object` after minor marking or null if object is young and dead. Old objects are always live.
+    unsafe fn young_forwardee(&self, object: *mut HeapObjectHeader) -> *mut HeapObjectHeader {
+        if self.nursery.contains(object.cast()) {
+            if (*object).is_forwarded() {
+                (*object).vtable() as _
+            } else {
+                null_mut()
+            }
+        } else if (*object).is_precise() && !(*PreciseAllocation::from_cell(object)).is_marked() {
+            null_mut()
+        } else {
+            object
+        }
+    }
+    /// Trace old objects that are on dirty cards and clear the cards. At the end of minor GC all young objects are promoted,
+    /// so old objects can't point to young objects anymore.
+    unsafe fn scan_dirty_cards(&mut self, mutator: &mut MutatorRef<Self>) {
+        let card_table = match self.card_table {
+            Some(ref card_table) => card_table as *const CardTable,
+            None => return,
+        };
+        let this = self as *mut Self;
+        (*card_table).visit_dirty_cards(
+            (*self.shared.old_space).begin(),
+            (*self.shared.old_space).end(),
+            true,
+            |card_begin| {
+                // write barrier dirties card of object header, so only objects that start on this card are traced.
+                let mut object = (*this)
+                    .shared
+                    .object_starts
+                    .find_header(card_begin.add(CARD_SIZE - 1));
+                while !object.is_null() && object.cast::<u8>() >= card_begin {
+                    (*object).get_dyn().trace(&mut YoungVisitor {
+                        minimark: &mut *this,
+                        parent_object: object,
+                        mutator,
+                    });
+                    if object.cast::<u8>() == card_begin {
+                        break;
+                    }
+                    object = (*this)
+                        .shared
+                        .object_starts
+                        .find_header(object.cast::<u8>().sub(1));
+                }
+            },
+        );
+
+        for i in 0..self.large_space.allocations.len() {
+            let allocation = self.large_space.allocations[i];
+            if (*allocation).is_card_dirty() {
+                (*allocation).clear_card();
+                let object = (*allocation).cell();
+                (*object).get_dyn().trace(&mut YoungVisitor {
+                    minimark: self,
+                    parent_object: object,
+                    mutator,
+                });
+            }
+        }
+    }
+
+    fn allocated_bytes(&self) -> usize {
+        self.nursery.allocated()
+            + self.shared.num_old_space_allocated.load(Ordering::Relaxed)
+            + self.large_space.bytes
+    }
+
+    fn footprint(&self) -> usize {
+        self.nursery.size() + self.next_major_collection_threshold.load(Ordering::Relaxed)
+    }
+
     unsafe fn minor(
         &mut self,
         mutator: &mut MutatorRef<Self>,
@@ -537,15 +1307,15 @@ impl MiniMark {
         reason: GcReason,
     ) -> bool {
         // threads must be suspended already
-        let time = if self.verbose {
-            Some(std::time::Instant::now())
-        } else {
-            None
-        };
+        self.verify(VerifyPhase::BeforeGc, keep);
+        let time = Instant::now();
+        let mut timer = PhaseTimer::new(self.safepoint.last_sync());
+        let prev = self.allocated_bytes();
+        let cycle = self.gc_stats.start_cycle(GcKind::Minor, reason, prev);
 
         self.large_space.prepare_for_marking(true);
         self.large_space.begin_marking(false);
-        self.minor_marking_phase(mutator, keep);
+        self.minor_marking_phase(mutator, keep, &mut timer);
         let nursery_start = self.nursery.start();
         let nursery_end = self.nursery.end();
 
@@ -554,12 +1324,15 @@ impl MiniMark {
             let object = *x;
             if (*object).is_forwarded() {
                 finalize_list_old.push_back((*object).vtable() as _);
-            } else if (*PreciseAllocation::from_cell(object)).is_marked() {
+            } else if (*object).is_precise() && (*PreciseAllocation::from_cell(object)).is_marked()
+            {
                 finalize_list_old.push_back(object);
+            } else {
+                (*object).get_dyn().finalize();
             }
-            (*object).get_dyn().finalize();
             false
         });
+        timer.end(GcPhase::Finalization);
 
         let is_young = |ptr: *mut HeapObjectHeader| {
             (ptr.cast::<u8>() >= nursery_start && ptr.cast::<u8>() < nursery_end)
@@ -588,32 +1361,61 @@ impl MiniMark {
                 true
             }
         });
+        let this = self as *mut Self;
+        self.soft_refs.retain_mut(|soft_ref| {
+            let header = (*this).young_forwardee(soft_ref.base());
+            if header.is_null() {
+                false
+            } else {
+                soft_ref.set_base(header);
+                soft_ref.after_mark(|object| (*this).young_forwardee(object));
+                true
+            }
+        });
+        self.ephemerons.retain_mut(|ephemeron| {
+            let header = (*this).young_forwardee(ephemeron.base());
+            if header.is_null() {
+                false
+            } else {
+                ephemeron.set_base(header);
+                ephemeron.after_mark(|key| (*this).young_forwardee(key));
+                true
+            }
+        });
+        timer.end(GcPhase::WeakProcessing);
         self.large_space.prepare_for_allocation(true);
         self.large_space.sweep();
 
-        self.nursery.reset();
-
-        if let Some(time) = time {
-            let elapsed = time.elapsed();
-            eprintln!(
-                "[gc] GC({}) Pause Young ({:?}) Promoted {}(old space: {} {}->{}) {:.4}ms",
-                self.total_gcs,
+        if self.clear_nursery {
+            self.nursery.clear();
+        } else {
+            self.nursery.reset();
+        }
+        timer.end(GcPhase::Sweep);
+        let bytes_allocated = self.allocated_bytes();
+        self.gc_stats.add_promoted(self.promoted);
+        self.gc_stats.end_cycle(cycle, bytes_allocated);
+
+        if let Some(logger) = self.safepoint.logger() {
+            logger.log_gc(&GcRecord {
+                policy: "MiniMark",
+                gc_id: self.total_gcs,
+                kind: GcKind::Minor,
                 reason,
-                formatted_size(self.promoted),
-                formatted_size(
-                    self.num_old_space_allocated.load(Ordering::Relaxed) + self.large_space.bytes
-                ),
-                print_color(self.alloc_color),
-                print_color(self.mark_color),
-                elapsed.as_micros() as f64 / 1000.0
-            )
+                heap_before: prev,
+                heap_after: bytes_allocated,
+                footprint: self.footprint(),
+                duration: time.elapsed(),
+                phases: timer.phases(),
+                extra: &[("promoted", self.promoted)],
+            });
         }
+        self.verify(VerifyPhase::AfterGc, keep);
         self.total_gcs += 1;
 
         self.promoted = 0;
 
-        self.large_space.bytes + self.num_old_space_allocated.load(Ordering::Relaxed)
-            > self.next_major_collection_threshold.load(Ordering::Acquire)
+        self.old_space_full()
     }
 
     unsafe fn major(
@@ -622,54 +1424,105 @@ impl MiniMark {
         keep: &mut [&mut dyn Trace],
         reason: GcReason,
     ) {
-        let time = if self.verbose {
-            Some(std::time::Instant::now())
+        let time = Instant::now();
+        // major collection always follows minor collection in the same pause.
+        let mut timer = PhaseTimer::new(Duration::ZERO);
+        let prev = self.allocated_bytes();
+        let kind = if reason == GcReason::RequestedByUser {
+            GcKind::Full
         } else {
-            None
+            GcKind::Major
         };
-        let prev = self.num_old_space_allocated.load(Ordering::Relaxed) + self.large_space.bytes;
-        self.major_marking_phase(mutator, keep);
+        let cycle = self.gc_stats.start_cycle(kind, reason, prev);
+        // objects that survived previous collection have mark color, after flip they're unmarked.
+        self.shared.flip_colors();
+        self.major_marking_phase(mutator, keep, &mut timer);
 
-        let rosalloc = self.old_space as usize;
-        let sweep_color = self.alloc_color;
-        let keep_color = self.mark_color;
+        let rosalloc = self.shared.old_space;
+        let keep_color = self.shared.mark_color();
         let this = self as *mut Self as usize;
 
-        self.large_space.prepare_for_allocation(false);
-        self.large_space.sweep();
-        self.gc_state.store(MajorPhase::Sweeping, Ordering::Relaxed);
+        self.large_space
+            .sweep_old(|object| (*object).get_color() == keep_color);
+        self.shared
+            .gc_state
+            .store(MajorPhase::Sweeping, Ordering::Relaxed);
 
-        let rosalloc = rosalloc as *mut RosAllocSpace;
         let this = &mut *(this as *mut Self);
 
+        // objects that do not have mark color are dead.
         let (freed, _) = (*rosalloc).sweep_colored(
-            |pointers, _| (*rosalloc).sweep_callback(pointers, false),
-            sweep_color,
+            |pointers, _| this.shared.free_old_objects(pointers),
+            keep_color,
             keep_color,
         );
         (*(*rosalloc).rosalloc()).trim();
-        this.num_old_space_allocated
+        this.shared
+            .num_old_space_allocated
             .fetch_sub(freed, Ordering::Relaxed);
         let total_bytes =
-            this.num_old_space_allocated.load(Ordering::Acquire) + this.large_space.bytes;
+            this.shared.num_old_space_allocated.load(Ordering::Acquire) + this.large_space.bytes;
         this.set_major_threshold_from(total_bytes as f64 * this.major_collection_threshold);
-
-        this.gc_state.store(MajorPhase::Scanning, Ordering::Relaxed);
-
-        if let Some(time) = time {
-            eprintln!(
-                "[gc] GC({}) Pause Old ({:?}) {}->{}({}) {:.4}ms",
-                self.total_gcs,
+        timer.end(GcPhase::Sweep);
+
+        this.shared
+            .gc_state
+            .store(MajorPhase::Scanning, Ordering::Relaxed);
+        let bytes_allocated = this.allocated_bytes();
+        this.gc_stats.end_cycle(cycle, bytes_allocated);
+
+        if let Some(logger) = this.safepoint.logger() {
+            logger.log_gc(&GcRecord {
+                policy: "MiniMark",
+                gc_id: this.total_gcs,
+                kind,
                 reason,
-                formatted_size(prev),
-                formatted_size(
-                    self.num_old_space_allocated.load(Ordering::Relaxed) + self.large_space.bytes
-                ),
-                formatted_size(self.next_major_collection_threshold.load(Ordering::Relaxed)),
-                time.elapsed().as_micros() as f64 / 1000.0
-            )
+                heap_before: prev,
+                heap_after: bytes_allocated,
+                footprint: this.footprint(),
+                duration: time.elapsed(),
+                phases: timer.phases(),
+                extra: &[],
+            });
         }
-        self.total_gcs += 1;
+        this.verify(VerifyPhase::AfterGc, keep);
+        this.total_gcs += 1;
+    }
+
+    /// Verify heap, see [verify](crate::verify). Must be invoked in GC pause with heap locks held. Heap is not verified while
+    /// concurrent major collection is in progress.
+    unsafe fn verify(&mut self, phase: VerifyPhase, keep: &mut [&mut dyn Trace]) {
+        if !self.verify_heap || self.shared.gc_state.load(Ordering::Acquire) != MajorPhase::Scanning
+        {
+            return;
+        }
+        let mut verifier = HeapVerifier::new("MiniMark", phase, self.total_gcs);
+        let nursery_end = self.nursery.start().add(self.nursery.allocated());
+        self.nursery.walk(|object| {
+            verifier.add_object(object, nursery_end);
+        });
+        let old_space = self.shared.old_space;
+        verifier.add_marked_objects(
+            &*(*old_space).get_live_bitmap(),
+            (*old_space).begin(),
+            (*old_space).end(),
+        );
+        for &allocation in self.large_space.allocations.iter() {
+            verifier.add_large_object(allocation);
+        }
+        verifier.trace_roots("shadow stack", |vis| {
+            for &mutator in self.mutators.iter() {
+                (*mutator).shadow_stack().walk(|entry| entry.trace(vis));
+            }
+        });
+        verifier.trace_roots("persistent root", |vis| self.persistent_roots.trace(vis));
+        verifier.trace_roots("pinned object", |vis| self.pinned_objects.trace(vis));
+        verifier.trace_roots("kept value", |vis| {
+            for object in keep.iter_mut() {
+                object.trace(vis);
+            }
+        });
+        verifier.finish();
     }
 
     fn set_major_threshold_from(&mut self, mut threshold: f64) {
@@ -686,16 +1539,19 @@ impl MiniMark {
             .store(threshold as _, Ordering::Relaxed);
         self.next_major_collection_threshold
             .store(threshold as _, Ordering::Release);
-        if self.verbose {
-            eprintln!(
-                "[gc] Major threshold set to {}",
-                formatted_size(threshold as _)
-            );
-        }
     }
     #[inline(always)]
     unsafe fn write_barrier_internal(&mut self, object: *mut HeapObjectHeader) {
-        if (*self.old_space).has_address(object.cast())
+        if let Some(ref card_table) = self.card_table {
+            if (*self.shared.old_space).has_address(object.cast()) {
+                card_table.mark_card(object.cast());
+            } else if (*object).is_precise() && (*PreciseAllocation::from_cell(object)).is_marked()
+            {
+                (*PreciseAllocation::from_cell(object)).mark_card();
+            }
+            return;
+        }
+        if (*self.shared.old_space).has_address(object.cast())
             || ((*object).is_precise() && (*PreciseAllocation::from_cell(object)).is_marked())
         // mark bit in LOS object header means it is in large old object space
         {
@@ -705,6 +1561,22 @@ impl MiniMark {
         }
     }
 
+    /// Snapshot-at-the-beginning barrier of concurrent marking. Old object that the marker has not traced yet is traced
+    /// before references in it are overwritten, so that objects that were reachable when marking started stay marked.
+    #[inline(always)]
+    unsafe fn pre_write_barrier_internal(&self, object: *mut HeapObjectHeader) {
+        if self.is_marking() && !self.is_young(object) {
+            let color = (*object).get_color();
+            // pairs with marker that blackens object after tracing it: values that are overwritten are already seen by it.
+            fence(Ordering::Acquire);
+            if color != self.shared.mark_color() {
+                (*object).get_dyn().trace(&mut OldVisitor {
+                    shared: &self.shared,
+                });
+            }
+        }
+    }
+
     #[cold]
     unsafe fn write_barrier_slow(&mut self, object: *mut HeapObjectHeader) {
         (*object).set_marked_bit(); // marked_bit is used for seeing if object is in remembered set
@@ -719,7 +1591,7 @@ impl MiniMark {
         mutator: &mut MutatorRef<Self>,
         value: T,
         _space: AllocationSpace,
-    ) -> Gc<T, Self> {
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
         let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
         let val = if Rosalloc::is_size_for_thread_local(size) {
             let (idx, _bracket_size) = Rosalloc::size_to_index_and_bracket_size(size);
@@ -741,16 +1613,21 @@ impl MiniMark {
                 (*header).set_metadata(vtable_of::<T>());
                 (*header).set_size(size);
                 ((*header).data() as *mut T).write(value);
+                (*header).force_set_color(self.shared.mark_color());
+                (*(*self.shared.old_space).get_live_bitmap()).set_sync(header.cast());
+                if self.card_table.is_some() {
+                    self.shared.object_starts.set_bit_atomic(header.cast());
+                }
                 Gc {
                     base: NonNull::new_unchecked(header),
                     marker: PhantomData,
                 }
             }
         } else {
-            self.alloc_once::<T, false, true>(mutator, value)
+            self.alloc_once::<T, false, true>(mutator, value)?
         };
         self.post_alloc(val);
-        val
+        Ok(val)
     }
 
     #[inline]
@@ -758,8 +1635,8 @@ impl MiniMark {
         &mut self,
         mutator: &mut MutatorRef<Self>,
         mut value: T,
-        _space: AllocationSpace,
-    ) -> Gc<T, Self> {
+        space: AllocationSpace,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
         let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
         let mut memory = self.nursery.bump_alloc(size);
         if memory.is_null() {
@@ -768,22 +1645,27 @@ impl MiniMark {
             //self.collect(mutator, &mut [&mut value]);
             memory = self.nursery.bump_alloc(size);
             if memory.is_null() {
-                oom_abort();
+                return Err(AllocError::new(value, size, space));
             }
         }
 
         unsafe {
             let hdr = memory.cast::<HeapObjectHeader>();
+            hdr.write(HeapObjectHeader {
+                type_id: small_type_id::<T>(),
+                padding: 0,
+                padding2: 0,
+                value: VTable { raw: 0 },
+            });
             (*hdr).set_metadata(vtable_of::<T>());
             (*hdr).set_size(size);
-            (*hdr).type_id = small_type_id::<T>();
             ((*hdr).data() as *mut T).write(value);
             let val = Gc {
                 base: NonNull::new_unchecked(hdr),
                 marker: Default::default(),
             };
             self.post_alloc(val);
-            val
+            Ok(val)
         }
     }
     #[cold]
@@ -791,7 +1673,7 @@ impl MiniMark {
         &mut self,
         mutator: &mut MutatorRef<Self>,
         mut value: T,
-    ) -> Gc<T, Self> {
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
         self.full_collection(mutator, &mut [&mut value]);
         self.alloc_once::<T, true, false>(mutator, value)
     }
@@ -802,7 +1684,7 @@ impl MiniMark {
         &mut self,
         mut mutator: &mut MutatorRef<Self>,
         value: T,
-    ) -> Gc<T, Self> {
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
         fn max_bytes_bulk_allocated_for(size: usize) -> usize {
             if !Rosalloc::is_size_for_thread_local(size) {
                 return size;
@@ -813,6 +1695,10 @@ impl MiniMark {
         let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
         let max_bytes_tl_bulk_allocated = max_bytes_bulk_allocated_for(size);
         if self.is_out_of_memory_on_allocation(max_bytes_tl_bulk_allocated, GROW) {
+            if !GC {
+                // GC already happened and we're still over the growth limit
+                return Err(AllocError::new(value, size, AllocationSpace::Old));
+            }
             // potentially run GC if we reached GC threshold
 
             return self.alloc_slow(mutator, value);
@@ -821,7 +1707,7 @@ impl MiniMark {
         let mut usable_size = 0;
         let mut bytes_tl_bulk_allocated = 0;
         unsafe {
-            let mem = (*self.old_space).alloc_common::<Self, true>(
+            let mem = (*self.shared.old_space).alloc_common::<Self, true>(
                 &mut mutator,
                 size,
                 &mut bytes_allocated,
@@ -833,11 +1719,12 @@ impl MiniMark {
                 return self.alloc_slow(mutator, value);
             } else if mem.is_null() && !GC {
                 // if GC hapenned and memory is still unavailbe just OOM
-                oom_abort();
+                return Err(AllocError::new(value, size, AllocationSpace::Old));
             }
             if bytes_tl_bulk_allocated > 0 {
                 // update num_bytes_allocated so we can start GC when necessary
-                self.num_old_space_allocated
+                self.shared
+                    .num_old_space_allocated
                     .fetch_add(bytes_tl_bulk_allocated, Ordering::Relaxed);
             }
 
@@ -845,11 +1732,16 @@ impl MiniMark {
             (*header).set_metadata(vtable_of::<T>());
             (*header).set_size(size);
             ((*header).data() as *mut T).write(value);
+            (*header).force_set_color(self.shared.mark_color());
+            (*(*self.shared.old_space).get_live_bitmap()).set_sync(mem);
+            if self.card_table.is_some() {
+                self.shared.object_starts.set_bit_atomic(mem);
+            }
 
-            Gc {
+            Ok(Gc {
                 base: NonNull::new_unchecked(header),
                 marker: PhantomData,
-            }
+            })
         }
     }
 
@@ -857,7 +1749,7 @@ impl MiniMark {
     fn is_out_of_memory_on_allocation(&self, alloc_size: usize, grow: bool) -> bool {
         let mut old_target = self.next_major_collection_threshold.load(Ordering::Relaxed);
         loop {
-            let old_allocated = self.num_old_space_allocated.load(Ordering::Relaxed);
+            let old_allocated = self.shared.num_old_space_allocated.load(Ordering::Relaxed);
             let new_footprint = old_allocated + alloc_size;
             if new_footprint <= old_target {
                 return false;
@@ -894,6 +1786,22 @@ fn promotion_oom(size: usize, object: *const u8) -> ! {
     std::process::abort()
 }
 
+impl Drop for MiniMark {
+    fn drop(&mut self) {
+        // background threads exit once they see that their phase is over.
+        self.shared.major_lock.lock();
+        self.shared
+            .gc_state
+            .store(MajorPhase::Scanning, Ordering::Release);
+        unsafe {
+            self.shared.major_lock.unlock();
+        }
+        for task in self.gc_tasks.drain(..) {
+            let _ = task.join();
+        }
+    }
+}
+
 pub struct YoungVisitor<'a> {
     minimark: &'a mut MiniMark,
     parent_object: *mut HeapObjectHeader,
@@ -910,13 +1818,13 @@ impl<'a> Visitor for YoungVisitor<'a> {
 }
 
 pub struct OldVisitor<'a> {
-    minimark: &'a mut MiniMark,
+    shared: &'a Shared,
 }
 
 impl<'a> Visitor for OldVisitor<'a> {
     fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
         unsafe {
-            self.minimark.trace(root);
+            self.shared.trace(root);
         }
     }
 }
@@ -950,9 +1858,98 @@ impl GcBase for MiniMark {
         }
         weak_ref
     }
+    fn allocate_soft<T: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        value: Gc<T, Self>,
+    ) -> SoftRef<T, Self> {
+        let soft_ref = unsafe { SoftRef::create(mutator, value) };
+        self.global_heap_lock.lock();
+
+        self.soft_refs.push(soft_ref.to_dyn());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        soft_ref
+    }
+    fn set_soft_ref_lru_policy(&mut self, ms_per_mb: u64) {
+        self.global_heap_lock.lock();
+        self.soft_ref_policy.set_ms_per_mb(ms_per_mb);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn allocate_ephemeron<K: Collectable + ?Sized, V: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        key: Gc<K, Self>,
+        value: Gc<V, Self>,
+    ) -> Ephemeron<K, V, Self> {
+        let ephemeron = unsafe { Ephemeron::create(mutator, key, value) };
+        self.global_heap_lock.lock();
+        self.ephemerons.push(ephemeron.to_dyn());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        ephemeron
+    }
+    fn persistent_roots(&self) -> Arc<PersistentRoots<Self>> {
+        self.persistent_roots.clone()
+    }
+    fn pinned_objects(&self) -> Arc<PinnedObjects<Self>> {
+        self.pinned_objects.clone()
+    }
+    fn stats(&self) -> HeapStats {
+        self.gc_stats
+            .snapshot(self.allocated_bytes(), self.footprint())
+    }
+    fn add_gc_listener(&mut self, listener: Box<dyn GcListener>) {
+        self.gc_stats.add_listener(listener);
+    }
+    fn prepare_pin(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        object: Gc<dyn Collectable, Self>,
+    ) -> Gc<dyn Collectable, Self> {
+        if !self.nursery.contains(object.base.as_ptr().cast()) {
+            return object;
+        }
+        // nursery is evacuated by every minor collection, object is promoted to old space where objects never move.
+        letroot!(object = mutator.shadow_stack(), object);
+        self.minor_collection(mutator, &mut []);
+        *object
+    }
+    fn register_ordered_finalizer(&mut self, finalizer: OrderedFinalizer<Self>) {
+        self.global_heap_lock.lock();
+        self.ordered_finalizers.push(finalizer);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn pop_ready_finalizer(&mut self) -> Option<OrderedFinalizer<Self>> {
+        self.global_heap_lock.lock();
+        let finalizer = pop_ready_finalizer(&mut self.ordered_finalizers);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        finalizer
+    }
+
+    /// Pre-write barrier of concurrent major marking, does nothing unless marking is in progress.
+    #[inline]
+    fn pre_write_barrier(
+        &mut self,
+        _: &mut MutatorRef<Self>,
+        object: Gc<dyn crate::api::Collectable, Self>,
+    ) {
+        unsafe {
+            self.pre_write_barrier_internal(object.base.as_ptr());
+        }
+    }
 
     /// Generational write barrier implementation. This is "always on" write barrier, this means that if `object` is from old space and not in
     /// remembered set it will be put to remembered set in any case. This write barrier must be used right after write to an object happened.
+    /// When card marking is enabled card of `object` is dirtied instead.
     #[inline]
     fn write_barrier(
         &mut self,
@@ -974,13 +1971,14 @@ impl GcBase for MiniMark {
                 self.global_heap_lock.lock();
                 self.rem_set_lock.lock();
                 self.large_space_lock.lock();
-                if self.minor(mutator, keep, GcReason::AllocationFailure) {
-                    self.major(mutator, keep, GcReason::OldSpaceFull);
-                }
+                self.shared.major_lock.lock();
+                let old_space_full = self.minor(mutator, keep, GcReason::AllocationFailure);
+                self.major_step(mutator, keep, old_space_full);
                 drop(x);
                 self.global_heap_lock.unlock();
                 self.rem_set_lock.unlock();
                 self.large_space_lock.unlock();
+                self.shared.major_lock.unlock();
             },
             None => return,
         }
@@ -992,13 +1990,14 @@ impl GcBase for MiniMark {
                     self.global_heap_lock.lock();
                     self.rem_set_lock.lock();
                     self.large_space_lock.lock();
-                    if self.minor(mutator, keep, GcReason::RequestedByUser) {
-                        self.major(mutator, keep, GcReason::OldSpaceFull);
-                    }
+                    self.shared.major_lock.lock();
+                    let old_space_full = self.minor(mutator, keep, GcReason::RequestedByUser);
+                    self.major_step(mutator, keep, old_space_full);
                     drop(safepoint);
                     self.global_heap_lock.unlock();
                     self.rem_set_lock.unlock();
                     self.large_space_lock.unlock();
+                    self.shared.major_lock.unlock();
                 }
                 None => return,
             }
@@ -1006,53 +2005,89 @@ impl GcBase for MiniMark {
     }
     fn full_collection(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
         unsafe {
-            let s = mutator.enter_unsafe();
-            self.wait_for_gc_to_complete();
-            drop(s);
-            match SafepointScope::new(mutator.clone()) {
-                Some(safepoint) => {
-                    self.global_heap_lock.lock();
-                    self.rem_set_lock.lock();
-                    self.large_space_lock.lock();
-                    self.minor(mutator, keep, GcReason::RequestedByUser);
-                    self.major(mutator, keep, GcReason::RequestedByUser);
-                    drop(safepoint);
-                    self.global_heap_lock.unlock();
-                    self.rem_set_lock.unlock();
-                    self.large_space_lock.unlock();
+            loop {
+                self.wait_for_gc_to_complete(mutator, keep);
+                match SafepointScope::new(mutator.clone()) {
+                    Some(safepoint) => {
+                        if self.shared.gc_state.load(Ordering::Acquire) != MajorPhase::Scanning {
+                            // other mutator started concurrent collection, finish it first.
+                            drop(safepoint);
+                            continue;
+                        }
+                        self.global_heap_lock.lock();
+                        self.rem_set_lock.lock();
+                        self.large_space_lock.lock();
+                        self.shared.major_lock.lock();
+                        self.minor(mutator, keep, GcReason::RequestedByUser);
+                        self.major(mutator, keep, GcReason::RequestedByUser);
+                        drop(safepoint);
+                        self.global_heap_lock.unlock();
+                        self.rem_set_lock.unlock();
+                        self.large_space_lock.unlock();
+                        self.shared.major_lock.unlock();
+                        return;
+                    }
+                    None => continue,
                 }
-                None => return,
             }
         }
     }
 
     fn minor_collection(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
         unsafe {
-            let s = mutator.enter_unsafe();
-            self.wait_for_gc_to_complete();
-            drop(s);
             match SafepointScope::new(mutator.clone()) {
                 Some(safepoint) => {
                     self.global_heap_lock.lock();
                     self.rem_set_lock.lock();
                     self.large_space_lock.lock();
+                    self.shared.major_lock.lock();
                     self.minor(mutator, keep, GcReason::RequestedByUser);
+                    self.major_step(mutator, keep, false);
                     drop(safepoint);
                     self.global_heap_lock.unlock();
                     self.rem_set_lock.unlock();
                     self.large_space_lock.unlock();
+                    self.shared.major_lock.unlock();
                 }
                 None => return,
             }
         }
     }
 
-    fn alloc_inline<T: crate::api::Collectable + Sized + 'static>(
+    fn set_heap_verification(&mut self, mutator: &mut MutatorRef<Self>, enabled: bool) {
+        self.global_heap_lock.lock();
+        let clear = enabled && !self.clear_nursery;
+        self.clear_nursery |= enabled;
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        if clear {
+            // nursery still has stale objects of previous cycles in unused ends of TLABs, it is cleared by minor collection.
+            self.minor_collection(mutator, &mut []);
+        }
+        self.global_heap_lock.lock();
+        self.verify_heap = enabled;
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn set_oom_handler(&mut self, handler: OomHandler<Self>) {
+        self.oom_handler.set(handler);
+    }
+    fn handle_oom(&mut self, mutator: &mut MutatorRef<Self>, size: usize) -> bool {
+        // emergency collection: clear all soft references before giving up.
+        if !self.soft_refs.is_empty() && self.soft_ref_policy.request_clear_all() {
+            self.full_collection(mutator, &mut []);
+            return true;
+        }
+        self.oom_handler.invoke(mutator, size)
+    }
+    fn try_alloc_inline<T: crate::api::Collectable + Sized + 'static>(
         &mut self,
         mutator: &mut MutatorRef<Self>,
         value: T,
         space: AllocationSpace,
-    ) -> crate::api::Gc<T, Self> {
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
         match space {
             AllocationSpace::New => self.alloc_inline_new(mutator, value, space),
             AllocationSpace::Old => self.alloc_inline_old(mutator, value, space),
@@ -1065,7 +2100,8 @@ impl GcBase for MiniMark {
             unsafe {
                 self.finalize_lock.lock();
                 if self.nursery.contains(value.base.as_ptr().cast())
-                    || !(*PreciseAllocation::from_cell(value.base.as_ptr())).is_marked()
+                    || (*value.base.as_ptr()).is_precise()
+                        && !(*PreciseAllocation::from_cell(value.base.as_ptr())).is_marked()
                 {
                     self.finalize_list.push_front(value.base.as_ptr());
                 } else {
@@ -1090,7 +2126,7 @@ impl GcBase for MiniMark {
         unsafe { self.global_heap_lock.unlock() };
     }
 
-    fn detach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
+    unsafe fn detach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
         self.global_heap_lock.lock();
 
         let mut detached = false;
@@ -1125,15 +2161,25 @@ impl GcBase for MiniMark {
         assert!(self.global_heap_lock.is_locked());
         &self.mutators
     }
-    fn allocate_large<T: crate::api::Collectable + Sized + 'static>(
+    fn try_allocate_large<T: crate::api::Collectable + Sized + 'static>(
         &mut self,
-        _mutator: &mut MutatorRef<Self>,
-        value: T,
-    ) -> crate::api::Gc<T, Self> {
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
         unsafe {
             let size = value.allocation_size() + size_of::<HeapObjectHeader>();
             self.large_space_lock.lock();
-            let object = self.large_space.allocate(size);
+            let mut object = self.large_space.try_allocate(size);
+            if object.is_null() {
+                self.large_space_lock.unlock();
+                self.collect_alloc_failure(mutator, &mut [&mut value]);
+                self.large_space_lock.lock();
+                object = self.large_space.try_allocate(size);
+                if object.is_null() {
+                    self.large_space_lock.unlock();
+                    return Err(AllocError::new(value, size, AllocationSpace::Large));
+                }
+            }
             (*object).set_metadata(vtable_of::<T>());
             (*object).type_id = small_type_id::<T>();
             let gc = Gc {
@@ -1143,7 +2189,7 @@ impl GcBase for MiniMark {
             ((*object).data() as *mut T).write(value);
             self.large_space_lock.unlock();
             self.post_alloc(gc);
-            gc
+            Ok(gc)
         }
     }
 }
@@ -1237,19 +2283,29 @@ impl TLABWithRuns for MiniMarkTLAB {
     }
 }
 
-fn print_color(c: u8) -> &'static str {
-    match c {
-        GC_WHITE => "white",
-        GC_GREY => "grey",
-        GC_BLACK => "black",
-        _ => unreachable!(),
-    }
-}
-
 #[cfg(test)]
 mod tests {
 
-    use super::{instantiate_minimark, MiniMarkOptions};
+    use super::{instantiate_minimark, MajorPhase, MiniMark, MiniMarkOptions};
+    use crate::api::{
+        Collectable, FinalizationRegistry, Finalize, Gc, OrderedFinalize, Reference,
+        ReferenceQueue, Trace, Visitor,
+    };
+    use crate::handle_scope::{EscapableHandleScope, Handle, HandleScope};
+    use crate::mutator::MutatorRef;
+    use crate::persistent::Persistent;
+    use crate::safepoint::SafepointScope;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn options() -> MiniMarkOptions {
+        let mut options = MiniMarkOptions::default();
+        options.nursery_size = 1 * 1024 * 1024;
+        options
+    }
+
+    fn heap() -> MutatorRef<MiniMark> {
+        instantiate_minimark(options())
+    }
 
     #[test]
     fn test_weak_refs() {
@@ -1281,4 +2337,463 @@ mod tests {
         assert!(weak1.upgrade().is_none());
         assert_eq!(*weak2.upgrade().unwrap(), 44);
     }
+
+    #[test]
+    fn test_soft_refs() {
+        let mut minimark = heap();
+
+        let value = minimark.allocate(42, crate::gc_base::AllocationSpace::New);
+        letroot!(
+            soft = minimark.shadow_stack(),
+            minimark.allocate_soft(value)
+        );
+
+        // heap is almost empty, recently used referent is kept alive.
+        minimark.full_collection(&mut []);
+        assert_eq!(*soft.upgrade().unwrap(), 42);
+
+        // referent is not used since last GC cycle and policy does not allow it to age.
+        minimark.set_soft_ref_lru_policy(0);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        minimark.full_collection(&mut []);
+        assert!(soft.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_reference_queue() {
+        let mut minimark = heap();
+
+        letroot!(
+            queue = minimark.shadow_stack(),
+            ReferenceQueue::new(&mut minimark)
+        );
+        let value = minimark.allocate(42, crate::gc_base::AllocationSpace::New);
+        letroot!(
+            weak = minimark.shadow_stack(),
+            minimark.allocate_weak_with_queue(value, *queue)
+        );
+        assert!(queue.is_empty());
+
+        minimark.collect(&mut []);
+
+        assert!(weak.upgrade().is_none());
+        match queue.poll() {
+            Some(Reference::Weak(cleared)) => assert!(cleared.ptr_eq(*weak)),
+            _ => panic!("cleared weak reference is not enqueued"),
+        }
+        assert!(queue.poll().is_none());
+    }
+
+    #[test]
+    fn test_finalization_registry() {
+        let mut minimark = heap();
+
+        letroot!(
+            registry = minimark.shadow_stack(),
+            FinalizationRegistry::new(&mut minimark)
+        );
+        letroot!(
+            live = minimark.shadow_stack(),
+            minimark.allocate(1, crate::gc_base::AllocationSpace::New)
+        );
+        let held = minimark.allocate(2, crate::gc_base::AllocationSpace::New);
+        registry.register(&mut minimark, *live, held);
+        let dead = minimark.allocate(3, crate::gc_base::AllocationSpace::New);
+        let held = minimark.allocate(4, crate::gc_base::AllocationSpace::New);
+        registry.register(&mut minimark, dead, held);
+        assert!(registry.poll().is_none());
+
+        minimark.collect(&mut []);
+
+        let held = registry
+            .poll()
+            .expect("held value of dead target is not queued");
+        assert_eq!(*unsafe { held.downcast_unchecked::<i32>() }, 4);
+        assert!(registry.poll().is_none());
+        assert_eq!(registry.len(), 1);
+    }
+
+    static CONNECTIONS_CLOSED: AtomicUsize = AtomicUsize::new(0);
+    static STATEMENTS_CLOSED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Connection;
+    unsafe impl Trace for Connection {}
+    unsafe impl Finalize for Connection {}
+    impl Collectable for Connection {}
+    impl OrderedFinalize<MiniMark> for Connection {
+        fn finalize(_this: Gc<Self, MiniMark>, _mutator: &mut MutatorRef<MiniMark>) {
+            assert_eq!(STATEMENTS_CLOSED.load(Ordering::Relaxed), 1);
+            CONNECTIONS_CLOSED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    struct Statement {
+        connection: Gc<Connection, MiniMark>,
+    }
+    unsafe impl Trace for Statement {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.connection.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Statement {}
+    impl Collectable for Statement {}
+    impl OrderedFinalize<MiniMark> for Statement {
+        fn finalize(this: Gc<Self, MiniMark>, mutator: &mut MutatorRef<MiniMark>) {
+            // connection is still alive, even if GC runs while statement is finalized.
+            mutator.collect(&mut []);
+            let _ = &*this.connection;
+            STATEMENTS_CLOSED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_ordered_finalization() {
+        let mut minimark = heap();
+        {
+            letroot!(
+                connection = minimark.shadow_stack(),
+                minimark
+                    .allocate_ordered_finalizable(Connection, crate::gc_base::AllocationSpace::New)
+            );
+            minimark.allocate_ordered_finalizable(
+                Statement {
+                    connection: *connection,
+                },
+                crate::gc_base::AllocationSpace::New,
+            );
+        }
+        minimark.collect(&mut []);
+        assert_eq!(minimark.run_ordered_finalizers(), 1);
+        assert_eq!(STATEMENTS_CLOSED.load(Ordering::Relaxed), 1);
+        assert_eq!(CONNECTIONS_CLOSED.load(Ordering::Relaxed), 0);
+
+        minimark.full_collection(&mut []);
+        assert_eq!(minimark.run_ordered_finalizers(), 1);
+        assert_eq!(CONNECTIONS_CLOSED.load(Ordering::Relaxed), 1);
+        minimark.full_collection(&mut []);
+        assert_eq!(minimark.run_ordered_finalizers(), 0);
+    }
+
+    static RESOURCES_CLOSED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Resource {
+        this: Option<Gc<Resource, MiniMark>>,
+        holder: Option<Gc<Holder, MiniMark>>,
+    }
+    unsafe impl Trace for Resource {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.this.trace(vis);
+            self.holder.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Resource {}
+    impl Collectable for Resource {}
+    impl OrderedFinalize<MiniMark> for Resource {
+        fn finalize(_this: Gc<Self, MiniMark>, _mutator: &mut MutatorRef<MiniMark>) {
+            RESOURCES_CLOSED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    struct Holder {
+        resource: Gc<Resource, MiniMark>,
+    }
+    unsafe impl Trace for Holder {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.resource.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Holder {}
+    impl Collectable for Holder {}
+
+    fn resource(minimark: &mut MutatorRef<MiniMark>) -> Gc<Resource, MiniMark> {
+        minimark.allocate_ordered_finalizable(
+            Resource {
+                this: None,
+                holder: None,
+            },
+            crate::gc_base::AllocationSpace::New,
+        )
+    }
+
+    #[test]
+    fn test_ordered_finalization_cycles() {
+        let mut minimark = heap();
+        {
+            letroot!(object = minimark.shadow_stack(), resource(&mut minimark));
+            object.this = Some(*object);
+        }
+        // reference of finalizable object to itself is ignored.
+        minimark.full_collection(&mut []);
+        assert_eq!(minimark.run_ordered_finalizers(), 1);
+        assert_eq!(RESOURCES_CLOSED.load(Ordering::Relaxed), 1);
+
+        {
+            letroot!(object = minimark.shadow_stack(), resource(&mut minimark));
+            let holder = minimark.allocate(
+                Holder { resource: *object },
+                crate::gc_base::AllocationSpace::New,
+            );
+            object.holder = Some(holder);
+        }
+        // object reaches itself through `holder`, so it is kept alive and never finalized.
+        minimark.full_collection(&mut []);
+        minimark.full_collection(&mut []);
+        assert_eq!(minimark.run_ordered_finalizers(), 0);
+        assert_eq!(RESOURCES_CLOSED.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_persistent_roots() {
+        let mut minimark = heap();
+
+        let value = minimark.allocate(42, crate::gc_base::AllocationSpace::New);
+        let persistent = Persistent::new(&minimark, value);
+        letroot!(
+            weak = minimark.shadow_stack(),
+            minimark.allocate_weak(persistent.get())
+        );
+        // object is promoted to old space by minor GC, persistent root must point to the new location.
+        minimark.collect(&mut []);
+        minimark.full_collection(&mut []);
+        assert_eq!(*persistent.get(), 42);
+
+        let other = persistent.clone();
+        std::thread::spawn(move || drop(persistent)).join().unwrap();
+        minimark.full_collection(&mut []);
+        assert_eq!(*other.get(), 42);
+        assert!(weak.upgrade().is_some());
+
+        drop(other);
+        minimark.full_collection(&mut []);
+        assert!(weak.upgrade().is_none());
+    }
+
+    fn allocate_escaped<'p>(
+        minimark: &mut MutatorRef<MiniMark>,
+        parent: &'p HandleScope<MiniMark>,
+    ) -> Handle<'p, i32, MiniMark> {
+        let scope = EscapableHandleScope::new(parent);
+        let value = scope.handle(minimark.allocate(42, crate::gc_base::AllocationSpace::New));
+        minimark.collect(&mut []);
+        scope.escape(value)
+    }
+
+    #[test]
+    fn test_handle_scopes() {
+        let mut minimark = heap();
+
+        let scope = minimark.handle_scope();
+        let value = allocate_escaped(&mut minimark, &scope);
+        assert_eq!(scope.len(), 1);
+        // value is promoted to old space by minor GC, handle must point to the new location.
+        minimark.collect(&mut []);
+        minimark.full_collection(&mut []);
+        assert_eq!(*value, 42);
+
+        letroot!(
+            weak = minimark.shadow_stack(),
+            minimark.allocate_weak(value.get())
+        );
+        drop(scope);
+        minimark.full_collection(&mut []);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_pinning() {
+        let mut minimark = heap();
+
+        let value = minimark.allocate(42, crate::gc_base::AllocationSpace::New);
+        // nursery object is promoted before it is pinned.
+        let pinned = minimark.pin(value);
+        assert!(!minimark.heap_ref().is_young(pinned.get().base.as_ptr()));
+        let address = pinned.as_ptr();
+        letroot!(
+            weak = minimark.shadow_stack(),
+            minimark.allocate_weak(pinned.get())
+        );
+        minimark.collect(&mut []);
+        minimark.full_collection(&mut []);
+        assert_eq!(pinned.as_ptr(), address);
+        assert_eq!(*pinned, 42);
+
+        std::thread::spawn(move || drop(pinned)).join().unwrap();
+        minimark.full_collection(&mut []);
+        assert!(weak.upgrade().is_none());
+    }
+
+    struct Node {
+        value: i32,
+        next: Option<Gc<Node, MiniMark>>,
+    }
+
+    unsafe impl Trace for Node {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Node {}
+    impl Collectable for Node {}
+
+    fn node(minimark: &mut MutatorRef<MiniMark>, value: i32) -> Gc<Node, MiniMark> {
+        minimark.allocate(
+            Node { value, next: None },
+            crate::gc_base::AllocationSpace::New,
+        )
+    }
+
+    #[test]
+    fn test_card_marking() {
+        let mut options = options();
+        options.card_marking = true;
+        let mut minimark = instantiate_minimark(options);
+
+        letroot!(holder = minimark.shadow_stack(), node(&mut minimark, 1));
+        minimark.minor_collection(&mut []);
+        // `holder` is old now and young object is reachable only through it.
+        holder.next = Some(node(&mut minimark, 2));
+        minimark.write_barrier(holder.to_dyn());
+        let card_table = minimark.heap_ref().card_table.as_ref().unwrap();
+        assert!(card_table.is_dirty(holder.base.as_ptr().cast()));
+
+        minimark.minor_collection(&mut []);
+        let card_table = minimark.heap_ref().card_table.as_ref().unwrap();
+        assert!(!card_table.is_dirty(holder.base.as_ptr().cast()));
+        let young = holder.next.unwrap().base.as_ptr();
+        assert!(!minimark.heap_ref().is_young(young));
+        assert_eq!(holder.next.unwrap().value, 2);
+        minimark.full_collection(&mut []);
+        assert_eq!(holder.value, 1);
+        assert_eq!(holder.next.unwrap().value, 2);
+    }
+
+    fn concurrent_heap() -> MutatorRef<MiniMark> {
+        let mut options = options();
+        options.min_heap_size = 0;
+        options.concurrent_major = true;
+        instantiate_minimark(options)
+    }
+
+    /// Start concurrent major collection without background marker, objects are traced only when the test drains mark stack.
+    fn start_marking(minimark: &mut MutatorRef<MiniMark>) {
+        let safepoint = SafepointScope::new(minimark.clone()).unwrap();
+        unsafe {
+            let heap = &mut *minimark.heap.get();
+            heap.start_marking(minimark, &mut []);
+        }
+        drop(safepoint);
+    }
+
+    #[test]
+    fn test_satb_barrier() {
+        let mut minimark = concurrent_heap();
+        letroot!(holder = minimark.shadow_stack(), node(&mut minimark, 1));
+        holder.next = Some(node(&mut minimark, 2));
+        minimark.minor_collection(&mut []);
+        start_marking(&mut minimark);
+        // `holder` is not traced yet. Once its reference is overwritten, old object is reachable only through young object
+        // that is promoted black.
+        letroot!(young = minimark.shadow_stack(), node(&mut minimark, 3));
+        young.next = holder.next;
+        minimark.pre_write_barrier(holder.to_dyn());
+        holder.next = None;
+        minimark.write_barrier(holder.to_dyn());
+        let heap = minimark.heap_ref();
+        assert!(unsafe { heap.shared.drain_old_mark_stack(usize::MAX) });
+        heap.shared
+            .gc_state
+            .store(MajorPhase::Finalizing, Ordering::Release);
+        minimark.minor_collection(&mut []);
+        let heap = unsafe { &mut *minimark.heap.get() };
+        heap.wait_for_gc_to_complete(&mut minimark, &mut []);
+        assert_eq!(minimark.stats().concurrent_collections, 1);
+
+        let old = young.next.unwrap();
+        let heap = minimark.heap_ref();
+        unsafe {
+            assert!((*(*heap.shared.old_space).get_live_bitmap()).test(old.base.as_ptr().cast()));
+            assert_eq!((*old.base.as_ptr()).get_color(), heap.shared.mark_color());
+        }
+        assert_eq!(old.value, 2);
+        assert_eq!(young.value, 3);
+    }
+
+    #[test]
+    fn test_degenerated_collection() {
+        let mut minimark = concurrent_heap();
+        letroot!(list = minimark.shadow_stack(), None::<Gc<Node, MiniMark>>);
+        letroot!(
+            garbage = minimark.shadow_stack(),
+            None::<Gc<Node, MiniMark>>
+        );
+        for value in 0..10000 {
+            let mut object = node(&mut minimark, value);
+            object.next = *list;
+            *list = Some(object);
+            let mut object = node(&mut minimark, value);
+            object.next = *garbage;
+            *garbage = Some(object);
+        }
+        minimark.minor_collection(&mut []);
+        *garbage = None;
+        let before = minimark.stats().allocated_bytes;
+        start_marking(&mut minimark);
+        // old space can't take promotion of whole nursery, collection is finished in the next pause.
+        let heap = minimark.heap_ref();
+        let growth_limit = heap.growth_limit;
+        heap.growth_limit = heap.shared.num_old_space_allocated.load(Ordering::Relaxed);
+        minimark.minor_collection(&mut []);
+        let heap = minimark.heap_ref();
+        heap.growth_limit = growth_limit;
+        assert_eq!(
+            heap.shared.gc_state.load(Ordering::Acquire),
+            MajorPhase::Scanning
+        );
+        assert_eq!(minimark.stats().concurrent_collections, 1);
+        assert!(minimark.stats().allocated_bytes < before);
+
+        let mut node = *list;
+        let mut expected = 10000;
+        while let Some(current) = node {
+            expected -= 1;
+            assert_eq!(current.value, expected);
+            node = current.next;
+        }
+        assert_eq!(expected, 0);
+    }
+
+    #[test]
+    fn test_concurrent_major_while_mutating() {
+        const SLOTS: i32 = 4096;
+        let mut minimark = concurrent_heap();
+        letroot!(
+            slots = minimark.shadow_stack(),
+            Vec::<Gc<Node, MiniMark>>::new()
+        );
+        for value in 0..SLOTS {
+            let object = node(&mut minimark, value);
+            slots.push(object);
+        }
+        // every slot points to the last two nodes allocated for it, the older one is cut off from the rest of the chain.
+        for value in SLOTS..1_000_000 {
+            let mut slot = slots[(value % SLOTS) as usize];
+            let mut object = node(&mut minimark, value);
+            if let Some(mut previous) = slot.next {
+                minimark.pre_write_barrier(previous.to_dyn());
+                previous.next = None;
+                minimark.write_barrier(previous.to_dyn());
+            }
+            object.next = slot.next;
+            minimark.pre_write_barrier(slot.to_dyn());
+            slot.next = Some(object);
+            minimark.write_barrier(slot.to_dyn());
+        }
+        minimark.full_collection(&mut []);
+        assert!(minimark.stats().concurrent_collections > 0);
+        for (index, slot) in slots.iter().enumerate() {
+            let last = slot.next.unwrap();
+            assert_eq!(last.value % SLOTS, index as i32);
+            assert_eq!(last.next.unwrap().value, last.value - SLOTS);
+        }
+    }
 }
@@ -1,6 +1,7 @@
 //! Mutator thread local information for GC
 use std::{
     cell::{Cell, UnsafeCell},
+    marker::PhantomData,
     mem::size_of,
     ops::{Deref, DerefMut},
     ptr::{null_mut, NonNull},
@@ -11,10 +12,19 @@ use atomic::{Atomic, Ordering};
 use parking_lot::{Condvar, Mutex};
 
 use crate::{
-    api::{Collectable, Finalize, Gc, HeapObjectHeader, Trace, Weak},
-    gc_base::{AllocationSpace, GcBase, MarkingConstraint, TLAB},
+    api::{
+        Collectable, Ephemeron, Finalize, Gc, HeapObjectHeader, OrderedFinalize, OrderedFinalizer,
+        ReferenceQueue, SoftRef, Trace, Weak,
+    },
+    conservative::{thread_stack_base, Registers},
+    gc_base::{AllocError, AllocationSpace, GcBase, MarkingConstraint, TLAB},
+    gc_log::GcLogger,
+    handle_scope::HandleScope,
+    pin::PinGuard,
     safepoint::GlobalSafepoint,
     shadow_stack::ShadowStack,
+    stats::{GcListener, HeapStats},
+    stress::GcStress,
     utils::align_usize,
 };
 
@@ -49,10 +59,18 @@ pub struct Mutator<H: GcBase + 'static> {
     safepoint: *const GlobalSafepoint,
     safepoint_cond: *const AtomicU32,
     last_sp: Cell<*mut *mut u8>,
+    /// Base of mutator thread stack, used by conservative stack scanning.
+    stack_base: Cell<*mut u8>,
+    /// Callee-saved registers spilled when `last_sp` is recorded.
+    registers: UnsafeCell<Registers>,
     join_data: Arc<JoinDataInternal>,
     shadow_stack: ShadowStack,
     pub(crate) heap: Arc<UnsafeCell<H>>,
     rc: u32,
+    /// Allocations since the last GC cycle forced by [stress](crate::stress) mode.
+    stress_allocations: Cell<usize>,
+    /// Set while this mutator performs GC cycle forced by stress mode.
+    in_stress_collection: Cell<bool>,
 }
 
 impl<H: 'static + GcBase> Mutator<H> {
@@ -67,6 +85,45 @@ impl<H: 'static + GcBase> Mutator<H> {
     pub fn add_constraint<T: MarkingConstraint + 'static>(&self, c: T) {
         self.heap_ref().add_constraint(c);
     }
+    /// Set handler that is invoked when heap is out of memory even after GC cycle. See [OomHandler](crate::gc_base::OomHandler)
+    /// for more information.
+    pub fn set_oom_handler(
+        &self,
+        handler: impl FnMut(&mut MutatorRef<H>, usize) -> bool + 'static,
+    ) {
+        self.heap_ref().set_oom_handler(Box::new(handler));
+    }
+    /// Set number of GC worker threads used for marking. See [parallel_marking](crate::parallel_marking).
+    pub fn set_marking_workers(&self, workers: usize) {
+        self.heap_ref().set_marking_workers(workers);
+    }
+    /// Set soft reference LRU policy. See [SoftRefPolicy](crate::api::SoftRefPolicy).
+    pub fn set_soft_ref_lru_policy(&self, ms_per_mb: u64) {
+        self.heap_ref().set_soft_ref_lru_policy(ms_per_mb);
+    }
+    /// Returns statistics of the heap. See [stats](crate::stats).
+    pub fn stats(&self) -> HeapStats {
+        self.heap_ref().stats()
+    }
+    /// Register listener that is notified when GC cycles start and end. See [stats](crate::stats).
+    pub fn add_gc_listener(&self, listener: Box<dyn GcListener>) {
+        self.heap_ref().add_gc_listener(listener);
+    }
+    /// Set logger that receives records of GC pauses and safepoints of the heap. Replaces the default stderr logger of verbose
+    /// heaps. See [gc_log](crate::gc_log).
+    pub fn set_gc_logger(&self, logger: GcLogger) {
+        self.heap_ref()
+            .safepoint()
+            .set_logger(Some(Arc::new(logger)));
+    }
+    /// Set [stress](crate::stress) mode of the heap. Overrides mode set with `COMET_GC_STRESS` environment variable.
+    pub fn set_gc_stress(&self, stress: GcStress) {
+        self.heap_ref().safepoint().set_gc_stress(stress);
+    }
+    /// Returns [stress](crate::stress) mode of the heap.
+    pub fn gc_stress(&self) -> GcStress {
+        self.heap_ref().safepoint().gc_stress()
+    }
     /// Reset TLAB data.
     ///
     /// # Safety
@@ -93,6 +150,7 @@ impl<H: 'static + GcBase> Mutator<H> {
         heap.attach_current_thread(&mut *mutator);
         drop(state);
         std::thread::spawn(move || {
+            mutator.stack_base.set(thread_stack_base());
             mutator.state_set(ThreadState::Safe, ThreadState::Unsafe);
             closure(mutator.clone());
             mutator.stop();
@@ -116,15 +174,23 @@ impl<H: 'static + GcBase> Mutator<H> {
             state: Atomic::new(ThreadState::Unsafe),
             tlab: H::TLAB::create(heap),
             last_sp: Cell::new(null_mut()),
+            stack_base: Cell::new(thread_stack_base()),
+            registers: UnsafeCell::new(Registers::new()),
             join_data,
             shadow_stack: ShadowStack::new(),
             rc: 1,
+            stress_allocations: Cell::new(0),
+            in_stress_collection: Cell::new(false),
         }
     }
     /// Get shadow stack reference for this thread.
     pub fn shadow_stack<'a>(&self) -> &'a ShadowStack {
         unsafe { std::mem::transmute(&self.shadow_stack) }
     }
+    /// Open new [HandleScope] on this thread.
+    pub fn handle_scope<'a>(&self) -> HandleScope<'a, H> {
+        HandleScope::new(self.shadow_stack())
+    }
 
     fn get_safepoint(&self) -> &GlobalSafepoint {
         unsafe { &*self.safepoint }
@@ -137,11 +203,21 @@ impl<H: 'static + GcBase> Mutator<H> {
         self.get_safepoint().wait_gc();
         self.state.store(state, Ordering::Release);
     }
-    /// Check if safepoint is requested. If it is requested mutator will wait for safepoint to be released.
+    /// Check if safepoint is requested. If it is requested mutator will wait for safepoint to be released. In
+    /// [stress](crate::stress) mode GC cycle is performed on every call.
     ///
     /// This function should be quite cheap because it is simple conditional check if safepoint is requested and call to slow path if it is requested.
     #[inline(always)]
     pub fn safepoint(&self) -> bool {
+        if self.get_safepoint().raw_gc_stress() == usize::MAX {
+            return self.stress_safepoint();
+        }
+        self.poll_safepoint()
+    }
+    /// Same as [safepoint](Self::safepoint) but never performs GC cycle in stress mode. Used inside of GC where collection
+    /// must not be started.
+    #[inline(always)]
+    pub(crate) fn poll_safepoint(&self) -> bool {
         unsafe {
             if (*self.safepoint_cond).load(Ordering::Relaxed) != 0 {
                 self.safepoint_slow();
@@ -152,17 +228,52 @@ impl<H: 'static + GcBase> Mutator<H> {
     }
     #[inline(never)]
     #[cold]
+    fn stress_safepoint(&self) -> bool {
+        let mut mutator = unsafe { MutatorRef::from_raw(self as *const Self as *mut Self) };
+        mutator.stress_collection(&mut []) || self.poll_safepoint()
+    }
+    #[inline(never)]
+    #[cold]
     fn safepoint_slow(&self) {
-        self.last_sp.set(approximate_stack_pointer());
+        self.record_stack_top();
         self.set_gc_and_wait();
     }
 
-    pub(crate) fn state_set(&self, state: ThreadState, old_state: ThreadState) -> ThreadState {
+    /// Record current stack pointer and spill callee-saved registers so GC can scan them conservatively.
+    #[inline(always)]
+    pub(crate) fn record_stack_top(&self) {
+        unsafe {
+            (*self.registers.get()).capture();
+        }
         self.last_sp.set(approximate_stack_pointer());
+    }
+    /// Conservatively scan stack of this mutator between the stack pointer recorded at the last safepoint and stack base,
+    /// and registers spilled at the same point. `visitor` is invoked for each word.
+    ///
+    /// # Safety
+    ///
+    /// Must be invoked only by GC when mutator is stopped.
+    pub(crate) unsafe fn scan_stack(&self, mut visitor: impl FnMut(*const u8)) {
+        let mut cursor = self.last_sp.get() as *const *const u8;
+        if cursor.is_null() {
+            return;
+        }
+        let end = self.stack_base.get() as *const *const u8;
+        while cursor < end {
+            visitor(cursor.read());
+            cursor = cursor.add(1);
+        }
+        for slot in (*self.registers.get()).slots() {
+            visitor(*slot as *const u8);
+        }
+    }
+
+    pub(crate) fn state_set(&self, state: ThreadState, old_state: ThreadState) -> ThreadState {
+        self.record_stack_top();
         self.state.store(state, Ordering::Release);
 
         if old_state.safe_for_safepoint() && !state.safe_for_safepoint() {
-            self.safepoint();
+            self.poll_safepoint();
         }
         old_state
     }
@@ -201,6 +312,11 @@ impl<H: 'static + GcBase> Mutator<H> {
 }
 
 impl<H: GcBase> MutatorRef<H> {
+    /// Must be invoked right before references in `object` are overwritten, see [GcBase::pre_write_barrier].
+    pub fn pre_write_barrier(&mut self, object: Gc<dyn Collectable, H>) {
+        let heap = unsafe { &mut *self.heap.get() };
+        heap.pre_write_barrier(self, object);
+    }
     pub fn write_barrier(&mut self, object: Gc<dyn Collectable, H>) {
         let heap = unsafe { &mut *self.heap.get() };
         heap.write_barrier(self, object);
@@ -214,10 +330,43 @@ impl<H: GcBase> MutatorRef<H> {
         heap.full_collection(self, keep);
     }
 
+    /// Enable or disable conservative scanning of mutator stacks. See [conservative](crate::conservative).
+    pub fn set_conservative_stack_scanning(&mut self, enabled: bool) {
+        let heap = unsafe { &mut *self.heap.get() };
+        heap.set_conservative_stack_scanning(self, enabled);
+    }
+
+    /// Enable or disable verification of heap before and after each GC cycle. See [verify](crate::verify).
+    pub fn set_heap_verification(&mut self, enabled: bool) {
+        let heap = unsafe { &mut *self.heap.get() };
+        heap.set_heap_verification(self, enabled);
+    }
+
+    /// Pin `object` so it is not moved by GC while returned guard exists. See [pin](crate::pin) for guarantees of each heap.
+    pub fn pin<T: Collectable + ?Sized>(&mut self, object: Gc<T, H>) -> PinGuard<T, H> {
+        let heap = unsafe { &mut *self.heap.get() };
+        let object = Gc {
+            base: heap.prepare_pin(self, object.to_dyn()).base,
+            marker: PhantomData,
+        };
+        PinGuard::new(heap.pinned_objects(), object)
+    }
+
     pub fn minor_collection(&mut self, keep: &mut [&mut dyn Trace]) {
         let heap = unsafe { &mut *self.heap.get() };
         heap.minor_collection(self, keep);
     }
+    /// Perform GC cycle forced by [stress](crate::stress) mode. Returns `false` if this mutator already performs one, e.g.
+    /// when finalizer allocates.
+    fn stress_collection(&mut self, keep: &mut [&mut dyn Trace]) -> bool {
+        if self.in_stress_collection.replace(true) {
+            return false;
+        }
+        let heap = unsafe { &mut *self.heap.get() };
+        heap.stress_collection(self, keep);
+        self.in_stress_collection.set(false);
+        true
+    }
     #[inline(always)]
     pub unsafe fn allocate_from_tlab<T: Collectable + Sized + 'static>(
         &mut self,
@@ -230,13 +379,122 @@ impl<H: GcBase> MutatorRef<H> {
         let href = unsafe { &mut *self.heap.get() };
         href.allocate_weak(self, value)
     }
-    /// Allocate `T` on GC heap
+    /// Allocate weak reference that is enqueued to `queue` once it is cleared by GC.
+    pub fn allocate_weak_with_queue<T: Collectable + ?Sized>(
+        &mut self,
+        value: Gc<T, H>,
+        queue: ReferenceQueue<H>,
+    ) -> Weak<T, H> {
+        let stack = self.shadow_stack();
+        letroot!(queue = stack, queue);
+        let weak_ref = self.allocate_weak(value);
+        weak_ref.set_queue(self, *queue);
+        weak_ref
+    }
+    #[inline]
+    pub fn allocate_soft<T: Collectable + ?Sized>(&mut self, value: Gc<T, H>) -> SoftRef<T, H> {
+        let href = unsafe { &mut *self.heap.get() };
+        href.allocate_soft(self, value)
+    }
+    /// Allocate soft reference that is enqueued to `queue` once it is cleared by GC.
+    pub fn allocate_soft_with_queue<T: Collectable + ?Sized>(
+        &mut self,
+        value: Gc<T, H>,
+        queue: ReferenceQueue<H>,
+    ) -> SoftRef<T, H> {
+        let stack = self.shadow_stack();
+        letroot!(queue = stack, queue);
+        let soft_ref = self.allocate_soft(value);
+        soft_ref.set_queue(self, *queue);
+        soft_ref
+    }
+    #[inline]
+    pub fn allocate_ephemeron<K: Collectable + ?Sized, V: Collectable + ?Sized>(
+        &mut self,
+        key: Gc<K, H>,
+        value: Gc<V, H>,
+    ) -> Ephemeron<K, V, H> {
+        let href = unsafe { &mut *self.heap.get() };
+        href.allocate_ephemeron(self, key, value)
+    }
+    /// Allocate `T` on GC heap and register its [OrderedFinalize] finalizer. Finalizer is run by [MutatorRef::run_ordered_finalizers]
+    /// once object becomes unreachable.
+    pub fn allocate_ordered_finalizable<T: OrderedFinalize<H>>(
+        &mut self,
+        value: T,
+        space: AllocationSpace,
+    ) -> Gc<T, H> {
+        let object = self.allocate(value, space);
+        let href = unsafe { &mut *self.heap.get() };
+        href.register_ordered_finalizer(OrderedFinalizer::new(object));
+        object
+    }
+    /// Run finalizers of unreachable objects allocated with [MutatorRef::allocate_ordered_finalizable]. Returns number of finalizers
+    /// that were run.
+    pub fn run_ordered_finalizers(&mut self) -> usize {
+        let mut count = 0;
+        loop {
+            let href = unsafe { &mut *self.heap.get() };
+            match href.pop_ready_finalizer() {
+                Some(finalizer) => {
+                    finalizer.run(self);
+                    count += 1;
+                }
+                None => break count,
+            }
+        }
+    }
+    /// Allocate `T` on GC heap. Aborts the process if heap is out of memory, use [MutatorRef::try_allocate] if you want to handle it.
     #[inline(always)]
     pub fn allocate<T: Collectable + Sized + 'static>(
         &mut self,
         value: T,
         space: AllocationSpace,
     ) -> Gc<T, H> {
+        match self.try_allocate(value, space) {
+            Ok(value) => value,
+            Err(_) => oom_abort(),
+        }
+    }
+    /// Allocate `T` on GC heap. If heap is out of memory even after GC cycle OOM handler is invoked (see [Mutator::set_oom_handler])
+    /// and if it can't free enough memory `value` is returned back inside of [AllocError].
+    #[inline(always)]
+    pub fn try_allocate<T: Collectable + Sized + 'static>(
+        &mut self,
+        mut value: T,
+        space: AllocationSpace,
+    ) -> Result<Gc<T, H>, AllocError<T>> {
+        if self.get_safepoint().raw_gc_stress() != 0 {
+            self.stress_allocation(&mut value);
+        }
+        match self.allocate_once(value, space) {
+            Ok(value) => Ok(value),
+            Err(err) => self.allocate_oom(err),
+        }
+    }
+
+    /// Count allocation in [stress](crate::stress) mode and perform GC cycle every N allocations. `value` is kept alive by
+    /// the cycle.
+    #[cold]
+    #[inline(never)]
+    fn stress_allocation(&mut self, value: &mut dyn Trace) {
+        if let GcStress::Allocations(count) = self.get_safepoint().gc_stress() {
+            let allocations = self.stress_allocations.get() + 1;
+            if allocations < count {
+                self.stress_allocations.set(allocations);
+            } else {
+                self.stress_allocations.set(0);
+                self.stress_collection(&mut [value]);
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn allocate_once<T: Collectable + Sized + 'static>(
+        &mut self,
+        value: T,
+        space: AllocationSpace,
+    ) -> Result<Gc<T, H>, AllocError<T>> {
         let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
         if (!self.tlab.can_thread_local_allocate(size) && size >= H::LARGE_ALLOCATION_SIZE)
             || space == AllocationSpace::Large
@@ -254,22 +512,41 @@ impl<H: GcBase> MutatorRef<H> {
         match result {
             Ok(value) => {
                 self.heap_ref().post_alloc(value);
-                value
+                Ok(value)
             }
             Err(value) => self.allocate_slow(value, size, space),
         }
     }
 
+    /// Invoked when heap failed to allocate memory even after GC cycle. Runs OOM handler and retries allocation until
+    /// handler gives up.
+    #[cold]
+    #[inline(never)]
+    fn allocate_oom<T: Collectable + Sized + 'static>(
+        &mut self,
+        mut err: AllocError<T>,
+    ) -> Result<Gc<T, H>, AllocError<T>> {
+        let heap = unsafe { &mut *self.heap.get() };
+        while heap.handle_oom(self, err.size()) {
+            let space = err.space();
+            match self.allocate_once(err.into_inner(), space) {
+                Ok(value) => return Ok(value),
+                Err(e) => err = e,
+            }
+        }
+        Err(err)
+    }
+
     #[cold]
     fn allocate_slow<T: Collectable + Sized + 'static>(
         &mut self,
         mut value: T,
         size: usize,
         space: AllocationSpace,
-    ) -> Gc<T, H> {
+    ) -> Result<Gc<T, H>, AllocError<T>> {
         let heap = unsafe { &mut *self.heap.get() };
         if size >= H::LARGE_ALLOCATION_SIZE || space == AllocationSpace::Large {
-            heap.allocate_large(self, value)
+            heap.try_allocate_large(self, value)
         } else if self.tlab.can_thread_local_allocate(size) && H::SUPPORTS_TLAB {
             // try to refill tlab if gc supports tlab
             let mut this = self.clone();
@@ -277,12 +554,12 @@ impl<H: GcBase> MutatorRef<H> {
                 // if tlab failed to be refilled we request GC cycle and try to get some memory
                 heap.collect_alloc_failure(self, &mut [&mut value]);
                 if !this.tlab.refill(&self, size) {
-                    // if refilling again fails we just OOM
-                    oom_abort();
+                    // if refilling again fails we are out of memory
+                    return Err(AllocError::new(value, size, space));
                 }
             }
             // must not fail
-            self.allocate(value, space)
+            self.allocate_once(value, space)
         } else {
             // this path should be reached only when `H::SUPPORTS_TLAB` returns true and `size` is `>= H::TLAB::LARGE_OBJECT_SIZE`
             self.allocate_inline(value, size, space)
@@ -291,17 +568,16 @@ impl<H: GcBase> MutatorRef<H> {
 
     /// Invoked when `H::SUPPORTS_TLAB` returns false or when allocation size is larger than [TLAB::TLAB_OBJET_SIZE] but smaller than `H::LARGE_ALLOCATION_SIZE`.
     ///
-    /// Performance of this function depends only on GC implementation of [GcBase::alloc_inline]
+    /// Performance of this function depends only on GC implementation of [GcBase::try_alloc_inline]
     #[inline(always)]
     fn allocate_inline<T: Collectable + Sized + 'static>(
         &mut self,
         value: T,
         _size: usize,
         space: AllocationSpace,
-    ) -> Gc<T, H> {
+    ) -> Result<Gc<T, H>, AllocError<T>> {
         let href = unsafe { &mut *self.heap.get() };
-        let val = href.alloc_inline(self, value, space);
-        val
+        href.try_alloc_inline(self, value, space)
     }
 }
 
@@ -386,7 +662,9 @@ impl<H: 'static + GcBase> Drop for Mutator<H> {
 
         let heap = mutator.heap_ref();
 
-        heap.detach_current_thread(mptr);
+        unsafe {
+            heap.detach_current_thread(mptr);
+        }
         mutator.stop();
         drop(state);
     }
@@ -408,6 +686,14 @@ impl<H: GcBase + 'static> MutatorRef<H> {
     pub unsafe fn ptr(&self) -> *mut Mutator<H> {
         self.mutator.as_ptr()
     }
+
+    /// Create new reference to `mutator`, same as [clone](Clone::clone) of existing reference.
+    unsafe fn from_raw(mutator: *mut Mutator<H>) -> Self {
+        (*mutator).rc += 1;
+        Self {
+            mutator: NonNull::new_unchecked(mutator),
+        }
+    }
 }
 
 impl<H: GcBase + 'static> Deref for MutatorRef<H> {
@@ -0,0 +1,288 @@
+//! # Parallel marking
+//!
+//! Shared marking engine that drains mark stack on several GC worker threads. Each worker owns a LIFO deque, objects
+//! it discovers are pushed into its own deque and workers that run out of work steal from others. Initial mark stack
+//! (roots) is distributed through a global injector queue.
+//!
+//! Heap specific part of marking is implemented by [ParallelMark]: it must mark object atomically (e.g with
+//! [set_color](crate::api::HeapObjectHeader::set_color) or `atomic_test_and_set` on mark bitmap) so that only one worker
+//! traces it.
+//!
+//! Marking terminates when all of the workers are idle: idle worker never has objects in its own deque, so when every worker is idle
+//! there is no work left.
+use std::{
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crossbeam::deque::{Injector, Steal, Stealer, Worker};
+use scoped_threadpool::Pool;
+
+use crate::api::{HeapObjectHeader, Visitor};
+
+/// Heap specific marking used by parallel marker. Invoked concurrently from all GC workers.
+pub trait ParallelMark: Sync {
+    /// Mark object referenced by `slot`. Returns `true` if object was marked by this invocation and must be traced. Implementation
+    /// is allowed to update `slot`.
+    fn try_mark(&self, slot: &mut NonNull<HeapObjectHeader>) -> bool;
+}
+
+/// Default number of marking workers: number of available CPUs.
+pub fn default_marking_workers() -> usize {
+    std::thread::available_parallelism()
+        .map(|x| x.get())
+        .unwrap_or(1)
+}
+
+/// Drain `mark_stack` and trace all reachable objects using all threads of `pool`. Objects in `mark_stack` must be already
+/// marked. If `pool` has single thread marking is done on current thread.
+pub fn drain_mark_stack<M: ParallelMark>(
+    pool: &mut Pool,
+    mark_stack: &mut Vec<*mut HeapObjectHeader>,
+    mark: &M,
+) {
+    let num_workers = pool.thread_count() as usize;
+    if num_workers <= 1 {
+        let mut marker = SerialMarker {
+            mark,
+            mark_stack: std::mem::take(mark_stack),
+        };
+        while let Some(object) = marker.mark_stack.pop() {
+            unsafe {
+                (*object).get_dyn().trace(&mut marker);
+            }
+        }
+        *mark_stack = marker.mark_stack;
+        return;
+    }
+    let injector = Injector::new();
+    for object in mark_stack.drain(..) {
+        injector.push(object as usize);
+    }
+    let workers = (0..num_workers)
+        .map(|_| Worker::new_lifo())
+        .collect::<Vec<_>>();
+    let stealers = workers.iter().map(|w| w.stealer()).collect::<Vec<_>>();
+    let idle = AtomicUsize::new(0);
+
+    pool.scoped(|scope| {
+        for (id, local) in workers.into_iter().enumerate() {
+            let mut marker = ParallelMarker {
+                id,
+                local,
+                injector: &injector,
+                stealers: &stealers,
+                idle: &idle,
+                mark,
+            };
+            scope.execute(move || marker.run());
+        }
+    });
+}
+
+struct SerialMarker<'a, M: ParallelMark> {
+    mark: &'a M,
+    mark_stack: Vec<*mut HeapObjectHeader>,
+}
+
+impl<'a, M: ParallelMark> Visitor for SerialMarker<'a, M> {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        if self.mark.try_mark(root) {
+            self.mark_stack.push(root.as_ptr());
+        }
+    }
+}
+
+struct ParallelMarker<'a, M: ParallelMark> {
+    id: usize,
+    local: Worker<usize>,
+    injector: &'a Injector<usize>,
+    stealers: &'a [Stealer<usize>],
+    idle: &'a AtomicUsize,
+    mark: &'a M,
+}
+
+impl<'a, M: ParallelMark> ParallelMarker<'a, M> {
+    fn run(&mut self) {
+        loop {
+            while let Some(object) = self.find_work() {
+                unsafe {
+                    (*(object as *mut HeapObjectHeader)).get_dyn().trace(self);
+                }
+            }
+            self.idle.fetch_add(1, Ordering::AcqRel);
+            loop {
+                if self.idle.load(Ordering::Acquire) == self.stealers.len() {
+                    return;
+                }
+                if !self.injector.is_empty() || self.stealers.iter().any(|s| !s.is_empty()) {
+                    self.idle.fetch_sub(1, Ordering::AcqRel);
+                    break;
+                }
+                std::thread::yield_now();
+            }
+        }
+    }
+
+    fn find_work(&self) -> Option<usize> {
+        if let Some(object) = self.local.pop() {
+            return Some(object);
+        }
+        loop {
+            let mut retry = false;
+            match self.injector.steal_batch_and_pop(&self.local) {
+                Steal::Success(object) => return Some(object),
+                Steal::Retry => retry = true,
+                Steal::Empty => (),
+            }
+            for (id, stealer) in self.stealers.iter().enumerate() {
+                if id == self.id {
+                    continue;
+                }
+                match stealer.steal_batch_and_pop(&self.local) {
+                    Steal::Success(object) => return Some(object),
+                    Steal::Retry => retry = true,
+                    Steal::Empty => (),
+                }
+            }
+            if !retry {
+                return None;
+            }
+        }
+    }
+}
+
+impl<'a, M: ParallelMark> Visitor for ParallelMarker<'a, M> {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        if self.mark.try_mark(root) {
+            self.local.push(root.as_ptr() as usize);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::{Collectable, Finalize, Gc, Trace, Visitor},
+        gc_base::{AllocationSpace, GcBase},
+        immix::{instantiate_immix, Immix},
+        marksweep::{instantiate_marksweep, MarkSweep},
+        mutator::MutatorRef,
+    };
+
+    const NUM_NODES: usize = 100000;
+
+    struct Node<H: GcBase> {
+        value: usize,
+        left: Option<Gc<Node<H>, H>>,
+        right: Option<Gc<Node<H>, H>>,
+        /// Edge to an earlier node so that objects are reachable from several workers at once.
+        shared: Option<Gc<Node<H>, H>>,
+    }
+
+    unsafe impl<H: GcBase> Trace for Node<H> {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.left.trace(vis);
+            self.right.trace(vis);
+            self.shared.trace(vis);
+        }
+    }
+    unsafe impl<H: GcBase> Finalize for Node<H> {}
+    impl<H: GcBase + 'static> Collectable for Node<H> {}
+
+    /// Index of earlier node that is referenced by node `value`.
+    fn shared(value: usize) -> usize {
+        value * 7919 % NUM_NODES % value
+    }
+
+    /// Build binary tree of [NUM_NODES] nodes with additional edges to earlier nodes, collect it with 4 marking workers and check
+    /// that every node survived.
+    fn mark_graph<H: GcBase + 'static>(mutator: &mut MutatorRef<H>) {
+        mutator.set_marking_workers(4);
+        letroot!(nodes = mutator.shadow_stack(), Vec::<Gc<Node<H>, H>>::new());
+        for value in 0..NUM_NODES {
+            let mut node = mutator.allocate(
+                Node {
+                    value,
+                    left: None,
+                    right: None,
+                    shared: None,
+                },
+                AllocationSpace::New,
+            );
+            if value != 0 {
+                node.shared = Some(nodes[shared(value)]);
+                let mut parent = nodes[(value - 1) / 2];
+                if value % 2 == 1 {
+                    parent.left = Some(node);
+                } else {
+                    parent.right = Some(node);
+                }
+            }
+            nodes.push(node);
+        }
+        letroot!(root = mutator.shadow_stack(), nodes[0]);
+        nodes.clear();
+        mutator.collect(&mut []);
+        // memory of dead objects is reused, so survivors that were freed by mistake are overwritten.
+        for value in 0..NUM_NODES {
+            mutator.allocate(
+                Node::<H> {
+                    value,
+                    left: None,
+                    right: None,
+                    shared: None,
+                },
+                AllocationSpace::New,
+            );
+        }
+
+        let mut seen = vec![false; NUM_NODES];
+        let mut stack = vec![*root];
+        while let Some(node) = stack.pop() {
+            assert!(!seen[node.value]);
+            seen[node.value] = true;
+            if node.value != 0 {
+                assert_eq!(node.shared.unwrap().value, shared(node.value));
+            }
+            for (child, value) in [
+                (node.left, node.value * 2 + 1),
+                (node.right, node.value * 2 + 2),
+            ] {
+                if let Some(child) = child {
+                    assert_eq!(child.value, value);
+                    stack.push(child);
+                }
+            }
+        }
+        assert!(seen.iter().all(|seen| *seen));
+    }
+
+    #[test]
+    fn test_immix_parallel_marking() {
+        let mut mutator = instantiate_immix(
+            128 * 1024 * 1024,
+            32 * 1024 * 1024,
+            32 * 1024 * 1024,
+            128 * 1024 * 1024,
+            false,
+        );
+        mark_graph::<Immix>(&mut mutator);
+    }
+
+    #[test]
+    fn test_marksweep_parallel_marking() {
+        let mut mutator = instantiate_marksweep(
+            32 * 1024 * 1024,
+            128 * 1024 * 1024,
+            512 * 1024,
+            2 * 1024 * 1024,
+            2.0,
+            128 * 1024 * 1024,
+            false,
+            4,
+            false,
+        );
+        mark_graph::<MarkSweep>(&mut mutator);
+    }
+}
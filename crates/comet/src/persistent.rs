@@ -0,0 +1,145 @@
+//! Persistent roots.
+//!
+//! [Persistent] is a root that is not tied to a shadow stack: it can be stored in any long-lived Rust structure (FFI callback
+//! tables, module registries etc), cloned and dropped from any thread. Each heap keeps [PersistentRoots] table and traces it
+//! together with shadow stacks of mutators, so persistent roots are updated when objects are moved.
+use std::{marker::PhantomData, sync::Arc};
+
+use parking_lot::Mutex;
+
+use crate::{
+    api::{Collectable, Gc, Trace, Visitor},
+    gc_base::GcBase,
+    mutator::MutatorRef,
+};
+
+struct PersistentSlot<H: GcBase> {
+    value: Option<Gc<dyn Collectable, H>>,
+    /// Number of [Persistent] handles that refer to this slot.
+    rc: usize,
+}
+
+struct PersistentSlots<H: GcBase> {
+    slots: Vec<PersistentSlot<H>>,
+    free: Vec<usize>,
+}
+
+/// Table of persistent roots of a heap.
+pub struct PersistentRoots<H: GcBase> {
+    slots: Mutex<PersistentSlots<H>>,
+}
+
+unsafe impl<H: GcBase> Send for PersistentRoots<H> {}
+unsafe impl<H: GcBase> Sync for PersistentRoots<H> {}
+
+impl<H: GcBase> PersistentRoots<H> {
+    pub fn new() -> Self {
+        Self {
+            slots: Mutex::new(PersistentSlots {
+                slots: Vec::new(),
+                free: Vec::new(),
+            }),
+        }
+    }
+    /// Number of live persistent roots.
+    pub fn len(&self) -> usize {
+        let slots = self.slots.lock();
+        slots.slots.len() - slots.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Trace all persistent roots. Must be invoked by heap when it walks roots.
+    pub fn trace(&self, vis: &mut dyn Visitor) {
+        let mut slots = self.slots.lock();
+        for slot in slots.slots.iter_mut() {
+            if let Some(value) = slot.value.as_mut() {
+                value.trace(vis);
+            }
+        }
+    }
+
+    fn add(&self, value: Gc<dyn Collectable, H>) -> usize {
+        let mut slots = self.slots.lock();
+        let slot = PersistentSlot {
+            value: Some(value),
+            rc: 1,
+        };
+        match slots.free.pop() {
+            Some(index) => {
+                slots.slots[index] = slot;
+                index
+            }
+            None => {
+                slots.slots.push(slot);
+                slots.slots.len() - 1
+            }
+        }
+    }
+}
+
+impl<H: GcBase> Default for PersistentRoots<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reference counted root handle. Object stays alive while at least one handle to it exists. Unlike [letroot!](crate::letroot)
+/// it does not have to live on the stack and can be dropped from any thread.
+pub struct Persistent<T: Collectable + ?Sized, H: GcBase> {
+    roots: Arc<PersistentRoots<H>>,
+    index: usize,
+    marker: PhantomData<*mut T>,
+}
+
+unsafe impl<T: Collectable + ?Sized, H: GcBase> Send for Persistent<T, H> {}
+unsafe impl<T: Collectable + ?Sized, H: GcBase> Sync for Persistent<T, H> {}
+
+impl<T: Collectable + ?Sized, H: GcBase> Persistent<T, H> {
+    /// Register `value` in root table of mutator's heap.
+    pub fn new(mutator: &MutatorRef<H>, value: Gc<T, H>) -> Self {
+        let roots = mutator.heap_ref().persistent_roots();
+        let index = roots.add(value.to_dyn());
+        Self {
+            roots,
+            index,
+            marker: PhantomData,
+        }
+    }
+    /// Returns current location of the object.
+    pub fn get(&self) -> Gc<T, H>
+    where
+        T: Sized,
+    {
+        unsafe { self.get_dyn().downcast_unchecked() }
+    }
+    /// Returns current location of the object as `Gc<dyn Collectable>`.
+    pub fn get_dyn(&self) -> Gc<dyn Collectable, H> {
+        let slots = self.roots.slots.lock();
+        slots.slots[self.index].value.unwrap()
+    }
+}
+
+impl<T: Collectable + ?Sized, H: GcBase> Clone for Persistent<T, H> {
+    fn clone(&self) -> Self {
+        self.roots.slots.lock().slots[self.index].rc += 1;
+        Self {
+            roots: self.roots.clone(),
+            index: self.index,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Collectable + ?Sized, H: GcBase> Drop for Persistent<T, H> {
+    fn drop(&mut self) {
+        let mut slots = self.roots.slots.lock();
+        let slot = &mut slots.slots[self.index];
+        slot.rc -= 1;
+        if slot.rc == 0 {
+            slot.value = None;
+            slots.free.push(self.index);
+        }
+    }
+}
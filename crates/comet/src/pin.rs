@@ -0,0 +1,195 @@
+//! Object pinning.
+//!
+//! [MutatorRef::pin](crate::mutator::MutatorRef::pin) returns [PinGuard] that keeps object alive and guarantees that its address
+//! does not change while guard exists, so raw pointers to the object can be passed to foreign code. Each heap keeps
+//! [PinnedObjects] table that is traced as a root. Collectors that move objects set [pinned bit](crate::api::HeapObjectHeader::pinned_bit)
+//! of every pinned object for the duration of a collection and clear it when collection is over.
+//!
+//! Guarantees per policy:
+//! - [Immix](crate::immix): pinned objects are never evacuated, blocks that contain them are not freed.
+//! - [MarkSweep](crate::marksweep): objects never move, pinning only keeps object alive.
+//! - [SemiSpace](crate::semispace): pinned objects are left in place in "from space". Pages they occupy are excluded from
+//!   allocation when that space becomes "to space" again, so pinned objects fragment semispaces.
+//! - [MiniMark](crate::minimark): old space never moves objects. Pinning object from nursery triggers minor collection that
+//!   promotes it to old space before pin is taken, so [PinGuard::get] may return other address than the pinned `Gc`.
+//! - [Shenandoah](crate::shenandoah): regions that contain pinned objects are not added to collection set and are not
+//!   compacted by Full GC. Dead objects in such regions are replaced with filler objects. Pinning object from collection
+//!   set while evacuation is in progress evacuates it first, so [PinGuard::get] may return other address than the pinned `Gc`.
+//! - [CMS](crate::cms): objects never move, pinning only keeps object alive.
+use std::{ops::Deref, ptr::NonNull, sync::Arc};
+
+use parking_lot::Mutex;
+
+use crate::{
+    api::{Collectable, Gc, HeapObjectHeader, Trace, Visitor},
+    gc_base::GcBase,
+};
+
+/// Table of pinned objects of a heap. Object can be pinned several times, it stays pinned until all of its guards are dropped.
+pub struct PinnedObjects<H: GcBase> {
+    objects: Mutex<Vec<Gc<dyn Collectable, H>>>,
+}
+
+unsafe impl<H: GcBase> Send for PinnedObjects<H> {}
+unsafe impl<H: GcBase> Sync for PinnedObjects<H> {}
+
+impl<H: GcBase> PinnedObjects<H> {
+    pub fn new() -> Self {
+        Self {
+            objects: Mutex::new(Vec::new()),
+        }
+    }
+    /// Number of pins. Object that is pinned several times is counted several times.
+    pub fn len(&self) -> usize {
+        self.objects.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Trace all pinned objects. Must be invoked by heap when it walks roots.
+    pub fn trace(&self, vis: &mut dyn Visitor) {
+        let mut objects = self.objects.lock();
+        for object in objects.iter_mut() {
+            object.trace(vis);
+        }
+    }
+    /// Set pinned bit of every pinned object. Returns objects whose bit was not set before, heap must clear pinned bit
+    /// of them once collection is over.
+    ///
+    /// # Safety
+    ///
+    /// Must be invoked only by GC when all mutators are stopped.
+    pub unsafe fn set_pinned_bits(&self) -> Vec<*mut HeapObjectHeader> {
+        let objects = self.objects.lock();
+        let mut pinned = Vec::with_capacity(objects.len());
+        for object in objects.iter() {
+            let header = object.base.as_ptr();
+            if !(*header).pinned_bit() {
+                (*header).set_pinned_bit(true);
+                pinned.push(header);
+            }
+        }
+        pinned
+    }
+
+    fn pin(&self, object: Gc<dyn Collectable, H>) {
+        self.objects.lock().push(object);
+    }
+
+    fn unpin(&self, object: NonNull<HeapObjectHeader>) {
+        let mut objects = self.objects.lock();
+        if let Some(index) = objects.iter().position(|pinned| pinned.base == object) {
+            objects.swap_remove(index);
+        }
+    }
+}
+
+impl<H: GcBase> Default for PinnedObjects<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Guard that keeps object pinned. Object is unpinned when guard is dropped, guard can be dropped from any thread.
+pub struct PinGuard<T: Collectable + ?Sized, H: GcBase> {
+    pinned: Arc<PinnedObjects<H>>,
+    object: Gc<T, H>,
+}
+
+unsafe impl<T: Collectable + ?Sized, H: GcBase> Send for PinGuard<T, H> {}
+unsafe impl<T: Collectable + ?Sized, H: GcBase> Sync for PinGuard<T, H> {}
+
+impl<T: Collectable + ?Sized, H: GcBase> PinGuard<T, H> {
+    pub(crate) fn new(pinned: Arc<PinnedObjects<H>>, object: Gc<T, H>) -> Self {
+        pinned.pin(object.to_dyn());
+        Self { pinned, object }
+    }
+    /// Returns pinned object. Address of the object does not change while this guard exists.
+    pub fn get(&self) -> Gc<T, H> {
+        self.object
+    }
+    /// Returns pointer to the data of pinned object.
+    pub fn as_ptr(&self) -> *const u8 {
+        unsafe { (*self.object.base.as_ptr()).data() }
+    }
+}
+
+impl<T: Collectable, H: GcBase> Deref for PinGuard<T, H> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*(&*self.object as *const T) }
+    }
+}
+
+impl<T: Collectable + ?Sized, H: GcBase> Drop for PinGuard<T, H> {
+    fn drop(&mut self) {
+        self.pinned.unpin(self.object.base);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::{Collectable, Finalize, Gc, Trace, Visitor},
+        gc_base::AllocationSpace,
+        semispace::{instantiate_semispace, SemiSpace},
+    };
+
+    struct Node {
+        value: i64,
+        next: Option<Gc<Node, SemiSpace>>,
+    }
+
+    unsafe impl Trace for Node {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Node {}
+    impl Collectable for Node {}
+
+    #[test]
+    fn test_semispace_pinning() {
+        let mut mutator = instantiate_semispace(4 * 1024 * 1024);
+        let child = mutator.allocate(
+            Node {
+                value: 1,
+                next: None,
+            },
+            AllocationSpace::New,
+        );
+        let node = mutator.allocate(
+            Node {
+                value: 42,
+                next: Some(child),
+            },
+            AllocationSpace::New,
+        );
+        let pinned = mutator.pin(node);
+        let address = pinned.as_ptr();
+        letroot!(weak = mutator.shadow_stack(), mutator.allocate_weak(node));
+        for _ in 0..4 {
+            for i in 0..1000 {
+                mutator.allocate(
+                    Node {
+                        value: i,
+                        next: None,
+                    },
+                    AllocationSpace::New,
+                );
+            }
+            mutator.collect(&mut []);
+            // pinned object stays in place, objects it references are still moved and updated.
+            assert_eq!(pinned.as_ptr(), address);
+            assert_eq!(weak.upgrade().unwrap().base, pinned.get().base);
+            assert_eq!(pinned.value, 42);
+            assert_eq!(pinned.next.unwrap().value, 1);
+        }
+        assert!(!unsafe { (*pinned.get().base.as_ptr()).pinned_bit() });
+
+        drop(pinned);
+        mutator.collect(&mut []);
+        assert!(weak.upgrade().is_none());
+    }
+}
@@ -17,7 +17,7 @@ use crate::{
     mutator::MutatorRef,
     small_type_id,
     space::MallocSpace,
-    utils::{align_usize, mmap::Mmap},
+    utils::{align_usize, mmap::Mmap, poison},
 };
 use parking_lot::{lock_api::RawMutex, RawMutex as Lock};
 pub struct RosAllocSpace {
@@ -215,7 +215,19 @@ impl RosAllocSpace {
                 }
             }
         }
-        unsafe { (*self.rosalloc).bulk_free(ptrs) }
+        unsafe { self.bulk_free(ptrs) }
+    }
+
+    /// [Poison](crate::utils::poison) dead objects at `ptrs` and free them. Returns number of freed bytes.
+    ///
+    /// # Safety
+    ///
+    /// `ptrs` must point to dead objects allocated in this space.
+    pub unsafe fn bulk_free(&self, ptrs: &[*mut u8]) -> usize {
+        for &ptr in ptrs.iter() {
+            poison(ptr, (*ptr.cast::<HeapObjectHeader>()).size());
+        }
+        (*self.rosalloc).bulk_free(ptrs)
     }
 }
 
@@ -1,14 +1,20 @@
 use std::{
     cell::Cell,
-    sync::atomic::{AtomicBool, AtomicU32},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use atomic::Ordering;
-use parking_lot::{lock_api::RawMutex, RawMutex as Lock};
+use parking_lot::{lock_api::RawMutex, Mutex, RawMutex as Lock};
 
 use crate::{
     gc_base::GcBase,
+    gc_log::GcLogger,
     mutator::{MutatorRef, ThreadState},
+    stress::GcStress,
 };
 
 static SAFEPOINT_VERBOSE: AtomicBool = AtomicBool::new(false);
@@ -22,6 +28,12 @@ pub struct GlobalSafepoint {
     pub(crate) safepoint_enable_cnt: Cell<u8>,
     pub(crate) gc_running: AtomicU32,
     pub(crate) n_mutators: AtomicU32,
+    /// Nanoseconds mutators needed to reach the last safepoint.
+    last_sync: AtomicU64,
+    /// Logger of the heap, see [gc_log](crate::gc_log).
+    logger: Mutex<Option<Arc<GcLogger>>>,
+    /// Encoded [GcStress] of the heap, see [stress](crate::stress).
+    gc_stress: AtomicUsize,
 }
 
 impl GlobalSafepoint {
@@ -31,6 +43,47 @@ impl GlobalSafepoint {
             safepoint_lock: Lock::INIT,
             gc_running: AtomicU32::new(0),
             n_mutators: AtomicU32::new(0),
+            last_sync: AtomicU64::new(0),
+            logger: Mutex::new(None),
+            gc_stress: AtomicUsize::new(GcStress::from_env().encode()),
+        }
+    }
+    /// Returns logger of the heap if heap is verbose or logger was set by user.
+    pub fn logger(&self) -> Option<Arc<GcLogger>> {
+        self.logger.lock().clone()
+    }
+
+    pub fn set_logger(&self, logger: Option<Arc<GcLogger>>) {
+        *self.logger.lock() = logger;
+    }
+    /// Stress mode of the heap, see [stress](crate::stress).
+    pub fn gc_stress(&self) -> GcStress {
+        GcStress::decode(self.gc_stress.load(Ordering::Relaxed))
+    }
+
+    pub fn set_gc_stress(&self, stress: GcStress) {
+        self.gc_stress.store(stress.encode(), Ordering::Relaxed);
+    }
+
+    /// Encoded stress mode, zero if stress mode is disabled.
+    #[inline(always)]
+    pub(crate) fn raw_gc_stress(&self) -> usize {
+        self.gc_stress.load(Ordering::Relaxed)
+    }
+    /// Time mutators needed to reach the last safepoint.
+    pub fn last_sync(&self) -> Duration {
+        Duration::from_nanos(self.last_sync.load(Ordering::Relaxed))
+    }
+
+    fn reached(&self, sync: Duration) {
+        self.last_sync
+            .store(sync.as_nanos() as u64, Ordering::Relaxed);
+        if SAFEPOINT_VERBOSE.load(Ordering::Relaxed) {
+            let mutators = self.n_mutators.load(Ordering::Relaxed) as usize;
+            match self.logger() {
+                Some(logger) => logger.log_safepoint(mutators, sync),
+                None => GcLogger::stderr().log_safepoint(mutators, sync),
+            }
         }
     }
     fn enable(&self) {
@@ -46,12 +99,6 @@ impl GlobalSafepoint {
     }
 
     pub fn start(&self) -> bool {
-        let verbose = SAFEPOINT_VERBOSE.load(Ordering::Relaxed);
-        let start = if verbose {
-            Some(std::time::Instant::now())
-        } else {
-            None
-        };
         self.safepoint_lock.lock();
         let running = 0;
         // In case multiple threads enter the GC at the same time, only allow
@@ -76,13 +123,6 @@ impl GlobalSafepoint {
         unsafe {
             self.safepoint_lock.unlock();
         }
-        if let Some(time) = start.map(|x| x.elapsed()) {
-            eprintln!(
-                "[safepoint] {} mutators reached safepoint in {:.4}ms",
-                self.n_mutators.load(Ordering::Relaxed),
-                time.as_micros() as f64 / 1000.0
-            );
-        }
         true
     }
 
@@ -115,6 +155,7 @@ impl<H: 'static + GcBase> SafepointScope<H> {
     pub fn new_no_mutator(heap: *mut H) -> Self {
         let href = unsafe { &*heap };
         let safepoint = href.safepoint();
+        let time = Instant::now();
         assert!(safepoint.start(), "Failed to create safepoint");
         let this = Self {
             heap,
@@ -143,6 +184,7 @@ impl<H: 'static + GcBase> SafepointScope<H> {
 
             href.global_unlock();
         }
+        safepoint.reached(time.elapsed());
         this
     }
 
@@ -150,6 +192,8 @@ impl<H: 'static + GcBase> SafepointScope<H> {
         let href = unsafe { &*mutator.heap.get() };
         let safepoint = href.safepoint();
         let old_state = mutator.state.load(Ordering::Relaxed);
+        let time = Instant::now();
+        mutator.record_stack_top();
         mutator
             .state
             .store(crate::mutator::ThreadState::Waiting, Ordering::Release);
@@ -185,6 +229,7 @@ impl<H: 'static + GcBase> SafepointScope<H> {
             }
 
             href.global_unlock();
+            href.safepoint().reached(time.elapsed());
         }
         Some(this)
     }
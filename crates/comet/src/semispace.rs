@@ -3,6 +3,9 @@
 //! Simple semi-space garbage collector that separates heap into two spaces: "to space" and "from space". During mutator time
 //! all allocations go to "to space" and when it is full they are swapped and all objects are copied from "from space" to "to space".
 //! If there is no enough memory to copy object process will abort with OOM error.
+//!
+//! [Pinned](crate::pin) objects are not copied: they stay in place and pages they occupy are not used for allocation until
+//! they are unpinned.
 
 use std::{
     cell::UnsafeCell,
@@ -10,20 +13,33 @@ use std::{
     mem::size_of,
     ptr::{null_mut, NonNull},
     sync::Arc,
+    time::Instant,
 };
 
 use crate::{
-    api::{vtable_of, Collectable, Gc, HeapObjectHeader, Trace, Visitor, Weak},
+    api::{
+        pop_ready_finalizer, trace_ephemerons, trace_ordered_finalizers, trace_soft_refs,
+        vtable_of, Collectable, Ephemeron, Gc, HeapObjectHeader, OrderedFinalizer, SoftRef,
+        SoftRefPolicy, Trace, VTable, Visitor, Weak,
+    },
     bump_pointer_space::BumpPointerSpace,
-    gc_base::{AllocationSpace, GcBase, MarkingConstraint, MarkingConstraintRuns, NoReadBarrier},
+    gc_base::{
+        AllocError, AllocationSpace, GcBase, MarkingConstraint, MarkingConstraintRuns,
+        NoReadBarrier, OomHandler, OomHandlerSlot,
+    },
+    gc_log::{GcPhase, GcRecord, PhaseTimer},
     large_space::{LargeObjectSpace, PreciseAllocation},
     make_small_type_id,
-    mutator::{oom_abort, JoinData, Mutator, MutatorRef, ThreadState},
+    mutator::{JoinData, Mutator, MutatorRef, ThreadState},
+    persistent::PersistentRoots,
+    pin::PinnedObjects,
     safepoint::{GlobalSafepoint, SafepointScope},
     small_type_id,
+    stats::{GcKind, GcListener, GcReason, GcStats, HeapStats},
     tlab::{InlineAllocationHelpersForSimpleTLAB, SimpleTLAB},
-    utils::align_usize,
+    utils::{align_down, align_usize},
 };
+use rosalloc::defs::PAGE_SIZE;
 
 use atomic::Ordering;
 use im::Vector;
@@ -39,9 +55,20 @@ pub struct SemiSpace {
     pub(crate) safepoint: GlobalSafepoint,
     pub(crate) mark_stack: Vec<*mut HeapObjectHeader>,
     weak_refs: Vec<Weak<dyn Collectable, Self>>,
+    ephemerons: Vec<Ephemeron<dyn Collectable, dyn Collectable, Self>>,
+    soft_refs: Vec<SoftRef<dyn Collectable, Self>>,
+    soft_ref_policy: SoftRefPolicy,
+    ordered_finalizers: Vec<OrderedFinalizer<Self>>,
+    persistent_roots: Arc<PersistentRoots<Self>>,
+    pinned_objects: Arc<PinnedObjects<Self>>,
+    /// Pinned objects that were left in place by the last collection.
+    retained: Vec<*mut HeapObjectHeader>,
     constraints: Vec<Box<dyn MarkingConstraint>>,
     finalize_list: Vector<*mut HeapObjectHeader>,
     finalize_lock: Lock,
+    oom_handler: OomHandlerSlot<Self>,
+    gc_stats: GcStats,
+    total_gcs: usize,
 }
 
 pub fn instantiate_semispace(semispace_size: usize) -> MutatorRef<SemiSpace> {
@@ -58,6 +85,16 @@ pub fn instantiate_semispace(semispace_size: usize) -> MutatorRef<SemiSpace> {
         from_space: BumpPointerSpace::new(semispace_size),
         to_space: BumpPointerSpace::new(semispace_size),
         weak_refs: vec![],
+        ephemerons: vec![],
+        soft_refs: vec![],
+        soft_ref_policy: SoftRefPolicy::new(),
+        ordered_finalizers: vec![],
+        persistent_roots: Arc::new(PersistentRoots::new()),
+        pinned_objects: Arc::new(PinnedObjects::new()),
+        retained: Vec::new(),
+        oom_handler: OomHandlerSlot::new(),
+        gc_stats: GcStats::new("SemiSpace"),
+        total_gcs: 0,
     }));
 
     let href = unsafe { &mut *heap.get() };
@@ -77,6 +114,10 @@ pub fn instantiate_semispace(semispace_size: usize) -> MutatorRef<SemiSpace> {
 }
 
 impl SemiSpace {
+    fn allocated_bytes(&self) -> usize {
+        self.to_space.allocated() + self.large_space.bytes
+    }
+
     unsafe fn after_mark_constraints(&mut self) {
         let this = self as *mut Self;
         (*this).constraints.retain_mut(|constraint| {
@@ -103,6 +144,46 @@ impl SemiSpace {
             }
         });
     }
+    /// New location of `object` or null if object is dead. Must be invoked after marking.
+    unsafe fn forwardee(&self, object: *mut HeapObjectHeader) -> *mut HeapObjectHeader {
+        if !self.from_space.contains(object.cast()) && (*object).is_precise() {
+            if (*PreciseAllocation::from_cell(object)).is_marked() {
+                object
+            } else {
+                null_mut()
+            }
+        } else if (*object).is_forwarded() {
+            (*object).vtable() as _
+        } else if (*object).pinned_bit() && (*object).marked_bit() {
+            object
+        } else {
+            null_mut()
+        }
+    }
+    /// Exclude pages of objects retained by this collection from allocation in "from space" which becomes "to space" in the
+    /// next cycle. Reserved pages are not poisoned when "from space" is reset.
+    unsafe fn reserve_retained(&mut self) {
+        let mut ranges: Vec<(*mut u8, *mut u8)> = Vec::new();
+        for &object in self.retained.iter() {
+            if !self.from_space.contains(object.cast()) {
+                continue;
+            }
+            let start = align_down(object as usize, PAGE_SIZE) as *mut u8;
+            let end = align_usize(object as usize + (*object).size(), PAGE_SIZE) as *mut u8;
+            ranges.push((start, end));
+        }
+        ranges.sort_unstable();
+        ranges.dedup_by(|next, prev| {
+            if next.0 <= prev.1 {
+                prev.1 = prev.1.max(next.1);
+                true
+            } else {
+                false
+            }
+        });
+        self.from_space.set_reserved(ranges);
+    }
+
     fn trace(&mut self, root: &mut NonNull<HeapObjectHeader>) {
         unsafe {
             let object = root.as_ptr();
@@ -110,12 +191,19 @@ impl SemiSpace {
                 if !(*PreciseAllocation::from_cell(object)).test_and_set_marked() {
                     self.mark_stack.push(object);
                 }
+            } else if (*object).pinned_bit() {
+                // pinned objects are not copied, mark bit is used to trace them only once.
+                if !(*object).marked_bit() {
+                    (*object).set_marked_bit();
+                    self.mark_stack.push(object);
+                }
             } else if (*object).is_forwarded() {
                 *root = NonNull::new_unchecked((*object).vtable() as _);
             } else {
-                let size = (*object).size();
+                let size = (*object).copy_size();
                 let mem = self.to_space.bump_alloc(size);
-                core::ptr::copy_nonoverlapping(object.cast::<u8>(), mem, size);
+                core::ptr::copy_nonoverlapping(object.cast::<u8>(), mem, (*object).size());
+                (*mem.cast::<HeapObjectHeader>()).finish_copy(object);
                 (*object).set_forwarded(mem as _);
                 *root = NonNull::new_unchecked(mem as _);
 
@@ -160,63 +248,146 @@ impl GcBase for SemiSpace {
         }
         weak_ref
     }
+    fn allocate_soft<T: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        value: Gc<T, Self>,
+    ) -> SoftRef<T, Self> {
+        let soft_ref = unsafe { SoftRef::create(mutator, value) };
+        self.global_heap_lock.lock();
+        self.soft_refs.push(soft_ref.to_dyn());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        soft_ref
+    }
+    fn set_soft_ref_lru_policy(&mut self, ms_per_mb: u64) {
+        self.global_heap_lock.lock();
+        self.soft_ref_policy.set_ms_per_mb(ms_per_mb);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn allocate_ephemeron<K: Collectable + ?Sized, V: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        key: Gc<K, Self>,
+        value: Gc<V, Self>,
+    ) -> Ephemeron<K, V, Self> {
+        let ephemeron = unsafe { Ephemeron::create(mutator, key, value) };
+        self.global_heap_lock.lock();
+        self.ephemerons.push(ephemeron.to_dyn());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        ephemeron
+    }
+    fn persistent_roots(&self) -> Arc<PersistentRoots<Self>> {
+        self.persistent_roots.clone()
+    }
+    fn pinned_objects(&self) -> Arc<PinnedObjects<Self>> {
+        self.pinned_objects.clone()
+    }
+    fn stats(&self) -> HeapStats {
+        self.gc_stats
+            .snapshot(self.allocated_bytes(), 2 * self.to_space.size())
+    }
+    fn add_gc_listener(&mut self, listener: Box<dyn GcListener>) {
+        self.gc_stats.add_listener(listener);
+    }
+    fn register_ordered_finalizer(&mut self, finalizer: OrderedFinalizer<Self>) {
+        self.global_heap_lock.lock();
+        self.ordered_finalizers.push(finalizer);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn pop_ready_finalizer(&mut self) -> Option<OrderedFinalizer<Self>> {
+        self.global_heap_lock.lock();
+        let finalizer = pop_ready_finalizer(&mut self.ordered_finalizers);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        finalizer
+    }
     fn alloc_tlab_area(&mut self, _mutator: &MutatorRef<Self>, _size: usize) -> *mut u8 {
         let memory = self.to_space.bump_alloc(32 * 1024);
         memory
     }
     #[inline]
-    fn allocate_raw(
+    fn try_allocate_raw(
         &mut self,
         mutator: &mut MutatorRef<Self>,
         size: usize,
         type_id: std::any::TypeId,
         vtable: usize,
-    ) -> *mut HeapObjectHeader {
+    ) -> Result<*mut HeapObjectHeader, AllocError<()>> {
         let size = align_usize(size + size_of::<HeapObjectHeader>(), 8);
         let mut memory = self.to_space.bump_alloc(size);
         if memory.is_null() {
-            self.collect(mutator, &mut []);
+            self.collect_alloc_failure(mutator, &mut []);
             memory = self.to_space.bump_alloc(size);
             if memory.is_null() {
-                oom_abort();
+                return Err(AllocError::new((), size, AllocationSpace::New));
             }
         }
 
         unsafe {
             let hdr = memory.cast::<HeapObjectHeader>();
+            hdr.write(HeapObjectHeader {
+                type_id: make_small_type_id(type_id),
+                padding: 0,
+                padding2: 0,
+                value: VTable { raw: 0 },
+            });
             (*hdr).set_vtable(vtable);
             (*hdr).set_size(size);
-            (*hdr).type_id = make_small_type_id(type_id);
             let val: Gc<(), Self> = Gc {
                 base: NonNull::new_unchecked(hdr),
                 marker: Default::default(),
             };
             self.post_alloc(val);
-            hdr
+            Ok(hdr)
+        }
+    }
+    fn set_oom_handler(&mut self, handler: OomHandler<Self>) {
+        self.oom_handler.set(handler);
+    }
+    fn handle_oom(&mut self, mutator: &mut MutatorRef<Self>, size: usize) -> bool {
+        // emergency collection: clear all soft references before giving up.
+        if !self.soft_refs.is_empty() && self.soft_ref_policy.request_clear_all() {
+            self.full_collection(mutator, &mut []);
+            return true;
         }
+        self.oom_handler.invoke(mutator, size)
     }
     #[inline]
-    fn alloc_inline<T: crate::api::Collectable + Sized + 'static>(
+    fn try_alloc_inline<T: crate::api::Collectable + Sized + 'static>(
         &mut self,
         mutator: &mut MutatorRef<Self>,
         mut value: T,
-        _: AllocationSpace,
-    ) -> crate::api::Gc<T, Self> {
+        space: AllocationSpace,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
         let size = align_usize(value.allocation_size() + size_of::<HeapObjectHeader>(), 8);
         let mut memory = self.to_space.bump_alloc(size);
         if memory.is_null() {
-            self.collect(mutator, &mut [&mut value]);
+            self.collect_alloc_failure(mutator, &mut [&mut value]);
             memory = self.to_space.bump_alloc(size);
             if memory.is_null() {
-                oom_abort();
+                return Err(AllocError::new(value, size, space));
             }
         }
 
         unsafe {
             let hdr = memory.cast::<HeapObjectHeader>();
+            hdr.write(HeapObjectHeader {
+                type_id: small_type_id::<T>(),
+                padding: 0,
+                padding2: 0,
+                value: VTable { raw: 0 },
+            });
             (*hdr).set_metadata(vtable_of::<T>());
             (*hdr).set_size(size);
-            (*hdr).type_id = small_type_id::<T>();
             ((*hdr).data() as *mut T).write(value);
 
             let val = Gc {
@@ -224,7 +395,7 @@ impl GcBase for SemiSpace {
                 marker: Default::default(),
             };
             self.post_alloc(val);
-            val
+            Ok(val)
         }
     }
     fn safepoint(&self) -> &GlobalSafepoint {
@@ -238,7 +409,7 @@ impl GcBase for SemiSpace {
         unsafe { self.global_heap_lock.unlock() };
     }
 
-    fn detach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
+    unsafe fn detach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
         self.global_heap_lock.lock();
 
         let mut detached = false;
@@ -274,15 +445,25 @@ impl GcBase for SemiSpace {
         &self.mutators
     }
 
-    fn allocate_large<T: Collectable + Sized + 'static>(
+    fn try_allocate_large<T: Collectable + Sized + 'static>(
         &mut self,
-        _mutator: &mut MutatorRef<Self>,
-        value: T,
-    ) -> crate::api::Gc<T, Self> {
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
         unsafe {
             let size = value.allocation_size() + size_of::<HeapObjectHeader>();
             self.large_space_lock.lock();
-            let object = self.large_space.allocate(size);
+            let mut object = self.large_space.try_allocate(size);
+            if object.is_null() {
+                self.large_space_lock.unlock();
+                self.collect_alloc_failure(mutator, &mut [&mut value]);
+                self.large_space_lock.lock();
+                object = self.large_space.try_allocate(size);
+                if object.is_null() {
+                    self.large_space_lock.unlock();
+                    return Err(AllocError::new(value, size, AllocationSpace::Large));
+                }
+            }
             (*object).set_metadata(vtable_of::<T>());
             (*object).type_id = small_type_id::<T>();
             let gc = Gc {
@@ -292,16 +473,41 @@ impl GcBase for SemiSpace {
             ((*object).data() as *mut T).write(value);
             self.large_space_lock.unlock();
             self.post_alloc(gc);
-            gc
+            Ok(gc)
         }
     }
-    fn collect(&mut self, mutator: &mut MutatorRef<Self>, mut keep: &mut [&mut dyn Trace]) {
+    fn collect(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        self.perform_collection(mutator, keep, GcReason::RequestedByUser);
+    }
+    fn collect_alloc_failure(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        keep: &mut [&mut dyn Trace],
+    ) {
+        self.perform_collection(mutator, keep, GcReason::AllocationFailure);
+    }
+}
+
+impl SemiSpace {
+    fn perform_collection(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        mut keep: &mut [&mut dyn Trace],
+        reason: GcReason,
+    ) {
         match SafepointScope::new(mutator.clone()) {
             Some(safepoint) => {
+                let time = Instant::now();
+                let mut timer = PhaseTimer::new(self.safepoint.last_sync());
                 self.global_heap_lock.lock();
                 self.large_space_lock.lock();
+                let prev = self.allocated_bytes();
+                let cycle = self.gc_stats.start_cycle(GcKind::Major, reason, prev);
 
+                self.soft_ref_policy
+                    .begin_cycle(self.from_space.allocated(), self.from_space.size());
                 std::mem::swap(&mut self.from_space, &mut self.to_space);
+                let pinned = unsafe { self.pinned_objects.set_pinned_bits() };
                 //self.to_space.commit();
                 self.large_space.prepare_for_marking(false);
                 unsafe {
@@ -319,7 +525,13 @@ impl GcBase for SemiSpace {
                         });
                     }
                 }
+                let this = self as *mut Self;
+                unsafe {
+                    (*this).persistent_roots.trace(&mut *this);
+                    (*this).pinned_objects.trace(&mut *this);
+                }
                 keep.trace(self);
+                timer.end(GcPhase::RootScan);
 
                 while let Some(object) = self.mark_stack.pop() {
                     unsafe {
@@ -328,37 +540,128 @@ impl GcBase for SemiSpace {
                 }
                 unsafe {
                     self.after_mark_constraints();
+                    trace_soft_refs(
+                        &(*this).soft_refs,
+                        &(*this).soft_ref_policy,
+                        |object| (*this).forwardee(object),
+                        |slot| (*this).mark_object(slot),
+                        || {
+                            while let Some(object) = (*this).mark_stack.pop() {
+                                (*object).get_dyn().trace(&mut *this);
+                            }
+                        },
+                    );
+                    trace_ephemerons(
+                        &(*this).ephemerons,
+                        |object| (*this).forwardee(object),
+                        |slot| (*this).mark_object(slot),
+                        || {
+                            while let Some(object) = (*this).mark_stack.pop() {
+                                (*object).get_dyn().trace(&mut *this);
+                            }
+                        },
+                    );
+                    if trace_ordered_finalizers(
+                        &mut (*this).ordered_finalizers,
+                        |object| (*this).forwardee(object),
+                        |slot| (*this).mark_object(slot),
+                        || {
+                            while let Some(object) = (*this).mark_stack.pop() {
+                                (*object).get_dyn().trace(&mut *this);
+                            }
+                        },
+                    ) {
+                        trace_ephemerons(
+                            &(*this).ephemerons,
+                            |object| (*this).forwardee(object),
+                            |slot| (*this).mark_object(slot),
+                            || {
+                                while let Some(object) = (*this).mark_stack.pop() {
+                                    (*object).get_dyn().trace(&mut *this);
+                                }
+                            },
+                        );
+                    }
                 }
-                self.finalize_list.retain(|x| unsafe {
-                    let object = *x;
-                    if (*object).is_forwarded() {
-                        return true;
+                timer.end(GcPhase::Mark);
+                // live objects are moved, so list is rebuilt with their new locations.
+                for object in std::mem::replace(&mut self.finalize_list, Vector::new()) {
+                    unsafe {
+                        let forwardee = (*this).forwardee(object);
+                        if forwardee.is_null() {
+                            (*object).get_dyn().finalize();
+                        } else {
+                            self.finalize_list.push_back(forwardee);
+                        }
                     }
-                    (*object).get_dyn().finalize();
-                    false
-                });
+                }
+                timer.end(GcPhase::Finalization);
                 self.weak_refs.retain_mut(|object| unsafe {
-                    let header = object.base();
-                    if (*header).is_forwarded() {
-                        let header = (*header).vtable() as *mut HeapObjectHeader;
+                    let header = (*this).forwardee(object.base());
+                    if header.is_null() {
+                        false
+                    } else {
                         object.set_base(header);
-                        object.after_mark(|object| {
-                            if (*object).is_forwarded() {
-                                (*object).vtable() as _
-                            } else {
-                                null_mut()
-                            }
-                        });
-
+                        object.after_mark(|object| (*this).forwardee(object));
                         true
+                    }
+                });
+                self.soft_refs.retain_mut(|soft_ref| unsafe {
+                    let header = (*this).forwardee(soft_ref.base());
+                    if header.is_null() {
+                        false
                     } else {
+                        soft_ref.set_base(header);
+                        soft_ref.after_mark(|object| (*this).forwardee(object));
+                        true
+                    }
+                });
+                self.ephemerons.retain_mut(|ephemeron| unsafe {
+                    let header = (*this).forwardee(ephemeron.base());
+                    if header.is_null() {
                         false
+                    } else {
+                        ephemeron.set_base(header);
+                        ephemeron.after_mark(|key| (*this).forwardee(key));
+                        true
                     }
                 });
+                timer.end(GcPhase::WeakProcessing);
+                self.retained.clear();
+                for object in pinned {
+                    unsafe {
+                        if (*object).marked_bit() {
+                            self.retained.push(object);
+                        }
+                        (*object).unmark();
+                        (*object).set_pinned_bit(false);
+                    }
+                }
                 self.large_space.sweep();
                 self.large_space.prepare_for_allocation(false);
+                unsafe {
+                    self.reserve_retained();
+                }
                 self.from_space.reset();
                 //self.from_space.decommit();
+                timer.end(GcPhase::Sweep);
+                let bytes_allocated = self.allocated_bytes();
+                self.gc_stats.end_cycle(cycle, bytes_allocated);
+                if let Some(logger) = self.safepoint.logger() {
+                    logger.log_gc(&GcRecord {
+                        policy: "SemiSpace",
+                        gc_id: self.total_gcs,
+                        kind: GcKind::Major,
+                        reason,
+                        heap_before: prev,
+                        heap_after: bytes_allocated,
+                        footprint: 2 * self.to_space.size(),
+                        duration: time.elapsed(),
+                        phases: timer.phases(),
+                        extra: &[],
+                    });
+                }
+                self.total_gcs += 1;
                 drop(safepoint);
                 unsafe {
                     self.global_heap_lock.unlock();
@@ -375,3 +678,156 @@ impl Visitor for SemiSpace {
         self.trace(root);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{instantiate_semispace, SemiSpace};
+    use crate::api::{FinalizationRegistry, Reference, ReferenceQueue};
+    use crate::gc_base::AllocationSpace;
+    use crate::handle_scope::{EscapableHandleScope, Handle, HandleScope};
+    use crate::mutator::MutatorRef;
+    use crate::persistent::Persistent;
+
+    fn heap() -> MutatorRef<SemiSpace> {
+        instantiate_semispace(4 * 1024 * 1024)
+    }
+
+    #[test]
+    fn test_soft_refs() {
+        let mut mutator = heap();
+
+        let value = mutator.allocate(42, AllocationSpace::New);
+        let address = value.base.as_ptr();
+        letroot!(soft = mutator.shadow_stack(), mutator.allocate_soft(value));
+
+        // heap is almost empty, recently used referent is kept alive and soft reference points to its new location.
+        mutator.collect(&mut []);
+        let value = soft.upgrade().unwrap();
+        assert_ne!(value.base.as_ptr(), address);
+        assert_eq!(*value, 42);
+
+        mutator.set_soft_ref_lru_policy(0);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        mutator.collect(&mut []);
+        assert!(soft.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_reference_queue() {
+        let mut mutator = heap();
+
+        letroot!(
+            queue = mutator.shadow_stack(),
+            ReferenceQueue::new(&mut mutator)
+        );
+        let value = mutator.allocate(42, AllocationSpace::New);
+        letroot!(
+            weak = mutator.shadow_stack(),
+            mutator.allocate_weak_with_queue(value, *queue)
+        );
+        let address = unsafe { weak.base() };
+        assert!(queue.is_empty());
+
+        // queue and reference are moved, enqueued reference must point to the new location of reference object.
+        mutator.collect(&mut []);
+
+        assert_ne!(unsafe { weak.base() }, address);
+        assert!(weak.upgrade().is_none());
+        match queue.poll() {
+            Some(Reference::Weak(cleared)) => assert!(cleared.ptr_eq(*weak)),
+            _ => panic!("cleared weak reference is not enqueued"),
+        }
+        assert!(queue.poll().is_none());
+    }
+
+    #[test]
+    fn test_finalization_registry() {
+        let mut mutator = heap();
+
+        letroot!(
+            registry = mutator.shadow_stack(),
+            FinalizationRegistry::new(&mut mutator)
+        );
+        letroot!(
+            live = mutator.shadow_stack(),
+            mutator.allocate(1, AllocationSpace::New)
+        );
+        let held = mutator.allocate(2, AllocationSpace::New);
+        registry.register(&mut mutator, *live, held);
+        let dead = mutator.allocate(3, AllocationSpace::New);
+        let held = mutator.allocate(4, AllocationSpace::New);
+        registry.register(&mut mutator, dead, held);
+
+        // held values are moved together with registry.
+        mutator.collect(&mut []);
+        mutator.collect(&mut []);
+
+        let held = registry
+            .poll()
+            .expect("held value of dead target is not queued");
+        assert_eq!(*unsafe { held.downcast_unchecked::<i32>() }, 4);
+        assert!(registry.poll().is_none());
+        assert_eq!(registry.len(), 1);
+        assert_eq!(**live, 1);
+    }
+
+    #[test]
+    fn test_persistent_roots() {
+        let mut mutator = heap();
+
+        let value = mutator.allocate(42, AllocationSpace::New);
+        let address = value.base.as_ptr();
+        let persistent = Persistent::new(&mutator, value);
+        letroot!(
+            weak = mutator.shadow_stack(),
+            mutator.allocate_weak(persistent.get())
+        );
+        // persistent root must point to the new location of object after every collection.
+        mutator.collect(&mut []);
+        assert_ne!(persistent.get().base.as_ptr(), address);
+        mutator.collect(&mut []);
+        assert_eq!(*persistent.get(), 42);
+
+        let other = persistent.clone();
+        std::thread::spawn(move || drop(persistent)).join().unwrap();
+        mutator.collect(&mut []);
+        assert_eq!(*other.get(), 42);
+        assert!(weak.upgrade().is_some());
+
+        drop(other);
+        mutator.collect(&mut []);
+        assert!(weak.upgrade().is_none());
+    }
+
+    fn allocate_escaped<'p>(
+        mutator: &mut MutatorRef<SemiSpace>,
+        parent: &'p HandleScope<SemiSpace>,
+    ) -> Handle<'p, i32, SemiSpace> {
+        let scope = EscapableHandleScope::new(parent);
+        let value = scope.handle(mutator.allocate(42, AllocationSpace::New));
+        mutator.collect(&mut []);
+        scope.escape(value)
+    }
+
+    #[test]
+    fn test_handle_scopes() {
+        let mut mutator = heap();
+
+        let scope = mutator.handle_scope();
+        let value = allocate_escaped(&mut mutator, &scope);
+        assert_eq!(scope.len(), 1);
+        // handles must point to the new location of object after every collection.
+        let address = value.get().base.as_ptr();
+        mutator.collect(&mut []);
+        assert_ne!(value.get().base.as_ptr(), address);
+        assert_eq!(*value, 42);
+
+        letroot!(
+            weak = mutator.shadow_stack(),
+            mutator.allocate_weak(value.get())
+        );
+        drop(scope);
+        mutator.collect(&mut []);
+        assert!(weak.upgrade().is_none());
+    }
+}
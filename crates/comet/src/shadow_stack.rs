@@ -1,18 +1,22 @@
 use crate::api::*;
+use crate::handle_scope::HandleArena;
 
-/// Shadow stack implementation. Internally this is singly-linked list of on stack rooted values.
+/// Shadow stack implementation. Internally this is singly-linked list of on stack rooted values and arena of
+/// [handles](crate::handle_scope::Handle).
 pub struct ShadowStack {
     #[doc(hidden)]
     pub head: core::cell::Cell<*mut RawShadowStackEntry>,
+    pub(crate) handles: HandleArena,
 }
 impl ShadowStack {
     /// Create new shadow stack instance.
     pub fn new() -> Self {
         Self {
             head: core::cell::Cell::new(core::ptr::null_mut()),
+            handles: HandleArena::new(),
         }
     }
-    /// Walk all rooted values in this shadow stack.
+    /// Walk all rooted values and handles in this shadow stack.
     ///
     /// # Safety
     /// TODO: I don't really know if this method should be safe or unsafe.
@@ -24,6 +28,7 @@ impl ShadowStack {
             visitor((*head).get_dyn());
             head = next;
         }
+        self.handles.walk(visitor);
     }
 }
 /// Raw entry in GC shadow stack. Internal fields is not exposed in public API in any ways.
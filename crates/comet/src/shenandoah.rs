@@ -1,5 +1,48 @@
+//! # Shenandoah
+//!
+//! Region based concurrent compacting collector modeled after [Shenandoah](https://wiki.openjdk.java.net/display/shenandoah) from OpenJDK.
+//! Heap is divided into equally sized regions, small objects are bump allocated in mutator TLABs carved out of regions and objects
+//! that do not fit into a single region ("humongous" objects) occupy several contiguous regions.
+//!
+//! Every object is preceded by Brooks forwarding pointer that points to the object itself or to its evacuated copy.
+//!
+//! ## GC cycle
+//!
+//! Cycle is started by mutator when [heuristics](heuristics) decide that it is time to collect, it consists of the following phases:
+//! - Init Mark (pause): mutator roots are scanned, after that GC thread is started and mutators are resumed.
+//! - Concurrent Mark: GC thread marks heap, write barrier re-greys black objects exactly like in [CMS](crate::cms).
+//! - Final Mark (pause): marking is finished, weak references and finalizers are processed, regions without live objects are reclaimed
+//!   right away and collection set is selected.
+//! - Concurrent Evacuation: live objects from collection set are copied to free regions.
+//! - Init Update Refs (pause): evacuation is finished.
+//! - Concurrent Update Refs: references to evacuated objects in the heap are updated.
+//! - Final Update Refs (pause): roots are updated and collection set regions are recycled.
+//!
+//! GC thread runs only concurrent phases and gets only the part of the heap state it needs. When it finishes a phase, the next
+//! pause is performed by the first mutator that reaches allocation slow path or waits for the cycle.
+//!
+//! ## Barriers
+//!
+//! Shenandoah requires both read and write barriers. Read barrier ([ShenandoahBarrier](barrier::ShenandoahBarrier)) resolves Brooks pointer
+//! and evacuates object itself if it is in collection set and is not yet evacuated, so mutators never access from-space copies.
+//! Write barrier must be invoked after every store into heap object: during marking it is retreating wavefront barrier,
+//! during evacuation and update-refs it evacuates and updates references in the object.
+//!
+//! ## Degenerated and Full GC
+//!
+//! When mutator fails to allocate while concurrent cycle is running, cycle is cancelled: GC thread stops its phase and mutator finishes
+//! the cycle in one pause ("degenerated" cycle). If GC runs out of memory during evacuation or degenerated cycle does not
+//! free enough memory, Full GC is performed: stop-the-world mark-compact that slides live objects to the start of the heap.
+//! [collect](crate::gc_base::GcBase::collect) always performs Full GC.
+//!
+//! ## Usage
+//! ```rust,ignore
+//! let mut mutator = instantiate_shenandoah::<ShenandoahStaticHeuristics>(ShenandoahHeapOptions::default());
+//! ```
+pub mod barrier;
 pub mod collection_set;
 pub mod free_set;
 pub mod heap;
 pub mod heuristics;
+pub mod marker;
 pub mod region;
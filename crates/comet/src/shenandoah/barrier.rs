@@ -0,0 +1,149 @@
+//! Shenandoah read barrier.
+//!
+//! Every object is preceded by Brooks forwarding pointer, read barrier simply loads it through [BrooksPointer]. While heap evacuates
+//! collection set mutators must not read or write from-space copy of an object that is not yet evacuated: GC thread might be copying it
+//! at the same time and the write would be lost. So when evacuation is in progress read barrier takes slow path and evacuates object itself.
+//!
+//! Read barrier does not have access to the heap, so each heap publishes its [EvacuationState] in global lock-free list that
+//! read barrier searches for heap of the object. Objects are evacuated through [Shared] state of the heap, the same state
+//! that GC thread uses.
+
+use std::{
+    marker::PhantomData,
+    ptr::{null_mut, NonNull},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+
+use super::{
+    heap::{Shared, ShenandoahHeap},
+    heuristics::ShenandoahHeuristics,
+};
+use crate::{
+    api::{Collectable, Gc, HeapObjectHeader},
+    gc_base::{BrooksPointer, ReadBarrier},
+};
+
+/// Brooks pointer is tagged with this bit when object failed to evacuate and stays in from-space.
+pub const EVACUATION_FAILED_TAG: usize = 1;
+
+/// Number of heaps that are evacuating objects right now, read barrier takes slow path only if it is not zero.
+static NUM_EVACUATING: AtomicUsize = AtomicUsize::new(0);
+/// Head of the list of evacuation states of all heaps, the most recently created heap is first.
+static HEAPS: AtomicPtr<EvacuationState> = AtomicPtr::new(null_mut());
+
+/// Evacuation state of a heap that is visible to read barrier.
+///
+/// States are never freed: state of dropped heap stays in the list with null shared state pointer. Memory of dropped heap can be reused
+/// only by heap that is created later and its state precedes the stale one in the list.
+pub struct EvacuationState {
+    start: usize,
+    end: usize,
+    shared: AtomicUsize,
+    /// Set while heap evacuates collection set.
+    evacuating: AtomicBool,
+    next: *mut EvacuationState,
+}
+
+impl EvacuationState {
+    /// Publish state of heap located at `[start, end)`. Pointer to [Shared] state of the heap is set later by
+    /// [set_shared](Self::set_shared).
+    pub(super) fn register(start: *mut u8, end: *mut u8) -> &'static Self {
+        let state = Box::leak(Box::new(Self {
+            start: start as usize,
+            end: end as usize,
+            shared: AtomicUsize::new(0),
+            evacuating: AtomicBool::new(false),
+            next: null_mut(),
+        }));
+        let mut head = HEAPS.load(Ordering::Acquire);
+        loop {
+            state.next = head;
+            match HEAPS.compare_exchange_weak(head, state, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+        state
+    }
+
+    pub(super) fn set_shared(&self, shared: *const Shared) {
+        self.shared.store(shared as usize, Ordering::Release);
+    }
+
+    /// Returns `true` if heap evacuates collection set.
+    #[inline]
+    pub fn is_evacuating(&self) -> bool {
+        self.evacuating.load(Ordering::Acquire)
+    }
+
+    /// Start or finish evacuation. Must be invoked when all mutators are stopped.
+    pub(super) fn set_evacuating(&self, evacuating: bool) {
+        if self.evacuating.swap(evacuating, Ordering::AcqRel) != evacuating {
+            if evacuating {
+                NUM_EVACUATING.fetch_add(1, Ordering::AcqRel);
+            } else {
+                NUM_EVACUATING.fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+    }
+
+    /// Mark state of dropped heap as stale.
+    pub(super) fn retire(&self) {
+        self.set_evacuating(false);
+        self.shared.store(0, Ordering::Release);
+    }
+}
+
+/// Load Brooks pointer of `object` and strip evacuation failure tag.
+#[inline(always)]
+pub fn resolve(object: *mut HeapObjectHeader) -> *mut HeapObjectHeader {
+    unsafe {
+        let forwardee = (*brooks_pointer(object)).load(Ordering::Acquire);
+        (forwardee & !EVACUATION_FAILED_TAG) as _
+    }
+}
+
+/// Brooks pointer slot of `object`.
+#[inline(always)]
+pub fn brooks_pointer(object: *mut HeapObjectHeader) -> *const AtomicUsize {
+    unsafe { object.cast::<AtomicUsize>().sub(1) }
+}
+
+/// Read barrier of [ShenandoahHeap]. Resolves Brooks pointer and evacuates objects from collection set when evacuation
+/// is in progress.
+pub struct ShenandoahBarrier;
+
+impl<H: ShenandoahHeuristics> ReadBarrier<ShenandoahHeap<H>> for ShenandoahBarrier {
+    #[inline(always)]
+    fn read_barrier<T: Collectable + ?Sized>(
+        x: Gc<T, ShenandoahHeap<H>>,
+    ) -> Gc<T, ShenandoahHeap<H>> {
+        let x = <BrooksPointer as ReadBarrier<ShenandoahHeap<H>>>::read_barrier(x);
+        let mut object = (x.base.as_ptr() as usize & !EVACUATION_FAILED_TAG) as *mut HeapObjectHeader;
+        if NUM_EVACUATING.load(Ordering::Relaxed) != 0 {
+            object = evacuate_slow(object);
+        }
+        Gc {
+            base: unsafe { NonNull::new_unchecked(object) },
+            marker: PhantomData,
+        }
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn evacuate_slow(object: *mut HeapObjectHeader) -> *mut HeapObjectHeader {
+    let addr = object as usize;
+    let mut state = HEAPS.load(Ordering::Acquire);
+    while let Some(current) = unsafe { state.as_ref() } {
+        if addr >= current.start && addr < current.end {
+            if !current.is_evacuating() {
+                return object;
+            }
+            let shared = current.shared.load(Ordering::Acquire) as *const Shared;
+            return unsafe { (*shared).evacuate_if_in_cset(object, None) };
+        }
+        state = current.next;
+    }
+    object
+}
@@ -1 +1,89 @@
-pub struct ShenandoahCollectionSet {}
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bit_vec::BitVec;
+
+use super::region::ShenandoahHeapRegion;
+
+/// Set of regions that are evacuated in current GC cycle.
+pub struct ShenandoahCollectionSet {
+    cset_map: BitVec,
+    regions: Vec<usize>,
+    garbage: usize,
+    live_data: usize,
+    used: usize,
+    current_index: AtomicUsize,
+}
+
+impl ShenandoahCollectionSet {
+    pub fn new(num_regions: usize) -> Self {
+        Self {
+            cset_map: BitVec::from_elem(num_regions, false),
+            regions: vec![],
+            garbage: 0,
+            live_data: 0,
+            used: 0,
+            current_index: AtomicUsize::new(0),
+        }
+    }
+
+    /// Add region to collection set and move it to [CSet](super::region::RegionState::CSet) state.
+    pub fn add_region(&mut self, region: &mut ShenandoahHeapRegion) {
+        debug_assert!(!self.is_in(region.index()));
+        region.make_cset();
+        self.cset_map.set(region.index(), true);
+        self.regions.push(region.index());
+        self.garbage += region.garbage();
+        self.live_data += region.live_data();
+        self.used += region.used();
+    }
+
+    /// Remove all regions from collection set. Region states are not changed.
+    pub fn clear(&mut self) {
+        self.cset_map.clear();
+        self.regions.clear();
+        self.garbage = 0;
+        self.live_data = 0;
+        self.used = 0;
+        self.current_index.store(0, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn is_in(&self, index: usize) -> bool {
+        self.cset_map[index]
+    }
+
+    pub fn count(&self) -> usize {
+        self.regions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    pub fn garbage(&self) -> usize {
+        self.garbage
+    }
+
+    pub fn live_data(&self) -> usize {
+        self.live_data
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Indices of regions in collection set.
+    pub fn regions(&self) -> &[usize] {
+        &self.regions
+    }
+
+    /// Claim next region for evacuation. Returns `None` when all regions are claimed.
+    pub fn claim_next(&self) -> Option<usize> {
+        let index = self.current_index.fetch_add(1, Ordering::Relaxed);
+        self.regions.get(index).copied()
+    }
+
+    pub fn clear_current_index(&self) {
+        self.current_index.store(0, Ordering::Relaxed);
+    }
+}
@@ -1,16 +1,317 @@
-use crate::bitmap::SpaceBitmap;
+use std::ptr::null_mut;
 
+use bit_vec::BitVec;
+
+use super::region::ShenandoahHeapRegion;
+
+/// Type of allocation request.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AllocType {
+    /// Mutator TLAB refill.
+    Tlab,
+    /// GC thread LAB refill.
+    Gclab,
+    /// Mutator allocation that does not fit into TLAB.
+    Shared,
+    /// Evacuation of single object outside of GC LAB (e.g by mutator in read barrier).
+    SharedGc,
+}
+
+impl AllocType {
+    pub fn is_mutator_alloc(self) -> bool {
+        matches!(self, Self::Tlab | Self::Shared)
+    }
+    pub fn is_gc_alloc(self) -> bool {
+        !self.is_mutator_alloc()
+    }
+    pub fn is_lab_alloc(self) -> bool {
+        matches!(self, Self::Tlab | Self::Gclab)
+    }
+}
+
+/// Regions with less free space than this are retired from free set.
+pub const MIN_FREE_SPACE: usize = 2 * 1024;
+
+/// Free set tracks regions that are available for allocation. Regions are split into two views: mutator view
+/// which is used for TLABs and shared allocations and collector view which is reserved for evacuation so
+/// that GC does not run out of memory while mutators allocate.
 pub struct ShenandoahFreeSet {
-    mutator_free_bitmap: SpaceBitmap<4096>,
-    collector_free_bitmap: SpaceBitmap<4096>,
+    mutator_free_bitmap: BitVec,
+    collector_free_bitmap: BitVec,
 
     max: usize,
     mutator_leftmost: usize,
     mutator_rightmost: usize,
     collector_leftmost: usize,
     collector_rightmost: usize,
+    /// Free bytes in mutator view at the time of the last rebuild.
     capacity: usize,
+    /// Bytes allocated (or retired) from mutator view since the last rebuild.
     used: usize,
 }
 
-impl ShenandoahFreeSet {}
+impl ShenandoahFreeSet {
+    pub fn new(max: usize) -> Self {
+        Self {
+            mutator_free_bitmap: BitVec::from_elem(max, false),
+            collector_free_bitmap: BitVec::from_elem(max, false),
+            max,
+            mutator_leftmost: max,
+            mutator_rightmost: 0,
+            collector_leftmost: max,
+            collector_rightmost: 0,
+            capacity: 0,
+            used: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.mutator_free_bitmap.clear();
+        self.collector_free_bitmap.clear();
+        self.mutator_leftmost = self.max;
+        self.mutator_rightmost = 0;
+        self.collector_leftmost = self.max;
+        self.collector_rightmost = 0;
+        self.capacity = 0;
+        self.used = 0;
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Number of bytes mutators can still allocate.
+    pub fn available(&self) -> usize {
+        self.capacity.saturating_sub(self.used)
+    }
+
+    pub fn is_mutator_free(&self, index: usize) -> bool {
+        self.mutator_free_bitmap[index]
+    }
+
+    pub fn is_collector_free(&self, index: usize) -> bool {
+        self.collector_free_bitmap[index]
+    }
+
+    /// Rebuild free set from the current region states. Empty regions at the end of the heap are moved to collector view
+    /// until `reserve` bytes are reserved for evacuation.
+    pub fn rebuild(&mut self, regions: &[ShenandoahHeapRegion], reserve: usize) {
+        self.clear();
+        for region in regions.iter() {
+            if region.is_alloc_allowed() && region.free() >= MIN_FREE_SPACE {
+                self.mutator_free_bitmap.set(region.index(), true);
+                self.capacity += region.free();
+            }
+        }
+
+        let mut reserved = 0;
+        for region in regions.iter().rev() {
+            if reserved >= reserve {
+                break;
+            }
+            let index = region.index();
+            if self.is_mutator_free(index) && region.is_empty() {
+                self.mutator_free_bitmap.set(index, false);
+                self.collector_free_bitmap.set(index, true);
+                self.capacity -= region.free();
+                reserved += region.free();
+            }
+        }
+        self.recompute_bounds();
+    }
+
+    fn recompute_bounds(&mut self) {
+        self.mutator_leftmost = self.max;
+        self.mutator_rightmost = 0;
+        self.collector_leftmost = self.max;
+        self.collector_rightmost = 0;
+        for index in 0..self.max {
+            if self.mutator_free_bitmap[index] {
+                self.mutator_leftmost = self.mutator_leftmost.min(index);
+                self.mutator_rightmost = index;
+            }
+            if self.collector_free_bitmap[index] {
+                self.collector_leftmost = self.collector_leftmost.min(index);
+                self.collector_rightmost = index;
+            }
+        }
+    }
+
+    fn adjust_bounds(&mut self) {
+        while self.mutator_leftmost < self.max && !self.is_mutator_free(self.mutator_leftmost) {
+            self.mutator_leftmost += 1;
+        }
+        while self.mutator_rightmost > 0 && !self.is_mutator_free(self.mutator_rightmost) {
+            self.mutator_rightmost -= 1;
+        }
+        while self.collector_leftmost < self.max
+            && !self.is_collector_free(self.collector_leftmost)
+        {
+            self.collector_leftmost += 1;
+        }
+        while self.collector_rightmost > 0 && !self.is_collector_free(self.collector_rightmost) {
+            self.collector_rightmost -= 1;
+        }
+    }
+
+    /// Remove region from both views. Free space left in mutator region is accounted as used.
+    fn retire(&mut self, region: &ShenandoahHeapRegion) {
+        let index = region.index();
+        if self.is_mutator_free(index) {
+            self.used += region.free();
+            self.mutator_free_bitmap.set(index, false);
+        }
+        self.collector_free_bitmap.set(index, false);
+        self.adjust_bounds();
+    }
+
+    /// Try to allocate in single region. LAB allocations take as much space as possible between `min_size` and `size`.
+    /// Returns null pointer and 0 if region does not have enough free space.
+    fn try_allocate_in(
+        &mut self,
+        region: &mut ShenandoahHeapRegion,
+        min_size: usize,
+        size: usize,
+        ty: AllocType,
+    ) -> (*mut u8, usize) {
+        let free = region.free();
+        let actual = if ty.is_lab_alloc() {
+            (free & !7).min(size)
+        } else {
+            size
+        };
+        if actual < min_size || free < actual {
+            if free < MIN_FREE_SPACE {
+                self.retire(region);
+            }
+            return (null_mut(), 0);
+        }
+        region.make_regular_allocation();
+        let result = region.allocate(actual, ty);
+        if ty.is_mutator_alloc() {
+            self.used += actual;
+        }
+        if region.free() < MIN_FREE_SPACE {
+            self.retire(region);
+        }
+        (result, actual)
+    }
+
+    /// Allocate memory for request of type `ty`. Returns pointer and size of allocated memory, LAB requests
+    /// may get less than `size` but never less than `min_size` bytes. Returns null pointer if there is no free region that can
+    /// satisfy request.
+    ///
+    /// Mutator requests are satisfied from mutator view only. GC requests use collector view and take empty regions
+    /// from mutator view when collector view is exhausted.
+    pub fn allocate(
+        &mut self,
+        regions: &mut [ShenandoahHeapRegion],
+        min_size: usize,
+        size: usize,
+        ty: AllocType,
+    ) -> (*mut u8, usize) {
+        if ty.is_mutator_alloc() {
+            let mut index = self.mutator_leftmost;
+            while index <= self.mutator_rightmost && index < self.max {
+                if self.is_mutator_free(index) {
+                    let result = self.try_allocate_in(&mut regions[index], min_size, size, ty);
+                    if !result.0.is_null() {
+                        return result;
+                    }
+                }
+                index += 1;
+            }
+        } else {
+            let mut index = self.collector_rightmost as isize;
+            while index >= self.collector_leftmost as isize {
+                let i = index as usize;
+                if self.is_collector_free(i) {
+                    let result = self.try_allocate_in(&mut regions[i], min_size, size, ty);
+                    if !result.0.is_null() {
+                        return result;
+                    }
+                }
+                index -= 1;
+            }
+            // collector view is exhausted, steal empty region from mutator view.
+            let mut index = self.mutator_rightmost as isize;
+            while index >= self.mutator_leftmost as isize {
+                let i = index as usize;
+                if self.is_mutator_free(i) && regions[i].is_empty() {
+                    self.flip_to_gc(&regions[i]);
+                    let result = self.try_allocate_in(&mut regions[i], min_size, size, ty);
+                    if !result.0.is_null() {
+                        return result;
+                    }
+                }
+                index -= 1;
+            }
+        }
+        (null_mut(), 0)
+    }
+
+    fn flip_to_gc(&mut self, region: &ShenandoahHeapRegion) {
+        let index = region.index();
+        self.mutator_free_bitmap.set(index, false);
+        self.collector_free_bitmap.set(index, true);
+        self.capacity = self.capacity.saturating_sub(region.free());
+        self.collector_leftmost = self.collector_leftmost.min(index);
+        self.collector_rightmost = self.collector_rightmost.max(index);
+        self.adjust_bounds();
+    }
+
+    /// Allocate humongous object of `size` bytes in contiguous empty regions from mutator view. Returns
+    /// pointer to the bottom of the first region or null pointer if there is no enough contiguous regions.
+    pub fn allocate_contiguous(
+        &mut self,
+        regions: &mut [ShenandoahHeapRegion],
+        size: usize,
+    ) -> *mut u8 {
+        if self.mutator_leftmost >= self.max {
+            return null_mut();
+        }
+        let region_size = regions[0].size();
+        let num = size.div_ceil(region_size);
+        let is_candidate =
+            |this: &Self, index: usize| this.is_mutator_free(index) && regions[index].is_empty();
+        let mut beg = self.mutator_leftmost;
+        let mut end = beg;
+        loop {
+            if end >= self.max || end > self.mutator_rightmost {
+                return null_mut();
+            }
+            if !is_candidate(self, end) {
+                end += 1;
+                beg = end;
+                continue;
+            }
+            if end - beg + 1 == num {
+                break;
+            }
+            end += 1;
+        }
+
+        let mut remainder = size;
+        for (offset, region) in regions[beg..=end].iter_mut().enumerate() {
+            let index = beg + offset;
+            if index == beg {
+                region.make_humongous_start();
+            } else {
+                region.make_humongous_cont();
+            }
+            let used = remainder.min(region_size);
+            remainder -= used;
+            unsafe {
+                region.set_top(region.bottom().add(used));
+            }
+            self.mutator_free_bitmap.set(index, false);
+            self.used += region_size;
+        }
+        self.adjust_bounds();
+        regions[beg].bottom()
+    }
+}
@@ -1,7 +1,2367 @@
-use crate::utils::mmap::Mmap;
+use std::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    mem::size_of,
+    ptr::{null_mut, NonNull},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Instant,
+};
 
-pub struct ShenandoahHeap {
+use atomic::{Atomic, Ordering};
+use im::Vector;
+use parking_lot::{lock_api::RawMutex, Mutex, RawMutex as Lock};
+
+use super::{
+    barrier::{brooks_pointer, resolve, EvacuationState, ShenandoahBarrier, EVACUATION_FAILED_TAG},
+    collection_set::ShenandoahCollectionSet,
+    free_set::{AllocType, ShenandoahFreeSet},
+    heuristics::{RegionData, ShenandoahHeuristics, ShenandoahStaticHeuristics},
+    marker::ShenandoahMarker,
+    region::{ShenandoahHeapRegion, ShenandoahOptions},
+};
+use crate::{
+    api::{
+        pop_ready_finalizer, trace_ephemerons, trace_ordered_finalizers, trace_soft_refs,
+        vtable_of, Collectable, Ephemeron, Gc, HeapObjectHeader, OrderedFinalizer, SoftRef,
+        SoftRefPolicy, Trace, VTable, Visitor, Weak, GC_BLACK, GC_GREY, GC_WHITE,
+    },
+    gc_base::{
+        AllocError, AllocationSpace, GcBase, MarkingConstraint, MarkingConstraintRuns, NoHelp,
+        OomHandler, OomHandlerSlot, ReadBarrier, TLAB,
+    },
+    gc_log::{GcLogger, GcRecord, PhaseTimer},
+    make_small_type_id,
+    mutator::{JoinData, Mutator, MutatorRef, ThreadState},
+    persistent::PersistentRoots,
+    pin::PinnedObjects,
+    safepoint::{GlobalSafepoint, SafepointScope},
+    small_type_id,
+    stats::{GcCycle, GcKind, GcListener, GcReason, GcStats, HeapStats},
+    utils::{align_usize, mmap::Mmap},
+};
+
+/// Size of Brooks forwarding pointer that precedes every object.
+pub const BROOKS_POINTER_SIZE: usize = size_of::<usize>();
+/// Size of the smallest possible object: Brooks pointer and object header.
+pub const MIN_OBJECT_SIZE: usize = BROOKS_POINTER_SIZE + size_of::<HeapObjectHeader>();
+/// Largest filler object. Object size must fit into size field of object header.
+const MAX_FILLER_SIZE: usize = 32 * 1024;
+
+pub struct ShenandoahHeapOptions {
+    pub verbose: bool,
+    pub max_heap_size: usize,
+    pub min_region_size: Option<usize>,
+    pub target_num_regions: Option<usize>,
+    pub max_region_size: Option<usize>,
+    /// Percent of heap that is reserved for evacuation.
+    pub evac_reserve: usize,
+}
+
+impl Default for ShenandoahHeapOptions {
+    fn default() -> Self {
+        Self {
+            verbose: false,
+            max_heap_size: 512 * 1024 * 1024,
+            min_region_size: None,
+            target_num_regions: None,
+            max_region_size: None,
+            evac_reserve: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Phase {
+    Idle,
+    /// GC thread marks objects concurrently with mutators.
+    Marking,
+    /// Concurrent marking is finished, final marking is performed by the next mutator that polls GC cycle.
+    FinalMark,
+    /// GC thread evacuates collection set concurrently with mutators.
+    Evacuation,
+    /// Concurrent evacuation is finished, init update-refs pause is performed by the next mutator that polls GC cycle.
+    InitUpdateRefs,
+    /// GC thread updates references concurrently with mutators.
+    UpdateRefs,
+    /// Concurrent update-refs is finished, final update-refs pause is performed by the next mutator that polls GC cycle.
+    FinalUpdateRefs,
+}
+
+impl Phase {
+    /// Returns `true` if GC thread runs this phase.
+    fn is_concurrent(self) -> bool {
+        matches!(self, Phase::Marking | Phase::Evacuation | Phase::UpdateRefs)
+    }
+
+    /// Returns `true` if GC thread finished its phase and waits for mutator to perform the next pause.
+    fn requests_pause(self) -> bool {
+        matches!(
+            self,
+            Phase::FinalMark | Phase::InitUpdateRefs | Phase::FinalUpdateRefs
+        )
+    }
+
+    /// Returns `true` if collection set is evacuated in this phase: objects that mutators write to or allocate must not point
+    /// to from-space.
+    fn updates_refs(self) -> bool {
+        matches!(
+            self,
+            Phase::Evacuation | Phase::InitUpdateRefs | Phase::UpdateRefs | Phase::FinalUpdateRefs
+        )
+    }
+}
+
+/// Heap state that is shared with GC thread. GC thread does not access the heap itself because mutators use it concurrently,
+/// it only marks objects with [ShenandoahMarker], evacuates collection set and updates references in heap regions. Read barrier
+/// evacuates objects through this state as well.
+pub(super) struct Shared {
     num_regions: usize,
-    heap_region_special: bool,
+    heap_start: *mut u8,
+    options: ShenandoahOptions,
+    /// Guards regions and free set.
+    heap_lock: Lock,
+    regions: UnsafeCell<Vec<ShenandoahHeapRegion>>,
+    free_set: UnsafeCell<ShenandoahFreeSet>,
+    collection_set: UnsafeCell<ShenandoahCollectionSet>,
+    marker: ShenandoahMarker,
+    /// LAB of the GC thread used for evacuation.
+    gclab: UnsafeCell<ShenandoahTLAB>,
+    /// Index of the next region to update references in.
+    update_refs_cursor: AtomicUsize,
+    phase: Atomic<Phase>,
+    /// Set when mutator fails to allocate while concurrent cycle is running, GC thread then stops its phase and mutator
+    /// finishes the cycle in STW pause.
+    cancelled: AtomicBool,
+    evacuation_failed: AtomicBool,
+    /// Evacuation state that is checked by read barrier.
+    evacuation: &'static EvacuationState,
+}
+
+// marker worklists, phase and flags are atomic, regions and free set are protected by heap lock. Collection set and GC LAB
+// are modified only in pauses, when GC thread is not running.
+unsafe impl Send for Shared {}
+unsafe impl Sync for Shared {}
+
+/// Concurrent phase of GC cycle that runs in GC thread.
+type ConcurrentPhase = fn(&Shared, Option<Arc<GcLogger>>, usize);
+
+/// Shenandoah heap.
+///
+/// Read top level module documentation for more information.
+///
+/// `H` is heuristics that decides when to start concurrent cycle and which regions to evacuate.
+pub struct ShenandoahHeap<H: ShenandoahHeuristics = ShenandoahStaticHeuristics> {
+    shared: Arc<Shared>,
     heap_region: Mmap,
+    heuristics: H,
+    pub(crate) global_heap_lock: Lock,
+    pub(crate) mutators: Vec<*mut Mutator<Self>>,
+    pub(crate) safepoint: GlobalSafepoint,
+    /// Evacuate every region with live objects in current cycle, set by [stress](crate::stress) mode.
+    force_evacuation: bool,
+    /// Number of bytes reserved for evacuation.
+    evac_reserve: usize,
+    /// Background thread that runs concurrent phases of GC cycle.
+    gc_thread: Mutex<Option<JoinHandle<()>>>,
+    start_time: Instant,
+    cycle_start: Option<Instant>,
+    /// Bytes used when concurrent cycle was started.
+    used_before_cycle: usize,
+    total_gcs: usize,
+    weak_refs: Vec<Weak<dyn Collectable, Self>>,
+    ephemerons: Vec<Ephemeron<dyn Collectable, dyn Collectable, Self>>,
+    soft_refs: Vec<SoftRef<dyn Collectable, Self>>,
+    soft_ref_policy: SoftRefPolicy,
+    ordered_finalizers: Vec<OrderedFinalizer<Self>>,
+    persistent_roots: Arc<PersistentRoots<Self>>,
+    pinned_objects: Arc<PinnedObjects<Self>>,
+    constraints: Vec<Box<dyn MarkingConstraint>>,
+    finalize_list: Vector<*mut HeapObjectHeader>,
+    finalize_lock: Lock,
+    oom_handler: OomHandlerSlot<Self>,
+    gc_stats: GcStats,
+    /// Concurrent GC cycle that is in progress.
+    concurrent_cycle: Option<GcCycle>,
+}
+
+pub fn instantiate_shenandoah<H: ShenandoahHeuristics>(
+    options: ShenandoahHeapOptions,
+) -> MutatorRef<ShenandoahHeap<H>> {
+    let time = Instant::now();
+    let sizes = ShenandoahHeapRegion::setup_sizes(
+        options.max_heap_size,
+        options.min_region_size,
+        options.target_num_regions,
+        options.max_region_size,
+    );
+    let num_regions = sizes.region_count;
+    let region_size = sizes.region_size_bytes;
+    let heap_region = Mmap::new(num_regions * region_size, region_size);
+    let heap_start = heap_region.aligned_start();
+    let regions = (0..num_regions)
+        .map(|index| unsafe {
+            ShenandoahHeapRegion::new(index, heap_start.add(index * region_size), region_size)
+        })
+        .collect::<Vec<_>>();
+    let marker = ShenandoahMarker::new(
+        regions.as_ptr(),
+        heap_start,
+        sizes.region_size_bytes_shift,
+    );
+    let evac_reserve = num_regions * region_size / 100 * options.evac_reserve;
+    let mut free_set = ShenandoahFreeSet::new(num_regions);
+    free_set.rebuild(&regions, evac_reserve);
+    let mut heuristics = H::default();
+    heuristics.set_region_data(
+        Box::into_raw(vec![RegionData::default(); num_regions].into_boxed_slice()).cast(),
+    );
+
+    let heap_end = unsafe { heap_start.add(num_regions * region_size) };
+    let shared = Arc::new(Shared {
+        num_regions,
+        heap_start,
+        options: sizes,
+        heap_lock: Lock::INIT,
+        regions: UnsafeCell::new(regions),
+        free_set: UnsafeCell::new(free_set),
+        collection_set: UnsafeCell::new(ShenandoahCollectionSet::new(num_regions)),
+        marker,
+        gclab: UnsafeCell::new(ShenandoahTLAB::new()),
+        update_refs_cursor: AtomicUsize::new(0),
+        phase: Atomic::new(Phase::Idle),
+        cancelled: AtomicBool::new(false),
+        evacuation_failed: AtomicBool::new(false),
+        evacuation: EvacuationState::register(heap_start, heap_end),
+    });
+    shared.evacuation.set_shared(Arc::as_ptr(&shared));
+    let heap = Arc::new(UnsafeCell::new(ShenandoahHeap::<H> {
+        shared,
+        heap_region,
+        heuristics,
+        global_heap_lock: Lock::INIT,
+        mutators: vec![],
+        safepoint: GlobalSafepoint::new(),
+        force_evacuation: false,
+        evac_reserve,
+        gc_thread: Mutex::new(None),
+        start_time: Instant::now(),
+        cycle_start: None,
+        used_before_cycle: 0,
+        total_gcs: 0,
+        weak_refs: vec![],
+        ephemerons: vec![],
+        soft_refs: vec![],
+        soft_ref_policy: SoftRefPolicy::new(),
+        ordered_finalizers: vec![],
+        persistent_roots: Arc::new(PersistentRoots::new()),
+        pinned_objects: Arc::new(PinnedObjects::new()),
+        constraints: vec![],
+        finalize_list: Vector::new(),
+        finalize_lock: Lock::INIT,
+        oom_handler: OomHandlerSlot::new(),
+        gc_stats: GcStats::new("Shenandoah"),
+        concurrent_cycle: None,
+    }));
+    let href = unsafe { &mut *heap.get() };
+    if options.verbose {
+        href.safepoint
+            .set_logger(Some(Arc::new(GcLogger::stderr())));
+        href.log_phase(
+            "Heap Setup",
+            time,
+            &[
+                ("region_size", sizes.region_size_bytes),
+                ("humongous_threshold", sizes.humongous_threshold_bytes),
+                ("max_tlab_size", sizes.max_tlab_size_bytes),
+                ("max_heap_size", sizes.max_heap_size),
+            ],
+        );
+    }
+    let join_data = JoinData::new();
+    let mut mutator = MutatorRef::new(Mutator::new(
+        heap.clone(),
+        &href.safepoint,
+        join_data.internal.clone(),
+    ));
+    href.mutators.push(&mut *mutator);
+    href.safepoint
+        .n_mutators
+        .fetch_add(1, atomic::Ordering::Relaxed);
+    mutator.state_set(ThreadState::Safe, ThreadState::Unsafe);
+    mutator
+}
+
+/// Fill `[start, end)` with filler objects so heap region stays parsable. Gaps that are too small for an object are zeroed,
+/// region walker skips zero words.
+///
+/// # Safety
+///
+/// `[start, end)` must be unused memory of a single heap region and its size must be a multiple of word size.
+pub unsafe fn fill_with_dummy_object(start: *mut u8, end: *mut u8) {
+    let mut cursor = start;
+    while cursor < end {
+        let remaining = end as usize - cursor as usize;
+        let mut size = remaining.min(MAX_FILLER_SIZE);
+        if remaining != size && remaining - size < MIN_OBJECT_SIZE {
+            size -= MIN_OBJECT_SIZE;
+        }
+        if size < MIN_OBJECT_SIZE {
+            std::ptr::write_bytes(cursor, 0, size);
+        } else {
+            let object = cursor.add(BROOKS_POINTER_SIZE).cast::<HeapObjectHeader>();
+            cursor.cast::<usize>().write(object as usize);
+            object.write(HeapObjectHeader {
+                value: VTable { raw: 0 },
+                padding: 0,
+                padding2: 0,
+                type_id: 0,
+            });
+            (*object).set_size(size);
+        }
+        cursor = cursor.add(size);
+    }
+}
+
+/// Invoke `f` on each object in `[start, end)`. Filler objects are skipped.
+unsafe fn walk_objects(start: *mut u8, end: *mut u8, mut f: impl FnMut(*mut HeapObjectHeader)) {
+    let mut cursor = start;
+    while cursor < end {
+        if cursor.cast::<usize>().read() == 0 {
+            cursor = cursor.add(size_of::<usize>());
+            continue;
+        }
+        let object = cursor.add(BROOKS_POINTER_SIZE).cast::<HeapObjectHeader>();
+        let size = (*object).size();
+        debug_assert!(size >= MIN_OBJECT_SIZE);
+        if !(*object).is_free() {
+            f(object);
+        }
+        cursor = cursor.add(size);
+    }
+}
+
+/// Write Brooks pointer and header of object of `size` bytes at `memory`. Returns object header.
+#[inline(always)]
+unsafe fn init_object(
+    memory: *mut u8,
+    size: usize,
+    vtable: usize,
+    type_id: u32,
+) -> *mut HeapObjectHeader {
+    let object = memory.add(BROOKS_POINTER_SIZE).cast::<HeapObjectHeader>();
+    memory.cast::<usize>().write(object as usize);
+    object.write(HeapObjectHeader {
+        value: VTable { raw: vtable as _ },
+        padding: 0,
+        padding2: 0,
+        type_id,
+    });
+    (*object).set_size(size);
+    object
+}
+
+/// Bump pointer allocation buffer in heap region. Used as mutator TLAB and as GC LAB for evacuation.
+pub struct ShenandoahTLAB {
+    cursor: *mut u8,
+    end: *mut u8,
+}
+
+impl ShenandoahTLAB {
+    pub const fn new() -> Self {
+        Self {
+            cursor: null_mut(),
+            end: null_mut(),
+        }
+    }
+
+    /// Allocate `size` bytes. Returns null pointer if buffer does not have enough space.
+    #[inline(always)]
+    pub fn allocate(&mut self, size: usize) -> *mut u8 {
+        if self.end as usize - (self.cursor as usize) < size {
+            return null_mut();
+        }
+        let result = self.cursor;
+        self.cursor = unsafe { result.add(size) };
+        result
+    }
+
+    fn init(&mut self, start: *mut u8, size: usize) {
+        self.cursor = start;
+        self.end = unsafe { start.add(size) };
+    }
+
+    /// Fill unused part of the buffer with filler objects and reset it.
+    pub fn retire(&mut self) {
+        if !self.cursor.is_null() {
+            unsafe {
+                fill_with_dummy_object(self.cursor, self.end);
+            }
+        }
+        self.cursor = null_mut();
+        self.end = null_mut();
+    }
+}
+
+impl Default for ShenandoahTLAB {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Mutator TLAB is bump allocated directly in [ShenandoahHeap::try_alloc_inline] so that allocation slow path can root the value.
+impl<H: ShenandoahHeuristics> TLAB<ShenandoahHeap<H>> for ShenandoahTLAB {
+    fn can_thread_local_allocate(&self, _size: usize) -> bool {
+        false
+    }
+    fn allocate<T: Collectable + 'static>(
+        &mut self,
+        _value: T,
+    ) -> Result<Gc<T, ShenandoahHeap<H>>, T> {
+        unreachable!()
+    }
+    fn refill(&mut self, _mutator: &MutatorRef<ShenandoahHeap<H>>, _alloc_size: usize) -> bool {
+        false
+    }
+    fn reset(&mut self) {
+        self.retire();
+    }
+    fn create(_heap: Arc<UnsafeCell<ShenandoahHeap<H>>>) -> Self {
+        Self::new()
+    }
+}
+
+/// Updates references to evacuated objects. References are updated with CAS because mutators might store into the same field concurrently.
+struct UpdateRefsVisitor<'a> {
+    shared: &'a Shared,
+    /// Evacuate objects from collection set that are not yet evacuated.
+    evacuate: bool,
+}
+
+impl Visitor for UpdateRefsVisitor<'_> {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        let object = root.as_ptr();
+        unsafe {
+            let new_object = if self.evacuate {
+                self.shared.evacuate_if_in_cset(object, None)
+            } else {
+                resolve(object)
+            };
+            if new_object != object {
+                let slot = &*(root as *mut NonNull<HeapObjectHeader> as *const AtomicUsize);
+                let _ = slot.compare_exchange(
+                    object as usize,
+                    new_object as usize,
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                );
+            }
+        }
+    }
+    fn visit_ephemeron(
+        &mut self,
+        key: &mut NonNull<HeapObjectHeader>,
+        value: &mut NonNull<HeapObjectHeader>,
+    ) {
+        self.mark_object(key);
+        self.mark_object(value);
+    }
+}
+
+/// Marker used by Full GC. Resolves every reference through Brooks pointer before marking it.
+struct FullGcMarker {
+    stack: Vec<*mut HeapObjectHeader>,
+}
+
+impl FullGcMarker {
+    fn drain(&mut self) {
+        while let Some(object) = self.stack.pop() {
+            unsafe {
+                (*object).get_dyn().trace(self);
+            }
+        }
+    }
+}
+
+impl Visitor for FullGcMarker {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        unsafe {
+            let object = resolve(root.as_ptr());
+            *root = NonNull::new_unchecked(object);
+            if (*object).get_color() != GC_BLACK {
+                (*object).force_set_color(GC_BLACK);
+                self.stack.push(object);
+            }
+        }
+    }
+}
+
+/// Replaces references with the value of Brooks pointer. Used to update references when all mutators are stopped.
+struct ResolveVisitor;
+
+impl Visitor for ResolveVisitor {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        unsafe {
+            *root = NonNull::new_unchecked(resolve(root.as_ptr()));
+        }
+    }
+    fn visit_ephemeron(
+        &mut self,
+        key: &mut NonNull<HeapObjectHeader>,
+        value: &mut NonNull<HeapObjectHeader>,
+    ) {
+        self.mark_object(key);
+        self.mark_object(value);
+    }
+}
+
+/// Regions, free set, collection set and GC LAB are accessed through these methods. Regions and free set may be accessed only
+/// with heap lock held or when all mutators are stopped and GC thread is not running. Collection set and GC LAB are modified only
+/// when all mutators are stopped and GC thread is not running.
+#[allow(clippy::mut_from_ref)]
+impl Shared {
+    unsafe fn regions(&self) -> &mut Vec<ShenandoahHeapRegion> {
+        &mut *self.regions.get()
+    }
+
+    unsafe fn free_set(&self) -> &mut ShenandoahFreeSet {
+        &mut *self.free_set.get()
+    }
+
+    unsafe fn collection_set(&self) -> &ShenandoahCollectionSet {
+        &*self.collection_set.get()
+    }
+
+    unsafe fn collection_set_mut(&self) -> &mut ShenandoahCollectionSet {
+        &mut *self.collection_set.get()
+    }
+
+    unsafe fn gclab(&self) -> &mut ShenandoahTLAB {
+        &mut *self.gclab.get()
+    }
+}
+
+impl Shared {
+    #[inline(always)]
+    fn region_index(&self, addr: *const u8) -> usize {
+        (addr as usize - self.heap_start as usize) >> self.options.region_size_bytes_shift
+    }
+
+    /// Returns `true` if `object` is located in collection set region.
+    #[inline]
+    fn in_cset(&self, object: *const HeapObjectHeader) -> bool {
+        unsafe {
+            self.collection_set()
+                .is_in(self.region_index(object.cast()))
+        }
+    }
+
+    /// Returns evacuated copy of `object`. If `object` is in collection set and is not evacuated yet it is evacuated right away.
+    pub(super) unsafe fn evacuate_if_in_cset(
+        &self,
+        object: *mut HeapObjectHeader,
+        gclab: Option<&mut ShenandoahTLAB>,
+    ) -> *mut HeapObjectHeader {
+        let forwardee = (*brooks_pointer(object)).load(Ordering::Acquire);
+        if forwardee != object as usize || !self.evacuation.is_evacuating() || !self.in_cset(object)
+        {
+            return (forwardee & !EVACUATION_FAILED_TAG) as _;
+        }
+        self.evacuate_object(object, gclab)
+    }
+
+    /// Copy `object` to to-space and install forwarding pointer. Copy is allocated in `gclab` if it is provided, otherwise
+    /// shared allocation in collector regions is used. If copy can't be allocated object is forwarded to itself with
+    /// [EVACUATION_FAILED_TAG] and cycle falls back to Full GC.
+    unsafe fn evacuate_object(
+        &self,
+        object: *mut HeapObjectHeader,
+        gclab: Option<&mut ShenandoahTLAB>,
+    ) -> *mut HeapObjectHeader {
+        let brooks = &*brooks_pointer(object);
+        let size = (*object).copy_size();
+        let copy = match gclab {
+            Some(gclab) => {
+                let memory = gclab.allocate(size);
+                if memory.is_null() {
+                    self.refill_gclab(gclab, size)
+                } else {
+                    memory
+                }
+            }
+            None => {
+                self.heap_lock.lock();
+                let (memory, _) =
+                    self.free_set()
+                        .allocate(self.regions(), size, size, AllocType::SharedGc);
+                self.heap_lock.unlock();
+                memory
+            }
+        };
+        if copy.is_null() {
+            self.evacuation_failed.store(true, Ordering::Release);
+            self.cancelled.store(true, Ordering::Release);
+            return match brooks.compare_exchange(
+                object as usize,
+                object as usize | EVACUATION_FAILED_TAG,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => object,
+                Err(forwardee) => (forwardee & !EVACUATION_FAILED_TAG) as _,
+            };
+        }
+        std::ptr::copy_nonoverlapping(
+            object.cast::<u8>().sub(BROOKS_POINTER_SIZE),
+            copy,
+            (*object).size(),
+        );
+        let new_object = copy.add(BROOKS_POINTER_SIZE).cast::<HeapObjectHeader>();
+        (*new_object).finish_copy(object);
+        copy.cast::<usize>().write(new_object as usize);
+        match brooks.compare_exchange(
+            object as usize,
+            new_object as usize,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => new_object,
+            Err(forwardee) => {
+                // another thread evacuated object first, our copy is garbage.
+                fill_with_dummy_object(copy, copy.add(size));
+                (forwardee & !EVACUATION_FAILED_TAG) as _
+            }
+        }
+    }
+
+    unsafe fn refill_gclab(&self, gclab: &mut ShenandoahTLAB, size: usize) -> *mut u8 {
+        self.heap_lock.lock();
+        gclab.retire();
+        let (memory, actual) = self.free_set().allocate(
+            self.regions(),
+            size,
+            self.options.max_tlab_size_bytes,
+            AllocType::Gclab,
+        );
+        let result = if memory.is_null() {
+            null_mut()
+        } else {
+            gclab.init(memory, actual);
+            gclab.allocate(size)
+        };
+        self.heap_lock.unlock();
+        result
+    }
+
+    /// Evacuate `object` if needed and update its references. Used by write barrier and for objects allocated during evacuation and
+    /// update-refs phases so that they never point to from-space.
+    #[cold]
+    unsafe fn update_object_refs(&self, object: *mut HeapObjectHeader) {
+        let object = self.evacuate_if_in_cset(object, None);
+        let mut visitor = UpdateRefsVisitor {
+            shared: self,
+            evacuate: self.evacuation.is_evacuating(),
+        };
+        (*object).get_dyn().trace(&mut visitor);
+    }
+
+    /// Evacuate marked objects from collection set regions. Returns `false` if evacuation was cancelled or failed. Must be invoked
+    /// by GC thread or when all mutators are stopped.
+    unsafe fn evacuate_collection_set(&self, cancellable: bool) -> bool {
+        let gclab = self.gclab();
+        while let Some(index) = self.collection_set().claim_next() {
+            // collection set regions are not allocated in, so their bounds stay the same once they are read.
+            self.heap_lock.lock();
+            let region = &self.regions()[index];
+            let (bottom, top) = (region.bottom(), region.top());
+            self.heap_lock.unlock();
+            walk_objects(bottom, top, |object| {
+                if (*object).get_color() == GC_BLACK {
+                    self.evacuate_if_in_cset(object, Some(&mut *gclab));
+                }
+            });
+            if self.evacuation_failed.load(Ordering::Acquire)
+                || (cancellable && self.cancelled.load(Ordering::Relaxed))
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Update references in objects allocated before final marking and colour them white for the next cycle.
+    /// Returns `false` if it was cancelled. Must be invoked by GC thread or when all mutators are stopped.
+    unsafe fn update_heap_references(&self, cancellable: bool) -> bool {
+        let mut visitor = UpdateRefsVisitor {
+            shared: self,
+            evacuate: false,
+        };
+        let mut update = |object: *mut HeapObjectHeader| {
+            if (*object).get_color() == GC_BLACK {
+                (*object).get_dyn().trace(&mut visitor);
+                (*object).force_set_color(GC_WHITE);
+            }
+        };
+        while self.update_refs_cursor.load(Ordering::Relaxed) < self.num_regions {
+            let index = self.update_refs_cursor.fetch_add(1, Ordering::Relaxed);
+            // mutators allocate in regions concurrently, objects below update watermark are not moved though.
+            self.heap_lock.lock();
+            let region = &self.regions()[index];
+            let bottom = region.bottom();
+            let watermark = region.get_update_watermark();
+            let skip = region.is_cset() || watermark == bottom;
+            let humongous_start = region.is_humongous_start();
+            let regular = region.is_regular();
+            self.heap_lock.unlock();
+            if skip {
+                continue;
+            }
+            if humongous_start {
+                update(bottom.add(BROOKS_POINTER_SIZE).cast());
+            } else if regular {
+                walk_objects(bottom, watermark, &mut update);
+            }
+            if cancellable && self.cancelled.load(Ordering::Relaxed) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl<H: ShenandoahHeuristics> ShenandoahHeap<H> {
+    unsafe fn after_mark_constraints(&mut self) {
+        let this = self as *mut Self;
+        (*this).constraints.retain_mut(|constraint| {
+            if constraint.is_over() {
+                false
+            } else {
+                if constraint.runs_at() == MarkingConstraintRuns::AfterMark {
+                    constraint.run(&mut (*this).shared.marker.visitor());
+                }
+                true
+            }
+        });
+    }
+    unsafe fn before_mark_constraints(&mut self) {
+        let this = self as *mut Self;
+        (*this).constraints.retain_mut(|constraint| {
+            if constraint.is_over() {
+                false
+            } else {
+                if constraint.runs_at() == MarkingConstraintRuns::BeforeMark {
+                    constraint.run(&mut (*this).shared.marker.visitor());
+                }
+                true
+            }
+        });
+    }
+
+    /// Seconds since heap creation.
+    fn elapsed(&self) -> f64 {
+        self.start_time.elapsed().as_secs_f64()
+    }
+
+    /// Returns `true` if `object` is located in collection set region.
+    #[inline]
+    pub fn in_cset(&self, object: *const HeapObjectHeader) -> bool {
+        self.shared.in_cset(object)
+    }
+
+    /// Number of bytes used by objects and filler objects in heap.
+    fn used(&self) -> usize {
+        self.shared.heap_lock.lock();
+        let used = unsafe { self.shared.regions() }
+            .iter()
+            .filter(|region| region.is_active())
+            .map(|region| region.used())
+            .sum();
+        unsafe {
+            self.shared.heap_lock.unlock();
+        }
+        used
+    }
+
+    fn log_phase(&self, phase: &str, time: Instant, extra: &[(&'static str, usize)]) {
+        if let Some(logger) = self.safepoint.logger() {
+            logger.log_phase("Shenandoah", self.total_gcs, phase, time.elapsed(), extra);
+        }
+    }
+
+    /// Record GC pause and log it.
+    fn log_pause(&self, phase: &str, time: Instant) {
+        self.gc_stats.record_pause(time.elapsed());
+        self.log_phase(phase, time, &[]);
+    }
+
+    /// Allocate `size` bytes for mutator: small objects get new TLAB, larger ones are allocated directly in region.
+    /// Returns null pointer if there is no free region.
+    unsafe fn allocate_memory(&mut self, mutator: &mut MutatorRef<Self>, size: usize) -> *mut u8 {
+        self.shared.heap_lock.lock();
+        let result = if size <= self.shared.options.max_tlab_size_bytes / 4 {
+            mutator.tlab.retire();
+            let (memory, actual) = self.shared.free_set().allocate(
+                self.shared.regions(),
+                size,
+                self.shared.options.max_tlab_size_bytes,
+                AllocType::Tlab,
+            );
+            if memory.is_null() {
+                null_mut()
+            } else {
+                mutator.tlab.init(memory, actual);
+                mutator.tlab.allocate(size)
+            }
+        } else {
+            self.shared
+                .free_set()
+                .allocate(self.shared.regions(), size, size, AllocType::Shared)
+                .0
+        };
+        self.shared.heap_lock.unlock();
+        result
+    }
+
+    unsafe fn allocate_humongous(&mut self, size: usize) -> *mut u8 {
+        self.shared.heap_lock.lock();
+        let memory = self
+            .shared
+            .free_set()
+            .allocate_contiguous(self.shared.regions(), size);
+        self.shared.heap_lock.unlock();
+        memory
+    }
+
+    fn should_start_gc(&self) -> bool {
+        if self.shared.phase.load(Ordering::Relaxed) != Phase::Idle {
+            return false;
+        }
+        self.shared.heap_lock.lock();
+        let result = self.heuristics.should_start_gc(
+            self.shared.num_regions * self.shared.options.region_size_bytes,
+            unsafe { self.shared.free_set().available() },
+            self.elapsed(),
+        );
+        unsafe {
+            self.shared.heap_lock.unlock();
+        }
+        result
+    }
+
+    /// Get memory for object of `size` bytes when TLAB is exhausted. Values in `keep` are rooted while mutator
+    /// waits for GC. Returns null pointer if heap is out of memory.
+    #[cold]
+    unsafe fn alloc_slow(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        size: usize,
+        keep: &mut [&mut dyn Trace],
+    ) -> *mut u8 {
+        letroot!(_keep = mutator.shadow_stack(), keep);
+        self.poll_concurrent_cycle(mutator);
+        // give GC pauses of other mutators a chance to stop this mutator.
+        mutator.poll_safepoint();
+        if self.should_start_gc() {
+            self.start_concurrent_cycle(mutator, &mut [], false);
+        }
+        let memory = self.allocate_memory(mutator, size);
+        if !memory.is_null() {
+            return memory;
+        }
+        self.handle_alloc_failure(mutator, |heap, mutator| heap.allocate_memory(mutator, size))
+    }
+
+    /// Allocation failed. If concurrent cycle is running it is degenerated: GC thread stops its phase and the rest of the cycle is
+    /// performed in STW pause. If that does not free enough memory Full GC is performed. All values mutator holds must be rooted.
+    unsafe fn handle_alloc_failure(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        mut retry: impl FnMut(&mut Self, &mut MutatorRef<Self>) -> *mut u8,
+    ) -> *mut u8 {
+        if self.shared.phase.load(Ordering::Acquire) != Phase::Idle {
+            self.shared.cancelled.store(true, Ordering::Release);
+            self.wait_for_gc_to_complete(mutator);
+            let memory = retry(self, mutator);
+            if !memory.is_null() {
+                return memory;
+            }
+        }
+        self.collect_alloc_failure(mutator, &mut []);
+        retry(self, mutator)
+    }
+
+    /// Initial marking. Must be invoked when all mutators are stopped.
+    unsafe fn init_mark(&mut self, mut keep: &mut [&mut dyn Trace]) {
+        for region in self.shared.regions().iter() {
+            region.clear_live_data();
+        }
+        self.shared.marker.set_marking(true);
+        self.before_mark_constraints();
+        let mut visitor = self.shared.marker.visitor();
+        for i in 0..self.mutators.len() {
+            let mutator = self.mutators[i];
+            (*mutator).shadow_stack().walk(|entry| {
+                entry.trace(&mut visitor);
+            });
+        }
+        self.persistent_roots.trace(&mut visitor);
+        self.pinned_objects.trace(&mut visitor);
+        keep.trace(&mut visitor);
+    }
+
+    /// Final marking. Must be invoked when all mutators are stopped. Finishes marking, processes soft references, ephemerons, weak references and finalizers,
+    /// reclaims immediate garbage and selects collection set. Returns `true` if collection set is not empty.
+    unsafe fn final_mark(&mut self, mut keep: &mut [&mut dyn Trace]) -> bool {
+        let mut visitor = self.shared.marker.visitor();
+        for i in 0..self.mutators.len() {
+            let mutator = self.mutators[i];
+            (*mutator).reset_tlab();
+            (*mutator).shadow_stack().walk(|entry| {
+                entry.trace(&mut visitor);
+            });
+        }
+        self.persistent_roots.trace(&mut visitor);
+        self.pinned_objects.trace(&mut visitor);
+        keep.trace(&mut visitor);
+        self.shared.marker.drain(None);
+        self.after_mark_constraints();
+        self.shared.marker.drain(None);
+        self.soft_ref_policy
+            .begin_cycle(self.used(), self.shared.options.max_heap_size);
+        let forwardee = |header: *mut HeapObjectHeader| {
+            if (*header).get_color() == GC_BLACK {
+                header
+            } else {
+                null_mut()
+            }
+        };
+        let this = self as *mut Self;
+        trace_soft_refs(
+            &(*this).soft_refs,
+            &(*this).soft_ref_policy,
+            forwardee,
+            |slot| (*this).shared.marker.mark_object(slot),
+            || {
+                (*this).shared.marker.drain(None);
+            },
+        );
+        trace_ephemerons(
+            &(*this).ephemerons,
+            forwardee,
+            |slot| (*this).shared.marker.mark_object(slot),
+            || {
+                (*this).shared.marker.drain(None);
+            },
+        );
+        if trace_ordered_finalizers(
+            &mut (*this).ordered_finalizers,
+            forwardee,
+            |slot| (*this).shared.marker.mark_object(slot),
+            || {
+                (*this).shared.marker.drain(None);
+            },
+        ) {
+            trace_ephemerons(
+                &(*this).ephemerons,
+                forwardee,
+                |slot| (*this).shared.marker.mark_object(slot),
+                || {
+                    (*this).shared.marker.drain(None);
+                },
+            );
+        }
+        self.shared.marker.set_marking(false);
+
+        self.weak_refs.retain_mut(|object| {
+            let header = object.base();
+            if (*header).get_color() == GC_BLACK {
+                object.after_mark(|header| {
+                    if (*header).get_color() == GC_BLACK {
+                        header
+                    } else {
+                        null_mut()
+                    }
+                });
+                true
+            } else {
+                false
+            }
+        });
+        self.soft_refs.retain_mut(|soft_ref| {
+            if (*soft_ref.base()).get_color() == GC_BLACK {
+                soft_ref.after_mark(forwardee);
+                true
+            } else {
+                false
+            }
+        });
+        self.ephemerons.retain_mut(|ephemeron| {
+            if (*ephemeron.base()).get_color() == GC_BLACK {
+                ephemeron.after_mark(|key| {
+                    if (*key).get_color() == GC_BLACK {
+                        key
+                    } else {
+                        null_mut()
+                    }
+                });
+                true
+            } else {
+                false
+            }
+        });
+        self.finalize_lock.lock();
+        self.finalize_list.retain(|object| {
+            if (**object).get_color() == GC_BLACK {
+                true
+            } else {
+                (**object).get_dyn().finalize();
+                false
+            }
+        });
+        self.finalize_lock.unlock();
+
+        self.shared.heap_lock.lock();
+        let time = Instant::now();
+        self.reclaim_humongous();
+        let free = self
+            .shared
+            .regions()
+            .iter()
+            .filter(|region| region.is_empty())
+            .map(|region| region.size())
+            .sum::<usize>();
+        let pinned = self.pin_regions();
+        let immediate_garbage = self.heuristics.choose_collection_set(
+            self.shared.collection_set_mut(),
+            self.shared.regions(),
+            free,
+            self.force_evacuation,
+        );
+        self.unpin_regions(pinned);
+        self.recycle_trash();
+        for region in self.shared.regions().iter_mut() {
+            region.set_update_watermark(region.top());
+            region.reset_alloc_metadata();
+        }
+        self.shared
+            .free_set()
+            .rebuild(self.shared.regions(), self.evac_reserve);
+        self.shared.heap_lock.unlock();
+        self.log_phase(
+            "Choose Collection Set",
+            time,
+            &[
+                ("immediate_garbage", immediate_garbage),
+                (
+                    "cset",
+                    self.shared.collection_set().count() * self.shared.options.region_size_bytes,
+                ),
+                ("cset_live", self.shared.collection_set().live_data()),
+            ],
+        );
+        !self.shared.collection_set().is_empty()
+    }
+
+    /// Set pinned bit of pinned objects and flag regions that contain them as pinned. Returns objects that must be passed to
+    /// [unpin_regions](Self::unpin_regions) once collection set is selected or Full GC is finished. Must be invoked when all
+    /// mutators are stopped.
+    unsafe fn pin_regions(&mut self) -> Vec<*mut HeapObjectHeader> {
+        let pinned = self.pinned_objects.set_pinned_bits();
+        for &object in pinned.iter() {
+            let index = self.shared.region_index(object.cast());
+            self.shared.regions()[index].set_pinned(true);
+        }
+        pinned
+    }
+
+    unsafe fn unpin_regions(&mut self, pinned: Vec<*mut HeapObjectHeader>) {
+        for object in pinned {
+            (*object).set_pinned_bit(false);
+            let index = self.shared.region_index(object.cast());
+            self.shared.regions()[index].set_pinned(false);
+        }
+    }
+
+    /// Trash humongous regions whose object is not marked. Must be invoked with heap lock held.
+    unsafe fn reclaim_humongous(&mut self) {
+        let mut index = 0;
+        while index < self.shared.num_regions {
+            if self.shared.regions()[index].is_humongous_start() {
+                let object = self.shared.regions()[index]
+                    .bottom()
+                    .add(BROOKS_POINTER_SIZE)
+                    .cast::<HeapObjectHeader>();
+                if (*object).get_color() != GC_BLACK {
+                    self.shared.regions()[index].make_trash();
+                    index += 1;
+                    while index < self.shared.num_regions
+                        && self.shared.regions()[index].is_humongous_continuation()
+                    {
+                        self.shared.regions()[index].make_trash();
+                        index += 1;
+                    }
+                    continue;
+                }
+            }
+            index += 1;
+        }
+    }
+
+    /// Recycle all trash regions and return their memory to OS. Must be invoked with heap lock held.
+    unsafe fn recycle_trash(&mut self) {
+        let now = self.elapsed();
+        for region in self.shared.regions().iter_mut() {
+            if region.is_trash() {
+                self.heap_region.dontneed(region.bottom(), region.size());
+                region.recycle(now);
+            }
+        }
+    }
+
+    /// Must be invoked when all mutators are stopped. Ends evacuation: objects in regions that received evacuated
+    /// copies need their references updated as well.
+    unsafe fn init_update_refs(&mut self) {
+        self.shared.evacuation.set_evacuating(false);
+        self.shared
+            .phase
+            .store(Phase::UpdateRefs, Ordering::Release);
+        self.shared.heap_lock.lock();
+        self.shared.gclab().retire();
+        for region in self.shared.regions().iter() {
+            if region.gclab_allocs() != 0 {
+                region.set_update_watermark(region.top());
+            }
+        }
+        self.shared.heap_lock.unlock();
+        self.shared.update_refs_cursor.store(0, Ordering::Relaxed);
+    }
+
+    /// Final update-refs. Must be invoked when all mutators are stopped. Updates roots and recycles collection set.
+    unsafe fn final_update_refs(&mut self, mut keep: &mut [&mut dyn Trace]) {
+        self.update_roots(&mut keep);
+        self.shared.heap_lock.lock();
+        for &index in self.shared.collection_set().regions() {
+            self.shared.regions()[index].make_trash();
+        }
+        self.shared.collection_set_mut().clear();
+        self.recycle_trash();
+        self.shared
+            .free_set()
+            .rebuild(self.shared.regions(), self.evac_reserve);
+        self.shared.heap_lock.unlock();
+    }
+
+    /// Replace all references in roots, weak references and finalize lists with their forwarding pointers. Must be invoked when all mutators are
+    /// stopped.
+    unsafe fn update_roots(&mut self, keep: &mut dyn Trace) {
+        let mut visitor = ResolveVisitor;
+        for i in 0..self.mutators.len() {
+            let mutator = self.mutators[i];
+            (*mutator).shadow_stack().walk(|entry| {
+                entry.trace(&mut visitor);
+            });
+        }
+        self.persistent_roots.trace(&mut visitor);
+        self.pinned_objects.trace(&mut visitor);
+        keep.trace(&mut visitor);
+        // constraints might hold references as well.
+        for constraint in self.constraints.iter_mut() {
+            constraint.run(&mut visitor);
+        }
+        for weak in self.weak_refs.iter_mut() {
+            weak.set_base(resolve(weak.base()));
+            weak.after_mark(resolve);
+        }
+        for soft_ref in self.soft_refs.iter_mut() {
+            soft_ref.set_base(resolve(soft_ref.base()));
+            soft_ref.after_mark(resolve);
+        }
+        // key and value are updated when ephemeron itself is traced by update-refs.
+        for ephemeron in self.ephemerons.iter_mut() {
+            ephemeron.set_base(resolve(ephemeron.base()));
+        }
+        for finalizer in self.ordered_finalizers.iter_mut() {
+            finalizer.set_base(resolve(finalizer.base()));
+        }
+        self.finalize_lock.lock();
+        for object in self.finalize_list.iter_mut() {
+            *object = resolve(*object);
+        }
+        self.finalize_lock.unlock();
+    }
+
+    /// Full GC. Must be invoked when all mutators are stopped. Marks heap from scratch and slides live objects to the start of the
+    /// heap (humongous objects are not moved). Works in any phase of concurrent cycle: all references are resolved through Brooks
+    /// pointers while marking.
+    unsafe fn full_gc(&mut self, mut keep: &mut [&mut dyn Trace]) {
+        self.shared.evacuation.set_evacuating(false);
+        // cycle might have been cancelled in the middle of marking.
+        self.shared.marker.set_marking(false);
+        while self
+            .shared
+            .marker
+            .marking_worklists()
+            .marking_worklist()
+            .pop()
+            .is_some()
+        {}
+        while self
+            .shared
+            .marker
+            .marking_worklists()
+            .write_barrier_worklist()
+            .pop()
+            .is_some()
+        {}
+        for i in 0..self.mutators.len() {
+            (*self.mutators[i]).reset_tlab();
+        }
+        let used = self.used();
+        self.shared.heap_lock.lock();
+        self.shared.gclab().retire();
+
+        // Phase 1: reset colours and mark live objects.
+        for region in self.shared.regions().iter() {
+            if region.is_regular() || region.is_cset() {
+                walk_objects(region.bottom(), region.top(), |object| {
+                    (*object).force_set_color(GC_WHITE);
+                });
+            } else if region.is_humongous_start() {
+                (*region.bottom().add(BROOKS_POINTER_SIZE).cast::<HeapObjectHeader>())
+                    .force_set_color(GC_WHITE);
+            }
+        }
+        let mut marker = FullGcMarker { stack: vec![] };
+        let this = self as *mut Self;
+        (*this).constraints.retain_mut(|constraint| {
+            if constraint.is_over() {
+                false
+            } else {
+                if constraint.runs_at() == MarkingConstraintRuns::BeforeMark {
+                    constraint.run(&mut marker);
+                }
+                true
+            }
+        });
+        for i in 0..self.mutators.len() {
+            let mutator = self.mutators[i];
+            (*mutator).shadow_stack().walk(|entry| {
+                entry.trace(&mut marker);
+            });
+        }
+        self.persistent_roots.trace(&mut marker);
+        self.pinned_objects.trace(&mut marker);
+        keep.trace(&mut marker);
+        marker.drain();
+        (*this).constraints.retain_mut(|constraint| {
+            if constraint.is_over() {
+                false
+            } else {
+                if constraint.runs_at() == MarkingConstraintRuns::AfterMark {
+                    constraint.run(&mut marker);
+                }
+                true
+            }
+        });
+        marker.drain();
+        let marker = &mut marker as *mut FullGcMarker;
+        self.soft_ref_policy
+            .begin_cycle(used, self.shared.options.max_heap_size);
+        let forwardee = |header: *mut HeapObjectHeader| {
+            let header = resolve(header);
+            if (*header).get_color() == GC_BLACK {
+                header
+            } else {
+                null_mut()
+            }
+        };
+        trace_soft_refs(
+            &(*this).soft_refs,
+            &(*this).soft_ref_policy,
+            forwardee,
+            |slot| (*marker).mark_object(slot),
+            || (*marker).drain(),
+        );
+        trace_ephemerons(
+            &(*this).ephemerons,
+            forwardee,
+            |slot| (*marker).mark_object(slot),
+            || (*marker).drain(),
+        );
+        // finalizable objects might have been evacuated by interrupted concurrent cycle, their children are traced from new location.
+        for finalizer in (*this).ordered_finalizers.iter_mut() {
+            finalizer.set_base(resolve(finalizer.base()));
+        }
+        if trace_ordered_finalizers(
+            &mut (*this).ordered_finalizers,
+            forwardee,
+            |slot| (*marker).mark_object(slot),
+            || (*marker).drain(),
+        ) {
+            trace_ephemerons(
+                &(*this).ephemerons,
+                forwardee,
+                |slot| (*marker).mark_object(slot),
+                || (*marker).drain(),
+            );
+        }
+
+        self.weak_refs.retain_mut(|object| {
+            let header = resolve(object.base());
+            object.set_base(header);
+            if (*header).get_color() == GC_BLACK {
+                object.after_mark(|header| {
+                    let header = resolve(header);
+                    if (*header).get_color() == GC_BLACK {
+                        header
+                    } else {
+                        null_mut()
+                    }
+                });
+                true
+            } else {
+                false
+            }
+        });
+        self.soft_refs.retain_mut(|soft_ref| {
+            let header = resolve(soft_ref.base());
+            soft_ref.set_base(header);
+            if (*header).get_color() == GC_BLACK {
+                soft_ref.after_mark(forwardee);
+                true
+            } else {
+                false
+            }
+        });
+        self.ephemerons.retain_mut(|ephemeron| {
+            let header = resolve(ephemeron.base());
+            ephemeron.set_base(header);
+            if (*header).get_color() == GC_BLACK {
+                ephemeron.after_mark(|key| {
+                    let key = resolve(key);
+                    if (*key).get_color() == GC_BLACK {
+                        key
+                    } else {
+                        null_mut()
+                    }
+                });
+                true
+            } else {
+                false
+            }
+        });
+        self.finalize_lock.lock();
+        self.finalize_list = self
+            .finalize_list
+            .iter()
+            .filter_map(|object| {
+                let object = resolve(*object);
+                if (*object).get_color() == GC_BLACK {
+                    Some(object)
+                } else {
+                    (*object).get_dyn().finalize();
+                    None
+                }
+            })
+            .collect();
+        self.finalize_lock.unlock();
+
+        self.reclaim_humongous();
+        self.recycle_trash();
+        for &index in self.shared.collection_set().regions() {
+            self.shared.regions()[index].make_regular_bypass();
+        }
+        self.shared.collection_set_mut().clear();
+
+        // Phase 2: compute new locations. Live objects are slid to lower addresses, new location is stored in Brooks pointer.
+        // Objects in regions that contain pinned objects stay in place.
+        let pinned = self.pin_regions();
+        for region in self.shared.regions().iter_mut() {
+            if region.is_pinned() {
+                region.set_new_top(region.top());
+            } else {
+                region.set_new_top(region.bottom());
+            }
+        }
+        let regions = self.shared.regions().as_mut_ptr();
+        let num_regions = self.shared.num_regions;
+        let is_compactable = |index: usize| {
+            let region = &*regions.add(index);
+            (region.is_regular() || region.is_empty()) && !region.is_pinned()
+        };
+        let mut to = 0;
+        while !is_compactable(to) {
+            to += 1;
+        }
+        let mut to_top = (*regions.add(to)).bottom();
+        for from in 0..num_regions {
+            let region = &*regions.add(from);
+            if !region.is_regular() || region.is_pinned() {
+                continue;
+            }
+            walk_objects(region.bottom(), region.top(), |object| {
+                if (*object).get_color() != GC_BLACK {
+                    return;
+                }
+                // identity hash is appended only to objects that are moved.
+                let size_at = |to_top: *mut u8| {
+                    if to_top.add(BROOKS_POINTER_SIZE) == object.cast() {
+                        (*object).size()
+                    } else {
+                        (*object).copy_size()
+                    }
+                };
+                if to_top.add(size_at(to_top)) > (*regions.add(to)).end() {
+                    (*regions.add(to)).set_new_top(to_top);
+                    to += 1;
+                    while !is_compactable(to) {
+                        to += 1;
+                    }
+                    to_top = (*regions.add(to)).bottom();
+                }
+                let size = size_at(to_top);
+                (*brooks_pointer(object))
+                    .store(to_top.add(BROOKS_POINTER_SIZE) as usize, Ordering::Relaxed);
+                to_top = to_top.add(size);
+            });
+        }
+        (*regions.add(to)).set_new_top(to_top);
+
+        // Phase 3: adjust references.
+        let mut visitor = ResolveVisitor;
+        for region in self.shared.regions().iter() {
+            if region.is_regular() {
+                walk_objects(region.bottom(), region.top(), |object| {
+                    if (*object).get_color() == GC_BLACK {
+                        (*object).get_dyn().trace(&mut visitor);
+                    }
+                });
+            } else if region.is_humongous_start() {
+                let object = region.bottom().add(BROOKS_POINTER_SIZE).cast::<HeapObjectHeader>();
+                (*object).get_dyn().trace(&mut visitor);
+            }
+        }
+        self.update_roots(&mut keep);
+
+        // Phase 4: move objects.
+        let now = self.elapsed();
+        for region in self.shared.regions().iter_mut() {
+            if region.is_regular() {
+                let is_pinned = region.is_pinned();
+                walk_objects(region.bottom(), region.top(), |object| {
+                    if (*object).get_color() != GC_BLACK {
+                        // dead objects of pinned region are not overwritten by compaction, region must stay parsable
+                        // without them.
+                        if is_pinned {
+                            let start = object.cast::<u8>().sub(BROOKS_POINTER_SIZE);
+                            fill_with_dummy_object(start, start.add((*object).size()));
+                        }
+                        return;
+                    }
+                    let new_object = resolve(object);
+                    if new_object != object {
+                        std::ptr::copy(
+                            object.cast::<u8>().sub(BROOKS_POINTER_SIZE),
+                            new_object.cast::<u8>().sub(BROOKS_POINTER_SIZE),
+                            (*object).size(),
+                        );
+                        (*new_object).finish_copy(object);
+                        (*brooks_pointer(new_object)).store(new_object as usize, Ordering::Relaxed);
+                    }
+                    (*new_object).force_set_color(GC_WHITE);
+                });
+            } else if region.is_humongous_start() {
+                (*region.bottom().add(BROOKS_POINTER_SIZE).cast::<HeapObjectHeader>())
+                    .force_set_color(GC_WHITE);
+            }
+        }
+        for region in self.shared.regions().iter_mut() {
+            if region.is_regular() || region.is_empty() {
+                if region.new_top() == region.bottom() {
+                    region.make_empty(now);
+                } else {
+                    region.make_regular_allocation();
+                    region.set_top(region.new_top());
+                }
+            }
+            region.clear_live_data();
+            region.reset_alloc_metadata();
+            region.set_update_watermark(region.bottom());
+        }
+        self.unpin_regions(pinned);
+        self.shared
+            .free_set()
+            .rebuild(self.shared.regions(), self.evac_reserve);
+        self.shared.heap_lock.unlock();
+        self.shared
+            .evacuation_failed
+            .store(false, Ordering::Relaxed);
+        self.shared.cancelled.store(false, Ordering::Relaxed);
+    }
+
+    /// Concurrent marking, runs in GC thread. Final marking is requested when marking is finished or cancelled.
+    fn concurrent_mark(shared: &Shared, logger: Option<Arc<GcLogger>>, gc_id: usize) {
+        let time = Instant::now();
+        shared.marker.drain(Some(&shared.cancelled));
+        if let Some(logger) = logger {
+            logger.log_phase("Shenandoah", gc_id, "Concurrent Mark", time.elapsed(), &[]);
+        }
+        shared.phase.store(Phase::FinalMark, Ordering::Release);
+    }
+
+    /// Concurrent evacuation, runs in GC thread. Init update-refs is requested when evacuation is finished, cancelled or failed.
+    fn concurrent_evacuation(shared: &Shared, logger: Option<Arc<GcLogger>>, gc_id: usize) {
+        let time = Instant::now();
+        unsafe {
+            shared.evacuate_collection_set(true);
+        }
+        if let Some(logger) = logger {
+            logger.log_phase(
+                "Shenandoah",
+                gc_id,
+                "Concurrent Evacuation",
+                time.elapsed(),
+                &[],
+            );
+        }
+        shared.phase.store(Phase::InitUpdateRefs, Ordering::Release);
+    }
+
+    /// Concurrent update-refs, runs in GC thread. Final update-refs is requested when update-refs is finished or cancelled.
+    fn concurrent_update_refs(shared: &Shared, logger: Option<Arc<GcLogger>>, gc_id: usize) {
+        let time = Instant::now();
+        unsafe {
+            shared.update_heap_references(true);
+        }
+        if let Some(logger) = logger {
+            logger.log_phase(
+                "Shenandoah",
+                gc_id,
+                "Concurrent Update Refs",
+                time.elapsed(),
+                &[],
+            );
+        }
+        shared
+            .phase
+            .store(Phase::FinalUpdateRefs, Ordering::Release);
+    }
+
+    /// Run `phase` of concurrent cycle in GC thread. GC thread gets only [Shared] state of the heap.
+    fn spawn_gc_thread(&mut self, phase: ConcurrentPhase) {
+        let shared = self.shared.clone();
+        let logger = self.safepoint.logger();
+        let gc_id = self.total_gcs;
+        let handle = std::thread::Builder::new()
+            .name("shenandoah-gc".to_string())
+            .spawn(move || phase(&shared, logger, gc_id))
+            .expect("failed to spawn Shenandoah thread");
+        if let Some(prev) = self.gc_thread.lock().replace(handle) {
+            // previous phase is finished, join it to release thread resources.
+            let _ = prev.join();
+        }
+    }
+
+    /// Perform the next pause of concurrent cycle if GC thread finished its phase. All of the values that mutator holds must be rooted.
+    fn poll_concurrent_cycle(&mut self, mutator: &mut MutatorRef<Self>) {
+        if !self.shared.phase.load(Ordering::Acquire).requests_pause() {
+            return;
+        }
+        let safepoint = match SafepointScope::new(mutator.clone()) {
+            Some(safepoint) => safepoint,
+            None => return,
+        };
+        // other mutator might have performed the pause before this one stopped the world.
+        if !self.shared.phase.load(Ordering::Acquire).requests_pause() {
+            return;
+        }
+        self.global_heap_lock.lock();
+        let next = unsafe { self.perform_pause() };
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        drop(safepoint);
+        if let Some(phase) = next {
+            self.spawn_gc_thread(phase);
+        }
+    }
+
+    /// Perform pause that GC thread requested. Returns concurrent phase that GC thread must run next or `None` if the cycle is
+    /// finished. If cycle was cancelled it is degenerated: the rest of the cycle is performed in this pause. Must be invoked when
+    /// all mutators are stopped.
+    unsafe fn perform_pause(&mut self) -> Option<ConcurrentPhase> {
+        let time = Instant::now();
+        let degenerated = self.shared.cancelled.load(Ordering::Acquire);
+        loop {
+            match self.shared.phase.load(Ordering::Acquire) {
+                Phase::Idle | Phase::Marking => unreachable!("GC thread did not request a pause"),
+                // evacuation and update-refs are reached only by degenerated cycle, GC thread is not running.
+                Phase::Evacuation => {
+                    self.shared.evacuate_collection_set(false);
+                    self.shared
+                        .phase
+                        .store(Phase::InitUpdateRefs, Ordering::Release);
+                }
+                Phase::UpdateRefs => {
+                    self.shared.update_heap_references(false);
+                    self.shared
+                        .phase
+                        .store(Phase::FinalUpdateRefs, Ordering::Release);
+                }
+                Phase::FinalMark => {
+                    let time = Instant::now();
+                    if self.final_mark(&mut []) {
+                        self.shared.evacuation.set_evacuating(true);
+                        self.shared
+                            .phase
+                            .store(Phase::Evacuation, Ordering::Release);
+                    } else {
+                        self.shared.update_refs_cursor.store(0, Ordering::Relaxed);
+                        self.shared
+                            .phase
+                            .store(Phase::UpdateRefs, Ordering::Release);
+                    }
+                    if !degenerated {
+                        self.log_pause("Pause Final Mark", time);
+                        return Some(
+                            if self.shared.phase.load(Ordering::Relaxed) == Phase::Evacuation {
+                                Self::concurrent_evacuation
+                            } else {
+                                Self::concurrent_update_refs
+                            },
+                        );
+                    }
+                }
+                Phase::InitUpdateRefs => {
+                    if self.shared.evacuation_failed.load(Ordering::Acquire) {
+                        self.full_gc(&mut []);
+                        self.heuristics.record_success_full();
+                        self.log_pause("Pause Full (Evacuation Failure)", time);
+                        self.finish_cycle();
+                        return None;
+                    }
+                    let time = Instant::now();
+                    self.init_update_refs();
+                    if !degenerated {
+                        self.log_pause("Pause Init Update Refs", time);
+                        return Some(Self::concurrent_update_refs);
+                    }
+                }
+                Phase::FinalUpdateRefs => {
+                    let time = Instant::now();
+                    self.final_update_refs(&mut []);
+                    if degenerated {
+                        self.heuristics.record_success_degenerated();
+                        self.log_pause("Pause Degenerated", time);
+                    } else {
+                        self.heuristics.record_success_concurrent();
+                        self.log_pause("Pause Final Update Refs", time);
+                    }
+                    self.finish_cycle();
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn finish_cycle(&mut self) {
+        self.heuristics.record_cycle_end(self.elapsed());
+        if let Some(cycle) = self.concurrent_cycle.take() {
+            self.gc_stats.end_cycle(cycle, self.used());
+        }
+        if let Some(start) = self.cycle_start.take() {
+            self.log_phase(
+                "Concurrent Cycle",
+                start,
+                &[
+                    ("heap_before", self.used_before_cycle),
+                    ("heap_after", self.used()),
+                ],
+            );
+        }
+        self.total_gcs += 1;
+        self.shared.phase.store(Phase::Idle, Ordering::Release);
+    }
+
+    /// Stop all mutators and start concurrent GC cycle. If `force_evacuation` is set cycle evacuates every region with live objects.
+    /// Does nothing and returns `false` if GC cycle is already running.
+    fn start_concurrent_cycle(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        keep: &mut [&mut dyn Trace],
+        force_evacuation: bool,
+    ) -> bool {
+        if self
+            .shared
+            .phase
+            .compare_exchange(Phase::Idle, Phase::Marking, Ordering::AcqRel, Ordering::Relaxed)
+            .is_err()
+        {
+            return false;
+        }
+        self.shared.cancelled.store(false, Ordering::Relaxed);
+        match SafepointScope::new(mutator.clone()) {
+            Some(safepoint) => unsafe {
+                self.global_heap_lock.lock();
+                self.used_before_cycle = self.used();
+                self.concurrent_cycle = Some(self.gc_stats.start_cycle(
+                    GcKind::Concurrent,
+                    GcReason::HeapFull,
+                    self.used_before_cycle,
+                ));
+                let time = Instant::now();
+                self.force_evacuation = force_evacuation;
+                self.heuristics.record_cycle_start(self.elapsed());
+                self.init_mark(keep);
+                self.cycle_start = Some(time);
+                self.log_pause("Pause Init Mark", time);
+                self.global_heap_lock.unlock();
+                drop(safepoint);
+                self.spawn_gc_thread(Self::concurrent_mark);
+                true
+            },
+            None => {
+                self.shared.phase.store(Phase::Idle, Ordering::Release);
+                false
+            }
+        }
+    }
+
+    /// Wait until concurrent GC cycle is finished. Pauses that GC thread requests are performed by this mutator if it gets to them
+    /// first. Mutator enters unsafe state while GC thread runs so GC pauses can stop it, all of the values that mutator holds must be
+    /// rooted.
+    fn wait_for_gc_to_complete(&mut self, mutator: &mut MutatorRef<Self>) {
+        loop {
+            let phase = self.shared.phase.load(Ordering::Acquire);
+            if phase == Phase::Idle {
+                return;
+            }
+            if phase.requests_pause() {
+                self.poll_concurrent_cycle(mutator);
+            } else {
+                let state = mutator.enter_unsafe();
+                while self.shared.phase.load(Ordering::Acquire).is_concurrent() {
+                    std::thread::yield_now();
+                }
+                drop(state);
+            }
+        }
+    }
+
+    /// Wait for concurrent cycle to complete and perform Full GC in STW pause.
+    fn perform_full_collection(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        keep: &mut [&mut dyn Trace],
+        reason: GcReason,
+    ) {
+        {
+            letroot!(_keep = mutator.shadow_stack(), &mut *keep);
+            loop {
+                self.wait_for_gc_to_complete(mutator);
+                if self
+                    .shared
+                    .phase
+                    .compare_exchange(Phase::Idle, Phase::Marking, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    break;
+                }
+            }
+        }
+        match SafepointScope::new(mutator.clone()) {
+            Some(safepoint) => unsafe {
+                self.global_heap_lock.lock();
+                let time = Instant::now();
+                let timer = PhaseTimer::new(self.safepoint.last_sync());
+                let prev = self.used();
+                let cycle = self.gc_stats.start_cycle(GcKind::Full, reason, prev);
+                self.full_gc(keep);
+                self.heuristics.record_success_full();
+                self.heuristics.record_cycle_end(self.elapsed());
+                self.gc_stats.end_cycle(cycle, self.used());
+                if let Some(logger) = self.safepoint.logger() {
+                    logger.log_gc(&GcRecord {
+                        policy: "Shenandoah",
+                        gc_id: self.total_gcs,
+                        kind: GcKind::Full,
+                        reason,
+                        heap_before: prev,
+                        heap_after: self.used(),
+                        footprint: self.shared.options.max_heap_size,
+                        duration: time.elapsed(),
+                        phases: timer.phases(),
+                        extra: &[],
+                    });
+                }
+                self.total_gcs += 1;
+                self.global_heap_lock.unlock();
+                drop(safepoint);
+            },
+            None => (),
+        }
+        self.shared.phase.store(Phase::Idle, Ordering::Release);
+    }
+}
+
+impl<H: ShenandoahHeuristics> GcBase for ShenandoahHeap<H> {
+    type TLAB = ShenandoahTLAB;
+    const SUPPORTS_TLAB: bool = false;
+    type ReadBarrier = ShenandoahBarrier;
+    const LARGE_ALLOCATION_SIZE: usize = 32 * 1024;
+
+    fn inline_allocation_helpers(&self) -> Self::InlineAllocationHelpers {
+        NoHelp
+    }
+
+    fn add_constraint<T: MarkingConstraint + 'static>(&mut self, constraint: T) {
+        self.global_lock();
+        self.constraints.push(Box::new(constraint));
+        self.global_unlock();
+    }
+
+    fn try_allocate_raw(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        size: usize,
+        type_id: std::any::TypeId,
+        vtable: usize,
+    ) -> Result<*mut HeapObjectHeader, AllocError<()>> {
+        let size = align_usize(
+            size + size_of::<HeapObjectHeader>() + BROOKS_POINTER_SIZE,
+            8,
+        );
+        unsafe {
+            let object = if size - BROOKS_POINTER_SIZE < Self::LARGE_ALLOCATION_SIZE {
+                let mut memory = mutator.tlab.allocate(size);
+                if memory.is_null() {
+                    memory = self.alloc_slow(mutator, size, &mut []);
+                    if memory.is_null() {
+                        return Err(AllocError::new((), size, AllocationSpace::New));
+                    }
+                }
+                init_object(memory, size, vtable, make_small_type_id(type_id))
+            } else {
+                let mut memory = self.allocate_humongous(size);
+                if memory.is_null() {
+                    memory =
+                        self.handle_alloc_failure(mutator, |heap, _| heap.allocate_humongous(size));
+                    if memory.is_null() {
+                        return Err(AllocError::new((), size, AllocationSpace::Large));
+                    }
+                }
+                // humongous objects have size 0 in header.
+                init_object(memory, 0, vtable, make_small_type_id(type_id))
+            };
+
+            let gced: Gc<(), Self> = Gc {
+                base: NonNull::new_unchecked(object),
+                marker: Default::default(),
+            };
+            self.post_alloc(gced);
+            Ok(object)
+        }
+    }
+
+    fn allocate_weak<T: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        value: Gc<T, Self>,
+    ) -> Weak<T, Self> {
+        let weak_ref = unsafe { Weak::<T, Self>::create(mutator, value) };
+        self.global_heap_lock.lock();
+        self.weak_refs.push(weak_ref.to_dyn());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        weak_ref
+    }
+    fn allocate_soft<T: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        value: Gc<T, Self>,
+    ) -> SoftRef<T, Self> {
+        let soft_ref = unsafe { SoftRef::<T, Self>::create(mutator, value) };
+        self.global_heap_lock.lock();
+        self.soft_refs.push(soft_ref.to_dyn());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        soft_ref
+    }
+    fn set_soft_ref_lru_policy(&mut self, ms_per_mb: u64) {
+        self.global_heap_lock.lock();
+        self.soft_ref_policy.set_ms_per_mb(ms_per_mb);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn allocate_ephemeron<K: Collectable + ?Sized, V: Collectable + ?Sized>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        key: Gc<K, Self>,
+        value: Gc<V, Self>,
+    ) -> Ephemeron<K, V, Self> {
+        let ephemeron = unsafe { Ephemeron::create(mutator, key, value) };
+        self.global_heap_lock.lock();
+        self.ephemerons.push(ephemeron.to_dyn());
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        ephemeron
+    }
+    fn persistent_roots(&self) -> Arc<PersistentRoots<Self>> {
+        self.persistent_roots.clone()
+    }
+    fn pinned_objects(&self) -> Arc<PinnedObjects<Self>> {
+        self.pinned_objects.clone()
+    }
+    fn prepare_pin(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        object: Gc<dyn Collectable, Self>,
+    ) -> Gc<dyn Collectable, Self> {
+        let _ = mutator;
+        // object that is in collection set is evacuated before it is pinned, region of its copy is never in current
+        // collection set.
+        <ShenandoahBarrier as ReadBarrier<Self>>::read_barrier(object)
+    }
+    fn stats(&self) -> HeapStats {
+        self.gc_stats.snapshot(
+            self.used(),
+            self.shared.num_regions * self.shared.options.region_size_bytes,
+        )
+    }
+    fn add_gc_listener(&mut self, listener: Box<dyn GcListener>) {
+        self.gc_stats.add_listener(listener);
+    }
+    fn register_ordered_finalizer(&mut self, finalizer: OrderedFinalizer<Self>) {
+        self.global_heap_lock.lock();
+        self.ordered_finalizers.push(finalizer);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+    }
+    fn pop_ready_finalizer(&mut self) -> Option<OrderedFinalizer<Self>> {
+        self.global_heap_lock.lock();
+        let finalizer = pop_ready_finalizer(&mut self.ordered_finalizers);
+        unsafe {
+            self.global_heap_lock.unlock();
+        }
+        finalizer
+    }
+    fn set_oom_handler(&mut self, handler: OomHandler<Self>) {
+        self.oom_handler.set(handler);
+    }
+    fn handle_oom(&mut self, mutator: &mut MutatorRef<Self>, size: usize) -> bool {
+        // emergency collection: clear all soft references before giving up.
+        if !self.soft_refs.is_empty() && self.soft_ref_policy.request_clear_all() {
+            self.full_collection(mutator, &mut []);
+            return true;
+        }
+        self.oom_handler.invoke(mutator, size)
+    }
+
+    #[inline(always)]
+    fn try_alloc_inline<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+        space: AllocationSpace,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
+        let size = align_usize(
+            value.allocation_size() + size_of::<HeapObjectHeader>() + BROOKS_POINTER_SIZE,
+            8,
+        );
+        unsafe {
+            let mut memory = mutator.tlab.allocate(size);
+            if memory.is_null() {
+                memory = self.alloc_slow(mutator, size, &mut [&mut value]);
+                if memory.is_null() {
+                    return Err(AllocError::new(value, size, space));
+                }
+            }
+            let object = init_object(memory, size, 0, small_type_id::<T>());
+            (*object).set_metadata(vtable_of::<T>());
+            ((*object).data() as *mut T).write(value);
+            let gced = Gc {
+                base: NonNull::new_unchecked(object),
+                marker: Default::default(),
+            };
+            self.post_alloc(gced);
+            Ok(gced)
+        }
+    }
+
+    /// Write barrier. While marking it is retreating wavefront barrier: black object is coloured grey and rescanned by marker. During
+    /// evacuation and update-refs phases references in `object` are evacuated and updated so that it never points to from-space.
+    /// This write barrier must be used right after write to an object happened.
+    #[inline]
+    fn write_barrier(&mut self, _: &mut MutatorRef<Self>, object: Gc<dyn Collectable, Self>) {
+        unsafe {
+            let object = object.base.as_ptr();
+            if self.shared.marker.is_marking() {
+                let object = resolve(object);
+                if !(*object).set_color(GC_BLACK, GC_GREY) {
+                    self.shared
+                        .marker
+                        .marking_worklists()
+                        .write_barrier_worklist()
+                        .push(object as usize);
+                }
+            } else if self.shared.phase.load(Ordering::Relaxed).updates_refs() {
+                self.shared.update_object_refs(object);
+            }
+        }
+    }
+
+    fn collect_alloc_failure(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        keep: &mut [&mut dyn Trace],
+    ) {
+        self.perform_full_collection(mutator, keep, GcReason::AllocationFailure);
+    }
+
+    fn collect(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        self.perform_full_collection(mutator, keep, GcReason::RequestedByUser);
+    }
+
+    /// Run concurrent cycle that evacuates every region with live objects and wait for it to complete, so that objects are moved
+    /// by concurrent evacuation rather than by Full GC.
+    fn stress_collection(&mut self, mutator: &mut MutatorRef<Self>, keep: &mut [&mut dyn Trace]) {
+        letroot!(_keep = mutator.shadow_stack(), &mut *keep);
+        loop {
+            self.wait_for_gc_to_complete(mutator);
+            if self.start_concurrent_cycle(mutator, &mut [], true) {
+                break;
+            }
+        }
+        self.wait_for_gc_to_complete(mutator);
+    }
+
+    fn alloc_tlab_area(&mut self, _mutator: &MutatorRef<Self>, _size: usize) -> *mut u8 {
+        null_mut()
+    }
+    fn safepoint(&self) -> &GlobalSafepoint {
+        &self.safepoint
+    }
+
+    fn attach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
+        self.global_heap_lock.lock();
+        self.safepoint.n_mutators.fetch_add(1, Ordering::Relaxed);
+        self.mutators.push(mutator);
+        unsafe { self.global_heap_lock.unlock() };
+    }
+
+    unsafe fn detach_current_thread(&mut self, mutator: *mut Mutator<Self>) {
+        self.global_heap_lock.lock();
+
+        let mut detached = false;
+        self.mutators.retain(|x| {
+            let x = *x;
+            let y = mutator;
+            if x == y {
+                detached = true;
+                false
+            } else {
+                true
+            }
+        });
+        self.safepoint.n_mutators.fetch_sub(1, Ordering::Relaxed);
+        assert!(detached, "mutator must be detached");
+        unsafe {
+            // keep region parsable
+            (*mutator).reset_tlab();
+            self.global_heap_lock.unlock();
+        }
+    }
+
+    fn global_lock(&self) {
+        self.global_heap_lock.lock();
+    }
+    fn global_unlock(&self) {
+        unsafe {
+            debug_assert!(self.global_heap_lock.is_locked());
+            self.global_heap_lock.unlock();
+        }
+    }
+
+    fn mutators(&self) -> &[*mut Mutator<Self>] {
+        assert!(self.global_heap_lock.is_locked());
+        &self.mutators
+    }
+
+    fn try_allocate_large<T: Collectable + Sized + 'static>(
+        &mut self,
+        mutator: &mut MutatorRef<Self>,
+        mut value: T,
+    ) -> Result<Gc<T, Self>, AllocError<T>> {
+        unsafe {
+            let size = align_usize(
+                value.allocation_size() + size_of::<HeapObjectHeader>() + BROOKS_POINTER_SIZE,
+                8,
+            );
+            if self.should_start_gc() {
+                self.start_concurrent_cycle(mutator, &mut [&mut value], false);
+            }
+            let mut memory = self.allocate_humongous(size);
+            if memory.is_null() {
+                letroot!(_keep = mutator.shadow_stack(), &mut [&mut value as &mut dyn Trace][..]);
+                memory =
+                    self.handle_alloc_failure(mutator, |heap, _| heap.allocate_humongous(size));
+            }
+            if memory.is_null() {
+                return Err(AllocError::new(value, size, AllocationSpace::Large));
+            }
+            // humongous objects have size 0 in header.
+            let object = init_object(memory, 0, 0, small_type_id::<T>());
+            (*object).set_metadata(vtable_of::<T>());
+            ((*object).data() as *mut T).write(value);
+            let gc = Gc {
+                base: NonNull::new_unchecked(object),
+                marker: PhantomData,
+            };
+            self.post_alloc(gc);
+            Ok(gc)
+        }
+    }
+
+    #[inline(always)]
+    fn post_alloc<T: Collectable + Sized + 'static>(&mut self, value: Gc<T, Self>) {
+        if std::mem::needs_drop::<T>() {
+            unsafe {
+                self.finalize_lock.lock();
+                self.finalize_list.push_back(value.base.as_ptr());
+                self.finalize_lock.unlock();
+            }
+        }
+        if self.shared.phase.load(Ordering::Acquire).updates_refs() {
+            unsafe {
+                self.shared.update_object_refs(value.base.as_ptr());
+            }
+        }
+    }
+}
+
+impl<H: ShenandoahHeuristics> Drop for ShenandoahHeap<H> {
+    fn drop(&mut self) {
+        if let Some(handle) = self.gc_thread.get_mut().take() {
+            let _ = handle.join();
+        }
+        self.shared.evacuation.retire();
+        unsafe {
+            let _ = Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+                self.heuristics.region_data(),
+                self.shared.num_regions,
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{instantiate_shenandoah, Phase, ShenandoahHeap, ShenandoahHeapOptions};
+    use crate::{
+        api::{Collectable, Finalize, Gc, Trace, Visitor, GC_BLACK, GC_GREY},
+        gc_base::{AllocationSpace, GcBase},
+        mutator::MutatorRef,
+        safepoint::SafepointScope,
+        shenandoah::{
+            barrier::resolve,
+            heuristics::{
+                ShenandoahAggressiveHeuristics, ShenandoahHeuristics, ShenandoahStaticHeuristics,
+            },
+        },
+    };
+    use atomic::Ordering;
+
+    struct Node<H: ShenandoahHeuristics> {
+        value: usize,
+        next: Option<Gc<Node<H>, ShenandoahHeap<H>>>,
+    }
+
+    unsafe impl<H: ShenandoahHeuristics> Trace for Node<H> {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+        }
+    }
+    unsafe impl<H: ShenandoahHeuristics> Finalize for Node<H> {}
+    impl<H: ShenandoahHeuristics + 'static> Collectable for Node<H> {}
+
+    struct Slots<H: ShenandoahHeuristics> {
+        slots: [Option<Gc<Node<H>, ShenandoahHeap<H>>>; 64],
+    }
+
+    unsafe impl<H: ShenandoahHeuristics> Trace for Slots<H> {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.slots.trace(vis);
+        }
+    }
+    unsafe impl<H: ShenandoahHeuristics> Finalize for Slots<H> {}
+    impl<H: ShenandoahHeuristics + 'static> Collectable for Slots<H> {}
+
+    fn heap<H: ShenandoahHeuristics>() -> MutatorRef<ShenandoahHeap<H>> {
+        instantiate_shenandoah::<H>(ShenandoahHeapOptions {
+            max_heap_size: 32 * 1024 * 1024,
+            ..Default::default()
+        })
+    }
+
+    fn node<H: ShenandoahHeuristics + 'static>(
+        mutator: &mut MutatorRef<ShenandoahHeap<H>>,
+        value: usize,
+    ) -> Gc<Node<H>, ShenandoahHeap<H>> {
+        mutator.allocate(Node { value, next: None }, AllocationSpace::New)
+    }
+
+    #[test]
+    fn test_write_barrier_while_marking() {
+        let mut mutator = heap::<ShenandoahStaticHeuristics>();
+        letroot!(holder = mutator.shadow_stack(), node(&mut mutator, 1));
+        holder.next = Some(node(&mut mutator, 2));
+        let heap = unsafe { &mut *mutator.heap.get() };
+        // cycle is driven by this thread so marking can be stopped at a known point.
+        heap.shared.phase.store(Phase::Marking, Ordering::Release);
+        let safepoint = SafepointScope::new(mutator.clone()).unwrap();
+        unsafe {
+            heap.init_mark(&mut []);
+        }
+        drop(safepoint);
+        heap.shared.marker.drain(None);
+        // `holder` is black, new object is stored only in it.
+        unsafe {
+            assert_eq!((*holder.base.as_ptr()).get_color(), GC_BLACK);
+        }
+        holder.next = Some(node(&mut mutator, 3));
+        mutator.write_barrier(holder.to_dyn());
+        unsafe {
+            assert_eq!((*holder.base.as_ptr()).get_color(), GC_GREY);
+        }
+        // this thread finishes concurrent marking itself, pauses and the rest of the cycle are driven by waiting for it.
+        ShenandoahHeap::<ShenandoahStaticHeuristics>::concurrent_mark(&heap.shared, None, 0);
+        heap.wait_for_gc_to_complete(&mut mutator);
+        assert_eq!(heap.shared.phase.load(Ordering::Acquire), Phase::Idle);
+        for value in 0..10000 {
+            node(&mut mutator, value);
+        }
+        mutator.collect(&mut []);
+        assert_eq!(holder.value, 1);
+        assert_eq!(holder.next.unwrap().value, 3);
+    }
+
+    #[test]
+    fn test_degenerated_cycle() {
+        let mut mutator = heap::<ShenandoahStaticHeuristics>();
+        letroot!(holder = mutator.shadow_stack(), node(&mut mutator, 1));
+        holder.next = Some(node(&mut mutator, 2));
+        let heap = unsafe { &mut *mutator.heap.get() };
+        assert!(heap.start_concurrent_cycle(&mut mutator, &mut [], true));
+        // cancelled cycle is finished in one pause by the mutator that waits for it.
+        heap.shared.cancelled.store(true, Ordering::Release);
+        heap.wait_for_gc_to_complete(&mut mutator);
+        assert_eq!(heap.shared.phase.load(Ordering::Acquire), Phase::Idle);
+        assert_eq!(heap.stats().concurrent_collections, 1);
+        assert_eq!(holder.value, 1);
+        assert_eq!(holder.next.unwrap().value, 2);
+    }
+
+    #[test]
+    fn test_evacuation_while_mutating() {
+        let mut mutator = heap::<ShenandoahAggressiveHeuristics>();
+        letroot!(
+            slots = mutator.shadow_stack(),
+            mutator.allocate(Slots { slots: [None; 64] }, AllocationSpace::New)
+        );
+        for value in 0..200000 {
+            let object = node(&mut mutator, value);
+            slots.slots[value % 64] = Some(object);
+            mutator.write_barrier(slots.to_dyn());
+            if value % 64 == 63 {
+                for (index, slot) in slots.slots.iter().enumerate() {
+                    assert_eq!(slot.unwrap().value, value - 63 + index);
+                }
+            }
+        }
+        assert!(mutator.stats().concurrent_collections > 0);
+        mutator.collect(&mut []);
+        for (index, slot) in slots.slots.iter().enumerate() {
+            assert_eq!(slot.unwrap().value, 200000 - 64 + index);
+        }
+    }
+
+    #[test]
+    fn test_update_refs() {
+        let mut mutator = heap::<ShenandoahStaticHeuristics>();
+        letroot!(
+            list = mutator.shadow_stack(),
+            None::<Gc<Node<ShenandoahStaticHeuristics>, _>>
+        );
+        for value in 0..10000 {
+            let mut object = node(&mut mutator, value);
+            object.next = *list;
+            *list = Some(object);
+        }
+        let mut addresses = vec![];
+        let mut object = *list;
+        while let Some(current) = object {
+            addresses.push(current.base);
+            object = current.next;
+        }
+        let heap = unsafe { &mut *mutator.heap.get() };
+        heap.stress_collection(&mut mutator, &mut []);
+        assert_eq!(mutator.stats().concurrent_collections, 1);
+        // update-refs replaced every reference to from-space copy with reference to to-space copy.
+        let mut object = *list;
+        let mut value = 10000;
+        let mut moved = 0;
+        while let Some(current) = object {
+            value -= 1;
+            assert_eq!(resolve(current.base.as_ptr()), current.base.as_ptr());
+            assert_eq!(current.value, value);
+            if current.base != addresses[9999 - value] {
+                moved += 1;
+            }
+            object = current.next;
+        }
+        assert_eq!(value, 0);
+        assert!(moved > 0);
+    }
+
+    #[test]
+    fn test_pinning() {
+        let mut mutator = heap::<ShenandoahStaticHeuristics>();
+        let mut object = node(&mut mutator, 42);
+        object.next = Some(node(&mut mutator, 1));
+        let pinned = mutator.pin(object);
+        let address = pinned.get().base;
+        letroot!(rooted = mutator.shadow_stack(), pinned.get());
+        let heap = unsafe { &mut *mutator.heap.get() };
+        for round in 0..4 {
+            // dead objects around pinned one are left in its region.
+            for value in 0..10000 {
+                node(&mut mutator, value);
+            }
+            if round % 2 == 0 {
+                heap.stress_collection(&mut mutator, &mut []);
+            } else {
+                mutator.collect(&mut []);
+            }
+            assert_eq!(resolve(address.as_ptr()), address.as_ptr());
+            assert_eq!(rooted.base, address);
+            assert_eq!(pinned.value, 42);
+            assert_eq!(pinned.next.unwrap().value, 1);
+        }
+        assert!(!unsafe { (*address.as_ptr()).pinned_bit() });
+
+        drop(pinned);
+        heap.stress_collection(&mut mutator, &mut []);
+        assert_ne!(rooted.base, address);
+        assert_eq!(rooted.value, 42);
+        assert_eq!(rooted.next.unwrap().value, 1);
+    }
 }
@@ -1,31 +1,62 @@
+use std::{cmp::Reverse, ptr::null_mut};
+
 use super::{collection_set::ShenandoahCollectionSet, region::ShenandoahHeapRegion};
 
+#[derive(Clone, Copy)]
 pub struct RegionData {
     pub region: *mut ShenandoahHeapRegion,
     pub garbage: usize,
 }
 
-pub trait ShenandoahHeuristics {
+impl Default for RegionData {
+    fn default() -> Self {
+        Self {
+            region: null_mut(),
+            garbage: 0,
+        }
+    }
+}
+
+pub trait ShenandoahHeuristics: Default + 'static {
     /// recover from penalties
     const CONCURRENT_ADJUST: isize = -1;
     /// how much to penalize average GC duration history on Degenerated GC
     const DEGENERATE_PENALTY: isize = 10;
     /// how much to penalize average GC duration history on Full GC
     const FULL_PENALTY: isize = 20;
+    /// how much garbage (in percents of region size) region must have to be added to collection set
+    const GARBAGE_THRESHOLD: usize = 25;
+    /// if immediate garbage is larger than this percent of total garbage collection set is not constructed at all
+    const IMMEDIATE_THRESHOLD: usize = 90;
+    /// start concurrent cycle when free space falls below this percent of heap capacity
+    const MIN_FREE_THRESHOLD: usize = 10;
+    /// how much more space than live data of collection set evacuation is expected to take
+    const EVAC_WASTE: f64 = 1.2;
+    /// start concurrent cycle if there was no GC for this many seconds
+    const GUARANTEED_GC_INTERVAL: f64 = 5.0 * 60.0;
 
     fn region_data(&self) -> *mut RegionData;
     fn set_region_data(&mut self, data: *mut RegionData);
 
     fn degenerated_cycles_in_a_row(&self) -> u32;
+    fn set_degenerated_cycles_in_a_row(&mut self, x: u32);
     fn successful_cycles_in_a_row(&self) -> u32;
+    fn set_successful_cycles_in_a_row(&mut self, x: u32);
     fn cycle_start(&self) -> f64;
+    fn set_cycle_start(&mut self, x: f64);
     fn last_cycle_end(&self) -> f64;
+    fn set_last_cycle_end(&mut self, x: f64);
 
     fn gc_times_learned(&self) -> usize;
     fn gc_time_penalties(&self) -> isize;
     fn set_gc_time_penalties(&mut self, x: isize);
 
-    fn choose_collection_set_from_regiondata(
+    /// Add regions to collection set, `free` bytes are available for evacuation.
+    ///
+    /// # Safety
+    ///
+    /// `data` must point to `data_size` initialized entries and regions of the entries must be valid.
+    unsafe fn choose_collection_set_from_regiondata(
         &mut self,
         set: &mut ShenandoahCollectionSet,
         data: *mut RegionData,
@@ -33,7 +64,239 @@ pub trait ShenandoahHeuristics {
         free: usize,
     );
 
-    fn should_start_gc(&self, _guaranteed_interval: f64) -> bool {
-        false
+    /// Select collection set. Regions without live data are trashed right away ("immediate garbage"), the rest except pinned ones
+    /// are passed to [choose_collection_set_from_regiondata](Self::choose_collection_set_from_regiondata) sorted by amount of garbage.
+    /// If `force_evacuation` is set every region with live data is added to collection set as far as free space allows.
+    /// Region data buffer must be able to hold all of the regions. Returns number of bytes of immediate garbage.
+    fn choose_collection_set(
+        &mut self,
+        set: &mut ShenandoahCollectionSet,
+        regions: &mut [ShenandoahHeapRegion],
+        free: usize,
+        force_evacuation: bool,
+    ) -> usize {
+        let data = self.region_data();
+        let mut count = 0;
+        let mut immediate_garbage = 0;
+        let mut total_garbage = 0;
+        for region in regions.iter_mut() {
+            if !region.is_regular() {
+                continue;
+            }
+            total_garbage += region.garbage();
+            if !region.has_live() {
+                immediate_garbage += region.used();
+                region.make_trash();
+            } else if !region.is_pinned() {
+                unsafe {
+                    data.add(count).write(RegionData {
+                        region,
+                        garbage: region.garbage(),
+                    });
+                }
+                count += 1;
+            }
+        }
+        if force_evacuation {
+            unsafe {
+                add_regions_to_cset::<Self>(set, data, count, free, |_, _| true);
+            }
+        } else if immediate_garbage * 100 <= total_garbage * Self::IMMEDIATE_THRESHOLD {
+            unsafe {
+                std::slice::from_raw_parts_mut(data, count)
+                    .sort_unstable_by_key(|region| Reverse(region.garbage));
+                self.choose_collection_set_from_regiondata(set, data, count, free);
+            }
+        }
+        immediate_garbage
+    }
+
+    fn should_start_gc(&self, _capacity: usize, _available: usize, now: f64) -> bool {
+        now - self.last_cycle_end() > Self::GUARANTEED_GC_INTERVAL
+    }
+
+    fn record_cycle_start(&mut self, now: f64) {
+        self.set_cycle_start(now);
+    }
+
+    fn record_cycle_end(&mut self, now: f64) {
+        self.set_last_cycle_end(now);
+    }
+
+    fn adjust_penalty(&mut self, step: isize) {
+        let penalties = (self.gc_time_penalties() + step).clamp(0, 100);
+        self.set_gc_time_penalties(penalties);
+    }
+
+    fn record_success_concurrent(&mut self) {
+        self.set_degenerated_cycles_in_a_row(0);
+        self.set_successful_cycles_in_a_row(self.successful_cycles_in_a_row() + 1);
+        self.adjust_penalty(Self::CONCURRENT_ADJUST);
+    }
+
+    fn record_success_degenerated(&mut self) {
+        self.set_degenerated_cycles_in_a_row(self.degenerated_cycles_in_a_row() + 1);
+        self.set_successful_cycles_in_a_row(0);
+        self.adjust_penalty(Self::DEGENERATE_PENALTY);
+    }
+
+    fn record_success_full(&mut self) {
+        self.set_degenerated_cycles_in_a_row(0);
+        self.set_successful_cycles_in_a_row(self.successful_cycles_in_a_row() + 1);
+        self.adjust_penalty(Self::FULL_PENALTY);
+    }
+}
+
+macro_rules! heuristics_state {
+    ($name: ident) => {
+        impl Default for $name {
+            fn default() -> Self {
+                Self {
+                    region_data: null_mut(),
+                    degenerated_cycles_in_a_row: 0,
+                    successful_cycles_in_a_row: 0,
+                    cycle_start: 0.0,
+                    last_cycle_end: 0.0,
+                    gc_times_learned: 0,
+                    gc_time_penalties: 0,
+                }
+            }
+        }
+    };
+}
+
+macro_rules! heuristics_accessors {
+    () => {
+        fn region_data(&self) -> *mut RegionData {
+            self.region_data
+        }
+        fn set_region_data(&mut self, data: *mut RegionData) {
+            self.region_data = data;
+        }
+        fn degenerated_cycles_in_a_row(&self) -> u32 {
+            self.degenerated_cycles_in_a_row
+        }
+        fn set_degenerated_cycles_in_a_row(&mut self, x: u32) {
+            self.degenerated_cycles_in_a_row = x;
+        }
+        fn successful_cycles_in_a_row(&self) -> u32 {
+            self.successful_cycles_in_a_row
+        }
+        fn set_successful_cycles_in_a_row(&mut self, x: u32) {
+            self.successful_cycles_in_a_row = x;
+        }
+        fn cycle_start(&self) -> f64 {
+            self.cycle_start
+        }
+        fn set_cycle_start(&mut self, x: f64) {
+            self.cycle_start = x;
+        }
+        fn last_cycle_end(&self) -> f64 {
+            self.last_cycle_end
+        }
+        fn set_last_cycle_end(&mut self, x: f64) {
+            self.last_cycle_end = x;
+        }
+        fn gc_times_learned(&self) -> usize {
+            self.gc_times_learned
+        }
+        fn gc_time_penalties(&self) -> isize {
+            self.gc_time_penalties
+        }
+        fn set_gc_time_penalties(&mut self, x: isize) {
+            self.gc_time_penalties = x;
+        }
+    };
+}
+
+/// Add regions from `data` (sorted by garbage) to collection set if `filter` accepts region and its garbage and
+/// their live data fits into `free` space available for evacuation.
+unsafe fn add_regions_to_cset<H: ShenandoahHeuristics>(
+    set: &mut ShenandoahCollectionSet,
+    data: *mut RegionData,
+    data_size: usize,
+    free: usize,
+    filter: impl Fn(&ShenandoahHeapRegion, usize) -> bool,
+) {
+    let max_cset = (free as f64 / H::EVAC_WASTE) as usize;
+    let mut cur_cset = 0;
+    for data in std::slice::from_raw_parts(data, data_size) {
+        let region = &mut *data.region;
+        if cur_cset + region.live_data() > max_cset {
+            continue;
+        }
+        if filter(region, data.garbage) {
+            cur_cset += region.live_data();
+            set.add_region(region);
+        }
+    }
+}
+
+/// Static heuristics. Starts concurrent cycle when free space drops below [MIN_FREE_THRESHOLD](ShenandoahHeuristics::MIN_FREE_THRESHOLD)
+/// percents of heap and collects regions that have more than [GARBAGE_THRESHOLD](ShenandoahHeuristics::GARBAGE_THRESHOLD) percents of garbage.
+pub struct ShenandoahStaticHeuristics {
+    region_data: *mut RegionData,
+    degenerated_cycles_in_a_row: u32,
+    successful_cycles_in_a_row: u32,
+    cycle_start: f64,
+    last_cycle_end: f64,
+    gc_times_learned: usize,
+    gc_time_penalties: isize,
+}
+
+heuristics_state!(ShenandoahStaticHeuristics);
+
+impl ShenandoahHeuristics for ShenandoahStaticHeuristics {
+    heuristics_accessors!();
+
+    unsafe fn choose_collection_set_from_regiondata(
+        &mut self,
+        set: &mut ShenandoahCollectionSet,
+        data: *mut RegionData,
+        data_size: usize,
+        free: usize,
+    ) {
+        add_regions_to_cset::<Self>(set, data, data_size, free, |region, garbage| {
+            garbage > region.size() * Self::GARBAGE_THRESHOLD / 100
+        });
+    }
+
+    fn should_start_gc(&self, capacity: usize, available: usize, now: f64) -> bool {
+        if available < capacity / 100 * Self::MIN_FREE_THRESHOLD {
+            return true;
+        }
+        now - self.last_cycle_end() > Self::GUARANTEED_GC_INTERVAL
+    }
+}
+
+/// Aggressive heuristics. Runs concurrent cycles back to back and evacuates every region that has any garbage, useful
+/// for testing.
+pub struct ShenandoahAggressiveHeuristics {
+    region_data: *mut RegionData,
+    degenerated_cycles_in_a_row: u32,
+    successful_cycles_in_a_row: u32,
+    cycle_start: f64,
+    last_cycle_end: f64,
+    gc_times_learned: usize,
+    gc_time_penalties: isize,
+}
+
+heuristics_state!(ShenandoahAggressiveHeuristics);
+
+impl ShenandoahHeuristics for ShenandoahAggressiveHeuristics {
+    heuristics_accessors!();
+
+    unsafe fn choose_collection_set_from_regiondata(
+        &mut self,
+        set: &mut ShenandoahCollectionSet,
+        data: *mut RegionData,
+        data_size: usize,
+        free: usize,
+    ) {
+        add_regions_to_cset::<Self>(set, data, data_size, free, |_, garbage| garbage > 0);
+    }
+
+    fn should_start_gc(&self, _capacity: usize, _available: usize, _now: f64) -> bool {
+        true
     }
 }
@@ -0,0 +1,105 @@
+use std::{
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::region::ShenandoahHeapRegion;
+use crate::{
+    api::{HeapObjectHeader, Visitor, GC_BLACK, GC_GREY, GC_WHITE},
+    cms::marking_worklist::MarkingWorklists,
+};
+
+/// Tri-color marker that also accumulates live data of each region. Live data is used to select collection set.
+pub struct ShenandoahMarker {
+    marking_worklists: MarkingWorklists,
+    is_marking: AtomicBool,
+    regions: *const ShenandoahHeapRegion,
+    heap_start: usize,
+    region_size_shift: usize,
+}
+
+impl ShenandoahMarker {
+    pub fn new(
+        regions: *const ShenandoahHeapRegion,
+        heap_start: *mut u8,
+        region_size_shift: usize,
+    ) -> Self {
+        Self {
+            marking_worklists: MarkingWorklists::new(),
+            is_marking: AtomicBool::new(false),
+            regions,
+            heap_start: heap_start as usize,
+            region_size_shift,
+        }
+    }
+
+    pub fn marking_worklists(&self) -> &MarkingWorklists {
+        &self.marking_worklists
+    }
+
+    /// Returns `true` if marking cycle is in progress and write barrier must be executed.
+    #[inline(always)]
+    pub fn is_marking(&self) -> bool {
+        self.is_marking.load(Ordering::Acquire)
+    }
+
+    pub fn set_marking(&self, is_marking: bool) {
+        self.is_marking.store(is_marking, Ordering::Release);
+    }
+
+    /// Returns [Visitor] that marks objects with this marker.
+    pub fn visitor(&self) -> MarkingVisitor<'_> {
+        MarkingVisitor { marker: self }
+    }
+
+    /// Colour white object grey, account its size in live data of its region and push it to marking worklist.
+    pub fn mark_object(&self, root: &mut NonNull<HeapObjectHeader>) {
+        let object = root.as_ptr();
+        unsafe {
+            if !(*object).set_color(GC_WHITE, GC_GREY) {
+                let index = (object as usize - self.heap_start) >> self.region_size_shift;
+                (*self.regions.add(index)).increase_live_data((*object).size());
+                self.marking_worklists
+                    .marking_worklist()
+                    .push(object as usize);
+            }
+        }
+    }
+
+    /// Process grey objects until both marking and write barrier worklists are empty or `cancelled` is set.
+    /// Returns `false` if marking was cancelled.
+    pub fn drain(&self, cancelled: Option<&AtomicBool>) -> bool {
+        let mut visitor = self.visitor();
+        while let Some(object) = self
+            .marking_worklists
+            .marking_worklist()
+            .pop()
+            .or_else(|| self.marking_worklists.write_barrier_worklist().pop())
+        {
+            let object = object as *mut HeapObjectHeader;
+            unsafe {
+                // object might be pushed twice if write barrier re-greyed it before it was popped from marking worklist.
+                if !(*object).set_color(GC_GREY, GC_BLACK) {
+                    (*object).get_dyn().trace(&mut visitor);
+                }
+            }
+            if let Some(cancelled) = cancelled {
+                if cancelled.load(Ordering::Relaxed) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+/// [Visitor] that marks objects with [ShenandoahMarker].
+pub struct MarkingVisitor<'a> {
+    marker: &'a ShenandoahMarker,
+}
+
+impl Visitor for MarkingVisitor<'_> {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        self.marker.mark_object(root);
+    }
+}
@@ -1,11 +1,12 @@
-use std::{mem::size_of, sync::atomic::AtomicPtr};
+use std::{
+    mem::size_of,
+    sync::atomic::{AtomicPtr, Ordering},
+};
 
 use atomic::Atomic;
 
-use crate::{
-    shenandoah::region,
-    utils::{align_down, align_up, align_usize, formatted_size},
-};
+use super::free_set::AllocType;
+use crate::utils::{align_down, align_up, align_usize, formatted_size};
 
 /*
  Region state is described by a state machine. Transitions are guarded by
@@ -74,10 +75,14 @@ pub struct ShenandoahHeapRegion {
     bottom: *mut u8,
     end: *mut u8,
 
+    /// Top of the region after Full GC compaction.
     new_top: *mut u8,
+    /// Time (in seconds since heap creation) when region was recycled.
     empty_time: f64,
 
     state: RegionState,
+    /// Region contains pinned object, set for the duration of collection set selection and Full GC.
+    pinned: bool,
 
     top: *mut u8,
 
@@ -172,8 +177,8 @@ impl ShenandoahHeapRegion {
                 }
             })
             .unwrap_or_else(|| Self::MIN_REGION_SIZE);
-        let target_num_regions = target_num_regions.unwrap_or_else(|| 2048);
-        let max_region_size = max_region_size.unwrap_or_else(|| Self::MAX_REGION_SIZE);
+        let target_num_regions = target_num_regions.unwrap_or(2048);
+        let max_region_size = max_region_size.unwrap_or(Self::MAX_REGION_SIZE);
         if min_region_size > max_heap_size / Self::MIN_NUM_REGIONS {
             panic!("Max heap size ({}) is too low to afford the minimum number of regions ({}) of minimum region size ({})",
                 formatted_size(max_heap_size),Self::MIN_NUM_REGIONS,formatted_size(min_region_size)
@@ -213,7 +218,276 @@ impl ShenandoahHeapRegion {
     }
 }
 
-/// Shenandoah in OpenJDK actually supports pinning but we do not support it there.
+impl ShenandoahHeapRegion {
+    /// Create region that occupies `size` bytes starting at `bottom`.
+    ///
+    /// # Safety
+    ///
+    /// `[bottom, bottom + size)` must be within single allocated memory range.
+    pub unsafe fn new(index: usize, bottom: *mut u8, size: usize) -> Self {
+        Self {
+            index,
+            bottom,
+            end: bottom.add(size),
+            new_top: bottom,
+            empty_time: 0.0,
+            state: RegionState::EmptyCommitted,
+            pinned: false,
+            top: bottom,
+            tlab_allocs: 0,
+            gclab_allocs: 0,
+            live_data: Atomic::new(0),
+            update_watermark: AtomicPtr::new(bottom),
+        }
+    }
+
+    #[inline]
+    pub fn index(&self) -> usize {
+        self.index
+    }
+    #[inline]
+    pub fn bottom(&self) -> *mut u8 {
+        self.bottom
+    }
+    #[inline]
+    pub fn end(&self) -> *mut u8 {
+        self.end
+    }
+    #[inline]
+    pub fn top(&self) -> *mut u8 {
+        self.top
+    }
+    pub fn set_top(&mut self, top: *mut u8) {
+        debug_assert!(top >= self.bottom && top <= self.end);
+        self.top = top;
+    }
+    pub fn new_top(&self) -> *mut u8 {
+        self.new_top
+    }
+    pub fn set_new_top(&mut self, new_top: *mut u8) {
+        debug_assert!(new_top >= self.bottom && new_top <= self.end);
+        self.new_top = new_top;
+    }
+    pub fn empty_time(&self) -> f64 {
+        self.empty_time
+    }
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.end as usize - self.bottom as usize
+    }
+    #[inline]
+    pub fn used(&self) -> usize {
+        self.top as usize - self.bottom as usize
+    }
+    #[inline]
+    pub fn free(&self) -> usize {
+        self.end as usize - self.top as usize
+    }
+    #[inline]
+    pub fn state(&self) -> RegionState {
+        self.state
+    }
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        matches!(
+            self.state,
+            RegionState::EmptyCommitted | RegionState::EmptyUncommitted
+        )
+    }
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        !self.is_empty() && !self.is_trash()
+    }
+    #[inline]
+    pub fn is_regular(&self) -> bool {
+        self.state == RegionState::Regular
+    }
+    #[inline]
+    pub fn is_humongous_start(&self) -> bool {
+        self.state == RegionState::HumongousStart
+    }
+    #[inline]
+    pub fn is_humongous_continuation(&self) -> bool {
+        self.state == RegionState::HumongousCont
+    }
+    #[inline]
+    pub fn is_humongous(&self) -> bool {
+        self.is_humongous_start() || self.is_humongous_continuation()
+    }
+    /// Returns `true` if region contains pinned object. Pinned region is not added to collection set and is not compacted.
+    #[inline]
+    pub fn is_pinned(&self) -> bool {
+        self.pinned
+    }
+    pub fn set_pinned(&mut self, pinned: bool) {
+        self.pinned = pinned;
+    }
+    #[inline]
+    pub fn is_cset(&self) -> bool {
+        self.state == RegionState::CSet
+    }
+    #[inline]
+    pub fn is_trash(&self) -> bool {
+        self.state == RegionState::Trash
+    }
+    /// Returns `true` if objects can be allocated in this region.
+    #[inline]
+    pub fn is_alloc_allowed(&self) -> bool {
+        self.is_empty() || self.is_regular()
+    }
+
+    /// Bump allocate `size` bytes in this region. Returns null pointer if region does not have enough free space.
+    pub fn allocate(&mut self, size: usize, ty: AllocType) -> *mut u8 {
+        debug_assert!(self.is_regular());
+        if self.free() < size {
+            return std::ptr::null_mut();
+        }
+        let result = self.top;
+        unsafe {
+            self.top = self.top.add(size);
+        }
+        match ty {
+            AllocType::Tlab | AllocType::Shared => self.tlab_allocs += size,
+            AllocType::Gclab | AllocType::SharedGc => self.gclab_allocs += size,
+        }
+        result
+    }
+
+    /// Number of bytes allocated by mutators since last [reset_alloc_metadata](Self::reset_alloc_metadata).
+    pub fn tlab_allocs(&self) -> usize {
+        self.tlab_allocs
+    }
+    /// Number of bytes allocated by GC for evacuated objects since last [reset_alloc_metadata](Self::reset_alloc_metadata).
+    pub fn gclab_allocs(&self) -> usize {
+        self.gclab_allocs
+    }
+    pub fn reset_alloc_metadata(&mut self) {
+        self.tlab_allocs = 0;
+        self.gclab_allocs = 0;
+    }
+
+    #[cold]
+    fn report_illegal_transition(&self, method: &str) -> ! {
+        panic!(
+            "Illegal region state transition from {:?} at {}, region #{}",
+            self.state, method, self.index
+        );
+    }
+
+    pub fn make_regular_allocation(&mut self) {
+        match self.state {
+            RegionState::EmptyUncommitted | RegionState::EmptyCommitted => {
+                self.state = RegionState::Regular
+            }
+            RegionState::Regular => (),
+            _ => self.report_illegal_transition("regular allocation"),
+        }
+    }
+
+    /// Return region to regular state bypassing regular transitions. Used by Full GC.
+    pub fn make_regular_bypass(&mut self) {
+        match self.state {
+            RegionState::EmptyUncommitted
+            | RegionState::EmptyCommitted
+            | RegionState::CSet
+            | RegionState::Regular => self.state = RegionState::Regular,
+            _ => self.report_illegal_transition("regular bypass"),
+        }
+    }
+
+    pub fn make_humongous_start(&mut self) {
+        match self.state {
+            RegionState::EmptyUncommitted | RegionState::EmptyCommitted => {
+                self.state = RegionState::HumongousStart
+            }
+            _ => self.report_illegal_transition("humongous start allocation"),
+        }
+    }
+
+    pub fn make_humongous_cont(&mut self) {
+        match self.state {
+            RegionState::EmptyUncommitted | RegionState::EmptyCommitted => {
+                self.state = RegionState::HumongousCont
+            }
+            _ => self.report_illegal_transition("humongous continuation allocation"),
+        }
+    }
+
+    pub fn make_cset(&mut self) {
+        match self.state {
+            RegionState::Regular => self.state = RegionState::CSet,
+            _ => self.report_illegal_transition("cset"),
+        }
+    }
+
+    pub fn make_trash(&mut self) {
+        match self.state {
+            RegionState::CSet
+            | RegionState::HumongousStart
+            | RegionState::HumongousCont
+            | RegionState::Regular => self.state = RegionState::Trash,
+            _ => self.report_illegal_transition("trashing"),
+        }
+    }
+
+    /// Make region empty after Full GC compaction moved all objects out of it.
+    pub fn make_empty(&mut self, now: f64) {
+        match self.state {
+            RegionState::Regular | RegionState::CSet | RegionState::Trash => {
+                self.state = RegionState::Trash;
+                self.recycle(now);
+            }
+            RegionState::EmptyCommitted | RegionState::EmptyUncommitted => (),
+            _ => self.report_illegal_transition("emptying"),
+        }
+    }
+
+    /// Recycle trash region: reset its top, live data and allocation metadata. `now` is the time in seconds since heap creation.
+    pub fn recycle(&mut self, now: f64) {
+        if !self.is_trash() {
+            self.report_illegal_transition("recycling");
+        }
+        self.top = self.bottom;
+        self.new_top = self.bottom;
+        self.clear_live_data();
+        self.reset_alloc_metadata();
+        self.set_update_watermark(self.bottom);
+        self.empty_time = now;
+        self.state = RegionState::EmptyCommitted;
+    }
+
+    #[inline]
+    pub fn live_data(&self) -> usize {
+        self.live_data.load(Ordering::Relaxed)
+    }
+    #[inline]
+    pub fn increase_live_data(&self, bytes: usize) {
+        self.live_data.fetch_add(bytes, Ordering::Relaxed);
+    }
+    pub fn clear_live_data(&self) {
+        self.live_data.store(0, Ordering::Relaxed);
+    }
+    pub fn has_live(&self) -> bool {
+        self.live_data() != 0
+    }
+    /// Number of bytes in this region that are not occupied by live objects.
+    pub fn garbage(&self) -> usize {
+        self.used().saturating_sub(self.live_data())
+    }
+
+    /// Objects below update watermark were allocated before final marking, objects above it were allocated during
+    /// evacuation or update-refs phase and do not need their references updated.
+    #[inline]
+    pub fn get_update_watermark(&self) -> *mut u8 {
+        self.update_watermark.load(Ordering::Acquire)
+    }
+    pub fn set_update_watermark(&self, watermark: *mut u8) {
+        self.update_watermark.store(watermark, Ordering::Release);
+    }
+}
+
+/// Shenandoah in OpenJDK has pinned variants of regular, collection set and humongous states. Here pinning is a flag of the
+/// region instead, see [ShenandoahHeapRegion::is_pinned].
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub enum RegionState {
@@ -0,0 +1,265 @@
+//! Heap statistics and GC events.
+//!
+//! Every heap keeps [GcStats] that records each GC cycle. Current statistics can be queried with
+//! [Mutator::stats](crate::mutator::Mutator::stats) and [GcListener]s registered with
+//! [Mutator::add_gc_listener](crate::mutator::Mutator::add_gc_listener) receive [GcEvent] when GC cycle starts and when it ends.
+//! Listeners are invoked while GC cycle is running: they must not allocate in GC heap or wait for mutators. Concurrent collectors
+//! invoke listeners on their background threads.
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// Kind of GC cycle.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum GcKind {
+    /// Only young objects are collected.
+    Minor,
+    /// Whole heap is collected in a stop-the-world pause.
+    Major,
+    /// Whole heap is collected mostly concurrently with mutators, only short pauses stop mutators.
+    Concurrent,
+    /// Whole heap is collected in a stop-the-world pause on request of the user or as the last resort when heap is exhausted.
+    /// Moving collectors compact or defragment the heap in full cycles.
+    Full,
+}
+
+/// Reason of GC cycle.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum GcReason {
+    /// Mutator failed to allocate memory.
+    AllocationFailure,
+    /// GC was requested with [collect](crate::mutator::MutatorRef::collect) or similar functions.
+    RequestedByUser,
+    /// Heap usage reached GC threshold.
+    HeapFull,
+}
+
+/// Structured description of GC cycle that is passed to [GcListener].
+#[derive(Clone, Debug)]
+pub struct GcEvent {
+    /// Name of GC policy, e.g. `"Immix"`.
+    pub policy: &'static str,
+    /// Number of the cycle, starting from 0.
+    pub cycle: usize,
+    pub kind: GcKind,
+    pub reason: GcReason,
+    /// Bytes allocated in heap when cycle started.
+    pub heap_before: usize,
+    /// Bytes allocated in heap when cycle ended. Equal to `heap_before` when cycle starts.
+    pub heap_after: usize,
+    /// Duration of the cycle. Zero when cycle starts.
+    pub duration: Duration,
+}
+
+/// Listener of GC cycles. See [stats](crate::stats) module documentation for restrictions.
+pub trait GcListener: Send {
+    /// Invoked when GC cycle starts.
+    fn on_cycle_start(&mut self, event: &GcEvent) {
+        let _ = event;
+    }
+    /// Invoked when GC cycle ends.
+    fn on_cycle_end(&mut self, event: &GcEvent) {
+        let _ = event;
+    }
+}
+
+/// Snapshot of heap statistics.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct HeapStats {
+    /// Bytes currently allocated in heap.
+    pub allocated_bytes: usize,
+    /// Bytes that were alive at the end of the last GC cycle.
+    pub live_bytes: usize,
+    /// Bytes that heap can use before the next GC cycle is triggered. For fixed-size heaps this is size of the heap.
+    pub footprint: usize,
+    pub minor_collections: usize,
+    pub major_collections: usize,
+    pub concurrent_collections: usize,
+    pub full_collections: usize,
+    /// Total time mutators were stopped by GC.
+    pub total_pause: Duration,
+    /// Duration of the last GC pause.
+    pub last_pause: Duration,
+    /// Total number of bytes promoted from nursery to old space. Always zero for non-generational heaps.
+    pub promoted_bytes: usize,
+}
+
+/// GC cycle that is being recorded. Returned by [GcStats::start_cycle].
+pub struct GcCycle {
+    event: GcEvent,
+    start: Instant,
+}
+
+struct GcStatsInner {
+    stats: HeapStats,
+    listeners: Vec<Box<dyn GcListener>>,
+    cycles: usize,
+}
+
+/// Statistics and listeners of a heap.
+pub struct GcStats {
+    policy: &'static str,
+    inner: Mutex<GcStatsInner>,
+}
+
+impl GcStats {
+    pub fn new(policy: &'static str) -> Self {
+        Self {
+            policy,
+            inner: Mutex::new(GcStatsInner {
+                stats: HeapStats::default(),
+                listeners: Vec::new(),
+                cycles: 0,
+            }),
+        }
+    }
+
+    /// Name of GC policy these statistics belong to.
+    pub fn policy(&self) -> &'static str {
+        self.policy
+    }
+
+    pub fn add_listener(&self, listener: Box<dyn GcListener>) {
+        self.inner.lock().listeners.push(listener);
+    }
+    /// Record start of GC cycle and notify listeners.
+    pub fn start_cycle(&self, kind: GcKind, reason: GcReason, heap_before: usize) -> GcCycle {
+        let mut inner = self.inner.lock();
+        let event = GcEvent {
+            policy: self.policy,
+            cycle: inner.cycles,
+            kind,
+            reason,
+            heap_before,
+            heap_after: heap_before,
+            duration: Duration::ZERO,
+        };
+        inner.cycles += 1;
+        for listener in inner.listeners.iter_mut() {
+            listener.on_cycle_start(&event);
+        }
+        GcCycle {
+            event,
+            start: Instant::now(),
+        }
+    }
+    /// Record end of GC cycle and notify listeners. Duration of cycles other than [GcKind::Concurrent] is recorded as a pause,
+    /// pauses of concurrent cycles must be recorded with [record_pause](Self::record_pause).
+    pub fn end_cycle(&self, cycle: GcCycle, heap_after: usize) {
+        let mut event = cycle.event;
+        event.heap_after = heap_after;
+        event.duration = cycle.start.elapsed();
+        let mut inner = self.inner.lock();
+        let stats = &mut inner.stats;
+        stats.live_bytes = heap_after;
+        match event.kind {
+            GcKind::Minor => stats.minor_collections += 1,
+            GcKind::Major => stats.major_collections += 1,
+            GcKind::Concurrent => stats.concurrent_collections += 1,
+            GcKind::Full => stats.full_collections += 1,
+        }
+        if event.kind != GcKind::Concurrent {
+            stats.total_pause += event.duration;
+            stats.last_pause = event.duration;
+        }
+        for listener in inner.listeners.iter_mut() {
+            listener.on_cycle_end(&event);
+        }
+    }
+    /// Record pause of concurrent GC cycle.
+    pub fn record_pause(&self, pause: Duration) {
+        let stats = &mut self.inner.lock().stats;
+        stats.total_pause += pause;
+        stats.last_pause = pause;
+    }
+    /// Record bytes promoted to old space.
+    pub fn add_promoted(&self, bytes: usize) {
+        self.inner.lock().stats.promoted_bytes += bytes;
+    }
+    /// Returns statistics with current heap usage.
+    pub fn snapshot(&self, allocated_bytes: usize, footprint: usize) -> HeapStats {
+        HeapStats {
+            allocated_bytes,
+            footprint,
+            ..self.inner.lock().stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+
+    use super::{GcEvent, GcKind, GcListener, GcReason};
+    use crate::{
+        api::{Collectable, Finalize, Trace},
+        gc_base::AllocationSpace,
+        semispace::instantiate_semispace,
+    };
+
+    struct Node {
+        _value: [u64; 4],
+    }
+
+    unsafe impl Trace for Node {}
+    unsafe impl Finalize for Node {}
+    impl Collectable for Node {}
+
+    struct Recorder {
+        events: Arc<Mutex<Vec<(bool, GcEvent)>>>,
+    }
+
+    impl GcListener for Recorder {
+        fn on_cycle_start(&mut self, event: &GcEvent) {
+            self.events.lock().push((true, event.clone()));
+        }
+        fn on_cycle_end(&mut self, event: &GcEvent) {
+            self.events.lock().push((false, event.clone()));
+        }
+    }
+
+    #[test]
+    fn test_semispace_stats() {
+        let mut mutator = instantiate_semispace(256 * 1024);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        mutator.add_gc_listener(Box::new(Recorder {
+            events: events.clone(),
+        }));
+        letroot!(
+            live = mutator.shadow_stack(),
+            mutator.allocate(Node { _value: [0; 4] }, AllocationSpace::New)
+        );
+        assert!(mutator.stats().allocated_bytes > 0);
+        mutator.collect(&mut []);
+        mutator.collect(&mut []);
+
+        let stats = mutator.stats();
+        assert_eq!(stats.major_collections, 2);
+        assert!(stats.footprint >= 2 * 256 * 1024);
+        assert!(stats.live_bytes >= std::mem::size_of::<Node>());
+        {
+            let events = events.lock();
+            assert_eq!(events.len(), 4);
+            for (index, (start, event)) in events.iter().enumerate() {
+                assert_eq!(*start, index % 2 == 0);
+                assert_eq!(event.cycle, index / 2);
+                assert_eq!(event.policy, "SemiSpace");
+                assert_eq!(event.kind, GcKind::Major);
+                assert_eq!(event.reason, GcReason::RequestedByUser);
+            }
+        }
+
+        // filling semispace triggers collection on allocation failure.
+        for _ in 0..10000 {
+            mutator.allocate(Node { _value: [0; 4] }, AllocationSpace::New);
+        }
+        assert!(mutator.stats().major_collections > 2);
+        assert!(events
+            .lock()
+            .iter()
+            .any(|(_, event)| event.reason == GcReason::AllocationFailure));
+        let _ = &*live;
+    }
+}
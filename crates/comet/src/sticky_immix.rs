@@ -1 +1,391 @@
+//! # Sticky Immix: generational Immix with sticky mark bits
+//!
+//! StickyImmix is [Immix](crate::immix::Immix) heap that collects memory generationally without copying nursery. Mark bits
+//! of objects that survived GC cycle are "sticky": they are not cleared after nursery collection so old objects are never
+//! traversed again until full collection.
+//!
+//! - Nursery collection traces objects only from roots and remembered set. Line marks are not cleared so lines that
+//!   are occupied by old objects are not reused. Newly allocated objects that survived nursery collection become old.
+//! - Full collection is always preceded by nursery collection so that all live objects are old. Colors of old and new
+//!   objects are swapped then and the heap is collected by [Immix](crate::immix::Immix) collection, including
+//!   opportunistic defragmentation and [pinning](crate::pin). Survivors keep their mark color and stay old.
+//!
+//! Old objects that are written to must be added to remembered set by invoking [MutatorRef::write_barrier](crate::mutator::MutatorRef::write_barrier)
+//! right after write happened. Objects in remembered set have [remembered bit](crate::api::HeapObjectHeader::remembered_bit) set.
+//!
+//! Conservative stack scanning and heap verification are supported in both collections. Object starts of nursery objects
+//! that died in nursery collection are cleared before their lines are reused.
+//!
+//! You can find more information about sticky mark bits in this [paper](https://www.steveblackburn.org/pubs/papers/stickyimmix-ismm-2013.pdf)
 
+use crate::{
+    api::{HeapObjectHeader, Trace},
+    gc_log::{GcPhase, GcRecord, PhaseTimer},
+    immix::{instantiate_immix_heap, space::ImmixSpace, Immix},
+    mutator::MutatorRef,
+    stats::{GcKind, GcReason},
+    verify::VerifyPhase,
+};
+use atomic::Ordering;
+use parking_lot::{lock_api::RawMutex, RawMutex as Lock};
+use std::time::Instant;
+
+pub struct StickyImmixOptions {
+    pub verbose: bool,
+    /// Size of memory reserved for Immix space.
+    pub size: usize,
+    pub initial_size: usize,
+    pub min_heap_size: usize,
+    pub max_heap_size: usize,
+    /// Full collection is triggered after nursery collection when heap usage exceeds `full_collection_threshold * target footprint`.
+    pub full_collection_threshold: f64,
+    /// Enable opportunistic defragmentation during full collections.
+    pub defrag: bool,
+}
+
+impl Default for StickyImmixOptions {
+    fn default() -> Self {
+        Self {
+            verbose: false,
+            size: 512 * 1024 * 1024,
+            initial_size: 64 * 1024 * 1024,
+            min_heap_size: 64 * 1024 * 1024,
+            max_heap_size: 512 * 1024 * 1024,
+            full_collection_threshold: 0.8,
+            defrag: true,
+        }
+    }
+}
+
+/// Generational Immix GC. This is [Immix] heap created by [instantiate_sticky_immix], read top level module documentation
+/// for more information.
+pub type StickyImmix = Immix;
+
+/// Generational state of [Immix] heap.
+pub(crate) struct StickyState {
+    rem_set_lock: Lock,
+    remembered_set: Vec<*mut HeapObjectHeader>,
+    full_collection_threshold: f64,
+    /// Bytes allocated in heap after previous GC cycle.
+    live_bytes: usize,
+}
+
+pub fn instantiate_sticky_immix(options: StickyImmixOptions) -> MutatorRef<StickyImmix> {
+    let space = Box::leak(Box::new(ImmixSpace::new(
+        options.size,
+        options.initial_size,
+        options.min_heap_size,
+        options.max_heap_size,
+        options.verbose,
+        options.defrag,
+    )));
+    instantiate_immix_heap(
+        space,
+        options.verbose,
+        Some(StickyState {
+            rem_set_lock: Lock::INIT,
+            remembered_set: vec![],
+            full_collection_threshold: options.full_collection_threshold,
+            live_bytes: 0,
+        }),
+    )
+}
+
+impl Immix {
+    fn sticky_state(&mut self) -> &mut StickyState {
+        self.sticky
+            .as_mut()
+            .expect("heap does not collect memory generationally")
+    }
+
+    /// Returns `true` if object survived at least one GC cycle.
+    #[inline(always)]
+    unsafe fn is_old(&self, object: *const HeapObjectHeader) -> bool {
+        (*object).get_color() == self.mark_color
+    }
+
+    #[inline(always)]
+    pub(crate) unsafe fn write_barrier_internal(&mut self, object: *mut HeapObjectHeader) {
+        if self.is_old(object) && !(*object).remembered_bit() {
+            self.write_barrier_slow(object);
+        }
+    }
+
+    #[cold]
+    unsafe fn write_barrier_slow(&mut self, object: *mut HeapObjectHeader) {
+        (*object).set_remembered_bit(true);
+        let sticky = self.sticky_state();
+        sticky.rem_set_lock.lock();
+        sticky.remembered_set.push(object);
+        sticky.rem_set_lock.unlock();
+    }
+
+    /// Perform nursery collection. Full collection is performed after it if `full` is `Some(true)`, or if `full` is `None`
+    /// and heap is close to be full. Must be invoked in GC pause with heap locks held.
+    pub(crate) unsafe fn sticky_collection(
+        &mut self,
+        keep: &mut [&mut dyn Trace],
+        reason: GcReason,
+        full: Option<bool>,
+    ) {
+        let full_required = self.nursery(keep, reason);
+        match full {
+            Some(true) => self.full(keep, reason, true),
+            Some(false) => {}
+            None if full_required => self.full(keep, GcReason::HeapFull, false),
+            None => {}
+        }
+    }
+
+    /// Nursery collection. Traces only from roots and remembered set, old objects are not traversed and their lines stay marked.
+    /// Returns `true` if full collection is required after this nursery collection.
+    unsafe fn nursery(&mut self, keep: &mut [&mut dyn Trace], reason: GcReason) -> bool {
+        if self.verify_heap {
+            self.verify(VerifyPhase::BeforeGc, keep);
+        }
+        let time = Instant::now();
+        let mut timer = PhaseTimer::new(self.safepoint.last_sync());
+        let cycle = self
+            .gc_stats
+            .start_cycle(GcKind::Minor, reason, self.allocated_bytes());
+        self.soft_ref_policy
+            .begin_cycle(self.allocated_bytes(), self.space.max_heap_size);
+        // nursery objects are not evacuated, conservative roots don't have to be pinned.
+        let (conservative_roots, conservative_pins) = self.scan_conservative_roots();
+        for object in conservative_pins {
+            (*object).set_pinned_bit(false);
+        }
+        self.space.prepare(false);
+        self.large_space.prepare_for_marking(true);
+        self.large_space.begin_marking(false);
+        self.mark_roots(keep, conservative_roots);
+        // old objects that were written to since last GC cycle might point to young objects. Mutators are stopped so
+        // remembered set is not modified concurrently.
+        let mut remembered_set = std::mem::take(&mut self.sticky_state().remembered_set);
+        for object in remembered_set.drain(..) {
+            (*object).set_remembered_bit(false);
+            (*object).get_dyn().trace(self);
+        }
+        self.sticky_state().remembered_set = remembered_set;
+        timer.end(GcPhase::RootScan);
+        self.finish_marking(false);
+        if self.track_object_starts {
+            self.clear_dead_object_starts();
+        }
+
+        let prev = self.allocated_bytes();
+        self.space.num_bytes_allocated.store(0, Ordering::Relaxed);
+        timer.end(GcPhase::Mark);
+        self.process_weak_refs_and_finalizers(&mut timer);
+        self.large_space.prepare_for_allocation(true);
+        self.large_space.sweep();
+        self.space.release();
+        timer.end(GcPhase::Sweep);
+
+        let bytes_allocated = self.allocated_bytes();
+        let sticky = self.sticky_state();
+        // memory of old objects is not freed by nursery collection, so live memory grows by size of promoted objects.
+        let promoted = bytes_allocated.saturating_sub(sticky.live_bytes);
+        sticky.live_bytes = bytes_allocated;
+        let threshold = sticky.full_collection_threshold;
+        self.gc_stats.add_promoted(promoted);
+        self.gc_stats.end_cycle(cycle, bytes_allocated);
+        let footprint = self.space.target_footprint.load(Ordering::Relaxed);
+        if let Some(logger) = self.safepoint.logger() {
+            logger.log_gc(&GcRecord {
+                policy: self.gc_stats.policy(),
+                gc_id: self.total_gcs,
+                kind: GcKind::Minor,
+                reason,
+                heap_before: prev,
+                heap_after: bytes_allocated,
+                footprint,
+                duration: time.elapsed(),
+                phases: timer.phases(),
+                extra: &[("promoted", promoted)],
+            });
+        }
+        if self.verify_heap {
+            self.verify(VerifyPhase::AfterGc, keep);
+        }
+        self.total_gcs += 1;
+        bytes_allocated as f64 > footprint as f64 * threshold
+    }
+
+    /// Clear object starts of nursery objects that were not marked by nursery collection. Old objects are not traversed by
+    /// nursery collection, so unlike full collection object starts can't be rebuilt by marking.
+    unsafe fn clear_dead_object_starts(&mut self) {
+        let mut dead = vec![];
+        self.space.visit_objects(|_, object| {
+            if !self.is_old(object) {
+                dead.push(object);
+            }
+        });
+        for object in dead {
+            self.space.object_start_bitmap.clear(object.cast());
+        }
+    }
+
+    /// Full collection. Must be invoked right after nursery collection when all live objects are old and have mark color.
+    unsafe fn full(
+        &mut self,
+        keep: &mut [&mut dyn Trace],
+        reason: GcReason,
+        user_triggered_full_collection: bool,
+    ) {
+        // Old objects become unmarked, Immix collection marks live objects with color of dead nursery objects. Colors
+        // are not swapped back after collection so survivors stay old.
+        std::mem::swap(&mut self.alloc_color, &mut self.mark_color);
+        self.collect_garbage(keep, reason, user_triggered_full_collection);
+        let bytes_allocated = self.allocated_bytes();
+        self.sticky_state().live_bytes = bytes_allocated;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{instantiate_sticky_immix, StickyImmix, StickyImmixOptions};
+    use crate::{
+        api::{Collectable, Finalize, Gc, Trace, Visitor},
+        gc_base::AllocationSpace,
+        mutator::MutatorRef,
+        stress::GcStress,
+    };
+
+    struct Node {
+        value: usize,
+        next: Option<Gc<Node, StickyImmix>>,
+    }
+
+    unsafe impl Trace for Node {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Node {}
+    impl Collectable for Node {}
+
+    fn heap() -> MutatorRef<StickyImmix> {
+        instantiate_sticky_immix(StickyImmixOptions {
+            size: 64 * 1024 * 1024,
+            initial_size: 4 * 1024 * 1024,
+            min_heap_size: 4 * 1024 * 1024,
+            max_heap_size: 64 * 1024 * 1024,
+            ..Default::default()
+        })
+    }
+
+    fn node(mutator: &mut MutatorRef<StickyImmix>, value: usize) -> Gc<Node, StickyImmix> {
+        mutator.allocate(Node { value, next: None }, AllocationSpace::New)
+    }
+
+    #[test]
+    fn test_remembered_set() {
+        let mut mutator = heap();
+        letroot!(holder = mutator.shadow_stack(), node(&mut mutator, 1));
+        mutator.minor_collection(&mut []);
+        // `holder` is old now and young child is reachable only through it.
+        let child = node(&mut mutator, 2);
+        holder.next = Some(child);
+        mutator.write_barrier(holder.to_dyn());
+        unsafe {
+            let header = holder.base.as_ptr();
+            assert!((*header).remembered_bit());
+            assert!(!(*header).marked_bit());
+        }
+        for value in 0..10000 {
+            node(&mut mutator, value);
+        }
+        mutator.minor_collection(&mut []);
+        assert!(!unsafe { (*holder.base.as_ptr()).remembered_bit() });
+        assert_eq!(holder.next.unwrap().value, 2);
+        assert_eq!(mutator.stats().minor_collections, 2);
+
+        mutator.full_collection(&mut []);
+        assert_eq!(holder.value, 1);
+        assert_eq!(holder.next.unwrap().value, 2);
+    }
+
+    #[test]
+    fn test_full_collection_frees_old_objects() {
+        let mut mutator = heap();
+        letroot!(list = mutator.shadow_stack(), None::<Gc<Node, StickyImmix>>);
+        for value in 0..10000 {
+            let mut object = node(&mut mutator, value);
+            object.next = *list;
+            *list = Some(object);
+        }
+        mutator.minor_collection(&mut []);
+        let old = mutator.stats().allocated_bytes;
+        *list = None;
+        // nursery collection does not free old objects.
+        mutator.minor_collection(&mut []);
+        assert!(mutator.stats().allocated_bytes >= old);
+        mutator.full_collection(&mut []);
+        assert!(mutator.stats().allocated_bytes < old / 2);
+    }
+
+    #[test]
+    fn test_pinned_objects_are_not_evacuated() {
+        let mut mutator = heap();
+        letroot!(
+            nodes = mutator.shadow_stack(),
+            Vec::<Gc<Node, StickyImmix>>::new()
+        );
+        for value in 0..1000 {
+            let object = node(&mut mutator, value);
+            nodes.push(object);
+        }
+        let pinned = mutator.pin(nodes[500]);
+        let address = pinned.as_ptr();
+        let moved = nodes[10].base;
+        // stress collection evacuates every block.
+        mutator.set_gc_stress(GcStress::Allocations(1));
+        node(&mut mutator, 0);
+        mutator.set_gc_stress(GcStress::Disabled);
+        assert_eq!(pinned.as_ptr(), address);
+        assert_eq!(nodes[500].base, pinned.get().base);
+        assert_ne!(nodes[10].base, moved);
+        for (value, object) in nodes.iter().enumerate() {
+            assert_eq!(object.value, value);
+        }
+    }
+
+    #[test]
+    fn test_verification_and_conservative_scanning() {
+        let mut mutator = heap();
+        mutator.set_heap_verification(true);
+        mutator.set_conservative_stack_scanning(true);
+        // not rooted: only conservative scan of the stack keeps it alive in nursery and full collections.
+        let young = node(&mut mutator, 42);
+        let weak = mutator.allocate_weak(young);
+        let address = std::hint::black_box(&young).base.as_ptr();
+        letroot!(list = mutator.shadow_stack(), None::<Gc<Node, StickyImmix>>);
+        for value in 0..10000 {
+            let mut object = node(&mut mutator, value);
+            object.next = *list;
+            *list = Some(object);
+            if value % 1000 == 0 {
+                // garbage that dies young and fills whole lines, its object starts must be cleared by nursery collection.
+                for value in 0..1000 {
+                    node(&mut mutator, value);
+                }
+                mutator.minor_collection(&mut []);
+            }
+        }
+        mutator.full_collection(&mut []);
+        mutator.minor_collection(&mut []);
+        assert!(weak.upgrade().is_some());
+        assert_eq!(std::hint::black_box(&young).base.as_ptr(), address);
+        assert_eq!(young.value, 42);
+
+        let mut object = *list;
+        let mut expected = 10000;
+        while let Some(current) = object {
+            expected -= 1;
+            assert_eq!(current.value, expected);
+            object = current.next;
+        }
+        assert_eq!(expected, 0);
+    }
+}
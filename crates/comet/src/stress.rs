@@ -0,0 +1,229 @@
+//! GC stress mode.
+//!
+//! Missing [letroot!](crate::letroot) shows up only when GC happens while unrooted [Gc](crate::api::Gc) is live, stress mode
+//! forces GC cycles at deterministic points so such bugs show up on every run:
+//! - [GcStress::Allocations] collects before every N-th allocation of a mutator made with
+//!   [MutatorRef::allocate](crate::mutator::MutatorRef::allocate) or [MutatorRef::try_allocate](crate::mutator::MutatorRef::try_allocate).
+//!   Value that is being allocated is kept alive by the cycle.
+//! - [GcStress::Safepoints] collects on every [Mutator::safepoint](crate::mutator::Mutator::safepoint) poll.
+//!
+//! Forced cycles are performed by [GcBase::stress_collection](crate::gc_base::GcBase::stress_collection) so moving policies
+//! move objects even when there is no space pressure:
+//! - [SemiSpace](crate::semispace) copies all live objects that are not pinned.
+//! - [MiniMark](crate::minimark) performs minor collection that promotes all live nursery objects.
+//! - [Immix](crate::immix) and [StickyImmix](crate::sticky_immix) perform defrag cycle in which every block is evacuated, as
+//!   far as defrag space allows.
+//! - [Shenandoah](crate::shenandoah) runs concurrent cycle that evacuates every region with live objects.
+//!
+//! With `check-dangling` feature enabled dereference of unrooted pointer then panics right after the cycle, see
+//! [poison](crate::utils::poison).
+//!
+//! Stress mode is set per heap with [Mutator::set_gc_stress](crate::mutator::Mutator::set_gc_stress) or with `COMET_GC_STRESS`
+//! environment variable that is read when heap is created: `COMET_GC_STRESS=1000` collects every 1000 allocations,
+//! `COMET_GC_STRESS=safepoint` collects on every safepoint poll and `COMET_GC_STRESS=0` disables stress mode.
+
+/// Environment variable that sets [GcStress] of new heaps.
+pub const GC_STRESS_ENV: &str = "COMET_GC_STRESS";
+
+/// When GC cycles are forced by stress mode.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GcStress {
+    /// Stress mode is disabled.
+    Disabled,
+    /// Collect every N allocations of each mutator. N must not be zero.
+    Allocations(usize),
+    /// Collect on every safepoint poll.
+    Safepoints,
+}
+
+impl GcStress {
+    /// Read stress mode from [GC_STRESS_ENV] variable. Returns [GcStress::Disabled] if variable is not set.
+    ///
+    /// # Panics
+    ///
+    /// Panics if value of the variable is neither a number nor `safepoint`.
+    pub fn from_env() -> Self {
+        match std::env::var(GC_STRESS_ENV) {
+            Ok(value) => Self::parse(&value).unwrap_or_else(|| {
+                panic!(
+                    "invalid {} value `{}`, expected number of allocations or `safepoint`",
+                    GC_STRESS_ENV, value
+                )
+            }),
+            Err(_) => Self::Disabled,
+        }
+    }
+
+    /// Parse stress mode in [GC_STRESS_ENV] format.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "" | "0" => Some(Self::Disabled),
+            "safepoint" | "safepoints" => Some(Self::Safepoints),
+            count => count.parse().ok().map(Self::Allocations),
+        }
+    }
+
+    /// Encode stress mode as a single word so it can be checked on allocation fast path: zero is [GcStress::Disabled] and
+    /// `usize::MAX` is [GcStress::Safepoints].
+    pub(crate) fn encode(self) -> usize {
+        match self {
+            Self::Disabled => 0,
+            Self::Allocations(count) => {
+                assert!(
+                    count != 0 && count != usize::MAX,
+                    "invalid number of allocations between stress GC cycles: {}",
+                    count
+                );
+                count
+            }
+            Self::Safepoints => usize::MAX,
+        }
+    }
+
+    pub(crate) fn decode(value: usize) -> Self {
+        match value {
+            0 => Self::Disabled,
+            usize::MAX => Self::Safepoints,
+            count => Self::Allocations(count),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GcStress;
+    use crate::{
+        api::{Collectable, Finalize, Gc, Trace, Visitor},
+        gc_base::{AllocationSpace, GcBase},
+        immix::{instantiate_immix, Immix},
+        mutator::MutatorRef,
+        semispace::{instantiate_semispace, SemiSpace},
+        shenandoah::{
+            heap::{instantiate_shenandoah, ShenandoahHeap, ShenandoahHeapOptions},
+            heuristics::ShenandoahStaticHeuristics,
+        },
+    };
+
+    struct Node<H: GcBase> {
+        value: i64,
+        next: Option<Gc<Node<H>, H>>,
+    }
+
+    unsafe impl<H: GcBase> Trace for Node<H> {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+        }
+    }
+    unsafe impl<H: GcBase> Finalize for Node<H> {}
+    impl<H: GcBase + 'static> Collectable for Node<H> {}
+
+    fn collections<H: GcBase>(mutator: &MutatorRef<H>) -> usize {
+        let stats = mutator.stats();
+        stats.minor_collections + stats.major_collections + stats.full_collections
+    }
+
+    /// Build rooted list while stress mode moves it around. Pointer to the next node is held only by the value that is being
+    /// allocated.
+    fn build_list<H: GcBase>(mutator: &mut MutatorRef<H>, length: i64) {
+        letroot!(head = mutator.shadow_stack(), None::<Gc<Node<H>, H>>);
+        for value in 0..length {
+            let next = *head;
+            *head = Some(mutator.allocate(Node { value, next }, AllocationSpace::New));
+            mutator.safepoint();
+        }
+        let mut node = *head;
+        let mut expected = length;
+        while let Some(current) = node {
+            expected -= 1;
+            assert_eq!(current.value, expected);
+            node = current.next;
+        }
+        assert_eq!(expected, 0);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(GcStress::parse("0"), Some(GcStress::Disabled));
+        assert_eq!(GcStress::parse("100"), Some(GcStress::Allocations(100)));
+        assert_eq!(GcStress::parse("safepoint"), Some(GcStress::Safepoints));
+        assert_eq!(GcStress::parse("often"), None);
+        for stress in [
+            GcStress::Disabled,
+            GcStress::Allocations(7),
+            GcStress::Safepoints,
+        ] {
+            assert_eq!(GcStress::decode(stress.encode()), stress);
+        }
+    }
+
+    #[test]
+    fn test_semispace_stress() {
+        let mut mutator = instantiate_semispace(4 * 1024 * 1024);
+        mutator.set_gc_stress(GcStress::Allocations(10));
+        let gcs = collections(&mutator);
+        build_list::<SemiSpace>(&mut mutator, 1000);
+        assert_eq!(collections(&mutator) - gcs, 100);
+
+        mutator.set_gc_stress(GcStress::Safepoints);
+        let gcs = collections(&mutator);
+        build_list::<SemiSpace>(&mut mutator, 100);
+        assert_eq!(collections(&mutator) - gcs, 100);
+        assert_eq!(mutator.gc_stress(), GcStress::Safepoints);
+    }
+
+    #[test]
+    fn test_immix_stress_moves_objects() {
+        let mut mutator = instantiate_immix(
+            64 * 1024 * 1024,
+            4 * 1024 * 1024,
+            2 * 1024 * 1024,
+            64 * 1024 * 1024,
+            false,
+        );
+        letroot!(
+            object = mutator.shadow_stack(),
+            mutator.allocate(
+                Node::<Immix> {
+                    value: 42,
+                    next: None
+                },
+                AllocationSpace::New
+            )
+        );
+        let address = object.base;
+        mutator.set_gc_stress(GcStress::Allocations(1));
+        build_list::<Immix>(&mut mutator, 100);
+        mutator.set_gc_stress(GcStress::Disabled);
+        // no space pressure, object is moved only because of stress mode.
+        assert_ne!(object.base, address);
+        assert_eq!(object.value, 42);
+    }
+
+    #[test]
+    fn test_shenandoah_stress_moves_objects() {
+        type Heap = ShenandoahHeap<ShenandoahStaticHeuristics>;
+        let mut mutator =
+            instantiate_shenandoah::<ShenandoahStaticHeuristics>(ShenandoahHeapOptions {
+                max_heap_size: 32 * 1024 * 1024,
+                ..Default::default()
+            });
+        letroot!(
+            object = mutator.shadow_stack(),
+            mutator.allocate(
+                Node::<Heap> {
+                    value: 42,
+                    next: None
+                },
+                AllocationSpace::New
+            )
+        );
+        let address = object.base;
+        mutator.set_gc_stress(GcStress::Allocations(10));
+        build_list::<Heap>(&mut mutator, 100);
+        mutator.set_gc_stress(GcStress::Disabled);
+        // no space pressure, object is evacuated only because of stress mode.
+        assert!(mutator.stats().concurrent_collections > 0);
+        assert_ne!(object.base, address);
+        assert_eq!(object.value, 42);
+    }
+}
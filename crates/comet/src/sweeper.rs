@@ -0,0 +1,269 @@
+//! # Sweeper
+//!
+//! Sweeping of [RosAllocSpace]. Space is split into chunks of [SWEEP_CHUNK_SIZE] bytes, chunk boundaries are aligned so that
+//! no two chunks share a word of live or mark bitmap and chunks can be swept independently. Bitmaps of the space must be swapped
+//! before sweeping starts: live bitmap then holds marked objects and mark bitmap holds objects that were live before GC. Dead
+//! objects are freed with [RosAllocSpace::sweep_callback] which uses thread-safe RosAlloc bulk free, mark bitmap must be cleared
+//! once sweeping is finished.
+//!
+//! Two modes are supported:
+//! - [rosalloc_parallel_sweep] sweeps whole space inside GC pause using all threads of a pool.
+//! - [LazySweeper] defers sweeping until after the pause: mutators sweep one chunk on each allocation that misses thread-local runs
+//!   and sweep the rest when allocation fails. Objects allocated while sweeping is in progress are set in live bitmap and are never
+//!   freed by sweeper.
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use scoped_threadpool::Pool;
+
+use crate::{bitmap::SpaceBitmap, rosalloc_space::RosAllocSpace};
+
+/// Number of bytes swept at once.
+pub const SWEEP_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Sweep `[begin, end)` of `space` which bitmaps are already swapped. Returns number of freed bytes.
+unsafe fn sweep_range(space: *mut RosAllocSpace, begin: usize, end: usize) -> usize {
+    let live_bitmap = &*(*space).get_live_bitmap();
+    let mark_bitmap = &*(*space).get_mark_bitmap();
+    let mut freed = 0;
+    SpaceBitmap::<8>::sweep_walk(mark_bitmap, live_bitmap, begin, end, |count, pointers| {
+        let pointers = std::slice::from_raw_parts(pointers.cast::<*mut u8>(), count);
+        freed += (*space).sweep_callback(pointers, true);
+    });
+    freed
+}
+
+/// Sweep whole `space` using all threads of `pool`. Returns number of freed bytes.
+///
+/// # Safety
+///
+/// `space` must be valid and its bitmaps must be swapped after marking. All mutators must be stopped.
+pub unsafe fn rosalloc_parallel_sweep(pool: &mut Pool, space: *mut RosAllocSpace) -> usize {
+    let begin = (*space).begin() as usize;
+    let end = (*space).end() as usize;
+    let num_workers = pool.thread_count() as usize;
+    if num_workers <= 1 {
+        return sweep_range(space, begin, end);
+    }
+    let num_chunks = (end - begin).div_ceil(SWEEP_CHUNK_SIZE);
+    let next_chunk = AtomicUsize::new(0);
+    let freed = AtomicUsize::new(0);
+    // raw pointers are not `Send`
+    let space = space as usize;
+    pool.scoped(|scope| {
+        for _ in 0..num_workers.min(num_chunks) {
+            let next_chunk = &next_chunk;
+            let freed = &freed;
+            scope.execute(move || loop {
+                let chunk = next_chunk.fetch_add(1, Ordering::Relaxed);
+                if chunk >= num_chunks {
+                    break;
+                }
+                let chunk_begin = begin + chunk * SWEEP_CHUNK_SIZE;
+                let chunk_end = end.min(chunk_begin + SWEEP_CHUNK_SIZE);
+                freed.fetch_add(
+                    sweep_range(space as *mut RosAllocSpace, chunk_begin, chunk_end),
+                    Ordering::Relaxed,
+                );
+            });
+        }
+    });
+    freed.load(Ordering::Relaxed)
+}
+
+/// Sweeps space incrementally after GC pause.
+pub struct LazySweeper {
+    begin: usize,
+    end: usize,
+    num_chunks: usize,
+    /// Index of the next chunk to sweep.
+    next_chunk: AtomicUsize,
+    /// Number of chunks that are completely swept.
+    swept_chunks: AtomicUsize,
+}
+
+impl LazySweeper {
+    pub const fn new() -> Self {
+        Self {
+            begin: 0,
+            end: 0,
+            num_chunks: 0,
+            next_chunk: AtomicUsize::new(0),
+            swept_chunks: AtomicUsize::new(0),
+        }
+    }
+
+    /// Start sweeping `space`. Mark bitmap must not be cleared until sweeping is finished.
+    ///
+    /// # Safety
+    ///
+    /// `space` must be valid and its bitmaps must be swapped after marking. Must be invoked in GC pause.
+    pub unsafe fn start(&mut self, space: *mut RosAllocSpace) {
+        self.begin = (*space).begin() as usize;
+        self.end = (*space).end() as usize;
+        self.num_chunks = (self.end - self.begin).div_ceil(SWEEP_CHUNK_SIZE);
+        self.next_chunk.store(0, Ordering::Relaxed);
+        self.swept_chunks.store(0, Ordering::Release);
+    }
+
+    /// Returns `true` if there are chunks that are not swept yet.
+    #[inline(always)]
+    pub fn is_sweeping(&self) -> bool {
+        self.swept_chunks.load(Ordering::Acquire) < self.num_chunks
+    }
+
+    /// Sweep next chunk of `space`. Returns number of freed bytes and `true` if this call finished sweeping
+    /// of the whole space.
+    ///
+    /// # Safety
+    ///
+    /// `space` must be the space that was passed to [start](Self::start).
+    pub unsafe fn sweep_chunk(&self, space: *mut RosAllocSpace) -> (usize, bool) {
+        let chunk = self.next_chunk.fetch_add(1, Ordering::Relaxed);
+        if chunk >= self.num_chunks {
+            return (0, false);
+        }
+        let chunk_begin = self.begin + chunk * SWEEP_CHUNK_SIZE;
+        let chunk_end = self.end.min(chunk_begin + SWEEP_CHUNK_SIZE);
+        let freed = sweep_range(space, chunk_begin, chunk_end);
+        let finished = self.swept_chunks.fetch_add(1, Ordering::AcqRel) + 1 == self.num_chunks;
+        (freed, finished)
+    }
+
+    /// Sweep all chunks that are not claimed by other threads yet. Returns number of freed bytes and `true` if this call
+    /// finished sweeping of the whole space.
+    ///
+    /// # Safety
+    ///
+    /// `space` must be the space that was passed to [start](Self::start).
+    pub unsafe fn sweep_all(&self, space: *mut RosAllocSpace) -> (usize, bool) {
+        let mut freed = 0;
+        let mut finished = false;
+        while self.next_chunk.load(Ordering::Relaxed) < self.num_chunks {
+            let (chunk_freed, chunk_finished) = self.sweep_chunk(space);
+            freed += chunk_freed;
+            finished |= chunk_finished;
+        }
+        (freed, finished)
+    }
+}
+
+impl Default for LazySweeper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::{Collectable, Finalize, Gc, Trace, Visitor},
+        gc_base::AllocationSpace,
+        marksweep::{instantiate_marksweep_with_options, MarkSweep, MarkSweepOptions},
+        mutator::MutatorRef,
+    };
+
+    struct Node {
+        value: usize,
+        next: Option<Gc<Node, MarkSweep>>,
+    }
+
+    unsafe impl Trace for Node {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Node {}
+    impl Collectable for Node {}
+
+    fn heap(lazy_sweep: bool) -> MutatorRef<MarkSweep> {
+        instantiate_marksweep_with_options(MarkSweepOptions {
+            initial_size: 4 * 1024 * 1024,
+            growth_limit: 64 * 1024 * 1024,
+            capacity: 64 * 1024 * 1024,
+            num_threads: 4,
+            lazy_sweep,
+            ..Default::default()
+        })
+    }
+
+    fn node(mutator: &mut MutatorRef<MarkSweep>, value: usize) -> Gc<Node, MarkSweep> {
+        mutator.allocate(Node { value, next: None }, AllocationSpace::New)
+    }
+
+    /// Build list of 10000 nodes with dead node allocated after each of them, collect and check that only dead nodes were freed.
+    fn sweep_list(mutator: &mut MutatorRef<MarkSweep>) {
+        letroot!(list = mutator.shadow_stack(), None::<Gc<Node, MarkSweep>>);
+        for value in 0..10000 {
+            let mut object = node(mutator, value);
+            object.next = *list;
+            *list = Some(object);
+            node(mutator, value);
+        }
+        let before = mutator.stats().allocated_bytes;
+        mutator.collect(&mut []);
+        // memory of dead nodes is reused, so live nodes that were freed by mistake are overwritten.
+        for value in 0..100000 {
+            node(mutator, value);
+        }
+        mutator.collect(&mut []);
+        assert!(mutator.stats().allocated_bytes < before);
+        let mut object = *list;
+        let mut value = 10000;
+        while let Some(current) = object {
+            value -= 1;
+            assert_eq!(current.value, value);
+            object = current.next;
+        }
+        assert_eq!(value, 0);
+    }
+
+    #[test]
+    fn test_parallel_sweep() {
+        let mut mutator = heap(false);
+        sweep_list(&mut mutator);
+    }
+
+    #[test]
+    fn test_lazy_sweep() {
+        let mut mutator = heap(true);
+        sweep_list(&mut mutator);
+        let heap = unsafe { &*mutator.heap.get() };
+        assert!(heap.sweeper.is_sweeping());
+        // allocations sweep the space chunk by chunk.
+        while heap.sweeper.is_sweeping() {
+            node(&mut mutator, 0);
+        }
+    }
+
+    #[test]
+    fn test_lazy_sweep_heap_full() {
+        let mut mutator = instantiate_marksweep_with_options(MarkSweepOptions {
+            initial_size: 1024 * 1024,
+            growth_limit: 2 * 1024 * 1024,
+            capacity: 2 * 1024 * 1024,
+            min_free: 64 * 1024,
+            max_free: 256 * 1024,
+            num_threads: 1,
+            lazy_sweep: true,
+            ..Default::default()
+        });
+        letroot!(list = mutator.shadow_stack(), None::<Gc<Node, MarkSweep>>);
+        // heap is full of dead objects that are not swept yet, allocation must finish sweeping or collect.
+        for value in 0..200000 {
+            let mut object = node(&mut mutator, value);
+            if value % 100 == 0 {
+                object.next = *list;
+                *list = Some(object);
+            }
+        }
+        assert!(mutator.stats().major_collections > 0);
+        let mut object = *list;
+        let mut value = 200000;
+        while let Some(current) = object {
+            value -= 100;
+            assert_eq!(current.value, value);
+            object = current.next;
+        }
+        assert_eq!(value, 0);
+    }
+}
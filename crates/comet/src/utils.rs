@@ -5,7 +5,7 @@ pub const fn align_down(addr: usize, align: usize) -> usize {
 #[inline(always)]
 pub const fn align_up(addr: usize, align: usize) -> usize {
     // See https://github.com/rust-lang/rust/blob/e620d0f337d0643c757bab791fc7d88d63217704/src/libcore/alloc.rs#L192
-    addr.wrapping_sub(align).wrapping_sub(1) & !align.wrapping_sub(1)
+    addr.wrapping_add(align).wrapping_sub(1) & !align.wrapping_sub(1)
 }
 #[inline(always)]
 pub const fn is_aligned(addr: usize, align: usize) -> bool {
@@ -40,11 +40,30 @@ pub struct ParentKnown;
 
 pub struct Pinned;
 
-impl BitFieldTrait<1, 1> for Pinned {
+pub struct Hashed;
+
+pub struct HashedAndMoved;
+
+pub struct Remembered;
+
+// NOTE: bits 0 and 1 are used by `ColourBit` and bit 3 by `ForwardedBit`, pinned and parent known bits must not overlap them.
+impl BitFieldTrait<2, 1> for Pinned {
     type Next = ParentKnown;
 }
 
-impl BitFieldTrait<1, 1> for ParentKnown {
+impl BitFieldTrait<4, 1> for ParentKnown {
+    type Next = Hashed;
+}
+
+impl BitFieldTrait<5, 1> for Hashed {
+    type Next = HashedAndMoved;
+}
+
+impl BitFieldTrait<6, 1> for HashedAndMoved {
+    type Next = Remembered;
+}
+
+impl BitFieldTrait<7, 1> for Remembered {
     type Next = MarkBit;
 }
 
@@ -118,6 +137,20 @@ pub const fn align_usize(value: usize, align: usize) -> usize {
 
 pub mod mmap;
 pub mod retain_mut;
+
+/// Fill `size` bytes at `start` with [POISON_BYTE](crate::api::POISON_BYTE). GC poisons memory it reclaims so that
+/// dereferencing dangling [Gc](crate::api::Gc) is detected by `check-dangling` feature. No-op in release builds unless
+/// that feature is enabled.
+///
+/// # Safety
+///
+/// Memory must be writable and must not contain live objects.
+#[inline]
+pub unsafe fn poison(start: *mut u8, size: usize) {
+    if cfg!(any(debug_assertions, feature = "check-dangling")) {
+        std::ptr::write_bytes(start, crate::api::POISON_BYTE, size);
+    }
+}
 #[inline]
 pub fn which_power_of_two(value: usize) -> usize {
     value.trailing_zeros() as _
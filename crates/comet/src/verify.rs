@@ -0,0 +1,575 @@
+//! Heap verification.
+//!
+//! Incorrect [Trace](crate::api::Trace) implementations corrupt heap silently: object that is not traced is freed while it is still
+//! referenced and the corruption shows up much later. When heap verification is enabled with
+//! [MutatorRef::set_heap_verification](crate::mutator::MutatorRef::set_heap_verification), or for every heap with `verify-heap`
+//! cargo feature, heap is verified before and after each GC cycle:
+//!
+//! 1. Every object of the heap is walked and its header is checked: vtable must be valid and size must be large enough for the value
+//!    and must fit into memory the object was allocated in.
+//! 2. Roots and every walked object are traced, each traced pointer must point to a valid header of an object of the same heap.
+//!
+//! Verification failure prints a report that lists each error together with the path it was found at and aborts the process:
+//! ```text
+//! heap verification failed before GC(3) of Immix, 1 error(s):
+//!   reference to 0x10c08 that is not an object of this heap
+//!     at shadow stack -> 0x7f3a00d0010 (type_id 0x5c1b2e44) +8 -> 0x7f3a00d0040 (type_id 0x91aa03f2) +16
+//! ```
+//! Path starts at a root, or at an object that is not reachable from roots. Field is given as byte offset from the start of object
+//! data, references that are stored outside of object (e.g. in a `Vec` owned by it) are given as `+?`.
+//!
+//! Heap is verified in GC pause while heap locks are held and mutators are stopped, panic can't unwind out of it without
+//! deadlocking, so failure is not reported as panic.
+//!
+//! Supported by [Immix](crate::immix), [StickyImmix](crate::sticky_immix), [MarkSweep](crate::marksweep) and [MiniMark](crate::minimark).
+//! Memory is walked as follows:
+//! - Immix space: starts of objects in allocated blocks are recorded in object start bitmap while verification is enabled. After GC
+//!   all lines occupied by each object must be marked. StickyImmix nursery collection clears starts of dead nursery objects.
+//! - rosalloc space: objects in runs are found through live bitmap, or through mark bitmap while MarkSweep sweeps lazily.
+//! - [LargeObjectSpace](crate::large_space::LargeObjectSpace): every allocation.
+//! - MiniMark nursery: [BumpPointerSpace](crate::bump_pointer_space::BumpPointerSpace) is walked linearly, nursery memory is zeroed
+//!   after each minor GC so unused ends of TLABs can be skipped. Heap is not verified while concurrent major GC is in progress.
+//!
+//! Verification traces the whole heap twice per GC cycle and is meant for debugging only.
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Write},
+    mem::size_of,
+    ptr::NonNull,
+};
+
+use crate::{
+    api::{HeapObjectHeader, Visitor},
+    bitmap::SpaceBitmap,
+    large_space::PreciseAllocation,
+};
+
+/// Maximal number of errors that are listed in report.
+const MAX_REPORTED_ERRORS: usize = 32;
+
+/// Point of GC cycle heap is verified at.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VerifyPhase {
+    BeforeGc,
+    AfterGc,
+}
+
+impl Display for VerifyPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BeforeGc => write!(f, "before"),
+            Self::AfterGc => write!(f, "after"),
+        }
+    }
+}
+
+/// Where reference was found.
+#[derive(Clone, Copy)]
+enum Source {
+    Root(&'static str),
+    /// Object that is not reachable from roots.
+    Unreachable,
+    /// Field of `object` at `offset` from start of its data, `None` if reference is stored outside of object.
+    Field {
+        object: usize,
+        offset: Option<usize>,
+    },
+}
+
+/// Verifier of a single heap. Heap adds every object it walks with [add_object](Self::add_object) and similar functions, traces
+/// its roots with [trace_roots](Self::trace_roots) and then invokes [finish](Self::finish).
+pub struct HeapVerifier {
+    policy: &'static str,
+    phase: VerifyPhase,
+    gc_id: usize,
+    /// Size of each walked object, `None` if header of object is invalid.
+    objects: HashMap<usize, Option<usize>>,
+    /// Where each traced object was reached from.
+    reached: HashMap<usize, Source>,
+    worklist: Vec<(usize, Source)>,
+    errors: Vec<String>,
+}
+
+impl HeapVerifier {
+    pub fn new(policy: &'static str, phase: VerifyPhase, gc_id: usize) -> Self {
+        Self {
+            policy,
+            phase,
+            gc_id,
+            objects: HashMap::new(),
+            reached: HashMap::new(),
+            worklist: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+    /// Check header of small object found by heap walk and record it as valid object. `limit` is end of memory region that object
+    /// is allocated in. Returns true if header is valid.
+    ///
+    /// # Safety
+    ///
+    /// `object` must point to readable memory of at least [HeapObjectHeader] size.
+    pub unsafe fn add_object(&mut self, object: *mut HeapObjectHeader, limit: *const u8) -> bool {
+        let size = (*object).size();
+        let result = if (*object).is_precise() {
+            Err("small object has size of large object".to_string())
+        } else {
+            Self::check_header(object, size, limit as usize)
+        };
+        self.record(object, size, result)
+    }
+    /// Check header of large object and record it as valid object. Returns true if header is valid.
+    ///
+    /// # Safety
+    ///
+    /// `allocation` must be allocation of [LargeObjectSpace](crate::large_space::LargeObjectSpace).
+    pub unsafe fn add_large_object(&mut self, allocation: *mut PreciseAllocation) -> bool {
+        let object = (*allocation).cell();
+        let size = (*allocation).cell_size();
+        let result = if !(*object).is_precise() {
+            Err("large object has size of small object".to_string())
+        } else {
+            Self::check_header(object, size, object as usize + size)
+        };
+        self.record(object, size, result)
+    }
+    /// Add objects whose starts are set in `bitmap` between `begin` and `end`.
+    ///
+    /// # Safety
+    ///
+    /// Every bit set in `bitmap` in this range must point into memory between `begin` and `end`.
+    pub unsafe fn add_marked_objects(
+        &mut self,
+        bitmap: &SpaceBitmap<8>,
+        begin: *const u8,
+        end: *const u8,
+    ) {
+        bitmap.visit_marked_range(begin, end, |object| {
+            self.add_object(object, end);
+        });
+    }
+    /// Report error that heap found in `object` while walking it.
+    ///
+    /// # Safety
+    ///
+    /// `object` must point to readable memory of at least [HeapObjectHeader] size.
+    pub unsafe fn report(&mut self, object: *const HeapObjectHeader, message: impl Display) {
+        let description = describe(object as usize, (*object).type_id());
+        self.errors.push(format!("{}: {}", description, message));
+    }
+    /// Trace roots of kind `name`, e.g. `"shadow stack"`.
+    pub fn trace_roots(&mut self, name: &'static str, trace: impl FnOnce(&mut dyn Visitor)) {
+        trace(&mut RootCollector {
+            name,
+            worklist: &mut self.worklist,
+        });
+        self.drain();
+    }
+    /// Trace objects that are not reachable from roots. If any error was found prints report to stderr and aborts the process.
+    pub fn finish(self) {
+        if let Err(report) = self.check() {
+            eprintln!("{}", report);
+            std::process::abort();
+        }
+    }
+
+    /// Trace objects that are not reachable from roots and return report if any error was found.
+    fn check(mut self) -> Result<(), String> {
+        // references of unreachable objects must stay valid until the objects are freed.
+        let mut unreachable = self
+            .objects
+            .iter()
+            .filter(|(object, size)| size.is_some() && !self.reached.contains_key(object))
+            .map(|(object, _)| *object)
+            .collect::<Vec<_>>();
+        unreachable.sort_unstable();
+        for object in unreachable {
+            self.worklist.push((object, Source::Unreachable));
+            self.drain();
+        }
+        if self.errors.is_empty() {
+            return Ok(());
+        }
+        let mut report = format!(
+            "heap verification failed {} GC({}) of {}, {} error(s):",
+            self.phase,
+            self.gc_id,
+            self.policy,
+            self.errors.len()
+        );
+        for error in self.errors.iter().take(MAX_REPORTED_ERRORS) {
+            let _ = write!(report, "\n  {}", error);
+        }
+        if self.errors.len() > MAX_REPORTED_ERRORS {
+            let _ = write!(
+                report,
+                "\n  ... and {} more",
+                self.errors.len() - MAX_REPORTED_ERRORS
+            );
+        }
+        Err(report)
+    }
+
+    unsafe fn check_header(
+        object: *mut HeapObjectHeader,
+        size: usize,
+        limit: usize,
+    ) -> Result<(), String> {
+        if !(object as usize).is_multiple_of(8) {
+            return Err("object is not aligned".to_string());
+        }
+        if (*object).is_forwarded() {
+            return Err(format!("object is forwarded to {:#x}", (*object).vtable()));
+        }
+        let vtable = (*object).vtable();
+        if vtable == 0 || !vtable.is_multiple_of(size_of::<usize>()) || (*object).is_free() {
+            return Err(format!("invalid vtable {:#x}", vtable));
+        }
+        let metadata = (*object).value.metadata;
+        if !metadata.align_of().is_power_of_two() {
+            return Err(format!("invalid vtable {:#x}", vtable));
+        }
+        if !size.is_multiple_of(8) || size < size_of::<HeapObjectHeader>() + metadata.size_of() {
+            return Err(format!("invalid size {}", size));
+        }
+        if object as usize + size > limit {
+            return Err(format!(
+                "object of {} bytes does not fit into its memory region",
+                size
+            ));
+        }
+        let value_size = (*object).get_dyn().allocation_size();
+        if size < size_of::<HeapObjectHeader>() + value_size {
+            return Err(format!(
+                "size {} is too small for value of {} bytes",
+                size, value_size
+            ));
+        }
+        Ok(())
+    }
+
+    unsafe fn record(
+        &mut self,
+        object: *mut HeapObjectHeader,
+        size: usize,
+        result: Result<(), String>,
+    ) -> bool {
+        match result {
+            Ok(()) => {
+                self.objects.insert(object as usize, Some(size));
+                true
+            }
+            Err(message) => {
+                self.objects.insert(object as usize, None);
+                self.report(object, message);
+                false
+            }
+        }
+    }
+
+    fn drain(&mut self) {
+        while let Some((object, source)) = self.worklist.pop() {
+            let size = match self.objects.get(&object) {
+                Some(Some(size)) => *size,
+                Some(None) => {
+                    self.error(
+                        source,
+                        format!("reference to {:#x} that has invalid header", object),
+                    );
+                    continue;
+                }
+                None => {
+                    self.error(
+                        source,
+                        format!(
+                            "reference to {:#x} that is not an object of this heap",
+                            object
+                        ),
+                    );
+                    continue;
+                }
+            };
+            if self.reached.contains_key(&object) {
+                continue;
+            }
+            self.reached.insert(object, source);
+            let header = object as *mut HeapObjectHeader;
+            unsafe {
+                (*header).get_dyn().trace(&mut ReferenceCollector {
+                    object,
+                    data: (*header).data() as usize,
+                    end: object + size,
+                    worklist: &mut self.worklist,
+                });
+            }
+        }
+    }
+
+    fn error(&mut self, source: Source, message: String) {
+        let path = self.path(source);
+        self.errors.push(format!("{}\n    at {}", message, path));
+    }
+
+    fn path(&self, mut source: Source) -> String {
+        let mut segments = vec![];
+        loop {
+            match source {
+                Source::Root(name) => {
+                    segments.push(name.to_string());
+                    break;
+                }
+                Source::Unreachable => {
+                    segments.push("unreachable object".to_string());
+                    break;
+                }
+                Source::Field { object, offset } => {
+                    let type_id = unsafe { (*(object as *const HeapObjectHeader)).type_id() };
+                    segments.push(match offset {
+                        Some(offset) => format!("{} +{}", describe(object, type_id), offset),
+                        None => format!("{} +?", describe(object, type_id)),
+                    });
+                    source = self.reached[&object];
+                }
+            }
+        }
+        segments.reverse();
+        segments.join(" -> ")
+    }
+}
+
+fn describe(object: usize, type_id: u32) -> String {
+    format!("{:#x} (type_id {:#010x})", object, type_id)
+}
+
+struct RootCollector<'a> {
+    name: &'static str,
+    worklist: &'a mut Vec<(usize, Source)>,
+}
+
+impl Visitor for RootCollector<'_> {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        self.worklist
+            .push((root.as_ptr() as usize, Source::Root(self.name)));
+    }
+}
+
+struct ReferenceCollector<'a> {
+    object: usize,
+    data: usize,
+    end: usize,
+    worklist: &'a mut Vec<(usize, Source)>,
+}
+
+impl Visitor for ReferenceCollector<'_> {
+    fn mark_object(&mut self, root: &mut NonNull<HeapObjectHeader>) {
+        let slot = root as *mut NonNull<HeapObjectHeader> as usize;
+        let offset = if slot >= self.data && slot < self.end {
+            Some(slot - self.data)
+        } else {
+            None
+        };
+        self.worklist.push((
+            root.as_ptr() as usize,
+            Source::Field {
+                object: self.object,
+                offset,
+            },
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        mem::size_of,
+        process::{Command, Stdio},
+        ptr::NonNull,
+        time::{Duration, Instant},
+    };
+
+    use super::{describe, HeapVerifier, VerifyPhase};
+    use crate::{
+        api::{vtable_of, Collectable, Finalize, Gc, HeapObjectHeader, Trace, Visitor},
+        gc_base::AllocationSpace,
+        immix::{instantiate_immix, Immix},
+        small_type_id,
+    };
+
+    struct Node {
+        value: i64,
+        next: Option<Gc<Node, Immix>>,
+    }
+
+    unsafe impl Trace for Node {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            self.next.trace(vis);
+        }
+    }
+    unsafe impl Finalize for Node {}
+    impl Collectable for Node {}
+
+    struct Large {
+        _data: [u64; 4096],
+    }
+
+    unsafe impl Trace for Large {}
+    unsafe impl Finalize for Large {}
+    impl Collectable for Large {}
+
+    struct Raw {
+        next: NonNull<HeapObjectHeader>,
+    }
+
+    unsafe impl Trace for Raw {
+        fn trace(&mut self, vis: &mut dyn Visitor) {
+            vis.mark_object(&mut self.next);
+        }
+    }
+    unsafe impl Finalize for Raw {}
+    impl Collectable for Raw {}
+
+    const RAW_SIZE: usize = size_of::<HeapObjectHeader>() + size_of::<Raw>();
+
+    unsafe fn write_raw(at: *mut u64, next: usize) -> *mut HeapObjectHeader {
+        let header = at.cast::<HeapObjectHeader>();
+        (*header).set_metadata(vtable_of::<Raw>());
+        (*header).type_id = small_type_id::<Raw>();
+        (*header).set_size(RAW_SIZE);
+        (*header).data().cast::<usize>().cast_mut().write(next);
+        header
+    }
+
+    #[test]
+    fn test_report() {
+        let mut memory = vec![0u64; 3 * RAW_SIZE / 8];
+        let begin = memory.as_mut_ptr();
+        let end = unsafe { begin.cast::<u8>().add(memory.len() * 8) };
+        let result = unsafe {
+            let second = begin.add(RAW_SIZE / 8);
+            let broken = begin.add(2 * RAW_SIZE / 8);
+            let first = write_raw(begin, second as usize);
+            let second = write_raw(second, 0x1000);
+            write_raw(broken, second as usize);
+            (*broken.cast::<HeapObjectHeader>()).set_vtable(0);
+
+            let mut verifier = HeapVerifier::new("Test", VerifyPhase::AfterGc, 7);
+            assert!(verifier.add_object(first, end));
+            assert!(verifier.add_object(second, end));
+            assert!(!verifier.add_object(broken.cast(), end));
+            verifier.trace_roots("test root", |vis| {
+                vis.mark_object(&mut NonNull::new_unchecked(first));
+                vis.mark_object(&mut NonNull::new_unchecked(broken.cast()));
+            });
+            verifier.check()
+        };
+        let report = result.unwrap_err();
+        let type_id = small_type_id::<Raw>();
+        let first = begin as usize;
+        let second = first + RAW_SIZE;
+        let broken = second + RAW_SIZE;
+        assert!(report.starts_with("heap verification failed after GC(7) of Test, 3 error(s):"));
+        assert!(report.contains(&format!(
+            "{}: invalid vtable 0x0",
+            describe(broken, type_id)
+        )));
+        assert!(report.contains(&format!(
+            "reference to {:#x} that has invalid header\n    at test root",
+            broken
+        )));
+        assert!(report.contains(&format!(
+            "reference to 0x1000 that is not an object of this heap\n    at test root -> {} +0 -> {} +0",
+            describe(first, type_id),
+            describe(second, type_id)
+        )));
+    }
+
+    #[test]
+    fn test_immix_verification() {
+        let mut mutator = instantiate_immix(
+            64 * 1024 * 1024,
+            4 * 1024 * 1024,
+            2 * 1024 * 1024,
+            64 * 1024 * 1024,
+            false,
+        );
+        mutator.set_heap_verification(true);
+        letroot!(head = mutator.shadow_stack(), None::<Gc<Node, Immix>>);
+        for value in 0..10000 {
+            let next = *head;
+            *head = Some(mutator.allocate(Node { value, next }, AllocationSpace::New));
+            // garbage that is freed by the next cycle. `next` is not rooted, so it can't be used after allocation.
+            let next = *head;
+            mutator.allocate(Node { value, next }, AllocationSpace::New);
+            if value % 1000 == 0 {
+                mutator.allocate(Large { _data: [0; 4096] }, AllocationSpace::New);
+                mutator.collect(&mut []);
+            }
+        }
+        mutator.full_collection(&mut []);
+        mutator.set_heap_verification(false);
+        mutator.collect(&mut []);
+
+        let mut node = *head;
+        let mut expected = 10000;
+        while let Some(current) = node {
+            expected -= 1;
+            assert_eq!(current.value, expected);
+            node = current.next;
+        }
+        assert_eq!(expected, 0);
+    }
+
+    /// Set in child process of [test_failure_aborts].
+    const ABORT_CHILD_ENV: &str = "COMET_VERIFY_ABORT_CHILD";
+
+    #[test]
+    fn test_failure_aborts() {
+        if std::env::var_os(ABORT_CHILD_ENV).is_some() {
+            let mut mutator = instantiate_immix(
+                64 * 1024 * 1024,
+                4 * 1024 * 1024,
+                2 * 1024 * 1024,
+                64 * 1024 * 1024,
+                false,
+            );
+            mutator.set_heap_verification(true);
+            let next = unsafe { NonNull::new_unchecked(0x1000 as *mut HeapObjectHeader) };
+            letroot!(
+                _broken = mutator.shadow_stack(),
+                mutator.allocate(Raw { next }, AllocationSpace::New)
+            );
+            // heap is verified before the cycle, so broken reference is never marked.
+            mutator.collect(&mut []);
+            return;
+        }
+        // failure is detected in GC pause, run it in child process so that abort or deadlock does not take the test runner down.
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "verify::tests::test_failure_aborts",
+                "--nocapture",
+            ])
+            .env(ABORT_CHILD_ENV, "1")
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let status = loop {
+            if let Some(status) = child.try_wait().unwrap() {
+                break status;
+            }
+            if Instant::now() > deadline {
+                let _ = child.kill();
+                panic!("heap verification failure hangs instead of aborting");
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        let mut stderr = String::new();
+        std::io::Read::read_to_string(&mut child.stderr.take().unwrap(), &mut stderr).unwrap();
+        assert!(!status.success());
+        // enabling verification might have collected already, so GC number is not checked.
+        assert!(stderr.contains("heap verification failed before GC("));
+        assert!(stderr.contains(") of Immix, 1 error(s):"));
+        assert!(stderr.contains("reference to 0x1000 that is not an object of this heap"));
+    }
+}